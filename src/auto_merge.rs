@@ -0,0 +1,188 @@
+//! Merge in background once enough of the data files is reclaimable, so embedders do not have to
+//! run their own timer loop around `Engine::merge`. Merges are spaced by at least
+//! `AutoMergeOptions::min_interval`, and never started within the quiet hours.
+
+use std::{
+    sync::{Arc, Condvar, Mutex, Weak},
+    thread::{self, JoinHandle},
+};
+
+use log::{info, warn};
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+    options::{AutoMergeOptions, Options},
+    utils::time::now_millis,
+};
+
+const MILLIS_PER_HOUR: u64 = 60 * 60 * 1000;
+
+impl Engine {
+    /// Open an engine shared between threads, merging in background if `Options::auto_merge` is
+    /// set.
+    pub fn open_shared(opts: Options) -> Result<Arc<Self>> {
+        let engine = Arc::new(Engine::open(opts)?);
+        if let Some(auto_merge_opts) = engine.options.auto_merge.clone() {
+            *engine.auto_merge.lock().unwrap() = Some(AutoMerge::start(&engine, auto_merge_opts));
+        }
+        Ok(engine)
+    }
+}
+
+/// Merges an engine in background when due, until it is stopped, or the engine is closed or
+/// dropped.
+pub(crate) struct AutoMerge {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl AutoMerge {
+    /// Check ENGINE every `OPTS.check_interval`, merging it when due.
+    fn start(engine: &Arc<Engine>, opts: AutoMergeOptions) -> Self {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let weak = Arc::downgrade(engine);
+        let stopped_clone = stopped.clone();
+        let handle = thread::spawn(move || Self::run(weak, opts, stopped_clone));
+        Self {
+            stopped,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stop merging, waiting for a running merge to finish.
+    pub(crate) fn stop(&mut self) {
+        let (lock, cvar) = &*self.stopped;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            // The engine may be dropped by the merging thread itself, which cannot join itself.
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+
+    fn run(engine: Weak<Engine>, opts: AutoMergeOptions, stopped: Arc<(Mutex<bool>, Condvar)>) {
+        let (lock, cvar) = &*stopped;
+        let mut last_merged_at: Option<u64> = None;
+        loop {
+            let guard = lock.lock().unwrap();
+            let (guard, _) = cvar
+                .wait_timeout_while(guard, opts.check_interval, |stopped| !*stopped)
+                .unwrap();
+            if *guard {
+                return;
+            }
+            drop(guard);
+
+            let now = now_millis();
+            if last_merged_at.is_some_and(|merged_at| {
+                now.saturating_sub(merged_at) < opts.min_interval.as_millis() as u64
+            }) {
+                continue;
+            }
+            if opts
+                .quiet_hours
+                .is_some_and(|quiet_hours| in_quiet_hours(now, quiet_hours))
+            {
+                continue;
+            }
+
+            let engine = match engine.upgrade() {
+                Some(engine) if !engine.is_closed() => engine,
+                _ => return,
+            };
+            if !engine.should_merge(&engine.merge_metrics()) {
+                continue;
+            }
+            match engine.merge() {
+                Ok(stats) => {
+                    info!("merged database in background: {:?}", stats);
+                    last_merged_at = Some(now_millis());
+                }
+                Err(Errors::MergeRationUnreached | Errors::MergeInProgress) => {}
+                Err(e) => warn!("failed to merge database in background: {:?}", e),
+            }
+        }
+    }
+}
+
+impl Drop for AutoMerge {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Check whether NOW, in milliseconds since the epoch, falls within the UTC hours [START, END),
+/// which wrap around midnight if START is greater than END.
+fn in_quiet_hours(now: u64, (start, end): (u8, u8)) -> bool {
+    let hour = ((now / MILLIS_PER_HOUR) % 24) as u8;
+    if start <= end {
+        start <= hour && hour < end
+    } else {
+        hour >= start || hour < end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, sync::atomic::Ordering, time::Duration};
+
+    use super::*;
+
+    use crate::utils::rand_kv::{get_test_key, get_test_value};
+
+    #[test]
+    fn test_in_quiet_hours() {
+        let at = |hour: u64| 3 * 24 * MILLIS_PER_HOUR + hour * MILLIS_PER_HOUR + 1;
+        assert!(in_quiet_hours(at(2), (1, 5)));
+        assert!(!in_quiet_hours(at(5), (1, 5)));
+        assert!(in_quiet_hours(at(23), (22, 6)));
+        assert!(in_quiet_hours(at(3), (22, 6)));
+        assert!(!in_quiet_hours(at(12), (22, 6)));
+        assert!(!in_quiet_hours(at(12), (3, 3)));
+    }
+
+    #[test]
+    fn test_engine_auto_merge() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-auto-merge");
+        opts.data_file_size = 32 * 1024;
+        opts.data_file_merge_ratio = 0.2;
+        opts.auto_merge = Some(AutoMergeOptions {
+            check_interval: Duration::from_millis(10),
+            min_interval: Duration::from_secs(60 * 60),
+            quiet_hours: None,
+        });
+
+        // Garbage is created before auto merge starts, so it is all merged at once.
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..500 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        std::mem::drop(engine);
+
+        let engine = Engine::open_shared(opts.clone()).expect("failed to open engine");
+
+        let mut merged = false;
+        for _ in 0..1000 {
+            thread::sleep(Duration::from_millis(10));
+            if engine.reclaim_size.load(Ordering::SeqCst) == 0 {
+                merged = true;
+                break;
+            }
+        }
+        assert!(merged);
+        assert!(engine.get(get_test_key(0)).is_err());
+        assert!(engine.get(get_test_key(999)).is_ok());
+
+        assert!(engine.close().is_ok());
+        assert!(engine.auto_merge.lock().unwrap().is_none());
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,224 @@
+//! Scheduled backups of the database. Each backup is a live clone of the database directory,
+//! created in its own subdirectory of the backup directory, so any backup can be opened as an
+//! engine directly. Old backups are rotated by count and age after each backup.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, Weak},
+    thread::{self, JoinHandle},
+};
+
+use log::{info, warn};
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+    options::BackupOptions,
+    utils::time::now_millis,
+};
+
+const BACKUP_DIR_PREFIX: &str = "backup-";
+
+impl Engine {
+    /// Back up the database to a new subdirectory of `OPTS.dir_path`, then remove the backups
+    /// beyond `OPTS.max_backups` or older than `OPTS.max_age`. Return the directory of the new
+    /// backup.
+    pub fn backup(&self, opts: &BackupOptions) -> Result<PathBuf> {
+        fs::create_dir_all(&opts.dir_path).map_err(|_| Errors::FailedToCreateDatabaseDir)?;
+
+        // Backups created within the same millisecond get the next free name.
+        let mut created_at = now_millis();
+        let mut backup_path = get_backup_path(&opts.dir_path, created_at);
+        while backup_path.exists() {
+            created_at += 1;
+            backup_path = get_backup_path(&opts.dir_path, created_at);
+        }
+        self.clone_to(&backup_path)?;
+
+        rotate_backups(opts, created_at)?;
+        Ok(backup_path)
+    }
+}
+
+/// Backs up an engine periodically in background, until it is stopped, or the engine is closed
+/// or dropped.
+pub struct BackupScheduler {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackupScheduler {
+    /// Back up ENGINE every `OPTS.interval`, starting one interval from now.
+    pub fn start(engine: &Arc<Engine>, opts: BackupOptions) -> Self {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let weak = Arc::downgrade(engine);
+        let stopped_clone = stopped.clone();
+        let handle = thread::spawn(move || Self::run(weak, opts, stopped_clone));
+        Self {
+            stopped,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stop scheduling backups, waiting for a running backup to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let (lock, cvar) = &*self.stopped;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+
+    fn run(engine: Weak<Engine>, opts: BackupOptions, stopped: Arc<(Mutex<bool>, Condvar)>) {
+        let (lock, cvar) = &*stopped;
+        loop {
+            let guard = lock.lock().unwrap();
+            let (guard, _) = cvar
+                .wait_timeout_while(guard, opts.interval, |stopped| !*stopped)
+                .unwrap();
+            if *guard {
+                return;
+            }
+            drop(guard);
+
+            let engine = match engine.upgrade() {
+                Some(engine) if !engine.is_closed() => engine,
+                _ => return,
+            };
+            let res = engine.backup(&opts);
+            match &res {
+                Ok(path) => info!("backed up database to {:?}", path),
+                Err(e) => warn!("failed to back up database: {:?}", e),
+            }
+            if let Some(on_backup) = &opts.on_backup {
+                on_backup(&res);
+            }
+        }
+    }
+}
+
+impl Drop for BackupScheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Get the directory of the backup created at CREATED_AT milliseconds since the epoch. Names are
+/// zero-padded, so they sort by creation time.
+fn get_backup_path(dir_path: &Path, created_at: u64) -> PathBuf {
+    dir_path.join(std::format!("{}{:020}", BACKUP_DIR_PREFIX, created_at))
+}
+
+/// Remove the backups under `OPTS.dir_path` beyond `OPTS.max_backups` or older than
+/// `OPTS.max_age`, where NOW is the creation time of the latest backup.
+fn rotate_backups(opts: &BackupOptions, now: u64) -> Result<()> {
+    let dir = fs::read_dir(&opts.dir_path).map_err(|_| Errors::FailedToReadDatabaseDir)?;
+    let mut backups: Vec<(u64, PathBuf)> = dir
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name();
+            let created_at = file_name
+                .to_str()?
+                .strip_prefix(BACKUP_DIR_PREFIX)?
+                .parse::<u64>()
+                .ok()?;
+            Some((created_at, entry.path()))
+        })
+        .collect();
+    backups.sort();
+
+    let max_age = opts.max_age.map(|max_age| max_age.as_millis() as u64);
+    let num = backups.len();
+    for (i, (created_at, path)) in backups.into_iter().enumerate() {
+        let too_many = opts.max_backups > 0 && num - i > opts.max_backups;
+        let too_old = max_age.is_some_and(|max_age| now.saturating_sub(created_at) > max_age);
+        if too_many || too_old {
+            fs::remove_dir_all(path).map_err(|_| Errors::FailedToReadDatabaseDir)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_engine_backup() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-backup");
+        let mut backup_opts = BackupOptions::default();
+        backup_opts.dir_path = PathBuf::from("/tmp/bitcask-rs-backup-target");
+        backup_opts.max_backups = 2;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let mut backups = Vec::new();
+        for i in 0..3 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+            backups.push(engine.backup(&backup_opts).unwrap());
+        }
+        assert!(!backups[0].exists());
+        let mut backup_engine_opts = opts.clone();
+        backup_engine_opts.dir_path = backups[2].clone();
+        let backup_engine = Engine::open(backup_engine_opts).expect("failed to open backup");
+        assert_eq!(backup_engine.list_keys().unwrap().len(), 3);
+        std::mem::drop(backup_engine);
+
+        // Backups older than the maximum age are removed.
+        backup_opts.max_age = Some(Duration::ZERO);
+        thread::sleep(Duration::from_millis(5));
+        let latest = engine.backup(&backup_opts).unwrap();
+        assert_eq!(fs::read_dir(&backup_opts.dir_path).unwrap().count(), 1);
+        assert!(latest.is_dir());
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(backup_opts.dir_path).expect("failed to remove path");
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_backup_scheduler() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-backup-scheduler");
+        let backup_num = Arc::new(AtomicUsize::new(0));
+        let backup_num_clone = backup_num.clone();
+        let mut backup_opts = BackupOptions::default();
+        backup_opts.dir_path = PathBuf::from("/tmp/bitcask-rs-backup-scheduler-target");
+        backup_opts.interval = Duration::from_millis(20);
+        backup_opts.on_backup = Some(Arc::new(move |res| {
+            assert!(res.is_ok());
+            backup_num_clone.fetch_add(1, Ordering::SeqCst);
+        }));
+        let engine = Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+        assert!(engine.put(get_test_key(0), get_test_value(0)).is_ok());
+
+        let scheduler = BackupScheduler::start(&engine, backup_opts.clone());
+        while backup_num.load(Ordering::SeqCst) < 2 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        scheduler.stop();
+        let stopped_num = backup_num.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(backup_num.load(Ordering::SeqCst), stopped_num);
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(backup_opts.dir_path).expect("failed to remove path");
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
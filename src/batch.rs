@@ -22,8 +22,12 @@
 //! key, indexing, and timestamps, this may insufficient as the disk memory grows rapidly.
 
 use std::{
-    collections::HashMap,
-    sync::{atomic::Ordering, Arc, Mutex},
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     usize,
 };
 
@@ -33,7 +37,8 @@ use crate::{
     data::log_record::{LogRecord, LogRecordType},
     db::{encode_log_record_key, Engine},
     errors::{Errors, Result},
-    options::{IndexType, WriteBatchOptions},
+    options::{IndexType, IteratorOptions, WriteBatchOptions},
+    utils::time::now_millis,
 };
 
 const TXN_FIN_KEY: &[u8] = "txn-fin".as_bytes();
@@ -44,10 +49,28 @@ pub(crate) const NON_TRANSACTION_SEQUENCE: usize = 0;
 /// - `engine` is a reference to the current bitcask instance, used to provide sequence
 ///     number to a transaction.
 /// - `options` is the configuration for the transaction.
+/// - `savepoints` stores, for each savepoint, the pending write of each key changed since
+///   then, as it was when the savepoint is set.
+/// - `pending_bytes` is the size of the keys and values of `pending_writes`.
+/// - `deleted_prefixes` records the prefixes deleted by `delete_prefix`, whose keys are listed
+///   at commit time.
 pub struct WriteBatch<'a> {
     pending_writes: Arc<Mutex<HashMap<Vec<u8>, LogRecord>>>,
     engine: &'a Engine,
     options: WriteBatchOptions,
+    savepoints: Arc<Mutex<Vec<Savepoint>>>,
+    pending_bytes: Arc<AtomicUsize>,
+    deleted_prefixes: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+/// Changes undone by rolling back to a savepoint, where:
+/// - `replaced` stores the pending writes replaced since the savepoint, None if the key had no
+///   pending write.
+/// - `prefix_num` is the number of prefixes deleted before the savepoint.
+#[derive(Default)]
+struct Savepoint {
+    replaced: HashMap<Vec<u8>, Option<LogRecord>>,
+    prefix_num: usize,
 }
 
 impl Engine {
@@ -63,8 +86,142 @@ impl Engine {
             pending_writes: Arc::new(Mutex::new(HashMap::new())),
             engine: self,
             options,
+            savepoints: Arc::new(Mutex::new(Vec::new())),
+            pending_bytes: Arc::new(AtomicUsize::new(0)),
+            deleted_prefixes: Arc::new(Mutex::new(Vec::new())),
         })
     }
+
+    /// Delete all entries with key in range [START, END) in a single transaction, so either all
+    /// or none of them are deleted after a crash. Return the number of entries deleted.
+    pub fn delete_range(&self, start: Bytes, end: Bytes) -> Result<usize> {
+        let wb = self.new_write_batch(WriteBatchOptions {
+            max_batch_num: usize::MAX,
+            sync_writes: self.options.sync_writes,
+            ..Default::default()
+        })?;
+
+        let mut keys = Vec::new();
+        let mut index_iter = self.index.range(
+            Bound::Included(start.to_vec()),
+            Bound::Excluded(end.to_vec()),
+        );
+        while let Some((key, _)) = index_iter.next() {
+            keys.push(Bytes::from(key.clone()));
+        }
+        for key in &keys {
+            wb.delete(key.clone())?;
+        }
+        wb.commit()?;
+        Ok(keys.len())
+    }
+
+    /// Move the value of OLD to NEW in a single transaction, so a crash never leaves both or
+    /// neither of them. Fail with `Errors::KeyNotFound` if OLD does not exist.
+    pub fn rename_key(&self, old: Bytes, new: Bytes) -> Result<()> {
+        if old.is_empty() || new.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        // OLD is not overwritten between the read and the transaction.
+        let _key_locks = self.key_locks.lock_all([old.as_ref(), new.as_ref()]);
+        let log_record = self.get_live_record(old.clone())?;
+        if old == new {
+            return Ok(());
+        }
+        // The value was accepted under OLD already.
+        self.check_entry_size(&new, &[])?;
+
+        let wb = self.new_write_batch(WriteBatchOptions {
+            max_batch_num: 2,
+            sync_writes: self.options.sync_writes,
+            ..Default::default()
+        })?;
+        // Only the key changes, NEW keeps the expiry, the user flags and the blob of OLD.
+        let log_record = LogRecord {
+            key: new.to_vec(),
+            timestamp: None,
+            ..log_record
+        };
+        wb.stage(
+            &mut wb.pending_writes.lock().unwrap(),
+            new.to_vec(),
+            Some(log_record),
+        );
+        wb.delete(old)?;
+        let pending_writes = wb.pending_writes.lock().unwrap();
+        wb.commit_key_locked(&pending_writes, &[])
+    }
+
+    /// Write RECORDS to the data file as the transaction SEQUENCE_NUMBER, and update the index
+    /// once the transaction is complete. The entries deleted by RECORDS are moved into the trash
+    /// beforehand. Must be called with `batch_commit_lock` and the key locks of RECORDS held.
+    pub(crate) fn write_transaction(
+        &self,
+        sequence_number: usize,
+        records: &[&LogRecord],
+        sync_writes: bool,
+    ) -> Result<()> {
+        for item in records {
+            if item.record_type == LogRecordType::Deleted {
+                self.move_to_trash(&Bytes::from(item.key.clone()))?;
+            }
+        }
+
+        // Writes all the changes into the data file at once, all stamped with the commit time.
+        let timestamp = now_millis();
+        let mut log_records: Vec<LogRecord> = records
+            .iter()
+            .map(|item| LogRecord {
+                key: encode_log_record_key(item.key.clone(), sequence_number),
+                value: item.value.clone(),
+                record_type: item.record_type,
+                timestamp: Some(timestamp),
+                user_flags: item.user_flags,
+            })
+            .collect();
+
+        // Append a delimiter at the end of current commitment, which indicates the whole commit
+        // is successful. On failure, we can roll back to the latest fin_record to ensure data
+        // consistency.
+        log_records.push(LogRecord {
+            key: encode_log_record_key(TXN_FIN_KEY.to_vec(), sequence_number),
+            value: Default::default(),
+            record_type: LogRecordType::TxnFinished,
+            timestamp: Some(timestamp),
+            user_flags: 0,
+        });
+        let positions = self.append_log_records(&log_records)?;
+        let position: HashMap<_, _> = records
+            .iter()
+            .zip(positions)
+            .map(|(item, pos)| (item.key.clone(), pos))
+            .collect();
+
+        if sync_writes {
+            self.sync()?;
+        }
+
+        // Update the indexer after commit.
+        for item in records {
+            match item.record_type {
+                LogRecordType::Normal | LogRecordType::Expiring | LogRecordType::Blob => {
+                    let record_pos = position.get(&item.key).unwrap();
+                    if let Some(old_pos) = self.index.put(item.key.clone(), *record_pos) {
+                        self.add_reclaim_size(&old_pos);
+                    }
+                }
+                LogRecordType::Deleted => {
+                    if let Some(old_pos) = self.index.delete(item.key.clone()) {
+                        self.add_reclaim_size(&old_pos);
+                    }
+                }
+                _ => (),
+            };
+        }
+        self.notify_change_sink();
+
+        Ok(())
+    }
 }
 
 impl WriteBatch<'_> {
@@ -73,17 +230,19 @@ impl WriteBatch<'_> {
         if key.is_empty() {
             return Err(Errors::KeyIsEmpty);
         }
+        self.engine.check_entry_size(&key, &value)?;
 
         let log_record = LogRecord {
             key: key.to_vec(),
             value: value.to_vec(),
             record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
         };
 
         let mut pending_write = self.pending_writes.lock().unwrap();
-        pending_write.insert(key.to_vec(), log_record);
-
-        Ok(())
+        self.stage(&mut pending_write, key.to_vec(), Some(log_record));
+        self.auto_commit(&mut pending_write)
     }
 
     /// Delete the entry with key KEY.
@@ -96,90 +255,253 @@ impl WriteBatch<'_> {
         let index_pos = self.engine.index.get(key.to_vec());
         if index_pos.is_none() {
             if pending_write.contains_key(&key.to_vec()) {
-                pending_write.remove(&key.to_vec());
+                self.stage(&mut pending_write, key.to_vec(), None);
             }
             return Ok(());
         }
 
-        let log_record = LogRecord {
-            key: key.to_vec(),
-            value: Default::default(),
-            record_type: LogRecordType::Deleted,
+        let log_record = LogRecord::new_tombstone(key.to_vec(), now_millis());
+
+        self.stage(&mut pending_write, key.to_vec(), Some(log_record));
+        self.auto_commit(&mut pending_write)
+    }
+
+    /// Delete all the entries with key starting with PREFIX, along with the pending writes of
+    /// such keys. The keys are listed at commit time in the same transaction as the other pending
+    /// writes, so the entries written in between are deleted too, while the writes staged
+    /// afterwards are kept.
+    pub fn delete_prefix(&self, prefix: Bytes) -> Result<()> {
+        if prefix.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let mut pending_write = self.pending_writes.lock().unwrap();
+        let keys: Vec<Vec<u8>> = pending_write
+            .keys()
+            .filter(|key| key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for key in keys {
+            self.stage(&mut pending_write, key, None);
+        }
+        self.deleted_prefixes.lock().unwrap().push(prefix.to_vec());
+        Ok(())
+    }
+
+    /// Set a savepoint, which the pending writes can be rolled back to by
+    /// `rollback_to_savepoint`. Savepoints can be nested.
+    pub fn set_savepoint(&self) {
+        let deleted_prefixes = self.deleted_prefixes.lock().unwrap();
+        self.savepoints.lock().unwrap().push(Savepoint {
+            replaced: HashMap::new(),
+            prefix_num: deleted_prefixes.len(),
+        });
+    }
+
+    /// Undo the changes staged since the last savepoint, and remove it. Fail with
+    /// `Errors::SavepointNotFound` if no savepoint is set. Savepoints set before an automatic
+    /// commit are removed by it.
+    pub fn rollback_to_savepoint(&self) -> Result<()> {
+        let mut pending_write = self.pending_writes.lock().unwrap();
+        let mut deleted_prefixes = self.deleted_prefixes.lock().unwrap();
+        let savepoint = self
+            .savepoints
+            .lock()
+            .unwrap()
+            .pop()
+            .ok_or(Errors::SavepointNotFound)?;
+        for (key, log_record) in savepoint.replaced {
+            self.replace(&mut pending_write, key, log_record);
+        }
+        deleted_prefixes.truncate(savepoint.prefix_num);
+        Ok(())
+    }
+
+    /// Replace the pending write of KEY in PENDING_WRITE with LOG_RECORD, or remove it if
+    /// LOG_RECORD is None. The replaced write is kept by the last savepoint, unless KEY was
+    /// already changed since then.
+    fn stage(
+        &self,
+        pending_write: &mut HashMap<Vec<u8>, LogRecord>,
+        key: Vec<u8>,
+        log_record: Option<LogRecord>,
+    ) {
+        let replaced = self.replace(pending_write, key.clone(), log_record);
+        if let Some(savepoint) = self.savepoints.lock().unwrap().last_mut() {
+            savepoint.replaced.entry(key).or_insert(replaced);
+        }
+    }
+
+    /// Replace the pending write of KEY in PENDING_WRITE with LOG_RECORD, or remove it if
+    /// LOG_RECORD is None, keeping `pending_bytes` up to date. Return the replaced write.
+    fn replace(
+        &self,
+        pending_write: &mut HashMap<Vec<u8>, LogRecord>,
+        key: Vec<u8>,
+        log_record: Option<LogRecord>,
+    ) -> Option<LogRecord> {
+        let replaced = match log_record {
+            Some(log_record) => {
+                self.pending_bytes
+                    .fetch_add(pending_size(&log_record), Ordering::SeqCst);
+                pending_write.insert(key, log_record)
+            }
+            None => pending_write.remove(&key),
         };
+        if let Some(replaced) = &replaced {
+            self.pending_bytes
+                .fetch_sub(pending_size(replaced), Ordering::SeqCst);
+        }
+        replaced
+    }
+
+    /// Commit PENDING_WRITE once it reaches `auto_commit_bytes` or `auto_commit_count`, and
+    /// start over with no pending write nor savepoint.
+    fn auto_commit(&self, pending_write: &mut HashMap<Vec<u8>, LogRecord>) -> Result<()> {
+        let count_reached = self
+            .options
+            .auto_commit_count
+            .is_some_and(|count| pending_write.len() >= count);
+        let bytes_reached = self
+            .options
+            .auto_commit_bytes
+            .is_some_and(|bytes| self.pending_bytes.load(Ordering::SeqCst) >= bytes);
+        if !count_reached && !bytes_reached {
+            return Ok(());
+        }
 
-        pending_write.insert(key.to_vec(), log_record);
+        let mut deleted_prefixes = self.deleted_prefixes.lock().unwrap();
+        self.commit_pending(pending_write, &deleted_prefixes)?;
+        pending_write.clear();
+        deleted_prefixes.clear();
+        self.pending_bytes.store(0, Ordering::SeqCst);
+        self.savepoints.lock().unwrap().clear();
         Ok(())
     }
 
+    /// Durably write the pending writes as a prepared transaction without applying them, and
+    /// return its sequence number. The transaction survives restarts until it is committed by
+    /// `Engine::commit_prepared` or dropped by `Engine::abort_prepared`. The prefixes deleted by
+    /// `delete_prefix` are expanded now instead of at commit time.
+    pub fn prepare(&self) -> Result<usize> {
+        let pending_writes = self.pending_writes.lock().unwrap();
+        let deleted_prefixes = self.deleted_prefixes.lock().unwrap();
+        let prefix_tombstones = self.prefix_tombstones(&pending_writes, &deleted_prefixes);
+        if pending_writes.len() + prefix_tombstones.len() > self.options.max_batch_num {
+            return Err(Errors::ExceedMaxBatchNum);
+        }
+
+        let records = pending_writes
+            .values()
+            .map(|item| LogRecord {
+                key: item.key.clone(),
+                value: item.value.clone(),
+                record_type: item.record_type,
+                timestamp: item.timestamp,
+                user_flags: 0,
+            })
+            .chain(prefix_tombstones)
+            .collect();
+        self.engine.prepare_transaction(records)
+    }
+
     /// Commits all the changes to the engine, indicating the end of current transaction.
     pub fn commit(&self) -> Result<()> {
         let pending_writes = self.pending_writes.lock().unwrap();
-        if pending_writes.len() == 0 {
+        let deleted_prefixes = self.deleted_prefixes.lock().unwrap();
+        self.commit_pending(&pending_writes, &deleted_prefixes)
+    }
+
+    /// Commit PENDING_WRITES along with the deletion of DELETED_PREFIXES to the engine as a
+    /// single transaction.
+    fn commit_pending(
+        &self,
+        pending_writes: &HashMap<Vec<u8>, LogRecord>,
+        deleted_prefixes: &[Vec<u8>],
+    ) -> Result<()> {
+        if pending_writes.is_empty() && deleted_prefixes.is_empty() {
             return Ok(());
         }
         if pending_writes.len() > self.options.max_batch_num {
             return Err(Errors::ExceedMaxBatchNum);
         }
+        // Read-modify-write primitives on the keys are serialized with the transaction, while the
+        // deleted prefixes may cover any key.
+        let _key_locks = match deleted_prefixes.is_empty() {
+            true => self
+                .engine
+                .key_locks
+                .lock_all(pending_writes.keys().map(Vec::as_slice)),
+            false => self.engine.key_locks.lock_every(),
+        };
+        self.commit_key_locked(pending_writes, deleted_prefixes)
+    }
+
+    /// Same as `commit_pending`, where the key locks of all PENDING_WRITES and DELETED_PREFIXES
+    /// are already held.
+    fn commit_key_locked(
+        &self,
+        pending_writes: &HashMap<Vec<u8>, LogRecord>,
+        deleted_prefixes: &[Vec<u8>],
+    ) -> Result<()> {
+        let _write_guard = self.engine.write_fence.enter()?;
+        self.engine.check_write_stall()?;
 
-        // Writes all the changes into the data file.
         let _batch_commit_lock = self.engine.batch_commit_lock.lock().unwrap();
-        let sequence_number = self.engine.sequence_number.fetch_add(1, Ordering::SeqCst);
-        let mut position = HashMap::new();
-        for (_, item) in pending_writes.iter() {
-            let mut log_record = LogRecord {
-                key: encode_log_record_key(item.key.clone(), sequence_number),
-                value: item.value.clone(),
-                record_type: item.record_type,
-            };
-            let pos = self.engine.append_log_record(&mut log_record)?;
-            position.insert(item.key.clone(), pos);
+        // No other transaction is committed from now on, so no key is left under the prefixes.
+        let prefix_tombstones = self.prefix_tombstones(pending_writes, deleted_prefixes);
+        let write_num = pending_writes.len() + prefix_tombstones.len();
+        if write_num == 0 {
+            return Ok(());
         }
-
-        // Append a delimiter at the end of current commitment, which indicates the whole commit
-        // is successful. On failure, we can roll back to the latest fin_record to ensure data
-        // consistency.
-        let mut fin_record = LogRecord {
-            key: encode_log_record_key(TXN_FIN_KEY.to_vec(), sequence_number),
-            value: Default::default(),
-            record_type: LogRecordType::TxnFinished,
-        };
-        self.engine.append_log_record(&mut fin_record)?;
-
-        if self.options.sync_writes {
-            self.engine.sync()?;
+        if write_num > self.options.max_batch_num {
+            return Err(Errors::ExceedMaxBatchNum);
         }
 
-        // Update the indexer after commit.
-        for (_, item) in pending_writes.iter() {
-            match item.record_type {
-                LogRecordType::Normal => {
-                    let record_pos = position.get(&item.key).unwrap();
-                    if let Some(old_pos) = self.engine.index.put(item.key.clone(), *record_pos) {
-                        self.engine
-                            .reclaim_size
-                            .fetch_add(old_pos.size as usize, Ordering::SeqCst);
-                    }
-                }
-                LogRecordType::Deleted => {
-                    if let Some(old_pos) = self.engine.index.delete(item.key.clone()) {
-                        self.engine
-                            .reclaim_size
-                            .fetch_add(old_pos.size as usize, Ordering::SeqCst);
-                    }
+        let sequence_number = self.engine.sequence_number.fetch_add(1, Ordering::SeqCst);
+        let records: Vec<&LogRecord> = pending_writes.values().chain(&prefix_tombstones).collect();
+        self.engine
+            .write_transaction(sequence_number, &records, self.options.sync_writes)
+    }
+
+    /// Get the tombstones of the indexed keys starting with any of PREFIXES, except the ones
+    /// written by PENDING_WRITES.
+    fn prefix_tombstones(
+        &self,
+        pending_writes: &HashMap<Vec<u8>, LogRecord>,
+        prefixes: &[Vec<u8>],
+    ) -> Vec<LogRecord> {
+        let mut tombstones = BTreeMap::new();
+        for prefix in prefixes {
+            let mut index_iter = self.engine.index.iterator(IteratorOptions {
+                prefix: prefix.clone(),
+                ..Default::default()
+            });
+            while let Some((key, _)) = index_iter.next() {
+                if !pending_writes.contains_key(key) && !tombstones.contains_key(key) {
+                    let tombstone = LogRecord::new_tombstone(key.clone(), now_millis());
+                    tombstones.insert(key.clone(), tombstone);
                 }
-                _ => (),
-            };
+            }
         }
-
-        Ok(())
+        tombstones.into_values().collect()
     }
 }
 
+/// Get the size LOG_RECORD takes up in `pending_bytes`.
+fn pending_size(log_record: &LogRecord) -> usize {
+    log_record.key.len() + log_record.value.len()
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
 
-    use crate::{options::Options, utils};
+    use crate::{
+        data::data_file::SEQUENCE_NUMBER_FILE_NAME,
+        options::{IndexType, Options, PutOptions},
+        utils,
+    };
 
     use super::*;
 
@@ -263,4 +585,330 @@ mod tests {
 
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     }
+
+    #[test]
+    fn test_write_batch_savepoint() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-batch-savepoint");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine
+            .put(
+                utils::rand_kv::get_test_key(3),
+                utils::rand_kv::get_test_value(3)
+            )
+            .is_ok());
+
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert_eq!(
+            wb.rollback_to_savepoint().err(),
+            Some(Errors::SavepointNotFound)
+        );
+        assert!(wb
+            .put(
+                utils::rand_kv::get_test_key(1),
+                utils::rand_kv::get_test_value(1)
+            )
+            .is_ok());
+
+        wb.set_savepoint();
+        assert!(wb
+            .put(utils::rand_kv::get_test_key(1), Bytes::from("changed"))
+            .is_ok());
+        assert!(wb
+            .put(
+                utils::rand_kv::get_test_key(2),
+                utils::rand_kv::get_test_value(2)
+            )
+            .is_ok());
+        wb.set_savepoint();
+        assert!(wb.delete(utils::rand_kv::get_test_key(3)).is_ok());
+        assert!(wb.delete(utils::rand_kv::get_test_key(2)).is_ok());
+
+        // Rolling back to the inner savepoint keeps the changes staged before it.
+        assert!(wb.rollback_to_savepoint().is_ok());
+        assert!(wb.rollback_to_savepoint().is_ok());
+        assert!(wb.rollback_to_savepoint().is_err());
+        assert!(wb.commit().is_ok());
+
+        assert_eq!(
+            engine.get(utils::rand_kv::get_test_key(1)).unwrap(),
+            utils::rand_kv::get_test_value(1)
+        );
+        assert_eq!(
+            engine.get(utils::rand_kv::get_test_key(2)).err(),
+            Some(Errors::KeyNotFound)
+        );
+        assert!(engine.get(utils::rand_kv::get_test_key(3)).is_ok());
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_write_batch_auto_commit() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-batch-auto-commit");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        // Streaming more writes than `max_batch_num` commits them in several transactions.
+        let wb = engine
+            .new_write_batch(WriteBatchOptions {
+                max_batch_num: 100,
+                auto_commit_count: Some(100),
+                ..Default::default()
+            })
+            .expect("failed to create write batch");
+        for i in 0..250 {
+            assert!(wb
+                .put(
+                    utils::rand_kv::get_test_key(i),
+                    utils::rand_kv::get_test_value(i)
+                )
+                .is_ok());
+        }
+        assert!(engine.get(utils::rand_kv::get_test_key(199)).is_ok());
+        assert!(engine.get(utils::rand_kv::get_test_key(200)).is_err());
+        assert!(wb.commit().is_ok());
+        assert!(engine.get(utils::rand_kv::get_test_key(249)).is_ok());
+        assert_eq!(engine.sequence_number.load(Ordering::SeqCst), 4);
+
+        let wb = engine
+            .new_write_batch(WriteBatchOptions {
+                auto_commit_bytes: Some(1024),
+                ..Default::default()
+            })
+            .expect("failed to create write batch");
+        wb.set_savepoint();
+        let mut num = 0;
+        while engine.get(utils::rand_kv::get_test_key(0)).is_ok() {
+            assert!(wb.delete(utils::rand_kv::get_test_key(num)).is_ok());
+            num += 1;
+        }
+        assert!(num > 1);
+        assert_eq!(
+            wb.rollback_to_savepoint().err(),
+            Some(Errors::SavepointNotFound)
+        );
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_write_batch_delete_prefix() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-batch-delete-prefix");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for key in ["user:1", "user:2", "item:1"] {
+            assert!(engine.put(Bytes::from(key), Bytes::from("value")).is_ok());
+        }
+
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert!(wb.put(Bytes::from("user:3"), Bytes::from("value")).is_ok());
+        assert!(wb.delete_prefix(Bytes::from("user:")).is_ok());
+        assert!(wb.put(Bytes::from("user:4"), Bytes::from("value")).is_ok());
+        wb.set_savepoint();
+        assert!(wb.delete_prefix(Bytes::from("item:")).is_ok());
+        assert!(wb.rollback_to_savepoint().is_ok());
+        assert_eq!(
+            wb.delete_prefix(Bytes::new()).err(),
+            Some(Errors::KeyIsEmpty)
+        );
+
+        // Keys written before the commit are deleted as well.
+        assert!(engine
+            .put(Bytes::from("user:5"), Bytes::from("value"))
+            .is_ok());
+        assert!(engine.get(Bytes::from("user:1")).is_ok());
+        assert!(wb.commit().is_ok());
+
+        for key in ["user:1", "user:2", "user:3", "user:5"] {
+            assert_eq!(
+                engine.get(Bytes::from(key)).err(),
+                Some(Errors::KeyNotFound)
+            );
+        }
+        assert!(engine.get(Bytes::from("user:4")).is_ok());
+        assert!(engine.get(Bytes::from("item:1")).is_ok());
+
+        // The deletion survives a restart.
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine.get(Bytes::from("user:1")).is_err());
+        assert!(engine.get(Bytes::from("user:4")).is_ok());
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_delete_range() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-delete-range");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..100 {
+            let res = engine.put(
+                utils::rand_kv::get_test_key(i),
+                utils::rand_kv::get_test_value(i),
+            );
+            assert!(res.is_ok());
+        }
+
+        let deleted = engine.delete_range(
+            utils::rand_kv::get_test_key(10),
+            utils::rand_kv::get_test_key(20),
+        );
+        assert_eq!(deleted.unwrap(), 10);
+        let res = engine.get(utils::rand_kv::get_test_key(10));
+        assert_eq!(Errors::KeyNotFound, res.err().unwrap());
+        assert!(engine.get(utils::rand_kv::get_test_key(9)).is_ok());
+        assert!(engine.get(utils::rand_kv::get_test_key(20)).is_ok());
+
+        // The range is deleted as a single transaction.
+        let seq_no = engine.sequence_number.load(Ordering::SeqCst);
+        assert_eq!(2, seq_no);
+
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 90);
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_rename_key() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-rename-key");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let res = engine.put(
+            utils::rand_kv::get_test_key(1),
+            utils::rand_kv::get_test_value(1),
+        );
+        assert!(res.is_ok());
+
+        let res = engine.rename_key(
+            utils::rand_kv::get_test_key(1),
+            utils::rand_kv::get_test_key(2),
+        );
+        assert!(res.is_ok());
+        let res = engine.get(utils::rand_kv::get_test_key(1));
+        assert_eq!(Errors::KeyNotFound, res.err().unwrap());
+        let res = engine.get(utils::rand_kv::get_test_key(2));
+        assert_eq!(utils::rand_kv::get_test_value(1), res.unwrap());
+
+        let res = engine.rename_key(
+            utils::rand_kv::get_test_key(1),
+            utils::rand_kv::get_test_key(3),
+        );
+        assert_eq!(Errors::KeyNotFound, res.err().unwrap());
+
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(
+            engine.list_keys().unwrap(),
+            vec![utils::rand_kv::get_test_key(2)]
+        );
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_rename_key_with_ttl() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-rename-key-ttl");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let options = PutOptions {
+            ttl: Some(std::time::Duration::from_secs(3600)),
+            user_flags: 7,
+        };
+        let res = engine.put_with_options(
+            utils::rand_kv::get_test_key(1),
+            utils::rand_kv::get_test_value(1),
+            options,
+        );
+        assert!(res.is_ok());
+        let (_, written) = engine
+            .get_with_metadata(utils::rand_kv::get_test_key(1))
+            .unwrap();
+        assert!(written.expire_at.is_some());
+
+        let res = engine.rename_key(
+            utils::rand_kv::get_test_key(1),
+            utils::rand_kv::get_test_key(2),
+        );
+        assert!(res.is_ok());
+
+        // The renamed entry keeps its TTL and flags, also after a restart.
+        let check_renamed = |engine: &Engine| {
+            let (value, metadata) = engine
+                .get_with_metadata(utils::rand_kv::get_test_key(2))
+                .unwrap();
+            assert_eq!(value, utils::rand_kv::get_test_value(1));
+            assert_eq!(metadata.expire_at, written.expire_at);
+            assert_eq!(metadata.user_flags, 7);
+        };
+        check_renamed(&engine);
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        check_renamed(&engine);
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_write_batch_sequence_number_recovery() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-batch-seq-recovery");
+        opts.data_file_size = 64 * 1024 * 1024;
+        opts.index_type = IndexType::BPTree;
+        let seq_file = opts.dir_path.join(SEQUENCE_NUMBER_FILE_NAME);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        for i in 0..2 {
+            wb.put(
+                utils::rand_kv::get_test_key(i),
+                utils::rand_kv::get_test_value(10),
+            )
+            .unwrap();
+            wb.commit().unwrap();
+        }
+        engine.close().expect("failed to close");
+        std::mem::drop(engine);
+
+        // A corrupted sequence number file falls back to scanning the data files.
+        std::fs::write(&seq_file, b"garbage").unwrap();
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(!seq_file.is_file());
+        assert_eq!(3, engine2.sequence_number.load(Ordering::SeqCst));
+        let wb = engine2
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        wb.put(
+            utils::rand_kv::get_test_key(2),
+            utils::rand_kv::get_test_value(10),
+        )
+        .unwrap();
+        wb.commit().unwrap();
+        std::mem::drop(engine2);
+
+        // So does a missing one, e.g. after a crash.
+        let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(4, engine3.sequence_number.load(Ordering::SeqCst));
+        assert_eq!(3, engine3.list_keys().unwrap().len());
+        engine3.close().expect("failed to close");
+        std::mem::drop(engine3);
+
+        let engine4 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(4, engine4.sequence_number.load(Ordering::SeqCst));
+        std::mem::drop(engine4);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
 }
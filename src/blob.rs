@@ -0,0 +1,380 @@
+//! Storage of large values in blob files. A value of at least `Options::large_value_threshold`
+//! bytes, or too large to fit comfortably in a data file, is written into its own file under the
+//! `blobs` subdirectory, and the data files only hold a `Blob` record referring to it. Merges
+//! copy the small reference instead of the value, and the index and hint files are unaware of
+//! blobs, so values may be larger than `data_file_size`.
+//!
+//! Each blob is referred to by a single live record, which is only copied by merges and by the
+//! TTL changes of its entry, see `ttl`. A full merge remembers the next blob id when it starts,
+//! and the blobs created before it and referred to neither by any merged record nor by any
+//! record written since the merge started are removed once the merged files are installed.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bytes::BytesMut;
+use prost::encoding::{decode_varint, encode_varint};
+
+use crate::{
+    clone::link_or_copy,
+    data::{
+        data_file::{DataFile, BLOB_GARBAGE_FILE_NAME},
+        log_record::{LogRecord, LogRecordType},
+    },
+    db::Engine,
+    errors::{Errors, Result},
+    fio::sync_dir,
+};
+
+const BLOB_DIR_NAME: &str = "blobs";
+const BLOB_FILE_NAME_SUFFIX: &str = ".blob";
+const BLOB_GARBAGE_KEY: &str = "blob-garbage";
+
+/// Get the directory of the blob files of the database under DIR_PATH.
+pub(crate) fn get_blob_path(dir_path: &Path) -> PathBuf {
+    dir_path.join(BLOB_DIR_NAME)
+}
+
+fn get_blob_file_name(blob_path: &Path, id: u64) -> PathBuf {
+    blob_path.join(std::format!("{:09}", id) + BLOB_FILE_NAME_SUFFIX)
+}
+
+/// The reference to a value stored in a blob file, where `len` and `crc` are the length and CRC
+/// of the value, checked when it is read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct BlobRef {
+    pub(crate) id: u64,
+    len: u64,
+    crc: u32,
+}
+
+/// The blob files of an engine, where `next_id` is the id of the next blob file created.
+pub(crate) struct BlobStore {
+    dir_path: PathBuf,
+    next_id: AtomicU64,
+}
+
+impl BlobStore {
+    /// Open the blob files under DIR_PATH, which is created on the first write.
+    pub(crate) fn open(dir_path: PathBuf) -> Result<Self> {
+        let next_id = list_blob_ids(&dir_path)?
+            .into_iter()
+            .max()
+            .map_or(0, |id| id + 1);
+        Ok(Self {
+            dir_path,
+            next_id: AtomicU64::new(next_id),
+        })
+    }
+
+    /// The id of the next blob file created. Blob files created afterwards never get a lower id.
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_id.load(Ordering::SeqCst)
+    }
+
+    /// Write VALUE into a new blob file, which is synced along with its directory before the
+    /// record referring to it is written.
+    fn write(&self, value: &[u8]) -> Result<BlobRef> {
+        fs::create_dir_all(&self.dir_path).map_err(|_| Errors::FailedToCreateDatabaseDir)?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut file = fs::File::create(get_blob_file_name(&self.dir_path, id))
+            .map_err(|_| Errors::FailedToOpenDataFile)?;
+        file.write_all(value)
+            .map_err(|_| Errors::FailedToWriteToDataFile)?;
+        file.sync_all()
+            .map_err(|_| Errors::FailedToSyncToDataFile)?;
+        sync_dir(&self.dir_path)?;
+
+        Ok(BlobRef {
+            id,
+            len: value.len() as u64,
+            crc: crc32fast::hash(value),
+        })
+    }
+
+    /// Read the value referred to by BLOB_REF.
+    pub(crate) fn read(&self, blob_ref: &BlobRef) -> Result<Vec<u8>> {
+        let value = fs::read(get_blob_file_name(&self.dir_path, blob_ref.id))
+            .map_err(|_| Errors::BlobFileCorrupted)?;
+        if value.len() as u64 != blob_ref.len || crc32fast::hash(&value) != blob_ref.crc {
+            return Err(Errors::BlobFileCorrupted);
+        }
+        Ok(value)
+    }
+
+    /// Get the value set by LOG_RECORD, without the expiry time of an expiring record, read from
+    /// its blob file if it is stored in one.
+    pub(crate) fn read_user_value(&self, log_record: LogRecord) -> Result<Vec<u8>> {
+        match decode_blob_record(&log_record) {
+            Some(blob_ref) => self.read(&blob_ref),
+            None => Ok(log_record.into_user_value()),
+        }
+    }
+
+    /// Hard-link all the blob files into DEST_PATH, or copy them if they are on different file
+    /// systems.
+    pub(crate) fn link_to(&self, dest_path: &Path) -> Result<()> {
+        let ids = list_blob_ids(&self.dir_path)?;
+        if ids.is_empty() {
+            return Ok(());
+        }
+        fs::create_dir_all(dest_path).map_err(|_| Errors::FailedToCreateDatabaseDir)?;
+        for id in ids {
+            link_or_copy(
+                &get_blob_file_name(&self.dir_path, id),
+                &get_blob_file_name(dest_path, id),
+            )?;
+        }
+        sync_dir(dest_path)
+    }
+
+    /// Record the blob files below WATERMARK and not in LIVE into the merge directory
+    /// MERGE_PATH, to be removed once the merged files are installed.
+    pub(crate) fn write_garbage(
+        &self,
+        merge_path: &Path,
+        watermark: u64,
+        live: &HashSet<u64>,
+    ) -> Result<()> {
+        let mut value = BytesMut::new();
+        for id in list_blob_ids(&self.dir_path)? {
+            if id < watermark && !live.contains(&id) {
+                encode_varint(id, &mut value);
+            }
+        }
+        let record = LogRecord {
+            key: BLOB_GARBAGE_KEY.as_bytes().to_vec(),
+            value: value.to_vec(),
+            record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
+        };
+
+        let garbage_file = DataFile::new_blob_garbage_file(merge_path)?;
+        garbage_file.write(&record.encode())?;
+        garbage_file.sync()
+    }
+}
+
+impl Engine {
+    /// Store the value of LOG_RECORD in a blob file if it is large, and return the record
+    /// referring to it in place of LOG_RECORD, or None if the value is kept in the data files.
+    pub(crate) fn store_large_value(&self, log_record: &LogRecord) -> Result<Option<LogRecord>> {
+        let value_len = log_record.user_value().len() as u64;
+        let is_large = value_len >= self.options.large_value_threshold
+            || value_len > self.options.data_file_size / 2;
+        if !matches!(
+            log_record.record_type,
+            LogRecordType::Normal | LogRecordType::Expiring
+        ) || !is_large
+        {
+            return Ok(None);
+        }
+
+        let blob_ref = self.blob_store.write(log_record.user_value())?;
+        let mut value = BytesMut::new();
+        encode_varint(log_record.expire_at().unwrap_or(0), &mut value);
+        encode_varint(blob_ref.id, &mut value);
+        encode_varint(blob_ref.len, &mut value);
+        encode_varint(blob_ref.crc as u64, &mut value);
+        Ok(Some(LogRecord {
+            key: log_record.key.clone(),
+            value: value.to_vec(),
+            record_type: LogRecordType::Blob,
+            timestamp: log_record.timestamp,
+            user_flags: log_record.user_flags,
+        }))
+    }
+
+    /// Add to LIVE the blobs referred to by the records of the data files from FILE_ID on, that
+    /// is the records written since a merge of the files before FILE_ID started. TTL changes
+    /// write new records referring to existing blobs, which the merged files may not refer to.
+    /// The active file is held while scanned, so no record is written meanwhile.
+    pub(crate) fn collect_blobs_since(&self, file_id: u64, live: &mut HashSet<u64>) -> Result<()> {
+        let active_file = self.active_file.read().unwrap();
+        let old_files = self.old_files();
+        let mut data_files: Vec<&DataFile> = old_files
+            .values()
+            .filter(|data_file| data_file.get_file_id() >= file_id)
+            .map(|data_file| data_file.as_ref())
+            .collect();
+        data_files.push(&active_file);
+
+        for data_file in data_files {
+            let mut ofs = 0;
+            loop {
+                let (log_record, size) = match data_file.read_log_record(ofs) {
+                    Ok(result) => result,
+                    Err(Errors::ReadDataFileEOF) => break,
+                    Err(e) => return Err(e),
+                };
+                if let Some(blob_ref) = decode_blob_record(&log_record) {
+                    live.insert(blob_ref.id);
+                }
+                ofs += size as u64;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get the blob referred to by LOG_RECORD, or None if it is not a `Blob` record.
+pub(crate) fn decode_blob_record(log_record: &LogRecord) -> Option<BlobRef> {
+    if log_record.record_type != LogRecordType::Blob {
+        return None;
+    }
+    let mut buf = log_record.value.as_slice();
+    let _expire_at = decode_varint(&mut buf).ok()?;
+    Some(BlobRef {
+        id: decode_varint(&mut buf).ok()?,
+        len: decode_varint(&mut buf).ok()?,
+        crc: decode_varint(&mut buf).ok()? as u32,
+    })
+}
+
+/// Get a copy of the `Blob` record LOG_RECORD referring to the same blob, but expiring at
+/// EXPIRE_AT milliseconds since the epoch, or never if None. Return None if LOG_RECORD is not a
+/// `Blob` record.
+pub(crate) fn with_blob_expiry(
+    log_record: &LogRecord,
+    expire_at: Option<u64>,
+) -> Option<LogRecord> {
+    if log_record.record_type != LogRecordType::Blob {
+        return None;
+    }
+    let mut blob_ref = log_record.value.as_slice();
+    let _expire_at = decode_varint(&mut blob_ref).ok()?;
+    let mut value = BytesMut::new();
+    encode_varint(expire_at.unwrap_or(0), &mut value);
+    value.extend_from_slice(blob_ref);
+    Some(LogRecord {
+        key: log_record.key.clone(),
+        value: value.to_vec(),
+        record_type: LogRecordType::Blob,
+        timestamp: log_record.timestamp,
+        user_flags: log_record.user_flags,
+    })
+}
+
+/// Remove the blob files under DIR_PATH recorded as garbage by the merge under MERGE_PATH. Blob
+/// files already removed by an interrupted installation are skipped.
+pub(crate) fn remove_blob_garbage(dir_path: &Path, merge_path: &Path) -> Result<()> {
+    if !merge_path.join(BLOB_GARBAGE_FILE_NAME).is_file() {
+        return Ok(());
+    }
+    let garbage_file = DataFile::new_blob_garbage_file(merge_path)?;
+    let record = garbage_file.read_log_record(0)?.0;
+
+    let blob_path = get_blob_path(dir_path);
+    let mut buf = record.value.as_slice();
+    while !buf.is_empty() {
+        let id = decode_varint(&mut buf).map_err(|_| Errors::BlobFileCorrupted)?;
+        let _ = fs::remove_file(get_blob_file_name(&blob_path, id));
+    }
+    if blob_path.is_dir() {
+        sync_dir(&blob_path)?;
+    }
+    Ok(())
+}
+
+/// Get the ids of the blob files under BLOB_PATH.
+fn list_blob_ids(blob_path: &Path) -> Result<Vec<u64>> {
+    if !blob_path.is_dir() {
+        return Ok(Vec::new());
+    }
+    let dir = fs::read_dir(blob_path).map_err(|_| Errors::FailedToReadDatabaseDir)?;
+    Ok(dir
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name();
+            file_name
+                .to_str()?
+                .strip_suffix(BLOB_FILE_NAME_SUFFIX)?
+                .parse::<u64>()
+                .ok()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use bytes::Bytes;
+
+    use crate::{options::Options, utils::rand_kv::get_test_key};
+
+    use super::*;
+
+    #[test]
+    fn test_engine_large_values() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-blob");
+        opts.data_file_size = 64 * 1024;
+        opts.data_file_merge_ratio = 0.0;
+        let clone_path = PathBuf::from("/tmp/bitcask-rs-blob-clone");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let value = |i: u8| Bytes::from(vec![i; 100 * 1024]);
+        for i in 0..4 {
+            assert!(engine.put(get_test_key(i), value(i as u8)).is_ok());
+        }
+        assert!(engine
+            .put_with_ttl(get_test_key(4), value(4), Duration::from_secs(1))
+            .is_ok());
+        // Overwritten and deleted values leave their blob files behind until a merge.
+        assert!(engine.put(get_test_key(0), value(10)).is_ok());
+        assert!(engine.delete(get_test_key(1)).is_ok());
+        let blob_path = get_blob_path(&opts.dir_path);
+        assert_eq!(list_blob_ids(&blob_path).unwrap().len(), 6);
+        assert!(engine.active_file.read().unwrap().get_write_ofs() < opts.data_file_size);
+
+        assert_eq!(engine.get(get_test_key(0)).unwrap(), value(10));
+        assert_eq!(engine.get(get_test_key(2)).unwrap(), value(2));
+        assert_eq!(engine.get(get_test_key(4)).unwrap(), value(4));
+        assert_eq!(
+            engine.get(get_test_key(1)).err().unwrap(),
+            Errors::KeyNotFound
+        );
+
+        assert!(engine.clone_to(&clone_path).is_ok());
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(
+            engine.get(get_test_key(4)).err().unwrap(),
+            Errors::KeyNotFound
+        );
+
+        // The merge keeps the blob files of the live values only.
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(list_blob_ids(&blob_path).unwrap().len(), 3);
+        assert_eq!(engine2.get(get_test_key(0)).unwrap(), value(10));
+        assert_eq!(engine2.get(get_test_key(3)).unwrap(), value(3));
+        assert_eq!(
+            engine2.get(get_test_key(1)).err().unwrap(),
+            Errors::KeyNotFound
+        );
+
+        // A new blob file never reuses the id of a removed one.
+        assert!(engine2.put(get_test_key(5), value(5)).is_ok());
+        assert_eq!(engine2.get(get_test_key(5)).unwrap(), value(5));
+        assert_eq!(list_blob_ids(&blob_path).unwrap().len(), 4);
+        std::mem::drop(engine2);
+
+        let mut clone_opts = opts.clone();
+        clone_opts.dir_path = clone_path.clone();
+        let clone = Engine::open(clone_opts).expect("failed to open engine");
+        assert_eq!(clone.get(get_test_key(0)).unwrap(), value(10));
+        assert_eq!(clone.get(get_test_key(2)).unwrap(), value(2));
+
+        std::mem::drop(clone);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+        std::fs::remove_dir_all(clone_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,251 @@
+//! Bulk loading writes a sorted stream of (key, value) pairs into data files under a staging
+//! directory, without touching the active file or the indexer for each record. After all records
+//! are written, the staged files are moved into the working directory right after the active file,
+//! and the indexer is updated with the collected positions at once.
+//!
+//! Bulk loaded files are ordinary data files, so they are recovered by the regular startup scan.
+
+use std::{fs, path::Path, sync::Arc};
+
+use bytes::Bytes;
+
+use crate::{
+    batch::NON_TRANSACTION_SEQUENCE,
+    data::{
+        data_file::{get_data_file_name, DataFile},
+        log_record::{LogRecord, LogRecordPos, LogRecordType},
+    },
+    db::{encode_log_record_key, Engine},
+    errors::{Errors, Result},
+    manifest::ManifestEdit,
+    options::IOType,
+    utils::time::now_millis,
+};
+
+const BULK_LOAD_DIR_NAME: &str = "bulk-load";
+
+/// Keys and the positions of their records, in the order of writing.
+type KeyPositions = Vec<(Vec<u8>, LogRecordPos)>;
+
+impl Engine {
+    /// Load PAIRS, which must be sorted by key in strictly ascending order, into the engine.
+    /// Loaded pairs overwrite the existing entries with the same key. Return the number of pairs
+    /// loaded. Merges are held off until the load completes.
+    pub fn bulk_load<I>(&self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (Bytes, Bytes)>,
+    {
+        self.check_write_stall()?;
+        // A merge would take the blob files of the staged records for garbage.
+        let _merge_lock = self.merge_lock.lock().unwrap();
+        let staging_path = self.options.dir_path.join(BULK_LOAD_DIR_NAME);
+        if staging_path.is_dir() {
+            fs::remove_dir_all(&staging_path).map_err(|_| Errors::FailedToCreateDatabaseDir)?;
+        }
+        fs::create_dir_all(&staging_path).map_err(|_| Errors::FailedToCreateDatabaseDir)?;
+
+        let res = self.write_staging_files(&staging_path, pairs);
+        let res = res.and_then(|(file_num, positions)| {
+            self.install_staging_files(&staging_path, file_num, positions)
+        });
+        let _ = fs::remove_dir_all(&staging_path);
+        if res.is_ok() {
+            self.notify_change_sink();
+        }
+        res
+    }
+
+    /// Write PAIRS into data files under STAGING_PATH, return the number of files written and
+    /// the position of each key, where file ids are numbered from 0.
+    fn write_staging_files<I>(&self, staging_path: &Path, pairs: I) -> Result<(u64, KeyPositions)>
+    where
+        I: IntoIterator<Item = (Bytes, Bytes)>,
+    {
+        let staging_path = staging_path.to_path_buf();
+        let mut positions: KeyPositions = Vec::new();
+        let mut data_file = self.new_data_file(&staging_path, 0, self.options.io_type)?;
+
+        for (key, value) in pairs {
+            if key.is_empty() {
+                return Err(Errors::KeyIsEmpty);
+            }
+            self.check_entry_size(&key, &value)?;
+            if let Some((last_key, _)) = positions.last() {
+                if last_key.as_slice() >= key.as_ref() {
+                    return Err(Errors::BulkLoadKeysUnsorted);
+                }
+            }
+
+            let log_record = LogRecord {
+                key: encode_log_record_key(key.to_vec(), NON_TRANSACTION_SEQUENCE),
+                value: value.to_vec(),
+                record_type: LogRecordType::Normal,
+                timestamp: Some(now_millis()),
+                user_flags: 0,
+            };
+            let encoded_record = self.encode_log_record(&log_record)?;
+            let write_ofs = data_file.get_write_ofs();
+            if write_ofs > 0
+                && write_ofs + encoded_record.len() as u64 > self.options.data_file_size
+            {
+                data_file.sync()?;
+                let file_id = data_file.get_file_id() + 1;
+                data_file = self.new_data_file(&staging_path, file_id, self.options.io_type)?;
+            }
+
+            let write_ofs = data_file.get_write_ofs();
+            data_file.write(&encoded_record)?;
+            positions.push((
+                key.to_vec(),
+                LogRecordPos {
+                    file_id: data_file.get_file_id(),
+                    ofs: write_ofs,
+                    size: encoded_record.len() as u32,
+                    expire_at: None,
+                },
+            ));
+        }
+        data_file.sync()?;
+
+        Ok((data_file.get_file_id() + 1, positions))
+    }
+
+    /// Move FILE_NUM staged files right after the current active file, then seal the active file
+    /// and index all POSITIONS.
+    fn install_staging_files(
+        &self,
+        staging_path: &Path,
+        file_num: u64,
+        positions: KeyPositions,
+    ) -> Result<usize> {
+        let dir_path = &self.options.dir_path;
+        let staging_path = staging_path.to_path_buf();
+
+        let mut active_file = self.active_file.write().unwrap();
+        self.ensure_open()?;
+        active_file.sync()?;
+        let active_file_id = active_file.get_file_id();
+        let first_file_id = active_file_id + 1;
+
+        let mut loaded_files = Vec::new();
+        for staging_file_id in 0..file_num {
+            let file_id = first_file_id + staging_file_id;
+            let staging_file_name = get_data_file_name(&staging_path, staging_file_id);
+            let size = fs::metadata(&staging_file_name)
+                .map_err(|_| Errors::FailedToReadFromDataFile)?
+                .len();
+            fs::rename(staging_file_name, get_data_file_name(dir_path, file_id))
+                .map_err(|_| Errors::FailedToWriteToDataFile)?;
+            self.manifest.append(ManifestEdit::NewFile(file_id))?;
+            self.manifest
+                .append(ManifestEdit::SealFile(file_id, size))?;
+            loaded_files.push((file_id, size));
+        }
+
+        // Seal the current active file, and continue writing after the loaded files.
+        let end_ofs = self.write_active_footer(&active_file)?;
+        loaded_files.push((active_file_id, end_ofs));
+        self.update_old_files(|old_files| {
+            for (file_id, size) in loaded_files {
+                let data_file = DataFile::new_lazy(dir_path, file_id, self.read_io_type)
+                    .with_io_wrapper(self.options.io_wrapper.clone());
+                data_file.set_end_ofs(size);
+                old_files.insert(file_id, Arc::new(data_file));
+            }
+        });
+        self.manifest
+            .append(ManifestEdit::SealFile(active_file_id, end_ofs))?;
+        *active_file =
+            self.new_data_file(dir_path, first_file_id + file_num, IOType::StandardFIO)?;
+        self.manifest
+            .append(ManifestEdit::NewFile(first_file_id + file_num))?;
+
+        // Keep the active file locked, so no concurrent write is shadowed by the loaded pairs.
+        let loaded = positions.len();
+        for (key, mut pos) in positions {
+            pos.file_id += first_file_id;
+            if let Some(extractor) = &self.options.prefix_extractor {
+                Self::record_loaded_prefix(
+                    &mut self.prefix_blooms.write().unwrap(),
+                    extractor.as_ref(),
+                    pos.file_id,
+                    &key,
+                );
+            }
+            if let Some(old_pos) = self.index.put(key, pos) {
+                self.add_reclaim_size(&old_pos);
+            }
+        }
+
+        Ok(loaded)
+    }
+}
+
+/// Remove the staging directory left by an interrupted bulk load under DIR_PATH.
+pub(crate) fn clean_bulk_load_dir(dir_path: &Path) -> Result<()> {
+    let staging_path = dir_path.join(BULK_LOAD_DIR_NAME);
+    if staging_path.is_dir() {
+        fs::remove_dir_all(staging_path).map_err(|_| Errors::FailedToReadDatabaseDir)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_bulk_load() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-bulk-load");
+        opts.data_file_size = 64 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..100 {
+            let res = engine.put(get_test_key(i), Bytes::from("old value"));
+            assert!(res.is_ok());
+        }
+
+        let pairs = (0..20000).map(|i| (get_test_key(i), get_test_value(i)));
+        assert_eq!(engine.bulk_load(pairs).unwrap(), 20000);
+        assert!(engine.old_files().len() > 1);
+        for i in 0..20000 {
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+
+        let res = engine.put(get_test_key(1), Bytes::from("new value"));
+        assert!(res.is_ok());
+
+        let unsorted = vec![
+            (get_test_key(2), get_test_value(2)),
+            (get_test_key(1), get_test_value(1)),
+        ];
+        assert_eq!(
+            engine.bulk_load(unsorted).err().unwrap(),
+            Errors::BulkLoadKeysUnsorted
+        );
+        std::mem::drop(engine);
+
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine2.list_keys().unwrap().len(), 20000);
+        assert_eq!(
+            engine2.get(get_test_key(1)).unwrap(),
+            Bytes::from("new value")
+        );
+        assert_eq!(engine2.get(get_test_key(2)).unwrap(), get_test_value(2));
+        assert_eq!(
+            engine2.get(get_test_key(19999)).unwrap(),
+            get_test_value(19999)
+        );
+
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
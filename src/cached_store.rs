@@ -0,0 +1,237 @@
+//! Using the engine as a durable local cache of a remote source of truth. Reads missing locally
+//! are loaded from the source and kept in the engine, while writes go to the source before the
+//! engine. Concurrent misses of the same key are collapsed into a single load, and keys missing
+//! from the source are remembered for a while, so a hot key never floods the source.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+};
+
+/// Loads the values missing from the cache from the source of truth.
+pub trait Loader: Sync + Send {
+    /// Load the value of KEY, or None if the source does not have it. Failures are expected to
+    /// be reported as `Errors::SourceReadFailed`.
+    fn load(&self, key: &Bytes) -> Result<Option<Bytes>>;
+}
+
+/// Writes changes through to the source of truth.
+pub trait Writer: Sync + Send {
+    /// Set KEY to VALUE in the source, or delete it if VALUE is None. Failures are expected to be
+    /// reported as `Errors::SourceWriteFailed`.
+    fn write(&self, key: &Bytes, value: Option<&Bytes>) -> Result<()>;
+}
+
+/// A read-through, write-through cache backed by an engine.
+///
+/// - `negative_ttl` is how long a key missing from the source is answered as missing without
+///   asking the source again.
+/// - `loading` holds a lock for each key being loaded, waited on by concurrent misses.
+/// - `missing` records when each key was found missing from the source.
+pub struct CachedStore {
+    engine: Arc<Engine>,
+    loader: Arc<dyn Loader>,
+    writer: Option<Arc<dyn Writer>>,
+    negative_ttl: Duration,
+    loading: Mutex<HashMap<Bytes, Arc<Mutex<()>>>>,
+    missing: Mutex<HashMap<Bytes, Instant>>,
+}
+
+impl CachedStore {
+    /// Cache the values loaded by LOADER in ENGINE. Writes are only applied to ENGINE unless a
+    /// writer is set by `with_writer`.
+    pub fn new(engine: Arc<Engine>, loader: Arc<dyn Loader>, negative_ttl: Duration) -> Self {
+        Self {
+            engine,
+            loader,
+            writer: None,
+            negative_ttl,
+            loading: Mutex::new(HashMap::new()),
+            missing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Write changes through WRITER before applying them to the cache.
+    pub fn with_writer(mut self, writer: Arc<dyn Writer>) -> Self {
+        self.writer = Some(writer);
+        self
+    }
+
+    /// Get the value of KEY, loading it from the source on a miss.
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        if let Some(value) = self.get_cached(&key)? {
+            return Ok(value);
+        }
+
+        // Only the first miss loads the key, the others wait for it and read the cache.
+        let key_lock = self
+            .loading
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let _key_guard = key_lock.lock().unwrap();
+        let res = match self.get_cached(&key) {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => self.load(&key),
+            Err(e) => Err(e),
+        };
+        self.loading.lock().unwrap().remove(&key);
+        res
+    }
+
+    /// Set KEY to VALUE in the source and in the cache.
+    pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.write(&key, Some(&value))?;
+        }
+        self.missing.lock().unwrap().remove(&key);
+        self.engine.put(key, value)
+    }
+
+    /// Delete KEY from the source and from the cache.
+    pub fn delete(&self, key: Bytes) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.write(&key, None)?;
+        }
+        self.engine.delete(key)
+    }
+
+    /// Get the value of KEY from the cache. Fail with `Errors::KeyNotFound` if the key is known
+    /// to be missing from the source, return None if it is not cached.
+    fn get_cached(&self, key: &Bytes) -> Result<Option<Bytes>> {
+        match self.engine.get(key.clone()) {
+            Ok(value) => return Ok(Some(value)),
+            Err(Errors::KeyNotFound) => (),
+            Err(e) => return Err(e),
+        }
+
+        let mut missing = self.missing.lock().unwrap();
+        match missing.get(key) {
+            Some(since) if since.elapsed() < self.negative_ttl => Err(Errors::KeyNotFound),
+            Some(_) => {
+                missing.remove(key);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Load KEY from the source into the cache.
+    fn load(&self, key: &Bytes) -> Result<Bytes> {
+        match self.loader.load(key)? {
+            Some(value) => {
+                self.engine.put(key.clone(), value.clone())?;
+                Ok(value)
+            }
+            None => {
+                self.missing
+                    .lock()
+                    .unwrap()
+                    .insert(key.clone(), Instant::now());
+                Err(Errors::KeyNotFound)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    /// A source holding even keys, counting the loads and writes.
+    #[derive(Default)]
+    struct TestSource {
+        loads: AtomicUsize,
+        writes: Mutex<Vec<(Bytes, Option<Bytes>)>>,
+    }
+
+    impl Loader for TestSource {
+        fn load(&self, key: &Bytes) -> Result<Option<Bytes>> {
+            self.loads.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            match (0..100).find(|i| get_test_key(*i) == key) {
+                Some(i) if i % 2 == 0 => Ok(Some(get_test_value(i))),
+                _ => Ok(None),
+            }
+        }
+    }
+
+    impl Writer for TestSource {
+        fn write(&self, key: &Bytes, value: Option<&Bytes>) -> Result<()> {
+            self.writes
+                .lock()
+                .unwrap()
+                .push((key.clone(), value.cloned()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_cached_store() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-cached-store");
+        let engine = Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+        let source = Arc::new(TestSource::default());
+        let store = Arc::new(
+            CachedStore::new(engine.clone(), source.clone(), Duration::from_secs(60))
+                .with_writer(source.clone()),
+        );
+
+        // Concurrent misses of the same key are loaded once.
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || store.get(get_test_key(2)).unwrap())
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), get_test_value(2));
+        }
+        assert_eq!(source.loads.load(Ordering::SeqCst), 1);
+        assert_eq!(engine.get(get_test_key(2)).unwrap(), get_test_value(2));
+
+        // Keys missing from the source are remembered.
+        assert_eq!(store.get(get_test_key(3)).err(), Some(Errors::KeyNotFound));
+        assert_eq!(store.get(get_test_key(3)).err(), Some(Errors::KeyNotFound));
+        assert_eq!(source.loads.load(Ordering::SeqCst), 2);
+
+        // Writes go through to the source.
+        assert!(store.put(get_test_key(3), get_test_value(30)).is_ok());
+        assert_eq!(store.get(get_test_key(3)).unwrap(), get_test_value(30));
+        assert!(store.delete(get_test_key(2)).is_ok());
+        assert_eq!(
+            *source.writes.lock().unwrap(),
+            vec![
+                (get_test_key(3), Some(get_test_value(30))),
+                (get_test_key(2), None)
+            ]
+        );
+        assert_eq!(source.loads.load(Ordering::SeqCst), 2);
+
+        std::mem::drop(store);
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,342 @@
+//! Shipping committed changes to downstream systems. The data files are the change log: changes
+//! are read from them in commit order, records of a transaction are only shipped once its
+//! finishing record is found, and the offset of the first change not yet delivered is persisted
+//! in the directory after each delivery. Delivery resumes from that offset after a restart, so
+//! each change is delivered at least once.
+//!
+//! The sink is invoked after every write when configured, and a failed delivery is retried on
+//! the next write or by calling `Engine::ship_changes`. Merge ships all pending changes before
+//! rewriting the sealed files, so offsets never point into a merged file.
+
+use std::{collections::BTreeMap, fs, path::Path, sync::atomic::Ordering};
+
+use bytes::{Bytes, BytesMut};
+use log::warn;
+use prost::encoding::{decode_varint, encode_varint};
+
+use crate::{
+    batch::NON_TRANSACTION_SEQUENCE,
+    blob::BlobStore,
+    data::{
+        data_file::DataFile,
+        log_record::{LogRecord, LogRecordType},
+    },
+    db::{parse_log_record_key, Engine},
+    errors::{Errors, Result},
+};
+
+pub const CHANGE_OFFSET_FILE_NAME: &str = "change-offset";
+const CHANGE_OFFSET_TMP_FILE_NAME: &str = "change-offset.tmp";
+const CHANGE_OFFSET_KEY: &str = "change-offset";
+
+/// A committed change, where `value` is None for a deletion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub key: Bytes,
+    pub value: Option<Bytes>,
+}
+
+/// Receives the committed changes of the engine in commit order.
+pub trait ChangeSink: Sync + Send {
+    /// Deliver CHANGES downstream. On failure, the same changes are delivered again later.
+    fn deliver(&self, changes: &[Change]) -> Result<()>;
+}
+
+/// Position in the data files, as the file id and the offset in the file.
+pub(crate) type ChangeOffset = (u64, u64);
+
+/// Progress of shipping changes, where:
+/// - `next` is where the next record is read.
+/// - `pending` are the changes of unfinished transactions by sequence number, with the offset
+///   of their first record.
+/// - `open_sequence_number` is the sequence number on startup. Transactions before it that are
+///   not finished by the end of the data files are aborted, and never shipped.
+#[derive(Default)]
+pub(crate) struct ChangeShipper {
+    next: ChangeOffset,
+    pending: BTreeMap<usize, (ChangeOffset, Vec<Change>)>,
+    open_sequence_number: usize,
+}
+
+impl ChangeShipper {
+    /// Offset to resume from after a restart, which is before all undelivered changes.
+    fn checkpoint(&self) -> ChangeOffset {
+        self.pending
+            .values()
+            .map(|(ofs, _)| *ofs)
+            .min()
+            .unwrap_or(self.next)
+    }
+}
+
+/// Changes read by a single shipping, which replace the progress of `ChangeShipper` once
+/// delivered.
+struct ShipProgress {
+    next: ChangeOffset,
+    pending: BTreeMap<usize, (ChangeOffset, Vec<Change>)>,
+    ready: Vec<Change>,
+}
+
+impl ShipProgress {
+    /// Read the changes of DATA_FILE from `next`, up to END_OFS or the end of the file. Large
+    /// values are read from BLOB_STORE.
+    fn read_file(
+        &mut self,
+        blob_store: &BlobStore,
+        data_file: &DataFile,
+        end_ofs: Option<u64>,
+    ) -> Result<()> {
+        let file_id = data_file.get_file_id();
+        let mut ofs = if self.next.0 == file_id {
+            self.next.1
+        } else {
+            0
+        };
+        while end_ofs.is_none_or(|end_ofs| ofs < end_ofs) {
+            let (log_record, size) = match data_file.read_log_record(ofs) {
+                Ok(res) => res,
+                Err(Errors::ReadDataFileEOF) => break,
+                Err(e) => return Err(e),
+            };
+            let (key, sequence_number) = parse_log_record_key(&log_record.key);
+            let change = match log_record.record_type {
+                LogRecordType::Normal | LogRecordType::Expiring | LogRecordType::Blob => {
+                    Some(Change {
+                        key: key.into(),
+                        value: Some(blob_store.read_user_value(log_record)?.into()),
+                    })
+                }
+                LogRecordType::Deleted => Some(Change {
+                    key: key.into(),
+                    value: None,
+                }),
+                LogRecordType::TxnFinished => None,
+            };
+            match change {
+                Some(change) if sequence_number == NON_TRANSACTION_SEQUENCE => {
+                    self.ready.push(change)
+                }
+                Some(change) => self
+                    .pending
+                    .entry(sequence_number)
+                    .or_insert_with(|| ((file_id, ofs), Vec::new()))
+                    .1
+                    .push(change),
+                None => {
+                    if let Some((_, changes)) = self.pending.remove(&sequence_number) {
+                        self.ready.extend(changes);
+                    }
+                }
+            }
+            ofs += size as u64;
+        }
+        self.next = (file_id, ofs);
+        Ok(())
+    }
+}
+
+impl Engine {
+    /// Restore the shipping progress persisted in the directory.
+    pub(crate) fn restore_change_shipper(&self) {
+        let mut shipper = self.change_shipper.lock().unwrap();
+        shipper.next = read_change_offset(&self.options.dir_path).unwrap_or_default();
+        shipper.open_sequence_number = self.sequence_number.load(Ordering::SeqCst);
+    }
+
+    /// Ship the changes committed since the last delivery to the configured change sink, return
+    /// the number of changes delivered.
+    pub fn ship_changes(&self) -> Result<usize> {
+        let sink = match &self.options.change_sink {
+            Some(sink) => sink.clone(),
+            None => return Ok(0),
+        };
+        let mut shipper = self.change_shipper.lock().unwrap();
+
+        // Work on a copy, so a failed delivery leaves the progress untouched.
+        let mut progress = ShipProgress {
+            next: shipper.next,
+            pending: shipper.pending.clone(),
+            ready: Vec::new(),
+        };
+        let old_files = self.old_files();
+        let mut file_ids: Vec<u64> = old_files.keys().copied().collect();
+        file_ids.sort();
+        for file_id in file_ids {
+            if file_id >= progress.next.0 {
+                progress.read_file(&self.blob_store, old_files.get(&file_id).unwrap(), None)?;
+            }
+        }
+        {
+            let active_file = self.active_file.read().unwrap();
+            if active_file.get_file_id() >= progress.next.0 {
+                progress.read_file(
+                    &self.blob_store,
+                    &active_file,
+                    Some(active_file.get_write_ofs()),
+                )?;
+            }
+        }
+        let ShipProgress {
+            next,
+            mut pending,
+            ready,
+        } = progress;
+
+        // All records written before startup are read, so their unfinished transactions are
+        // aborted.
+        pending.retain(|sequence_number, _| *sequence_number >= shipper.open_sequence_number);
+
+        if !ready.is_empty() {
+            sink.deliver(&ready)?;
+        }
+        let progressed = shipper.next != next || shipper.pending.len() != pending.len();
+        shipper.next = next;
+        shipper.pending = pending;
+        if progressed {
+            write_change_offset(&self.options.dir_path, shipper.checkpoint())?;
+        }
+        Ok(ready.len())
+    }
+
+    /// Ship changes after a write, a failure is retried by the next write.
+    pub(crate) fn notify_change_sink(&self) {
+        if self.options.change_sink.is_none() {
+            return;
+        }
+        if let Err(e) = self.ship_changes() {
+            warn!("failed to ship changes: {:?}", e);
+        }
+    }
+}
+
+/// Read the persisted shipping offset under DIR_PATH.
+fn read_change_offset(dir_path: &Path) -> Option<ChangeOffset> {
+    if !dir_path.join(CHANGE_OFFSET_FILE_NAME).is_file() {
+        return None;
+    }
+    let offset_file = DataFile::new_change_offset_file(dir_path, CHANGE_OFFSET_FILE_NAME).ok()?;
+    let (record, _) = offset_file.read_log_record(0).ok()?;
+    let mut buf = BytesMut::from(record.value.as_slice());
+    Some((decode_varint(&mut buf).ok()?, decode_varint(&mut buf).ok()?))
+}
+
+/// Persist the shipping offset OFFSET under DIR_PATH, replacing the previous one atomically.
+fn write_change_offset(dir_path: &Path, offset: ChangeOffset) -> Result<()> {
+    let mut value = BytesMut::new();
+    encode_varint(offset.0, &mut value);
+    encode_varint(offset.1, &mut value);
+    let record = LogRecord {
+        key: CHANGE_OFFSET_KEY.as_bytes().to_vec(),
+        value: value.to_vec(),
+        record_type: LogRecordType::Normal,
+        timestamp: None,
+        user_flags: 0,
+    };
+
+    let _ = fs::remove_file(dir_path.join(CHANGE_OFFSET_TMP_FILE_NAME));
+    let tmp_file = DataFile::new_change_offset_file(dir_path, CHANGE_OFFSET_TMP_FILE_NAME)?;
+    tmp_file.write(&record.encode())?;
+    tmp_file.sync()?;
+    fs::rename(
+        dir_path.join(CHANGE_OFFSET_TMP_FILE_NAME),
+        dir_path.join(CHANGE_OFFSET_FILE_NAME),
+    )
+    .map_err(|_| Errors::FailedToWriteToDataFile)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        options::{Options, WriteBatchOptions},
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    /// A sink collecting the delivered changes, failing while `fail` is set.
+    #[derive(Default)]
+    struct TestSink {
+        changes: Mutex<Vec<Change>>,
+        fail: Mutex<bool>,
+    }
+
+    impl ChangeSink for TestSink {
+        fn deliver(&self, changes: &[Change]) -> Result<()> {
+            if *self.fail.lock().unwrap() {
+                return Err(Errors::SinkDeliveryFailed);
+            }
+            self.changes.lock().unwrap().extend_from_slice(changes);
+            Ok(())
+        }
+    }
+
+    fn put_change(i: i32) -> Change {
+        Change {
+            key: get_test_key(i),
+            value: Some(get_test_value(i)),
+        }
+    }
+
+    #[test]
+    fn test_change_sink() {
+        let sink = Arc::new(TestSink::default());
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-change-sink");
+        opts.data_file_size = 32 * 1024;
+        opts.change_sink = Some(sink.clone());
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        engine.put(get_test_key(0), get_test_value(0)).unwrap();
+        engine.delete(get_test_key(0)).unwrap();
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .unwrap();
+        wb.put(get_test_key(1), get_test_value(1)).unwrap();
+        assert_eq!(sink.changes.lock().unwrap().len(), 2);
+        wb.commit().unwrap();
+        assert_eq!(
+            *sink.changes.lock().unwrap(),
+            vec![
+                put_change(0),
+                Change {
+                    key: get_test_key(0),
+                    value: None
+                },
+                put_change(1)
+            ]
+        );
+
+        // Failed deliveries are retried in order, across data files.
+        *sink.fail.lock().unwrap() = true;
+        for i in 2..1000 {
+            engine.put(get_test_key(i), get_test_value(i)).unwrap();
+        }
+        assert_eq!(sink.changes.lock().unwrap().len(), 3);
+        *sink.fail.lock().unwrap() = false;
+        assert_eq!(engine.ship_changes().unwrap(), 998);
+        assert_eq!(engine.ship_changes().unwrap(), 0);
+        assert_eq!(
+            sink.changes.lock().unwrap()[3..],
+            (2..1000).map(put_change).collect::<Vec<_>>()
+        );
+
+        // Delivery resumes after a restart without losing undelivered changes.
+        *sink.fail.lock().unwrap() = true;
+        engine
+            .put(get_test_key(1000), get_test_value(1000))
+            .unwrap();
+        std::mem::drop(engine);
+        *sink.fail.lock().unwrap() = false;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.ship_changes().unwrap(), 1);
+        assert_eq!(sink.changes.lock().unwrap().last(), Some(&put_change(1000)));
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,155 @@
+//! Live clone of the database directory. Sealed data files never change, so they are hard-linked
+//! into the destination, while the active file is copied up to the offset written when the clone
+//! starts. The source engine keeps serving reads and writes during the copy, and the clone is
+//! indexed from its data files when it is opened.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::{
+    blob::get_blob_path,
+    data::data_file::{get_data_file_name, HINT_FILE_NAME, MERGE_FIN_FILE_NAME},
+    db::Engine,
+    errors::{Errors, Result},
+    options::IndexType,
+};
+
+impl Engine {
+    /// Create an independent copy of the database under DEST_PATH, which must not exist or be
+    /// empty. The copy contains all writes finished before the call, and can be opened as a
+    /// separate engine. Merges are held off until the copy completes.
+    ///
+    /// Engines indexed by a BPTree are not supported, since their index file is updated in place
+    /// and cannot be copied consistently while writes are served.
+    pub fn clone_to<P: AsRef<Path>>(&self, dest_path: P) -> Result<()> {
+        if self.options.index_type == IndexType::BPTree {
+            return Err(Errors::UnsupportedIndexType);
+        }
+        self.ensure_open()?;
+        let dest_path = dest_path.as_ref().to_path_buf();
+        if dest_path.is_dir()
+            && fs::read_dir(&dest_path)
+                .map_err(|_| Errors::FailedToReadDatabaseDir)?
+                .next()
+                .is_some()
+        {
+            return Err(Errors::DatabaseDirNotEmpty);
+        }
+        fs::create_dir_all(&dest_path).map_err(|_| Errors::FailedToCreateDatabaseDir)?;
+
+        // Merge replaces the sealed files and the hint file, hold it off until all are linked.
+        let _merge_lock = self.merge_lock.lock().unwrap();
+
+        // Cut the active file, so the copy ends at a record boundary.
+        let (active_file_id, active_ofs, old_files) = {
+            let active_file = self.active_file.write().unwrap();
+            active_file.sync()?;
+            (
+                active_file.get_file_id(),
+                active_file.get_write_ofs(),
+                self.old_files(),
+            )
+        };
+
+        let dir_path = &self.options.dir_path;
+        for file_id in old_files.keys() {
+            link_or_copy(
+                &get_data_file_name(dir_path, *file_id),
+                &get_data_file_name(&dest_path, *file_id),
+            )?;
+        }
+        for file_name in [HINT_FILE_NAME, MERGE_FIN_FILE_NAME] {
+            let src = dir_path.join(file_name);
+            if src.is_file() {
+                link_or_copy(&src, &dest_path.join(file_name))?;
+            }
+        }
+        copy_prefix(
+            &get_data_file_name(dir_path, active_file_id),
+            &get_data_file_name(&dest_path, active_file_id),
+            active_ofs,
+        )?;
+
+        // Blob files are written before the records referring to them, so the records copied
+        // above never refer to a missing blob.
+        self.blob_store.link_to(&get_blob_path(&dest_path))
+    }
+}
+
+/// Hard-link SRC to DEST, or copy it if they are on different file systems.
+pub(crate) fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_ok() {
+        return Ok(());
+    }
+    fs::copy(src, dest)
+        .map(|_| ())
+        .map_err(|_| Errors::FailedToWriteToDataFile)
+}
+
+/// Copy the first LEN bytes of SRC to DEST.
+fn copy_prefix(src: &Path, dest: &Path, len: u64) -> Result<()> {
+    let src_file = File::open(src).map_err(|_| Errors::FailedToOpenDataFile)?;
+    let mut dest_file = File::create(dest).map_err(|_| Errors::FailedToOpenDataFile)?;
+    io::copy(&mut src_file.take(len), &mut dest_file)
+        .map_err(|_| Errors::FailedToWriteToDataFile)?;
+    dest_file
+        .sync_all()
+        .map_err(|_| Errors::FailedToSyncToDataFile)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_engine_clone_to() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-clone-src");
+        opts.data_file_size = 32 * 1024;
+        let dest_path = PathBuf::from("/tmp/bitcask-rs-clone-dest");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+
+        assert!(engine.clone_to(&dest_path).is_ok());
+        assert_eq!(
+            engine.clone_to(&dest_path).err(),
+            Some(Errors::DatabaseDirNotEmpty)
+        );
+
+        // Writes after the clone only go to the source, and the other way around.
+        for i in 0..1000 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        let mut clone_opts = opts.clone();
+        clone_opts.dir_path = dest_path.clone();
+        let clone = Engine::open(clone_opts).expect("failed to open clone");
+        assert_eq!(clone.list_keys().unwrap().len(), 2000);
+        assert!(clone.put(get_test_key(5000), get_test_value(5000)).is_ok());
+        assert_eq!(engine.list_keys().unwrap().len(), 1000);
+        assert_eq!(
+            engine.get(get_test_key(5000)).err(),
+            Some(Errors::KeyNotFound)
+        );
+        std::mem::drop(engine);
+        std::mem::drop(clone);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 1000);
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(dest_path).expect("failed to remove path");
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
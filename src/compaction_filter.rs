@@ -0,0 +1,82 @@
+//! Compaction filters apply application-level retention policies during merge, for instance
+//! dropping stale sessions or redacting old values. The filter configured in `Options` sees every
+//! live record copied by merge, and decides whether it is kept, dropped or rewritten.
+//!
+//! Decisions take effect once the merged files are installed on the next startup, the running
+//! engine keeps serving the records as they were before the merge.
+
+/// The decision of a compaction filter on a live record.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterDecision {
+    /// Copy the record unchanged.
+    Keep,
+
+    /// Drop the record, as if the key was deleted.
+    Remove,
+
+    /// Copy the record with its value replaced.
+    ChangeValue(Vec<u8>),
+}
+
+/// Decides the fate of each live record copied by merge.
+pub trait CompactionFilter: Sync + Send {
+    /// Decide what to do with the live record of KEY with VALUE.
+    fn filter(&self, key: &[u8], value: &[u8]) -> FilterDecision;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    use bytes::Bytes;
+
+    use crate::{
+        db::Engine,
+        errors::Errors,
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    /// Drops the keys ending with an even digit, and redacts the values of the others.
+    struct TestFilter;
+
+    impl CompactionFilter for TestFilter {
+        fn filter(&self, key: &[u8], _value: &[u8]) -> FilterDecision {
+            match key.last().map(|c| (c - b'0') % 2) {
+                Some(0) => FilterDecision::Remove,
+                _ => FilterDecision::ChangeValue(b"redacted".to_vec()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-compaction-filter");
+        opts.data_file_size = 32 * 1024;
+        opts.data_file_merge_ratio = 0.0;
+        opts.compaction_filter = Some(Arc::new(TestFilter));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(engine.merge().is_ok());
+
+        // The running engine is not affected until the merged files are installed.
+        assert_eq!(engine.get(get_test_key(2)).unwrap(), get_test_value(2));
+        std::mem::drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 500);
+        assert_eq!(engine.get(get_test_key(2)).err(), Some(Errors::KeyNotFound));
+        assert_eq!(
+            engine.get(get_test_key(3)).unwrap(),
+            Bytes::from("redacted")
+        );
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,150 @@
+//! Counters. A counter is an entry whose value is an `i64` encoded as 8 little-endian bytes, and
+//! updated by `Engine::incr`. Updates of the same counter are serialized by the key locks of the
+//! engine, so concurrent increments are never lost.
+
+use bytes::Bytes;
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+};
+
+impl Engine {
+    /// Add DELTA to the counter KEY, return the new value. A missing counter starts from 0. Fail
+    /// with `Errors::ValueNotInteger` if the value of KEY is not a counter, and with
+    /// `Errors::IntegerOverflow` if the result does not fit in an `i64`.
+    ///
+    /// Increments are atomic with respect to all other writes of the key, except for
+    /// `bulk_load`.
+    pub fn incr(&self, key: Bytes, delta: i64) -> Result<i64> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let _key_lock = self.key_locks.lock(&key);
+        let current = match self.get(key.clone()) {
+            Ok(value) => decode_counter(&value)?,
+            Err(Errors::KeyNotFound) => 0,
+            Err(e) => return Err(e),
+        };
+        let value = current.checked_add(delta).ok_or(Errors::IntegerOverflow)?;
+        self.put_record(key, Bytes::copy_from_slice(&value.to_le_bytes()))?;
+        Ok(value)
+    }
+
+    /// Subtract DELTA from the counter KEY, return the new value. Same as `incr`.
+    pub fn decr(&self, key: Bytes, delta: i64) -> Result<i64> {
+        self.incr(key, delta.checked_neg().ok_or(Errors::IntegerOverflow)?)
+    }
+}
+
+fn decode_counter(value: &[u8]) -> Result<i64> {
+    let buf: [u8; 8] = value.try_into().map_err(|_| Errors::ValueNotInteger)?;
+    Ok(i64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, sync::Arc, thread};
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_engine_incr() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-incr");
+        let engine = Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+
+        assert_eq!(engine.incr(get_test_key(0), 5).unwrap(), 5);
+        assert_eq!(engine.decr(get_test_key(0), 7).unwrap(), -2);
+
+        // Concurrent increments are never lost.
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        engine.incr(get_test_key(1), 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(engine.incr(get_test_key(1), 0).unwrap(), 800);
+
+        assert!(engine.put(get_test_key(2), get_test_value(2)).is_ok());
+        assert_eq!(
+            engine.incr(get_test_key(2), 1).err(),
+            Some(Errors::ValueNotInteger)
+        );
+        assert!(engine.incr(get_test_key(3), i64::MAX).is_ok());
+        assert_eq!(
+            engine.incr(get_test_key(3), 1).err(),
+            Some(Errors::IntegerOverflow)
+        );
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_incr_conditional_writes() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-incr-conditional");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let key = get_test_key(0);
+        assert_eq!(engine.incr(key.clone(), 0).unwrap(), 0);
+
+        // Increments, compare-and-swaps and versioned writes of the same counter never lose one
+        // another's update.
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        engine.incr(key.clone(), 1).unwrap();
+                    }
+                });
+                s.spawn(|| {
+                    let mut swapped = 0;
+                    while swapped < 100 {
+                        let current = engine.get(key.clone()).unwrap();
+                        let next = decode_counter(&current).unwrap() + 1;
+                        let next = Bytes::copy_from_slice(&next.to_le_bytes());
+                        if engine
+                            .compare_and_swap(key.clone(), Some(current), Some(next))
+                            .is_ok()
+                        {
+                            swapped += 1;
+                        }
+                    }
+                });
+                s.spawn(|| {
+                    let mut written = 0;
+                    while written < 100 {
+                        let version = engine.get_version(key.clone()).unwrap();
+                        let current = engine.get(key.clone()).unwrap();
+                        let next = decode_counter(&current).unwrap() + 1;
+                        let next = Bytes::copy_from_slice(&next.to_le_bytes());
+                        if engine
+                            .put_with_version(key.clone(), next, Some(version))
+                            .is_ok()
+                        {
+                            written += 1;
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(engine.incr(key, 0).unwrap(), 600);
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
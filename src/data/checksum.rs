@@ -0,0 +1,89 @@
+//! Checksums of log records. Each record ends with the checksum of its header, key and value,
+//! computed by the `Options::checksum` it is written with. Records checked by CRC32 are written in
+//! the formats read by earlier versions, while the header of the other records names their
+//! algorithm, so files written with different settings are read alike.
+
+use xxhash_rust::xxh64::Xxh64;
+
+use crate::{data::data_file::CRC_LEN, options::ChecksumType};
+
+const CHECKSUM_CRC32: u8 = 0;
+const CHECKSUM_XXHASH64: u8 = 1;
+const CHECKSUM_NONE: u8 = 2;
+
+/// The seed of xxHash64 checksums.
+const XXHASH64_SEED: u64 = 0;
+
+/// Get the byte naming CHECKSUM in a record header.
+pub(crate) fn checksum_id(checksum: ChecksumType) -> u8 {
+    match checksum {
+        ChecksumType::Crc32 => CHECKSUM_CRC32,
+        ChecksumType::XxHash64 => CHECKSUM_XXHASH64,
+        ChecksumType::None => CHECKSUM_NONE,
+    }
+}
+
+/// Get the checksum named by ID in a record header, or None if it is unknown.
+pub(crate) fn checksum_from_id(id: u8) -> Option<ChecksumType> {
+    match id {
+        CHECKSUM_CRC32 => Some(ChecksumType::Crc32),
+        CHECKSUM_XXHASH64 => Some(ChecksumType::XxHash64),
+        CHECKSUM_NONE => Some(ChecksumType::None),
+        _ => None,
+    }
+}
+
+/// The size of a CHECKSUM at the end of a record.
+pub(crate) fn checksum_len(checksum: ChecksumType) -> usize {
+    match checksum {
+        ChecksumType::Crc32 => CRC_LEN,
+        ChecksumType::XxHash64 => std::mem::size_of::<u64>(),
+        ChecksumType::None => 0,
+    }
+}
+
+/// Compute CHECKSUM over PARTS, encoded as it ends a record.
+pub(crate) fn compute_checksum(checksum: ChecksumType, parts: &[&[u8]]) -> Vec<u8> {
+    match checksum {
+        ChecksumType::Crc32 => {
+            let mut hasher = crc32fast::Hasher::new();
+            parts.iter().for_each(|part| hasher.update(part));
+            hasher.finalize().to_be_bytes().to_vec()
+        }
+        ChecksumType::XxHash64 => {
+            let mut hasher = Xxh64::new(XXHASH64_SEED);
+            parts.iter().for_each(|part| hasher.update(part));
+            hasher.digest().to_be_bytes().to_vec()
+        }
+        ChecksumType::None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_checksum() {
+        let record: &[u8] = b"prince hamlet";
+        for checksum in [
+            ChecksumType::Crc32,
+            ChecksumType::XxHash64,
+            ChecksumType::None,
+        ] {
+            assert_eq!(checksum_from_id(checksum_id(checksum)), Some(checksum));
+            let computed = compute_checksum(checksum, &[&record[..6], &record[6..]]);
+            assert_eq!(computed.len(), checksum_len(checksum));
+            assert_eq!(computed, compute_checksum(checksum, &[record]));
+        }
+        assert_eq!(
+            compute_checksum(ChecksumType::Crc32, &[record]),
+            crc32fast::hash(record).to_be_bytes()
+        );
+        assert_ne!(
+            compute_checksum(ChecksumType::XxHash64, &[record]),
+            compute_checksum(ChecksumType::XxHash64, &[b"prince hamlef"])
+        );
+        assert_eq!(checksum_from_id(3), None);
+    }
+}
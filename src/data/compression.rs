@@ -0,0 +1,77 @@
+//! Compression of the values of log records. A compressed value is prefixed by a byte naming its
+//! codec, so files written with different `Options::compression` are read alike, and the record
+//! is flagged as compressed in its header.
+
+use crate::{
+    errors::{Errors, Result},
+    options::CompressionType,
+};
+
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+
+/// The level of Zstd compression, which is its default level.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compress VALUE with COMPRESSION, prefixed by the codec. Return None if it is not compressed,
+/// or the compressed value is not smaller.
+pub(crate) fn compress(compression: CompressionType, value: &[u8]) -> Option<Vec<u8>> {
+    let (codec, compressed) = match compression {
+        CompressionType::None => return None,
+        CompressionType::Lz4 => (CODEC_LZ4, lz4_flex::compress_prepend_size(value)),
+        CompressionType::Zstd => (CODEC_ZSTD, zstd::bulk::compress(value, ZSTD_LEVEL).ok()?),
+    };
+    if compressed.len() + 1 >= value.len() {
+        return None;
+    }
+
+    let mut buf = Vec::with_capacity(compressed.len() + 1);
+    buf.push(codec);
+    buf.extend_from_slice(&compressed);
+    Some(buf)
+}
+
+/// Decompress the VALUE returned by `compress`.
+pub(crate) fn decompress(value: &[u8]) -> Result<Vec<u8>> {
+    match value.split_first() {
+        Some((&CODEC_LZ4, compressed)) => {
+            lz4_flex::decompress_size_prepended(compressed).map_err(|_| Errors::DecompressionFailed)
+        }
+        Some((&CODEC_ZSTD, compressed)) => {
+            zstd::stream::decode_all(compressed).map_err(|_| Errors::DecompressionFailed)
+        }
+        _ => Err(Errors::DecompressionFailed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_decompress() {
+        let value = br#"{"name":"Prince Hamlet","city":"Elsinore"}"#.repeat(20);
+        for compression in [CompressionType::Lz4, CompressionType::Zstd] {
+            let compressed = compress(compression, &value).unwrap();
+            assert!(compressed.len() < value.len() / 5);
+            assert_eq!(decompress(&compressed).unwrap(), value);
+        }
+
+        // Values which do not shrink are left uncompressed.
+        assert!(compress(CompressionType::None, &value).is_none());
+        assert!(compress(CompressionType::Lz4, b"hamlet").is_none());
+
+        assert_eq!(decompress(b"").err(), Some(Errors::DecompressionFailed));
+        assert_eq!(
+            decompress(&[9, 1, 2]).err(),
+            Some(Errors::DecompressionFailed)
+        );
+        for compression in [CompressionType::Lz4, CompressionType::Zstd] {
+            let compressed = compress(compression, &value).unwrap();
+            assert_eq!(
+                decompress(&compressed[..compressed.len() / 2]).err(),
+                Some(Errors::DecompressionFailed)
+            );
+        }
+    }
+}
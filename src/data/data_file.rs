@@ -1,16 +1,33 @@
-use bytes::{Buf, BytesMut};
-use prost::{decode_length_delimiter, length_delimiter_len};
+use bytes::Buf;
+use prost::{
+    decode_length_delimiter,
+    encoding::{decode_varint, encoded_len_varint},
+    length_delimiter_len,
+};
 
 use std::{
-    path::PathBuf,
-    sync::{Arc, RwLock},
+    cell::RefCell,
+    fs,
+    io::IoSlice,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
 };
 
 use crate::{
-    data::log_record::{max_log_record_header_size, LogRecord, LogRecordType},
+    data::checksum::{checksum_from_id, checksum_len, compute_checksum},
+    data::compression::decompress,
+    data::log_record::{
+        max_log_record_header_size, LogRecord, LogRecordType, RECORD_FLAG_COMPRESSED,
+        RECORD_FORMAT_CHECKSUM, RECORD_FORMAT_FLAGS, RECORD_FORMAT_LATEST, RECORD_FORMAT_LEGACY,
+        RECORD_FORMAT_SHIFT, RECORD_FORMAT_TIMESTAMP, RECORD_FORMAT_USER_FLAGS, RECORD_TYPE_MASK,
+    },
     errors::{Errors, Result},
-    fio::{new_io_manager, IOManager},
-    options::IOType,
+    fio::{new_io_manager, IOManager, IOWrapper},
+    options::{ChecksumType, IOType},
+    utils::buffer_pool::take_zeroed_buffer,
 };
 
 use super::log_record::LogRecordPos;
@@ -19,66 +36,347 @@ use super::log_record::LogRecordPos;
 pub const DATA_FILE_NAME_SUFFIX: &str = ".data";
 pub const HINT_FILE_NAME: &str = "hint-index";
 pub const SEQUENCE_NUMBER_FILE_NAME: &str = "seq-no";
+pub const SEQUENCE_NUMBER_TMP_FILE_NAME: &str = "seq-no.tmp";
 pub const MERGE_FIN_FILE_NAME: &str = "merge-finished";
+pub const RECLAIM_STAT_FILE_NAME: &str = "reclaim-stat";
+pub const BLOB_GARBAGE_FILE_NAME: &str = "blob-garbage";
 
 pub const RECORD_TYPE_LEN: usize = 1;
 pub const CRC_LEN: usize = 4;
 
+/// Bytes read at once for a record whose size is not known, so that most records are parsed from a
+/// single read.
+const RECORD_READ_AHEAD: usize = 4096;
+
+/// Source of the `read_id` of the data files.
+static NEXT_READ_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static READ_AHEAD: RefCell<ReadAheadWindow> = RefCell::new(ReadAheadWindow::default());
+}
+
+/// The bytes of a sealed data file read ahead by the current thread, where:
+/// - `read_id` is the `read_id` of the file the window was read from, None if it is invalid.
+/// - `ofs` is the offset of the window in the file.
+/// - `buf` holds the `len` bytes read, followed by zeros up to at least the size of a header.
+#[derive(Default)]
+struct ReadAheadWindow {
+    read_id: Option<u64>,
+    ofs: u64,
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl ReadAheadWindow {
+    /// Whether the window holds the LEN bytes at offset OFS of the file of READ_ID.
+    fn holds(&self, read_id: u64, ofs: u64, len: usize) -> bool {
+        self.read_id == Some(read_id)
+            && ofs >= self.ofs
+            && ofs + len as u64 <= self.ofs + self.len as u64
+    }
+}
+
+/// The decoded header of a log record, see `LogRecord` for the layout.
+struct RecordHeader {
+    record_type: LogRecordType,
+    key_size: usize,
+    value_size: usize,
+    timestamp: Option<u64>,
+    compressed: bool,
+    checksum: ChecksumType,
+    user_flags: u8,
+    header_size: usize,
+}
+
+impl RecordHeader {
+    /// The size of the whole record.
+    fn record_size(&self) -> usize {
+        self.header_size + self.key_size + self.value_size + checksum_len(self.checksum)
+    }
+
+    /// Decode the header of the log record at offset OFS from HEADER_BUF, which holds at least
+    /// `max_log_record_header_size` bytes, zero past the end of the file. END_OFS is the logical
+    /// end of the file if known.
+    fn parse(header_buf: &[u8], ofs: u64, end_ofs: Option<u64>) -> Result<Self> {
+        // A zero-filled header is the unwritten tail of the file, either past its physical end or
+        // in space reserved ahead of the appends, so it ends the records unless the logical end
+        // of file is known.
+        if header_buf.iter().all(|b| *b == 0) {
+            return match end_ofs {
+                Some(_) => Err(Errors::InvalidLogRecordHeader),
+                None => Err(Errors::ReadDataFileEOF),
+            };
+        }
+
+        let mut buf = header_buf;
+        let type_byte = buf.get_u8();
+        let format = type_byte >> RECORD_FORMAT_SHIFT;
+        if format > RECORD_FORMAT_LATEST {
+            return Err(Errors::UnsupportedFormatVersion);
+        }
+        let mut type_bits = type_byte & ((1 << RECORD_FORMAT_SHIFT) - 1);
+        let mut compressed = false;
+        if format >= RECORD_FORMAT_FLAGS {
+            compressed = type_bits & RECORD_FLAG_COMPRESSED != 0;
+            type_bits &= RECORD_TYPE_MASK;
+        }
+        let record_type = LogRecordType::try_from_u8(type_bits);
+        let key_size = decode_length_delimiter(&mut buf);
+        let value_size = decode_length_delimiter(&mut buf);
+        let (key_size, value_size) = match (key_size, value_size) {
+            (Ok(key_size), Ok(value_size)) => (key_size, value_size),
+            _ => return Err(Errors::InvalidLogRecordHeader),
+        };
+        // The fields following the sizes depend on the format.
+        let timestamp = match format {
+            RECORD_FORMAT_LEGACY => None,
+            RECORD_FORMAT_TIMESTAMP
+            | RECORD_FORMAT_FLAGS
+            | RECORD_FORMAT_CHECKSUM
+            | RECORD_FORMAT_USER_FLAGS => {
+                Some(decode_varint(&mut buf).map_err(|_| Errors::InvalidLogRecordHeader)?)
+            }
+            _ => return Err(Errors::UnsupportedFormatVersion),
+        };
+        // Corrupted varints may take up all of HEADER_BUF, leaving none of the bytes following.
+        let get_u8 = |buf: &mut &[u8]| match buf.has_remaining() {
+            true => Ok(buf.get_u8()),
+            false => Err(Errors::InvalidLogRecordHeader),
+        };
+        let checksum = match format {
+            RECORD_FORMAT_CHECKSUM | RECORD_FORMAT_USER_FLAGS => Some(get_u8(&mut buf)?),
+            _ => None,
+        };
+        let user_flags = match format {
+            RECORD_FORMAT_USER_FLAGS => Some(get_u8(&mut buf)?),
+            _ => None,
+        };
+
+        // If there were no key, nor value, it is indicating we reach the end of file, unless the
+        // logical end of file is known.
+        if key_size == 0 && value_size == 0 {
+            return match end_ofs {
+                Some(_) => Err(Errors::InvalidLogRecordHeader),
+                None => Err(Errors::ReadDataFileEOF),
+            };
+        }
+        let record_type = record_type.ok_or(Errors::InvalidLogRecordHeader)?;
+
+        // HEADER_SIZE = 1 bytes for type + len(key_size) + len(value_size) + len(timestamp)
+        //             + len(checksum_id) + len(user_flags)
+        let header_size = RECORD_TYPE_LEN
+            + length_delimiter_len(key_size)
+            + length_delimiter_len(value_size)
+            + timestamp.map_or(0, encoded_len_varint)
+            + checksum.map_or(0, |_| std::mem::size_of::<u8>())
+            + user_flags.map_or(0, |_| std::mem::size_of::<u8>());
+        let checksum = match checksum {
+            Some(id) => checksum_from_id(id).ok_or(Errors::InvalidLogRecordHeader)?,
+            None => ChecksumType::Crc32,
+        };
+        let header = RecordHeader {
+            record_type,
+            key_size,
+            value_size,
+            timestamp,
+            compressed,
+            checksum,
+            user_flags: user_flags.unwrap_or(0),
+            header_size,
+        };
+        if end_ofs.is_some_and(|end_ofs| ofs + header.record_size() as u64 > end_ofs) {
+            return Err(Errors::InvalidLogRecordHeader);
+        }
+        Ok(header)
+    }
+
+    /// Decode the record of the header from RECORD, the whole record as it is stored. The
+    /// checksum is verified only if VERIFY_CRC is set to TRUE.
+    fn decode(&self, record: &[u8], verify_crc: bool) -> Result<LogRecord> {
+        // Check for the checksum, over the record as written, before the value is decompressed.
+        let (record, checksum) =
+            record.split_at(self.header_size + self.key_size + self.value_size);
+        if verify_crc && compute_checksum(self.checksum, &[record]) != checksum {
+            return Err(Errors::InvalidLogRecordCRC);
+        }
+
+        let (key, value) = record[self.header_size..].split_at(self.key_size);
+        Ok(LogRecord {
+            key: key.to_vec(),
+            value: match self.compressed {
+                true => decompress(value)?,
+                false => value.to_vec(),
+            },
+            record_type: self.record_type,
+            timestamp: self.timestamp,
+            user_flags: self.user_flags,
+        })
+    }
+}
+
 /// The struct used for storing data file, where
 /// - `file_id` is an unique identifier to for a data file.
 /// - `write_ofs` determines the current offset for writing a log record. When writing a new
 ///     record into the current data file, the encoded record is write at the position `write_ofs`.
-/// - `io_manager` provides the interface for file input and output.
+/// - `io_manager` provides the interface for file input and output. It is opened on the first
+///   access, and can be released by `close_io` to bound the number of opened descriptors.
+/// - `file_name` and `io_type` are used for (re)opening `io_manager`.
+/// - `io_wrapper` wraps `io_manager` whenever it is opened, if set.
+/// - `end_ofs` is the logical end of a sealed file. Reading at it returns EOF, and a record
+///   crossing it is corrupted. It is unknown for the active file, whose end is detected by an
+///   empty header or the physical end of the file.
+/// - `read_id` identifies the bytes read ahead from the file, and is renewed whenever the file is
+///   closed, so no window read before is used again.
 pub struct DataFile {
-    file_id: Arc<RwLock<u32>>,
+    file_id: Arc<RwLock<u64>>,
     write_ofs: Arc<RwLock<u64>>,
-    io_manager: Box<dyn IOManager>,
+    io_manager: RwLock<Option<Box<dyn IOManager>>>,
+    file_name: PathBuf,
+    io_type: IOType,
+    io_wrapper: Option<Arc<dyn IOWrapper>>,
+    end_ofs: RwLock<Option<u64>>,
+    read_id: AtomicU64,
 }
 
 impl DataFile {
     /// Initialize a new DataFile struct according to DIR_PATH and FILE_ID.
-    pub fn new(dir_path: &PathBuf, file_id: u32, io_type: IOType) -> Result<DataFile> {
+    pub fn new(dir_path: &PathBuf, file_id: u64, io_type: IOType) -> Result<DataFile> {
         let file_name = get_data_file_name(dir_path, file_id);
-        let io_manager = new_io_manager(file_name, io_type);
-        Ok(DataFile {
+        DataFile::open(file_name, file_id, io_type)
+    }
+
+    /// Initialize a DataFile struct according to DIR_PATH and FILE_ID without opening the
+    /// underlying file, which is deferred to the first read or write.
+    pub fn new_lazy(dir_path: &PathBuf, file_id: u64, io_type: IOType) -> DataFile {
+        DataFile {
             file_id: Arc::new(RwLock::new(file_id)),
             write_ofs: Arc::new(RwLock::new(0)),
-            io_manager,
-        })
+            io_manager: RwLock::new(None),
+            file_name: get_data_file_name(dir_path, file_id),
+            io_type,
+            io_wrapper: None,
+            end_ofs: RwLock::new(None),
+            read_id: AtomicU64::new(new_read_id()),
+        }
     }
 
     pub fn new_hint_file(dir_path: &PathBuf) -> Result<DataFile> {
-        let file_name = dir_path.join(HINT_FILE_NAME);
-        let io_manager = new_io_manager(file_name, IOType::StandardFIO);
-        Ok(DataFile {
-            file_id: Arc::new(RwLock::new(0)),
-            write_ofs: Arc::new(RwLock::new(0)),
-            io_manager,
-        })
+        DataFile::open(dir_path.join(HINT_FILE_NAME), 0, IOType::StandardFIO)
     }
 
     pub fn new_merge_fin_file(dir_path: &PathBuf) -> Result<DataFile> {
-        let file_name = dir_path.join(MERGE_FIN_FILE_NAME);
-        let io_manager = new_io_manager(file_name, IOType::StandardFIO);
-        Ok(DataFile {
-            file_id: Arc::new(RwLock::new(0)),
-            write_ofs: Arc::new(RwLock::new(0)),
-            io_manager,
-        })
+        DataFile::open(dir_path.join(MERGE_FIN_FILE_NAME), 0, IOType::StandardFIO)
+    }
+
+    pub fn new_sequence_number_file(dir_path: &Path, file_name: &str) -> Result<DataFile> {
+        DataFile::open(dir_path.join(file_name), 0, IOType::StandardFIO)
+    }
+
+    pub fn new_manifest_file(dir_path: &Path, file_name: &str) -> Result<DataFile> {
+        DataFile::open(dir_path.join(file_name), 0, IOType::StandardFIO)
+    }
+
+    pub fn new_change_offset_file(dir_path: &Path, file_name: &str) -> Result<DataFile> {
+        DataFile::open(dir_path.join(file_name), 0, IOType::StandardFIO)
+    }
+
+    /// Open a scratch file FILE_NAME under DIR_PATH, written and then renamed over its final
+    /// name.
+    pub fn new_tmp_file(dir_path: &Path, file_name: &str) -> Result<DataFile> {
+        DataFile::open(dir_path.join(file_name), 0, IOType::StandardFIO)
+    }
+
+    pub fn new_prepared_file(dir_path: &Path, file_name: &str) -> Result<DataFile> {
+        DataFile::open(dir_path.join(file_name), 0, IOType::StandardFIO)
+    }
+
+    pub fn new_reclaim_stat_file(dir_path: &Path) -> Result<DataFile> {
+        DataFile::open(
+            dir_path.join(RECLAIM_STAT_FILE_NAME),
+            0,
+            IOType::StandardFIO,
+        )
     }
 
-    pub fn new_sequence_number_file(dir_path: &PathBuf) -> Result<DataFile> {
-        let file_name = dir_path.join(SEQUENCE_NUMBER_FILE_NAME);
-        let io_manager = new_io_manager(file_name, IOType::StandardFIO);
+    pub fn new_blob_garbage_file(dir_path: &Path) -> Result<DataFile> {
+        DataFile::open(
+            dir_path.join(BLOB_GARBAGE_FILE_NAME),
+            0,
+            IOType::StandardFIO,
+        )
+    }
+
+    fn open(file_name: PathBuf, file_id: u64, io_type: IOType) -> Result<DataFile> {
+        let io_manager = new_io_manager(file_name.clone(), io_type)?;
         Ok(DataFile {
-            file_id: Arc::new(RwLock::new(0)),
+            file_id: Arc::new(RwLock::new(file_id)),
             write_ofs: Arc::new(RwLock::new(0)),
-            io_manager,
+            io_manager: RwLock::new(Some(io_manager)),
+            file_name,
+            io_type,
+            io_wrapper: None,
+            end_ofs: RwLock::new(None),
+            read_id: AtomicU64::new(new_read_id()),
         })
     }
 
+    /// Invoke F with the IO manager of the current file, open the file if it is not opened yet.
+    fn with_io_manager<T>(&self, f: impl FnOnce(&dyn IOManager) -> Result<T>) -> Result<T> {
+        {
+            let io_manager = self.io_manager.read().unwrap();
+            if let Some(io_manager) = io_manager.as_ref() {
+                return f(io_manager.as_ref());
+            }
+        }
+
+        let mut io_manager = self.io_manager.write().unwrap();
+        if io_manager.is_none() {
+            *io_manager =
+                Some(self.wrap_io_manager(new_io_manager(self.file_name.clone(), self.io_type)?));
+        }
+        f(io_manager.as_ref().unwrap().as_ref())
+    }
+
+    /// Wrap the IO manager of the file by IO_WRAPPER whenever it is opened, if set.
+    pub fn with_io_wrapper(mut self, io_wrapper: Option<Arc<dyn IOWrapper>>) -> DataFile {
+        self.io_wrapper = io_wrapper;
+        let io_manager = self.io_manager.get_mut().unwrap().take();
+        *self.io_manager.get_mut().unwrap() = io_manager.map(|io| self.wrap_io_manager(io));
+        self
+    }
+
+    fn wrap_io_manager(&self, io_manager: Box<dyn IOManager>) -> Box<dyn IOManager> {
+        match &self.io_wrapper {
+            Some(io_wrapper) => io_wrapper.wrap(&self.file_name, io_manager),
+            None => io_manager,
+        }
+    }
+
+    /// Set the logical end of a sealed file to END_OFS.
+    pub fn set_end_ofs(&self, end_ofs: u64) {
+        *self.end_ofs.write().unwrap() = Some(end_ofs);
+    }
+
+    pub fn get_end_ofs(&self) -> Option<u64> {
+        *self.end_ofs.read().unwrap()
+    }
+
+    /// Whether the underlying file is currently opened.
+    pub fn is_open(&self) -> bool {
+        self.io_manager.read().unwrap().is_some()
+    }
+
+    /// Release the underlying file handle, it is reopened on the next access.
+    pub fn close_io(&self) {
+        *self.io_manager.write().unwrap() = None;
+        self.read_id.store(new_read_id(), Ordering::Release);
+    }
+
     pub fn file_size(&self) -> u64 {
-        self.io_manager.size()
+        if let Some(io_manager) = self.io_manager.read().unwrap().as_ref() {
+            return io_manager.size();
+        }
+        fs::metadata(&self.file_name).map(|m| m.len()).unwrap_or(0)
     }
 
     pub fn get_write_ofs(&self) -> u64 {
@@ -90,48 +388,204 @@ impl DataFile {
         *write_ofs = ofs;
     }
 
-    pub fn get_file_id(&self) -> u32 {
+    pub fn get_file_id(&self) -> u64 {
         *self.file_id.read().unwrap()
     }
 
+    /// Get the IO type the file is opened with.
+    pub fn get_io_type(&self) -> IOType {
+        self.io_type
+    }
+
     // Read the log record from
     pub fn read_log_record(&self, ofs: u64) -> Result<(LogRecord, usize)> {
-        let mut header_buf = BytesMut::zeroed(max_log_record_header_size());
-        self.io_manager.read(&mut header_buf, ofs)?;
-
-        let record_type = LogRecordType::from_u8(header_buf.get_u8());
-        let key_size = decode_length_delimiter(&mut header_buf).unwrap();
-        let value_size = decode_length_delimiter(&mut header_buf).unwrap();
+        self.read_log_record_with_crc(ofs, true)
+    }
 
-        // If there were no key, nor value, it is indicating we reach the end of file.
-        if key_size == 0 && value_size == 0 {
+    /// Read the log record at offset OFS, the CRC is verified only if VERIFY_CRC is set to TRUE.
+    /// The records of a sealed file are parsed from a window of `RECORD_READ_AHEAD` bytes read
+    /// ahead by the current thread, so scanning them reads the file in large chunks.
+    pub fn read_log_record_with_crc(
+        &self,
+        ofs: u64,
+        verify_crc: bool,
+    ) -> Result<(LogRecord, usize)> {
+        // The unwritten tail of a file whose end is unknown reads as zeros until it is appended,
+        // so it is never read ahead.
+        let end_ofs = match self.get_end_ofs() {
+            Some(end_ofs) => end_ofs,
+            None => return self.read_log_record_sized(ofs, RECORD_READ_AHEAD, verify_crc),
+        };
+        if ofs >= end_ofs {
             return Err(Errors::ReadDataFileEOF);
         }
 
-        // HEADER_SIZE = 1 bytes for type + len(key_size) + len(value_size)
-        let header_size =
-            RECORD_TYPE_LEN + length_delimiter_len(key_size) + length_delimiter_len(value_size);
+        let header_len = max_log_record_header_size();
+        let read_id = self.read_id.load(Ordering::Acquire);
+        READ_AHEAD.with(|window| {
+            let mut window = window.borrow_mut();
+            if !window.holds(read_id, ofs, header_len) {
+                self.read_ahead(&mut window, read_id, ofs, RECORD_READ_AHEAD, end_ofs)?;
+            }
+            let start = (ofs - window.ofs) as usize;
+            let header =
+                RecordHeader::parse(&window.buf[start..start + header_len], ofs, Some(end_ofs))?;
+            let record_size = header.record_size();
+            if !window.holds(read_id, ofs, record_size) {
+                // The record crosses the end of the window, which is moved to its start.
+                let len = record_size.max(RECORD_READ_AHEAD);
+                self.read_ahead(&mut window, read_id, ofs, len, end_ofs)?;
+                if window.len < record_size {
+                    return Err(Errors::TruncatedLogRecord);
+                }
+            }
+            let start = (ofs - window.ofs) as usize;
+            let log_record = header.decode(&window.buf[start..start + record_size], verify_crc)?;
+            Ok((log_record, record_size))
+        })
+    }
 
-        let mut kv_buf = BytesMut::zeroed(key_size + value_size + CRC_LEN);
-        self.io_manager
-            .read(&mut kv_buf, ofs + header_size as u64)?;
-        let log_record = LogRecord {
-            key: kv_buf.get(..key_size).unwrap().to_vec(),
-            value: kv_buf.get(key_size..kv_buf.len() - 4).unwrap().to_vec(),
-            record_type,
+    /// Same as `read_log_record_with_crc`, expecting the record to be SIZE_HINT bytes long, such
+    /// as the size of its position in the index. The record is parsed from a single read of
+    /// SIZE_HINT bytes if it fits, and the rest is read otherwise, so a wrong hint only costs
+    /// another read.
+    pub fn read_log_record_sized(
+        &self,
+        ofs: u64,
+        size_hint: usize,
+        verify_crc: bool,
+    ) -> Result<(LogRecord, usize)> {
+        let end_ofs = self.get_end_ofs();
+        if end_ofs.is_some_and(|end_ofs| ofs >= end_ofs) {
+            return Err(Errors::ReadDataFileEOF);
+        }
+
+        // The bytes past those read are zero, as expected by `RecordHeader::parse`.
+        let header_len = max_log_record_header_size();
+        let len = size_hint.max(header_len);
+        let len = match end_ofs {
+            Some(end_ofs) => len.min((end_ofs - ofs) as usize),
+            None => len,
         };
+        let mut buf = take_zeroed_buffer(len.max(header_len));
+        let read_size = self.read_clamped(&mut buf[..len], ofs)?;
+
+        let header = RecordHeader::parse(&buf[..header_len], ofs, end_ofs)?;
+        let record_size = header.record_size();
+        if read_size < record_size {
+            buf.resize(record_size, 0);
+            let rest = &mut buf[read_size..record_size];
+            let rest_size = self
+                .with_io_manager(|io| io.read(rest, ofs + read_size as u64))
+                .map_err(|e| match e {
+                    Errors::ReadDataFileEOF => Errors::TruncatedLogRecord,
+                    e => e,
+                })?;
+            if rest_size < record_size - read_size {
+                return Err(Errors::TruncatedLogRecord);
+            }
+        }
+        let log_record = header.decode(&buf[..record_size], verify_crc)?;
+        Ok((log_record, record_size))
+    }
 
-        // Check for CRC.
-        kv_buf.advance(key_size + value_size);
-        if kv_buf.get_u32() != log_record.get_crc() {
-            return Err(Errors::InvalidLogRecordCRC);
+    /// Move WINDOW to the LEN bytes at offset OFS, read before END_OFS. READ_ID is the current
+    /// `read_id` of the file.
+    fn read_ahead(
+        &self,
+        window: &mut ReadAheadWindow,
+        read_id: u64,
+        ofs: u64,
+        len: usize,
+        end_ofs: u64,
+    ) -> Result<()> {
+        let len = len.min((end_ofs - ofs) as usize);
+        // The window is invalid until it is read.
+        window.read_id = None;
+        window.buf.clear();
+        window.buf.resize(len.max(max_log_record_header_size()), 0);
+        window.len = self.read_clamped(&mut window.buf[..len], ofs)?;
+        (window.read_id, window.ofs) = (Some(read_id), ofs);
+        Ok(())
+    }
+
+    /// Read into BUF the bytes at offset OFS, up to the end of the file. Some IO managers refuse
+    /// to read past the end of the file, so the read is retried up to it then.
+    fn read_clamped(&self, buf: &mut [u8], ofs: u64) -> Result<usize> {
+        match self.with_io_manager(|io| io.read(buf, ofs)) {
+            Err(Errors::ReadDataFileEOF) => {
+                let len = buf.len().min(self.file_size().saturating_sub(ofs) as usize);
+                self.with_io_manager(|io| io.read(&mut buf[..len], ofs))
+            }
+            res => res,
         }
+    }
 
-        Ok((log_record, header_size + key_size + value_size + 4))
+    /// Get the size of the log record at offset OFS from its header, without reading the key
+    /// and value.
+    pub fn read_log_record_size(&self, ofs: u64) -> Result<usize> {
+        Ok(self.read_header(ofs)?.record_size())
+    }
+
+    /// Get the size of the value of the log record at offset OFS from its header, without reading
+    /// the key and value.
+    pub fn read_value_size(&self, ofs: u64) -> Result<usize> {
+        Ok(self.read_header(ofs)?.value_size)
+    }
+
+    /// Read the LEN bytes at offset OFS as they are stored, regardless of the records.
+    pub fn read_bytes(&self, ofs: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        let read_size = self.with_io_manager(|io| io.read(&mut buf, ofs))?;
+        if read_size < len {
+            return Err(Errors::ReadDataFileEOF);
+        }
+        Ok(buf)
+    }
+
+    /// Decode the header of the log record at offset OFS.
+    fn read_header(&self, ofs: u64) -> Result<RecordHeader> {
+        let end_ofs = self.get_end_ofs();
+        if end_ofs.is_some_and(|end_ofs| ofs >= end_ofs) {
+            return Err(Errors::ReadDataFileEOF);
+        }
+
+        let mut header_buf = take_zeroed_buffer(max_log_record_header_size());
+        self.with_io_manager(|io| io.read(&mut header_buf, ofs))?;
+
+        RecordHeader::parse(&header_buf, ofs, end_ofs)
+    }
+
+    /// Truncate the underlying file to SIZE bytes, the file is reopened on the next access.
+    pub fn truncate(&self, size: u64) -> Result<()> {
+        self.close_io();
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(&self.file_name)
+            .map_err(|_| Errors::FailedToOpenDataFile)?;
+        file.set_len(size)
+            .map_err(|_| Errors::FailedToWriteToDataFile)?;
+        self.set_write_ofs(size);
+        if self.get_end_ofs().is_some() {
+            self.set_end_ofs(size);
+        }
+        Ok(())
     }
 
     pub fn write(&self, buf: &[u8]) -> Result<usize> {
-        let size = self.io_manager.write(buf)?;
+        let size = self.with_io_manager(|io| io.write(buf))?;
+        *self.write_ofs.write().unwrap() += size as u64;
+        Ok(size)
+    }
+
+    /// Reserve the disk space of the first LEN bytes of the data file, without changing its size.
+    pub fn preallocate(&self, len: u64) -> Result<()> {
+        self.with_io_manager(|io| io.preallocate(len))
+    }
+
+    /// Write the contents of BUFS to the data file in order, at once if the IO manager allows.
+    pub fn write_vectored(&self, bufs: &[IoSlice]) -> Result<usize> {
+        let size = self.with_io_manager(|io| io.write_vectored(bufs))?;
         *self.write_ofs.write().unwrap() += size as u64;
         Ok(size)
     }
@@ -142,6 +596,8 @@ impl DataFile {
             key,
             value: pos.encode(),
             record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
         };
         let encoded_record = hint_record.encode();
         self.write(&encoded_record)?;
@@ -149,19 +605,37 @@ impl DataFile {
     }
 
     pub fn sync(&self) -> Result<()> {
-        self.io_manager.sync()
+        match self.io_manager.read().unwrap().as_ref() {
+            Some(io_manager) => io_manager.sync(),
+            // Nothing is written through a closed handle, so there is nothing to sync.
+            None => Ok(()),
+        }
     }
 
     pub fn set_io_manager(&mut self, dir_path: &PathBuf, io_type: IOType) {
-        self.io_manager = new_io_manager(get_data_file_name(dir_path, self.get_file_id()), io_type);
+        self.file_name = get_data_file_name(dir_path, self.get_file_id());
+        self.io_type = io_type;
+        self.close_io();
     }
 }
 
-pub(crate) fn get_data_file_name(dir_path: &PathBuf, file_id: u32) -> PathBuf {
+pub(crate) fn get_data_file_name(dir_path: &PathBuf, file_id: u64) -> PathBuf {
     let name = std::format!("{:09}", file_id) + DATA_FILE_NAME_SUFFIX;
     dir_path.join(name)
 }
 
+/// Get the file id from the name of a data file, or None if FILE_NAME is not a data file.
+pub(crate) fn parse_data_file_id(file_name: &str) -> Option<u64> {
+    file_name
+        .strip_suffix(DATA_FILE_NAME_SUFFIX)?
+        .parse::<u64>()
+        .ok()
+}
+
+fn new_read_id() -> u64 {
+    NEXT_READ_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
@@ -234,6 +708,8 @@ mod tests {
             key: "Protagonist".as_bytes().to_vec(),
             value: "Prince Hamlet".as_bytes().to_vec(),
             record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
         };
         let write_res1 = data_file1.write(&record1.encode());
         assert!(write_res1.is_ok());
@@ -243,21 +719,181 @@ mod tests {
         let (read1, size1) = read_res1.unwrap();
         assert_eq!(read1, record1);
 
-        // second rw
+        // second rw, in the timestamped format
         let record2 = LogRecord {
             key: "Author".as_bytes().to_vec(),
             value: "William Shakespeare".as_bytes().to_vec(),
             record_type: LogRecordType::Normal,
+            timestamp: Some(1_700_000_000_000),
+            user_flags: 0,
         };
         let write_res2 = data_file1.write(&record2.encode());
         assert!(write_res2.is_ok());
         let read_res2 = data_file1.read_log_record(size1 as u64);
         assert!(read_res2.is_ok());
-        let (read2, _) = read_res2.unwrap();
+        let (read2, size2) = read_res2.unwrap();
         assert_eq!(read2, record2);
+        assert_eq!(size2, record2.encode().len());
         assert!(fs::remove_file(get_data_file_name(&dir_path, data_file1.get_file_id())).is_ok());
     }
 
+    #[test]
+    fn test_data_file_lazy_open() {
+        let dir_path = std::env::temp_dir();
+        let data_file1 = DataFile::new(&dir_path, 7, IOType::StandardFIO).unwrap();
+        let record1 = LogRecord {
+            key: "Protagonist".as_bytes().to_vec(),
+            value: "Prince Hamlet".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
+        };
+        data_file1.write(&record1.encode()).unwrap();
+        data_file1.sync().unwrap();
+
+        let data_file2 = DataFile::new_lazy(&dir_path, 7, IOType::StandardFIO);
+        assert!(!data_file2.is_open());
+        assert_eq!(data_file2.file_size(), record1.encode().len() as u64);
+
+        let (read1, _) = data_file2.read_log_record(0).unwrap();
+        assert_eq!(read1, record1);
+        assert!(data_file2.is_open());
+
+        data_file2.close_io();
+        assert!(!data_file2.is_open());
+        let (read2, _) = data_file2.read_log_record(0).unwrap();
+        assert_eq!(read2, record1);
+        assert!(fs::remove_file(get_data_file_name(&dir_path, 7)).is_ok());
+    }
+
+    #[test]
+    fn test_data_file_end_ofs() {
+        let dir_path = std::env::temp_dir();
+        let data_file1 = DataFile::new(&dir_path, 8, IOType::StandardFIO).unwrap();
+        let record1 = LogRecord {
+            key: "Protagonist".as_bytes().to_vec(),
+            value: "Prince Hamlet".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
+        };
+        let encoded = record1.encode();
+        data_file1.write(&encoded).unwrap();
+        data_file1.write(&[0; 16]).unwrap();
+        data_file1.sync().unwrap();
+
+        // without a known end, the zeroed region reads as the end of file
+        assert_eq!(
+            data_file1.read_log_record(encoded.len() as u64).err(),
+            Some(Errors::ReadDataFileEOF)
+        );
+
+        // within a known end, the zeroed region is an invalid record
+        let file_size = data_file1.file_size();
+        data_file1.set_end_ofs(file_size);
+        assert_eq!(
+            data_file1.read_log_record(encoded.len() as u64).err(),
+            Some(Errors::InvalidLogRecordHeader)
+        );
+        assert_eq!(
+            data_file1.read_log_record(file_size).err(),
+            Some(Errors::ReadDataFileEOF)
+        );
+
+        // a record cut short by the end of the file is truncated
+        data_file1.truncate(encoded.len() as u64 - 4).unwrap();
+        assert_eq!(
+            data_file1.read_log_record(0).err(),
+            Some(Errors::InvalidLogRecordHeader)
+        );
+        *data_file1.end_ofs.write().unwrap() = None;
+        assert_eq!(
+            data_file1.read_log_record(0).err(),
+            Some(Errors::TruncatedLogRecord)
+        );
+        assert!(fs::remove_file(get_data_file_name(&dir_path, 8)).is_ok());
+    }
+
+    #[test]
+    fn test_data_file_read_ahead() {
+        let dir_path = PathBuf::from("/tmp/bitcask-rs-read-ahead");
+        fs::create_dir_all(&dir_path).unwrap();
+        for io_type in [IOType::StandardFIO, IOType::MemoryMapped] {
+            let data_file = DataFile::new(&dir_path, 0, IOType::StandardFIO).unwrap();
+            // Small records share the windows read ahead, and the large ones cross them.
+            let records: Vec<LogRecord> = (0..200)
+                .map(|i| LogRecord {
+                    key: format!("guildenstern-{}", i).into_bytes(),
+                    value: vec![
+                        i as u8;
+                        if i % 50 == 7 {
+                            3 * RECORD_READ_AHEAD
+                        } else {
+                            40
+                        }
+                    ],
+                    record_type: LogRecordType::Normal,
+                    timestamp: Some(i),
+                    user_flags: 0,
+                })
+                .collect();
+            let mut positions = Vec::new();
+            for record in &records {
+                let encoded = record.encode();
+                positions.push((data_file.get_write_ofs(), encoded.len()));
+                data_file.write(&encoded).unwrap();
+            }
+            data_file.sync().unwrap();
+            drop(data_file);
+
+            let data_file = DataFile::new(&dir_path, 0, io_type).unwrap();
+            data_file.set_end_ofs(data_file.file_size());
+            let mut ofs = 0;
+            for record in &records {
+                let (read, size) = data_file.read_log_record(ofs).unwrap();
+                assert_eq!(&read, record);
+                ofs += size as u64;
+            }
+            assert_eq!(
+                data_file.read_log_record(ofs).err(),
+                Some(Errors::ReadDataFileEOF)
+            );
+
+            // A wrong size hint only costs another read.
+            let (ofs, size) = positions[57];
+            for size_hint in [0, 10, size, size * 2] {
+                let (read, read_size) = data_file
+                    .read_log_record_sized(ofs, size_hint, true)
+                    .unwrap();
+                assert_eq!((&read, read_size), (&records[57], size));
+            }
+
+            // The windows read ahead are not used once the file is rewritten.
+            let (last_ofs, _) = positions[199];
+            assert!(data_file.read_log_record(last_ofs).is_ok());
+            data_file.truncate(last_ofs).unwrap();
+            data_file.write(&records[0].encode()).unwrap();
+            data_file.set_end_ofs(data_file.file_size());
+            assert_eq!(data_file.read_log_record(last_ofs).unwrap().0, records[0]);
+
+            fs::remove_file(get_data_file_name(&dir_path, 0)).unwrap();
+        }
+        fs::remove_dir_all(dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_data_file_name_wide_id() {
+        let dir_path = std::env::temp_dir();
+        let file_id = u32::MAX as u64 + 1;
+        let file_name = get_data_file_name(&dir_path, file_id);
+        let file_name = file_name.file_name().unwrap().to_str().unwrap();
+        assert_eq!(parse_data_file_id(file_name), Some(file_id));
+
+        // Names written with u32 file ids are still recognized.
+        assert_eq!(parse_data_file_id("000000042.data"), Some(42));
+        assert_eq!(parse_data_file_id("MANIFEST"), None);
+    }
+
     #[test]
     fn test_data_file_rld_deleted() {
         let dir_path = std::env::temp_dir();
@@ -271,6 +907,8 @@ mod tests {
             key: "nothing".as_bytes().to_vec(),
             value: Default::default(),
             record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
         };
         let write_res1 = data_file1.write(&record1.encode());
         assert!(write_res1.is_ok());
@@ -280,4 +918,31 @@ mod tests {
         assert_eq!(read1, record1);
         assert!(fs::remove_file(get_data_file_name(&dir_path, data_file1.get_file_id())).is_ok());
     }
+
+    #[test]
+    fn test_data_file_corrupted_header() {
+        let dir_path = std::env::temp_dir().join("data-file-corrupted-header");
+        fs::create_dir_all(&dir_path).unwrap();
+        let data_file = DataFile::new(&dir_path, 0, IOType::StandardFIO).unwrap();
+
+        // The 10-byte sizes and the timestamp take up the whole header read, leaving nothing for
+        // the checksum id and the user flags.
+        let mut header = vec![RECORD_FORMAT_USER_FLAGS << RECORD_FORMAT_SHIFT];
+        for _ in 0..2 {
+            header.extend_from_slice(&[0xff; 9]);
+            header.push(0x01);
+        }
+        header.extend_from_slice(&[0x81, 0x01]);
+        assert_eq!(header.len(), max_log_record_header_size());
+        assert!(data_file.write(&header).is_ok());
+        assert_eq!(
+            data_file.read_log_record(0).err(),
+            Some(Errors::InvalidLogRecordHeader)
+        );
+        assert_eq!(
+            data_file.read_log_record_size(0).err(),
+            Some(Errors::InvalidLogRecordHeader)
+        );
+        fs::remove_dir_all(dir_path).unwrap();
+    }
 }
@@ -0,0 +1,241 @@
+//! The footer of a sealed data file summarizes its records, so they need not be scanned to learn
+//! where they end or their highest sequence number. It is appended right after the last record
+//! when the file is sealed by rotation or written by a merge, past the logical end of the file,
+//! so readers of the records never see it. Files sealed before footers were written have none.
+//!
+//! The footer is formatted as follows, where all integers are little-endian:
+//! ```text
+//!  +--------------+-----------+----------+---------+---------+---------+---------+-----+------+-------+
+//!  | record_count | data_size | data_crc | min_seq | max_seq | min_key | max_key | CRC | size | magic |
+//!  +--------------+-----------+----------+---------+---------+---------+---------+-----+------+-------+
+//! ```
+//! - `data_size` is the size of the records, and `data_crc` their CRC32.
+//! - each key is `| key_size (u32) | key |`.
+//! - `CRC` covers the fields before it, and `size` is the size of the whole footer, so it is
+//!   found from the end of the file.
+
+use crate::{
+    data::{data_file::DataFile, log_record::LogRecordType},
+    db::parse_log_record_key,
+    errors::Result,
+};
+
+const FOOTER_MAGIC: &[u8; 8] = b"SDBFOOT1";
+
+/// Size of the CRC, size and magic ending a footer.
+const FOOTER_TRAILER_LEN: usize = 4 + 4 + 8;
+
+/// Summary of the records of a sealed data file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DataFileFooter {
+    /// Number of records in the file.
+    pub record_count: u64,
+
+    /// Size of the records, which is the logical end of the file.
+    pub data_size: u64,
+
+    /// CRC32 of the records.
+    pub data_crc: u32,
+
+    /// The lowest and highest sequence numbers of the records, 0 if the file is empty.
+    pub min_sequence_number: u64,
+    pub max_sequence_number: u64,
+
+    /// The lowest and highest keys of the records setting or deleting a key, empty if there is
+    /// none.
+    pub min_key: Vec<u8>,
+    pub max_key: Vec<u8>,
+}
+
+impl DataFileFooter {
+    /// Read the footer ending DATA_FILE, or None if it has no valid one.
+    pub fn read(data_file: &DataFile) -> Option<Self> {
+        let file_size = data_file.file_size();
+        let trailer_ofs = file_size.checked_sub(8 + 4)?;
+        let trailer = data_file.read_bytes(trailer_ofs, 8 + 4).ok()?;
+        if &trailer[4..] != FOOTER_MAGIC {
+            return None;
+        }
+        let size = u32::from_le_bytes(trailer[..4].try_into().unwrap()) as u64;
+        let buf = data_file
+            .read_bytes(file_size.checked_sub(size)?, size as usize)
+            .ok()?;
+        Self::decode(&buf).filter(|footer| footer.data_size + size == file_size)
+    }
+
+    /// Append the footer to DATA_FILE, right after its records.
+    pub(crate) fn write(&self, data_file: &DataFile) -> Result<()> {
+        data_file.write(&self.encode()).map(|_| ())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.record_count.to_le_bytes());
+        buf.extend_from_slice(&self.data_size.to_le_bytes());
+        buf.extend_from_slice(&self.data_crc.to_le_bytes());
+        buf.extend_from_slice(&self.min_sequence_number.to_le_bytes());
+        buf.extend_from_slice(&self.max_sequence_number.to_le_bytes());
+        for key in [&self.min_key, &self.max_key] {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key);
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        let size = buf.len() + 4 + FOOTER_MAGIC.len();
+        buf.extend_from_slice(&(size as u32).to_le_bytes());
+        buf.extend_from_slice(FOOTER_MAGIC);
+        buf
+    }
+
+    /// Decode the footer encoded as BUF, or None if it is corrupted.
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let body = buf.get(..buf.len().checked_sub(FOOTER_TRAILER_LEN)?)?;
+        let crc = u32::from_le_bytes(buf.get(body.len()..body.len() + 4)?.try_into().ok()?);
+        if crc32fast::hash(body) != crc {
+            return None;
+        }
+
+        let mut ofs = 0;
+        let mut take = |len: usize| -> Option<&[u8]> {
+            let field = body.get(ofs..ofs + len)?;
+            ofs += len;
+            Some(field)
+        };
+        let record_count = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let data_size = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let data_crc = u32::from_le_bytes(take(4)?.try_into().ok()?);
+        let min_sequence_number = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let max_sequence_number = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let min_key_len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+        let min_key = take(min_key_len)?.to_vec();
+        let max_key_len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+        let max_key = take(max_key_len)?.to_vec();
+        Some(Self {
+            record_count,
+            data_size,
+            data_crc,
+            min_sequence_number,
+            max_sequence_number,
+            min_key,
+            max_key,
+        })
+    }
+}
+
+/// Builds the footer of a data file from its records as they are appended.
+#[derive(Clone, Default)]
+pub(crate) struct FooterBuilder {
+    footer: DataFileFooter,
+    hasher: crc32fast::Hasher,
+}
+
+impl FooterBuilder {
+    /// Account for a record of ENCODED_KEY and RECORD_TYPE, encoded as ENCODED_RECORD, appended
+    /// after the records added so far.
+    pub(crate) fn add(
+        &mut self,
+        encoded_key: &[u8],
+        record_type: LogRecordType,
+        encoded_record: &[u8],
+    ) {
+        let (key, sequence_number) = parse_log_record_key(&encoded_key.to_vec());
+        let sequence_number = sequence_number as u64;
+        let footer = &mut self.footer;
+        if footer.record_count == 0 {
+            footer.min_sequence_number = sequence_number;
+        }
+        footer.min_sequence_number = footer.min_sequence_number.min(sequence_number);
+        footer.max_sequence_number = footer.max_sequence_number.max(sequence_number);
+        if record_type != LogRecordType::TxnFinished {
+            if footer.min_key.is_empty() || key < footer.min_key {
+                footer.min_key = key.clone();
+            }
+            if key > footer.max_key {
+                footer.max_key = key;
+            }
+        }
+        footer.record_count += 1;
+        footer.data_size += encoded_record.len() as u64;
+        self.hasher.update(encoded_record);
+    }
+
+    /// Get the footer of the records added so far.
+    pub(crate) fn finish(&self) -> DataFileFooter {
+        DataFileFooter {
+            data_crc: self.hasher.clone().finalize(),
+            ..self.footer.clone()
+        }
+    }
+
+    /// Build the footer of the records of DATA_FILE before offset END_OFS.
+    pub(crate) fn scan(data_file: &DataFile, end_ofs: u64) -> Result<Self> {
+        let mut builder = Self::default();
+        let mut ofs = 0;
+        while ofs < end_ofs {
+            let (log_record, size) = data_file.read_log_record(ofs)?;
+            let encoded_record = data_file.read_bytes(ofs, size)?;
+            builder.add(&log_record.key, log_record.record_type, &encoded_record);
+            ofs += size as u64;
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        data::log_record::LogRecord, db::encode_log_record_key, options::IOType,
+        utils::time::now_millis,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_data_file_footer() {
+        let dir_path = PathBuf::from("/tmp/bitcask-rs-footer");
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let data_file = DataFile::new(&dir_path, 0, IOType::StandardFIO).unwrap();
+
+        let mut builder = FooterBuilder::default();
+        for (key, sequence_number) in [("hamlet", 3), ("ophelia", 0), ("claudius", 5)] {
+            let record = LogRecord {
+                key: encode_log_record_key(key.as_bytes().to_vec(), sequence_number),
+                value: b"elsinore".to_vec(),
+                record_type: LogRecordType::Normal,
+                timestamp: Some(now_millis()),
+                user_flags: 0,
+            };
+            let encoded_record = record.encode();
+            data_file.write(&encoded_record).unwrap();
+            builder.add(&record.key, record.record_type, &encoded_record);
+        }
+        let footer = builder.finish();
+        assert_eq!(footer.record_count, 3);
+        assert_eq!(footer.data_size, data_file.get_write_ofs());
+        assert_eq!(
+            (footer.min_sequence_number, footer.max_sequence_number),
+            (0, 5)
+        );
+        assert_eq!(
+            (footer.min_key.as_slice(), footer.max_key.as_slice()),
+            (&b"claudius"[..], &b"ophelia"[..])
+        );
+        assert_eq!(
+            FooterBuilder::scan(&data_file, footer.data_size)
+                .unwrap()
+                .finish(),
+            footer
+        );
+
+        // The footer is found from the end of the file, and a damaged one is ignored.
+        assert_eq!(DataFileFooter::read(&data_file), None);
+        footer.write(&data_file).unwrap();
+        assert_eq!(DataFileFooter::read(&data_file), Some(footer.clone()));
+        data_file.truncate(data_file.file_size() - 1).unwrap();
+        assert_eq!(DataFileFooter::read(&data_file), None);
+
+        std::fs::remove_dir_all(dir_path).expect("failed to remove path");
+    }
+}
@@ -1,31 +1,86 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use prost::{
     encode_length_delimiter,
-    encoding::{decode_varint, encode_varint},
+    encoding::{decode_varint, encode_varint, encoded_len_varint},
     length_delimiter_len,
 };
 
-use crate::data::data_file::CRC_LEN;
+use crate::{
+    data::{
+        checksum::{checksum_id, checksum_len, compute_checksum},
+        compression::compress,
+    },
+    options::{ChecksumType, CompressionType},
+    utils::time::now_millis,
+};
+
+/// The format of a record is kept in the upper bits of its type byte.
+pub(crate) const RECORD_FORMAT_SHIFT: u8 = 4;
+
+/// Format of the records written before timestamps were recorded.
+pub(crate) const RECORD_FORMAT_LEGACY: u8 = 0;
+
+/// Format of the records whose header carries a timestamp.
+pub(crate) const RECORD_FORMAT_TIMESTAMP: u8 = 1;
+
+/// Format of the records whose header carries a timestamp, and flags in the lower bits of the
+/// type byte above the record type.
+pub(crate) const RECORD_FORMAT_FLAGS: u8 = 2;
+
+/// Format of the records in the flags format whose header also carries a byte naming their
+/// checksum after the timestamp, written unless the checksum is CRC32.
+pub(crate) const RECORD_FORMAT_CHECKSUM: u8 = 3;
+
+/// Format of the records in the checksum format whose header also carries their user flags after
+/// the checksum, written if any user flag is set.
+pub(crate) const RECORD_FORMAT_USER_FLAGS: u8 = 4;
+
+/// The latest format this version can decode. Records of a later format were written by a newer
+/// version, and fail to load with `Errors::UnsupportedFormatVersion` instead of being misread.
+pub(crate) const RECORD_FORMAT_LATEST: u8 = RECORD_FORMAT_USER_FLAGS;
+
+/// The bits of the type byte holding the record type in the flags format.
+pub(crate) const RECORD_TYPE_MASK: u8 = 0x07;
+
+/// Flags a record whose value is compressed, see `compression`.
+pub(crate) const RECORD_FLAG_COMPRESSED: u8 = 0x08;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum LogRecordType {
     Normal,
     Deleted,
     TxnFinished,
+
+    /// A normal record expiring at a point in time, whose value is prefixed by the expiry time in
+    /// milliseconds since the epoch as a varint.
+    Expiring,
+
+    /// A normal or expiring record whose value is stored in a blob file, see `blob`. Its value
+    /// holds the expiry time as a varint, 0 if it never expires, followed by the reference to the
+    /// blob file.
+    Blob,
 }
 
 /// On encoding, we formate the struct into the following format:
 /// ```
-///  +------+----------+------------+----------+-------------------------+-----+
-///  | Type | key_size | value_size |    key   |         value           | CRC |
-///  +------+----------+------------+----------+-------------------------+-----+
+///  +------+----------+------------+-----------+----------+------------------+-----+
+///  | Type | key_size | value_size | timestamp |    key   |      value       | CRC |
+///  +------+----------+------------+-----------+----------+------------------+-----+
 ///
-///  |------------------------------|
-///               header
+///  |------------------------------------------|
+///                     header
 /// ```
 /// Remark:
 /// In bitcask's original essay, CRC is at the beginning of a log record. Whereas for convenience,
 /// I put it at the end, which has no effects on the implementation, nor the performance.
+///
+/// The upper bits of Type hold the format of the record. The timestamp is the time the record is
+/// written in milliseconds since the epoch as a varint, and is absent from the records written in
+/// the legacy format, which are still read. A record whose value is compressed is written in the
+/// flags format, with the value compressed on disk, and decompressed when it is read. A record
+/// ended by another checksum than CRC32 is written in the checksum format, whose header names the
+/// checksum right after the timestamp, see `checksum`. A record with user flags is written in the
+/// user flags format, whose header carries them right after the checksum.
 #[derive(Debug, PartialEq)]
 pub struct LogRecord {
     pub(crate) key: Vec<u8>,
@@ -34,6 +89,10 @@ pub struct LogRecord {
                                             * Because we can not change the already written
                                             * records, so an identifier for deletion and writing
                                             * is required. */
+    pub(crate) timestamp: Option<u64>, /* None for the records in the legacy format, which are
+                                        * encoded without it. */
+    pub(crate) user_flags: u8, /* Set by the application with `PutOptions::user_flags`, 0 if
+                                * none. */
 }
 
 pub struct TransactionRecord {
@@ -42,57 +101,220 @@ pub struct TransactionRecord {
 }
 
 /// struct used for log record lookup within a data file, where:
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct LogRecordPos {
     /// The identifier of the file read.
-    pub(crate) file_id: u32,
+    pub(crate) file_id: u64,
 
     /// The offset of log record to be looked up.
     pub(crate) ofs: u64,
 
     /// The size of log record on disk.
     pub(crate) size: u32,
+
+    /// The time the record expires in milliseconds since the epoch, if any, so that expiry is
+    /// checked without reading the record.
+    pub(crate) expire_at: Option<u64>,
 }
 
 impl LogRecord {
     pub fn encode(&self) -> Vec<u8> {
-        let (encoded_buf, _) = self.encode_and_get_crc();
-        encoded_buf
+        let mut buf = Vec::new();
+        self.encode_with_value_into(&self.value, 0, ChecksumType::Crc32, &mut buf);
+        buf
     }
 
+    /// Get the CRC ending the encoded record, hashed over its encoded header, key and value
+    /// without encoding the whole record.
     pub fn get_crc(&self) -> u32 {
-        let (_, crc) = self.encode_and_get_crc();
-        crc
+        let mut header = Vec::with_capacity(max_log_record_header_size());
+        self.encode_header_into(self.value.len(), 0, ChecksumType::Crc32, &mut header);
+        let crc = compute_checksum(ChecksumType::Crc32, &[&header, &self.key, &self.value]);
+        crc.as_slice().get_u32()
     }
 
-    fn encode_and_get_crc(&self) -> (Vec<u8>, u32) {
-        let mut buf = BytesMut::new();
-        buf.reserve(self.get_encoded_record_length());
+    /// Encode the record with its value compressed by COMPRESSION, if the value is at least
+    /// MIN_SIZE bytes long and shrinks, and ended by CHECKSUM. Records in the legacy format are
+    /// never compressed, and always checked by CRC32.
+    pub(crate) fn encode_with_options(
+        &self,
+        compression: CompressionType,
+        min_size: usize,
+        checksum: ChecksumType,
+    ) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_with_options_into(compression, min_size, checksum, &mut buf);
+        buf
+    }
+
+    /// Same as `encode_with_options`, appending the encoded record to BUF, so a buffer can be
+    /// reused to encode many records.
+    pub(crate) fn encode_with_options_into(
+        &self,
+        compression: CompressionType,
+        min_size: usize,
+        checksum: ChecksumType,
+        buf: &mut Vec<u8>,
+    ) {
+        if self.timestamp.is_none() {
+            return self.encode_with_value_into(&self.value, 0, ChecksumType::Crc32, buf);
+        }
+        let compressed = match self.value.len() < min_size {
+            true => None,
+            false => compress(compression, &self.value),
+        };
+        match compressed {
+            Some(value) => {
+                self.encode_with_value_into(&value, RECORD_FLAG_COMPRESSED, checksum, buf)
+            }
+            None => self.encode_with_value_into(&self.value, 0, checksum, buf),
+        }
+    }
 
-        // Append BUF with the encoded TYPE, KEY_SIZE, VALUE_SIZE, KEY, VALUE.
-        buf.put_u8(self.record_type as u8);
-        encode_length_delimiter(self.key.len(), &mut buf).unwrap();
-        encode_length_delimiter(self.value.len(), &mut buf).unwrap();
+    /// Append to BUF the record encoded with VALUE in place of its value, FLAGS set in its header,
+    /// and ended by CHECKSUM.
+    fn encode_with_value_into(
+        &self,
+        value: &[u8],
+        flags: u8,
+        checksum: ChecksumType,
+        buf: &mut Vec<u8>,
+    ) {
+        let start = buf.len();
+        buf.reserve(self.get_encoded_record_length(value.len(), checksum));
+        self.encode_header_into(value.len(), flags, checksum, buf);
         buf.extend_from_slice(&self.key);
-        buf.extend_from_slice(&self.value);
+        buf.extend_from_slice(value);
 
-        // Append Buf with CRC.
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.update(&buf);
-        let crc = hasher.finalize();
-        buf.put_u32(crc);
+        // Append Buf with the checksum of the record, hashed in one pass over the bytes just
+        // written.
+        let checksum = compute_checksum(checksum, &[&buf[start..]]);
+        buf.extend_from_slice(&checksum);
+    }
 
-        (buf.to_vec(), crc)
+    /// Append to BUF the header of the record with a value of VALUE_LEN bytes, FLAGS set, and
+    /// ended by CHECKSUM.
+    fn encode_header_into(
+        &self,
+        value_len: usize,
+        flags: u8,
+        checksum: ChecksumType,
+        buf: &mut Vec<u8>,
+    ) {
+        // Append BUF with the encoded TYPE, KEY_SIZE, VALUE_SIZE, TIMESTAMP, CHECKSUM_ID,
+        // USER_FLAGS.
+        let format = match self.timestamp {
+            Some(_) if self.user_flags != 0 => RECORD_FORMAT_USER_FLAGS,
+            Some(_) if checksum != ChecksumType::Crc32 => RECORD_FORMAT_CHECKSUM,
+            Some(_) if flags != 0 => RECORD_FORMAT_FLAGS,
+            Some(_) => RECORD_FORMAT_TIMESTAMP,
+            None => RECORD_FORMAT_LEGACY,
+        };
+        buf.put_u8(format << RECORD_FORMAT_SHIFT | flags | self.record_type as u8);
+        encode_length_delimiter(self.key.len(), buf).unwrap();
+        encode_length_delimiter(value_len, buf).unwrap();
+        if let Some(timestamp) = self.timestamp {
+            encode_varint(timestamp, buf);
+        }
+        if format >= RECORD_FORMAT_CHECKSUM {
+            buf.put_u8(checksum_id(checksum));
+        }
+        if format >= RECORD_FORMAT_USER_FLAGS {
+            buf.put_u8(self.user_flags);
+        }
+    }
+
+    /// Build an expiring record of KEY with VALUE, expiring at EXPIRE_AT milliseconds since the
+    /// epoch.
+    pub(crate) fn new_expiring(key: Vec<u8>, value: &[u8], expire_at: u64) -> Self {
+        let mut buf = BytesMut::new();
+        encode_varint(expire_at, &mut buf);
+        buf.extend_from_slice(value);
+        LogRecord {
+            key,
+            value: buf.to_vec(),
+            record_type: LogRecordType::Expiring,
+            timestamp: Some(now_millis()),
+            user_flags: 0,
+        }
+    }
+
+    /// Build a deletion record of KEY, deleted at DELETED_AT milliseconds since the epoch.
+    pub(crate) fn new_tombstone(key: Vec<u8>, deleted_at: u64) -> Self {
+        let mut buf = BytesMut::new();
+        encode_varint(deleted_at, &mut buf);
+        LogRecord {
+            key,
+            value: buf.to_vec(),
+            record_type: LogRecordType::Deleted,
+            timestamp: Some(deleted_at),
+            user_flags: 0,
+        }
+    }
+
+    /// Get the deletion time of a deletion record in milliseconds since the epoch, or None if
+    /// it is not a deletion record or written without the deletion time.
+    pub(crate) fn deleted_at(&self) -> Option<u64> {
+        if self.record_type != LogRecordType::Deleted || self.value.is_empty() {
+            return None;
+        }
+        let mut buf = self.value.as_slice();
+        decode_varint(&mut buf).ok()
+    }
+
+    /// Get the expiry time of the record in milliseconds since the epoch, or None if it never
+    /// expires.
+    pub(crate) fn expire_at(&self) -> Option<u64> {
+        let mut buf = self.value.as_slice();
+        match self.record_type {
+            LogRecordType::Expiring => decode_varint(&mut buf).ok(),
+            LogRecordType::Blob => decode_varint(&mut buf)
+                .ok()
+                .filter(|expire_at| *expire_at > 0),
+            _ => None,
+        }
+    }
+
+    /// Whether the record has expired at NOW milliseconds since the epoch.
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expire_at().is_some_and(|expire_at| expire_at <= now)
+    }
+
+    /// Get the value set by the record, without the expiry time of an expiring record. The value
+    /// of a `Blob` record is read by `BlobStore::read_user_value` instead.
+    pub(crate) fn into_user_value(self) -> Vec<u8> {
+        if self.record_type != LogRecordType::Expiring {
+            return self.value;
+        }
+        self.user_value().to_vec()
+    }
+
+    /// Same as `into_user_value`, without consuming the record.
+    pub(crate) fn user_value(&self) -> &[u8] {
+        let mut buf = self.value.as_slice();
+        if self.record_type == LogRecordType::Expiring {
+            let _ = decode_varint(&mut buf);
+        }
+        buf
     }
 
-    /// Calculate the size of a LOG_RECORD after encoding.
-    fn get_encoded_record_length(&self) -> usize {
+    /// Calculate the size of a LOG_RECORD after encoding, with a value of VALUE_LEN bytes, and
+    /// ended by CHECKSUM.
+    fn get_encoded_record_length(&self, value_len: usize, checksum: ChecksumType) -> usize {
+        // The checksum id and the user flags are only written by the formats carrying them.
+        let extra_len = match checksum {
+            _ if self.user_flags != 0 => std::mem::size_of::<u8>() * 2,
+            ChecksumType::Crc32 => 0,
+            _ => std::mem::size_of::<u8>(),
+        };
         std::mem::size_of::<u8>()
             + length_delimiter_len(self.key.len())
-            + length_delimiter_len(self.value.len())
+            + length_delimiter_len(value_len)
+            + self.timestamp.map_or(0, encoded_len_varint)
+            + extra_len
             + self.key.len()
-            + self.value.len()
-            + CRC_LEN
+            + value_len
+            + checksum_len(checksum)
     }
 }
 
@@ -102,19 +324,49 @@ impl LogRecordType {
             0 => LogRecordType::Normal,
             1 => LogRecordType::Deleted,
             2 => LogRecordType::TxnFinished,
+            3 => LogRecordType::Expiring,
+            4 => LogRecordType::Blob,
             _ => panic!("unknown log record type"),
         }
     }
+
+    /// Same as `from_u8`, but return None on an unknown type.
+    pub fn try_from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(LogRecordType::Normal),
+            1 => Some(LogRecordType::Deleted),
+            2 => Some(LogRecordType::TxnFinished),
+            3 => Some(LogRecordType::Expiring),
+            4 => Some(LogRecordType::Blob),
+            _ => None,
+        }
+    }
+
+    /// Whether the record sets the value of its key.
+    pub fn is_value(&self) -> bool {
+        matches!(
+            self,
+            LogRecordType::Normal | LogRecordType::Expiring | LogRecordType::Blob
+        )
+    }
 }
 
 impl LogRecordPos {
     pub fn encode(&self) -> Vec<u8> {
         let mut buf = BytesMut::new();
-        encode_varint(self.file_id as u64, &mut buf);
+        encode_varint(self.file_id, &mut buf);
         encode_varint(self.ofs, &mut buf);
         encode_varint(self.size as u64, &mut buf);
+        if let Some(expire_at) = self.expire_at {
+            encode_varint(expire_at, &mut buf);
+        }
         buf.to_vec()
     }
+
+    /// Whether the record is expired at NOW milliseconds since the epoch.
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|expire_at| expire_at <= now)
+    }
 }
 
 pub fn decode_log_record_pos(pos: Vec<u8>) -> LogRecordPos {
@@ -132,17 +384,27 @@ pub fn decode_log_record_pos(pos: Vec<u8>) -> LogRecordPos {
         Ok(size) => size,
         Err(e) => panic!("decode log record pos Error: {}", e),
     };
+    // Positions encoded before expiries were recorded end after the size.
+    let expire_at = match buf.has_remaining() {
+        true => decode_varint(&mut buf).ok(),
+        false => None,
+    };
     LogRecordPos {
-        file_id: fid as u32,
+        file_id: fid,
         ofs,
         size: size as u32,
+        expire_at,
     }
 }
 
 pub fn max_log_record_header_size() -> usize {
-    // MAX_SIZE = len(type) + len(key_size) + len(value_size)
-    //          = len(u8) + len(u32) + len(u32)
-    std::mem::size_of::<u8>() + length_delimiter_len(std::u32::MAX as usize) * 2
+    // MAX_SIZE = len(type) + len(key_size) + len(value_size) + len(timestamp) + len(checksum_id)
+    //            + len(user_flags)
+    //          = len(u8) + len(u32) + len(u32) + len(u64) + len(u8) + len(u8)
+    std::mem::size_of::<u8>()
+        + length_delimiter_len(u32::MAX as usize) * 2
+        + encoded_len_varint(u64::MAX)
+        + std::mem::size_of::<u8>() * 2
 }
 
 #[cfg(test)]
@@ -155,6 +417,8 @@ mod tests {
             key: "name".as_bytes().to_vec(),
             value: "Prince Hamlet".as_bytes().to_vec(),
             record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
         };
         let encoded1 = record1.encode();
         assert!(encoded1.len() > 5);
@@ -164,6 +428,8 @@ mod tests {
             key: "name".as_bytes().to_vec(),
             value: Default::default(),
             record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
         };
         let encoded2 = record2.encode();
         assert!(encoded2.len() > 5);
@@ -173,9 +439,132 @@ mod tests {
             key: "name".as_bytes().to_vec(),
             value: "Prince Hamlet".as_bytes().to_vec(),
             record_type: LogRecordType::Deleted,
+            timestamp: None,
+            user_flags: 0,
         };
         let encoded3 = record3.encode();
         assert!(encoded3.len() > 5);
         assert_eq!(4109989888, record3.get_crc());
+
+        // The CRC hashed without encoding the record is the one ending the encoded record.
+        let record4 = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: "Prince Hamlet".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            timestamp: Some(now_millis()),
+            user_flags: 0x05,
+        };
+        let encoded4 = record4.encode();
+        let crc = (&encoded4[encoded4.len() - checksum_len(ChecksumType::Crc32)..]).get_u32();
+        assert_eq!(crc, record4.get_crc());
+    }
+
+    #[test]
+    fn test_log_record_encode_timestamp() {
+        let legacy = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: "Prince Hamlet".as_bytes().to_vec(),
+            record_type: LogRecordType::Deleted,
+            timestamp: None,
+            user_flags: 0,
+        };
+        let legacy_len = legacy.encode().len();
+
+        let record = LogRecord {
+            timestamp: Some(1_700_000_000_000),
+            ..legacy
+        };
+        let encoded = record.encode();
+        assert_eq!(
+            encoded[0],
+            RECORD_FORMAT_TIMESTAMP << RECORD_FORMAT_SHIFT | LogRecordType::Deleted as u8
+        );
+        assert_eq!(
+            encoded.len(),
+            legacy_len + encoded_len_varint(1_700_000_000_000)
+        );
+    }
+
+    #[test]
+    fn test_log_record_encode_compressed() {
+        let record = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: "Prince Hamlet".repeat(100).into_bytes(),
+            record_type: LogRecordType::Normal,
+            timestamp: Some(1_700_000_000_000),
+            user_flags: 0,
+        };
+        let encoded = record.encode_with_options(CompressionType::Lz4, 512, ChecksumType::Crc32);
+        assert_eq!(
+            encoded[0],
+            RECORD_FORMAT_FLAGS << RECORD_FORMAT_SHIFT
+                | RECORD_FLAG_COMPRESSED
+                | LogRecordType::Normal as u8
+        );
+        assert!(encoded.len() < record.encode().len() / 5);
+
+        // Short values and records in the legacy format are not compressed.
+        let encoded = record.encode_with_options(CompressionType::Lz4, 2048, ChecksumType::Crc32);
+        assert_eq!(encoded, record.encode());
+        let legacy = LogRecord {
+            timestamp: None,
+            ..record
+        };
+        assert_eq!(
+            legacy.encode_with_options(CompressionType::Zstd, 0, ChecksumType::Crc32),
+            legacy.encode()
+        );
+    }
+
+    #[test]
+    fn test_log_record_encode_checksum() {
+        let record = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: "Prince Hamlet".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            timestamp: Some(1_700_000_000_000),
+            user_flags: 0,
+        };
+        let crc_len = record.encode().len();
+        let encoded = record.encode_with_options(CompressionType::None, 0, ChecksumType::XxHash64);
+        assert_eq!(
+            encoded[0],
+            RECORD_FORMAT_CHECKSUM << RECORD_FORMAT_SHIFT | LogRecordType::Normal as u8
+        );
+        assert_eq!(encoded[9], checksum_id(ChecksumType::XxHash64));
+        assert_eq!(encoded.len(), crc_len + 1 + 4);
+        let encoded = record.encode_with_options(CompressionType::None, 0, ChecksumType::None);
+        assert_eq!(encoded.len(), crc_len + 1 - 4);
+
+        // Records in the legacy format are always checked by CRC32.
+        let legacy = LogRecord {
+            timestamp: None,
+            ..record
+        };
+        assert_eq!(
+            legacy.encode_with_options(CompressionType::None, 0, ChecksumType::XxHash64),
+            legacy.encode()
+        );
+    }
+
+    #[test]
+    fn test_log_record_pos_encode() {
+        let pos = LogRecordPos {
+            file_id: 3,
+            ofs: 1024,
+            size: 42,
+            expire_at: None,
+        };
+        let legacy_len = pos.encode().len();
+        assert!(decode_log_record_pos(pos.encode()) == pos);
+
+        let expiring = LogRecordPos {
+            expire_at: Some(1_700_000_000_000),
+            ..pos
+        };
+        assert!(expiring.encode().len() > legacy_len);
+        assert!(decode_log_record_pos(expiring.encode()) == expiring);
+        assert!(!expiring.is_expired(1_699_999_999_999));
+        assert!(expiring.is_expired(1_700_000_000_000));
     }
 }
@@ -1,2 +1,5 @@
+pub mod checksum;
+pub mod compression;
 pub mod data_file;
+pub mod footer;
 pub mod log_record;
@@ -1,31 +1,64 @@
-use bytes::{BufMut, Bytes, BytesMut};
-use fs2::FileExt;
+use arc_swap::ArcSwap;
+use bytes::Bytes;
 use log::warn;
-use prost::{decode_length_delimiter, encode_length_delimiter};
+use prost::{decode_length_delimiter, encode_length_delimiter, length_delimiter_len};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fs::{self, File},
+    io::IoSlice,
+    ops::Range,
     path::PathBuf,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Mutex, RwLock,
     },
+    time::Duration,
 };
 
 use crate::{
+    auto_merge::AutoMerge,
     batch::NON_TRANSACTION_SEQUENCE,
-    data::{data_file::*, log_record::*},
+    blob::{get_blob_path, BlobStore},
+    bulk_load::clean_bulk_load_dir,
+    change_sink::ChangeShipper,
+    data::{
+        data_file::*,
+        footer::{DataFileFooter, FooterBuilder},
+        log_record::*,
+    },
     errors::{Errors, Result},
-    index::{new_indexer, Indexer},
+    fence::WriteFence,
+    fio::{
+        metered_io::{IOMetrics, IOStat, MeteredWrapper},
+        sync_dir,
+    },
+    index::{
+        keydir::{KeydirFile, LayeredIndex},
+        new_indexer, IndexMemoryUsage, Indexer,
+    },
+    key_lock::{KeyLockTable, KeyLocks},
+    lock::lock_dir,
+    manifest::{Manifest, ManifestEdit},
     merge::load_merge_files,
-    options::{IOType, IndexType, Options},
-    utils,
+    merge_stats::MergeCounters,
+    options::{ChecksumPolicy, IOType, IndexType, IteratorOptions, Options, PutOptions},
+    periodic_sync::PeriodicSync,
+    prefix::new_prefix_bloom,
+    prepared::has_prepared_files,
+    reclaim::{take_reclaim_stats, ReclaimStats},
+    recovery::RecoveredCorruption,
+    snapshot::{clean_obsolete_dir, SnapshotIndex, Snapshots},
+    utils::{self, bloom::BloomFilter, buffer_pool::take_buffer, time::now_millis},
+    value_cache::ValueCache,
 };
 
-const INITIAL_FILE_ID: u32 = 1;
+const INITIAL_FILE_ID: u64 = 1;
 const SEQUENCE_NUMBER_KEY: &str = "seq-no";
 pub(crate) const LOCK_FILE_NAME: &str = "flock";
 
+/// Sealed data files by file id.
+pub(crate) type OldFiles = HashMap<u64, Arc<DataFile>>;
+
 /// struct used for storage, the running instance of Bitcask, where
 pub struct Engine {
     /// Rhe configuration for the database engine.
@@ -34,14 +67,21 @@ pub struct Engine {
     /// Records the current file that is used for storing all log record.
     pub(crate) active_file: Arc<RwLock<DataFile>>,
 
-    /// Records all the closed data file, also called keydir.
-    pub(crate) old_files: Arc<RwLock<HashMap<u32, DataFile>>>,
+    /// Records all the closed data file, also called keydir. The map is an immutable snapshot,
+    /// which is replaced as a whole on rotation and merge, so readers load it without locking.
+    pub(crate) old_files: ArcSwap<OldFiles>,
+
+    /// Serializes the replacements of `old_files`, so none of them is lost.
+    old_files_update: Mutex<()>,
 
     /// Interface used for data file indexing.
     pub(crate) index: Box<dyn Indexer>,
 
+    /// Snapshots taken by the open iterators.
+    pub(crate) snapshots: Arc<Snapshots>,
+
     /// A collection all the data file id.
-    file_ids: Vec<u32>,
+    pub(crate) file_ids: Vec<u64>,
 
     /// Prevents race conditions while committing transaction.
     pub(crate) batch_commit_lock: Mutex<()>,
@@ -55,6 +95,8 @@ pub struct Engine {
 
     /// `sequence_file_exists` and `is_first_time_init` disable the usage of BPTree if they where both set to true.
     /// Otherwise, after reboot, engine cannot obtain the current sequence number to perform a correct batch write.
+    /// With BPTree, `sequence_file_exists` is set once the sequence number is recovered, either from the sequence
+    /// number file or by scanning the data files.
     pub(crate) sequence_file_exists: bool,
     pub(crate) is_first_time_init: bool,
 
@@ -62,35 +104,145 @@ pub struct Engine {
     lock_file: File,
 
     /// Records how many bytes were written by engine, used for automatic sync.
-    bytes_write: Arc<AtomicUsize>,
+    pub(crate) bytes_write: Arc<AtomicUsize>,
 
     /// Records how many bytes are available.
     pub(crate) reclaim_size: Arc<AtomicUsize>,
 
-    /// Records the volume of storage that can be saved after merge process.
+    /// Records the lifecycle of data files.
+    pub(crate) manifest: Manifest,
+
+    /// Records how many bytes are available in each data file.
+    pub(crate) file_reclaim_sizes: Mutex<ReclaimStats>,
+
+    /// IO type of the data files once the engine is started.
     io_type: IOType,
+
+    /// IO type of the sealed data files once the engine is started, see `Options::read_io_type`.
+    pub(crate) read_io_type: IOType,
+
+    /// Ids of the old files with an opened handle, ordered from the least recently read.
+    open_files: Mutex<VecDeque<u64>>,
+
+    /// Bloom filters of the key prefixes contained in each data file.
+    pub(crate) prefix_blooms: RwLock<HashMap<u64, BloomFilter>>,
+
+    /// Set once the engine is closed, after which writes and merges are rejected.
+    closed: AtomicBool,
+
+    /// Progress of shipping changes to `Options::change_sink`.
+    pub(crate) change_shipper: Mutex<ChangeShipper>,
+
+    /// Engine holding the deleted entries, if `Options::trash_retention` is set.
+    pub(crate) trash: Option<Box<Engine>>,
+
+    /// Serializes the read-modify-write primitives and write batches on the same key.
+    pub(crate) key_locks: KeyLocks,
+
+    /// Keys locked by `lock_key`.
+    pub(crate) key_lock_table: KeyLockTable,
+
+    /// Transactions prepared by `WriteBatch::prepare` and not yet committed nor aborted, by
+    /// sequence number.
+    pub(crate) prepared: Mutex<BTreeMap<usize, Vec<LogRecord>>>,
+
+    /// Sequence numbers of the transactions found committed while replaying the data files on
+    /// open, so prepared transactions committed right before a crash are not prepared again.
+    pub(crate) replayed_commits: Mutex<HashSet<usize>>,
+
+    /// Corrupted records skipped or truncated on startup.
+    pub(crate) recovered_corruptions: Mutex<Vec<RecoveredCorruption>>,
+
+    /// Syncs the active file in background, if `Options::sync_interval` is set.
+    pub(crate) periodic_sync: Mutex<Option<PeriodicSync>>,
+
+    /// Counters of the running merge.
+    pub(crate) merge_counters: Mutex<Option<Arc<MergeCounters>>>,
+
+    /// Merges the engine in background, if `Options::auto_merge` is set.
+    pub(crate) auto_merge: Mutex<Option<AutoMerge>>,
+
+    /// Tracks the in-flight writes, drained on shutdown.
+    pub(crate) write_fence: WriteFence,
+
+    /// Counters of the IO of the data files.
+    io_metrics: Arc<IOMetrics>,
+
+    /// Blob files storing the large values, shared with the engines writing merged files.
+    pub(crate) blob_store: Arc<BlobStore>,
+
+    /// Summary of the records appended to the active file, written as its footer when sealed.
+    active_summary: Mutex<FooterBuilder>,
+
+    /// Cache of the values read, if `Options::cache_size` is set.
+    pub(crate) value_cache: Option<ValueCache>,
 }
 
 /// Statistics of the engine.
+#[derive(Default)]
 pub struct Stat {
     /// Number of keys in the engine.
-    key_num: usize,
+    pub key_num: usize,
 
     /// Number of data files in the engine.
-    data_file_num: usize,
+    pub data_file_num: usize,
 
     /// Data that can be compacted.
-    reclaim_size: usize,
+    pub reclaim_size: usize,
 
     /// The capacity occupied by the engine on disk.
-    disk_size: u64,
+    pub disk_size: u64,
+
+    /// Estimated memory used by the index.
+    pub index_memory_size: usize,
+
+    /// Operations, bytes, errors and time spent in the IO of the data files since the engine is
+    /// opened.
+    pub io: IOStat,
+
+    /// Number of reads served by the value cache, and of those missing it, since the engine is
+    /// opened. Always 0 if the cache is disabled.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+/// Metadata of an entry, returned by `Engine::get_with_metadata`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntryMetadata {
+    /// The time the value was written in milliseconds since the epoch, kept by merges. None if
+    /// the value was written before timestamps were recorded.
+    pub timestamp: Option<u64>,
+
+    /// The time the entry expires in milliseconds since the epoch, if written with a TTL.
+    pub expire_at: Option<u64>,
+
+    /// The flags the entry was written with by `Engine::put_with_options`, 0 if none.
+    pub user_flags: u8,
+}
+
+impl EntryMetadata {
+    /// Get the metadata of the entry held by LOG_RECORD.
+    pub(crate) fn from_record(log_record: &LogRecord) -> Self {
+        Self {
+            timestamp: log_record.timestamp,
+            expire_at: log_record.expire_at(),
+            user_flags: log_record.user_flags,
+        }
+    }
 }
 
 impl Engine {
     /// Open a bitcask instance with configuration OPTS.
-    pub fn open(opts: Options) -> Result<Self> {
+    pub fn open(mut opts: Options) -> Result<Self> {
         check_options(&opts)?;
 
+        // The IO of the data files is metered beneath the configured IO wrapper.
+        let io_metrics = Arc::new(IOMetrics::default());
+        opts.io_wrapper = Some(Arc::new(MeteredWrapper::new(
+            io_metrics.clone(),
+            opts.io_wrapper.take(),
+        )));
+
         let mut is_first_time_init = false;
         let options = opts.clone();
         let dir_path = opts.dir_path.clone();
@@ -103,50 +255,72 @@ impl Engine {
         }
 
         // Ensure only one process is accessing the current keydir.
-        let lock_file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(dir_path.join(LOCK_FILE_NAME))
-            .unwrap();
-        if let Err(_) = lock_file.try_lock_exclusive() {
-            return Err(Errors::DatabaseInUse);
-        }
+        let lock_file = lock_dir(&dir_path)?;
 
         let entries = fs::read_dir(&dir_path).unwrap();
         if entries.count() == 0 {
             is_first_time_init = true;
         }
 
-        load_merge_files(&dir_path)?;
+        let manifest = Manifest::open(&dir_path)?;
+        load_merge_files(&dir_path, &manifest)?;
+        clean_bulk_load_dir(&dir_path)?;
+        clean_obsolete_dir(&dir_path)?;
 
         let mut data_files = load_data_files(&dir_path, &opts)?;
-        let file_ids: Vec<u32> = data_files
+        let file_ids: Vec<u64> = data_files
             .iter()
             .map(|data_file| data_file.get_file_id())
             .collect();
+        manifest.check_files(&file_ids)?;
 
-        // The last file is the active file, and the rest are old files.
+        // The last file is the active file, and the rest are old files, whose logical end is
+        // recorded by the MANIFEST.
         data_files.reverse();
         let mut old_files = HashMap::new();
+        let mut sealed_files = Vec::new();
         if data_files.len() > 1 {
             for _ in 0..=data_files.len() - 2 {
                 let data_file = data_files.pop().unwrap();
-                old_files.insert(data_file.get_file_id(), data_file);
+                let file_id = data_file.get_file_id();
+                let end_ofs = manifest
+                    .sealed_size(file_id)
+                    .or_else(|| DataFileFooter::read(&data_file).map(|f| f.data_size))
+                    .unwrap_or_else(|| data_file.file_size());
+                data_file.set_end_ofs(end_ofs);
+                sealed_files.push((file_id, end_ofs));
+                old_files.insert(file_id, Arc::new(data_file));
             }
         };
 
         let active_file = match data_files.pop() {
             Some(v) => v,
             // It is possible to have an empty directory, so create an empty data file.
-            None => DataFile::new(&dir_path, INITIAL_FILE_ID, IOType::StandardFIO)?,
+            None => new_data_file(&options, &dir_path, INITIAL_FILE_ID, options.io_type)?,
         };
+        // A crash while sealing the active file may leave its footer behind, which is dropped so
+        // that appends continue right after the last record.
+        if let Some(footer) = DataFileFooter::read(&active_file) {
+            active_file.truncate(footer.data_size)?;
+        }
+        manifest.rewrite(&sealed_files, active_file.get_file_id())?;
 
+        let snapshots = Arc::new(Snapshots::new(dir_path.clone()));
+        let blob_store = Arc::new(BlobStore::open(get_blob_path(&dir_path))?);
         let mut engine = Self {
             options: Arc::new(opts),
             active_file: Arc::new(RwLock::new(active_file)),
-            old_files: Arc::new(RwLock::new(old_files)),
-            index: new_indexer(options.index_type, options.dir_path),
+            old_files: ArcSwap::from_pointee(old_files),
+            old_files_update: Mutex::new(()),
+            index: Box::new(SnapshotIndex::new(
+                new_indexer(
+                    options.index_type,
+                    options.dir_path,
+                    options.index_shard_num,
+                ),
+                snapshots.clone(),
+            )),
+            snapshots,
             file_ids,
             batch_commit_lock: Mutex::new(()),
             sequence_number: Arc::new(AtomicUsize::new(1)), // Initialized to 1 to prevent conflict to NON_TRANSACTION_SEQUENCE
@@ -156,121 +330,325 @@ impl Engine {
             lock_file,
             bytes_write: Arc::new(AtomicUsize::new(0)),
             reclaim_size: Arc::new(AtomicUsize::new(0)),
-            io_type: IOType::StandardFIO,
+            file_reclaim_sizes: Mutex::new(HashMap::new()),
+            io_type: options.io_type,
+            read_io_type: options.read_io_type.unwrap_or(options.io_type),
+            open_files: Mutex::new(VecDeque::new()),
+            prefix_blooms: RwLock::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            change_shipper: Mutex::new(ChangeShipper::default()),
+            trash: None,
+            key_locks: KeyLocks::new(),
+            key_lock_table: KeyLockTable::default(),
+            prepared: Mutex::new(BTreeMap::new()),
+            replayed_commits: Mutex::new(HashSet::new()),
+            recovered_corruptions: Mutex::new(Vec::new()),
+            periodic_sync: Mutex::new(None),
+            merge_counters: Mutex::new(None),
+            auto_merge: Mutex::new(None),
+            write_fence: WriteFence::default(),
+            io_metrics,
+            blob_store,
+            manifest,
+            active_summary: Mutex::new(FooterBuilder::default()),
+            value_cache: (options.cache_size > 0).then(|| ValueCache::new(options.cache_size)),
         };
 
-        match engine.options.index_type {
-            IndexType::BTree | IndexType::SkipList => {
+        // Data files are not scanned if the index is restored from the keydir file or the BPTree,
+        // so the reclaimable bytes are restored from the last close instead.
+        let reclaim_stats = take_reclaim_stats(&engine.options.dir_path);
+
+        let restored_reclaim_stats = match engine.options.index_type {
+            IndexType::BTree | IndexType::SkipList | IndexType::Hash => {
+                if engine.options.persist_keydir && engine.load_index_from_keydir()? {
+                    return engine.finish_open(Some(reclaim_stats.unwrap_or_default()));
+                }
+
                 // Load index from hint file to speed up the reboot of bitcask engine.
-                engine.load_index_from_hint_file()?;
+                let non_merge_fid = engine.load_index_from_hint_file()?;
 
-                let current_sequence_number = engine.load_index_from_data_files()?;
+                let current_sequence_number =
+                    engine.load_index_from_data_files(non_merge_fid.map(|fid| (fid, 0)))?;
                 if current_sequence_number > 0 {
                     engine
                         .sequence_number
                         .store(current_sequence_number + 1, Ordering::Relaxed);
                 }
+                None
             }
             IndexType::BPTree => {
-                let (exists, sequence_number) = engine.load_sequence_number();
+                // Fall back to scanning the data files if the sequence number file is missing or
+                // unreadable, so that write batches stay available after a crash.
+                let sequence_number = match engine.load_sequence_number() {
+                    Some(sequence_number) => sequence_number,
+                    None if engine.file_ids.is_empty() => 1,
+                    None => engine.scan_sequence_number() + 1,
+                };
                 engine
                     .sequence_number
                     .store(sequence_number, Ordering::SeqCst);
-                engine.sequence_file_exists = exists;
+                engine.sequence_file_exists = true;
 
                 // Set the offset of current active file
                 let active_file = engine.active_file.write().unwrap();
                 active_file.set_write_ofs(active_file.file_size());
-
-                if engine.options.startup_io_type == IOType::MemoryMapped {
-                    engine.reset_io_type();
-                }
+                Some(reclaim_stats.unwrap_or_default())
             }
+        };
+
+        engine.finish_open(restored_reclaim_stats)
+    }
+
+    /// Complete the opening of the engine once its index is loaded, restoring the reclaimable
+    /// bytes from RECLAIM_STATS if the data files were not scanned.
+    fn finish_open(mut self, reclaim_stats: Option<ReclaimStats>) -> Result<Self> {
+        if let Some(reclaim_stats) = reclaim_stats {
+            self.restore_reclaim_stats(reclaim_stats);
+        }
+        self.reset_io_type();
+        self.load_prepared_transactions()?;
+        self.restore_change_shipper();
+        self.open_trash()?;
+        self.start_periodic_sync();
+
+        // The sequence number file is only consumed once the engine is fully open, so a failed
+        // open does not lose it. It is written again on close.
+        let sequence_number_file = self.options.dir_path.join(SEQUENCE_NUMBER_FILE_NAME);
+        if sequence_number_file.is_file() {
+            fs::remove_file(sequence_number_file).map_err(|_| Errors::FailedToReadFromDataFile)?;
         }
 
-        Ok(engine)
+        Ok(self)
     }
 
+    /// Close the engine, same as `shutdown`.
     pub fn close(&self) -> Result<()> {
+        self.shutdown()
+    }
+
+    /// Shut the engine down, persisting its state and releasing the directory lock. New writes
+    /// are fenced off with `Errors::EngineClosed`, while in-flight writes, batch commits and
+    /// merges are waited for. All data files are synced. The engine stays readable afterwards,
+    /// and shutting down an already shut down engine does nothing.
+    pub fn shutdown(&self) -> Result<()> {
+        // The background sync takes the lock of the active file, so it is stopped first.
+        if let Some(mut periodic_sync) = self.periodic_sync.lock().unwrap().take() {
+            periodic_sync.stop();
+        }
+        if let Some(mut auto_merge) = self.auto_merge.lock().unwrap().take() {
+            auto_merge.stop();
+        }
+        self.write_fence.fence_and_drain();
+
+        // Locks are taken in the same order as merge and batch commit do, so that both are
+        // drained before the engine is marked as closed.
+        let _merge_lock = self.merge_lock.lock().unwrap();
+        let _batch_commit_lock = self.batch_commit_lock.lock().unwrap();
+        let active_file = self.active_file.write().unwrap();
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
         if !self.options.dir_path.is_dir() {
             return Ok(());
         }
 
-        let sequence_number_file = DataFile::new_sequence_number_file(&self.options.dir_path)?;
         let sequence_number = self.sequence_number.load(Ordering::SeqCst);
-        let record = LogRecord {
-            key: SEQUENCE_NUMBER_KEY.as_bytes().to_vec(),
-            value: sequence_number.to_string().into_bytes(),
-            record_type: LogRecordType::Normal,
-        };
-        sequence_number_file.write(&record.encode())?;
-        sequence_number_file.sync()?;
+        self.save_sequence_number(sequence_number)?;
+        self.save_reclaim_stats()?;
 
-        self.active_file.read().unwrap().sync()?;
+        // Sealed files are synced on rotation, except those written by merge or bulk load.
+        for old_file in self.old_files().values() {
+            if old_file.is_open() {
+                old_file.sync()?;
+            }
+        }
+        active_file.sync()?;
+
+        if self.options.persist_keydir && self.options.index_type != IndexType::BPTree {
+            let mut iter = self.index.iterator(IteratorOptions::default());
+            KeydirFile::write(
+                &self.options.dir_path,
+                iter.as_mut(),
+                active_file.get_file_id(),
+                active_file.get_write_ofs(),
+                sequence_number,
+            )?;
+        }
+        self.index.persist()?;
 
         self.lock_file.unlock().unwrap();
 
+        if let Some(trash) = &self.trash {
+            trash.close()?;
+        }
+
         Ok(())
     }
 
+    /// Start syncing the active file in background if `Options::sync_interval` is set.
+    fn start_periodic_sync(&self) {
+        if let Some(interval) = self.options.sync_interval {
+            *self.periodic_sync.lock().unwrap() = Some(PeriodicSync::start(
+                self.active_file.clone(),
+                self.bytes_write.clone(),
+                interval,
+            ));
+        }
+    }
+
+    /// Whether the engine has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Fail with `Errors::EngineClosed` if the engine has been closed.
+    pub(crate) fn ensure_open(&self) -> Result<()> {
+        match self.is_closed() {
+            true => Err(Errors::EngineClosed),
+            false => Ok(()),
+        }
+    }
+
     pub fn stat(&self) -> Result<Stat> {
         let keys = self.list_keys()?;
-        let data_files = self.old_files.read().unwrap();
+        let data_files = self.old_files();
+        let (cache_hits, cache_misses) = self
+            .value_cache
+            .as_ref()
+            .map_or((0, 0), |value_cache| value_cache.hits_and_misses());
         Ok(Stat {
             key_num: keys.len(),
             data_file_num: data_files.len() + 1,
             reclaim_size: self.reclaim_size.load(Ordering::SeqCst),
             disk_size: utils::file::dir_disk_size(&self.options.dir_path),
+            index_memory_size: self.index.memory_usage().estimated_bytes,
+            io: self.io_metrics.stat(),
+            cache_hits,
+            cache_misses,
         })
     }
 
+    /// Get the memory consumed by the index.
+    pub fn index_memory_usage(&self) -> IndexMemoryUsage {
+        self.index.memory_usage()
+    }
+
     /// Write the pair (KEY, VALUE) into the database
     pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
-        if key.is_empty() {
-            return Err(Errors::KeyIsEmpty);
-        }
+        let _key_lock = self.key_locks.lock(&key);
+        self.put_record(key, value).map(|_| ())
+    }
+
+    /// Write the pair (KEY, VALUE) into the database, which expires after TTL. Expired entries
+    /// are no longer returned by reads, and are discarded by the next merge.
+    pub fn put_with_ttl(&self, key: Bytes, value: Bytes, ttl: Duration) -> Result<()> {
+        let options = PutOptions {
+            ttl: Some(ttl),
+            ..Default::default()
+        };
+        self.put_with_options(key, value, options)
+    }
+
+    /// Write the pair (KEY, VALUE) into the database, configured by OPTIONS.
+    pub fn put_with_options(&self, key: Bytes, value: Bytes, options: PutOptions) -> Result<()> {
+        let encoded_key = encode_log_record_key(key.to_vec(), NON_TRANSACTION_SEQUENCE);
+        let mut log_record = match options.ttl {
+            Some(ttl) => {
+                let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
+                LogRecord::new_expiring(encoded_key, &value, expire_at)
+            }
+            None => LogRecord {
+                key: encoded_key,
+                value: value.to_vec(),
+                record_type: LogRecordType::Normal,
+                timestamp: Some(now_millis()),
+                user_flags: 0,
+            },
+        };
+        log_record.user_flags = options.user_flags;
+        let _key_lock = self.key_locks.lock(&key);
+        self.put_log_record(key, log_record).map(|_| ())
+    }
 
-        let mut log_record = LogRecord {
+    /// Write the pair (KEY, VALUE) into the database, return the position of the record. The key
+    /// lock of KEY must be held, so the write is serialized with the read-modify-write primitives.
+    pub(crate) fn put_record(&self, key: Bytes, value: Bytes) -> Result<LogRecordPos> {
+        let log_record = LogRecord {
             key: encode_log_record_key(key.to_vec(), NON_TRANSACTION_SEQUENCE),
             value: value.to_vec(),
             record_type: LogRecordType::Normal,
+            timestamp: Some(now_millis()),
+            user_flags: 0,
         };
+        self.put_log_record(key, log_record)
+    }
+
+    /// Append LOG_RECORD setting the value of KEY, return the position of the record.
+    pub(crate) fn put_log_record(
+        &self,
+        key: Bytes,
+        mut log_record: LogRecord,
+    ) -> Result<LogRecordPos> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.check_entry_size(&key, log_record.user_value())?;
+        let _write_guard = self.write_fence.enter()?;
+        self.check_write_stall()?;
 
         // Update the location of newest data.
         let log_record_pos = self.append_log_record(&mut log_record)?;
         if let Some(old_pos) = self.index.put(key.to_vec(), log_record_pos) {
-            self.reclaim_size
-                .fetch_add(old_pos.size as usize, Ordering::SeqCst);
+            self.add_reclaim_size(&old_pos);
         }
+        self.notify_change_sink();
 
+        Ok(log_record_pos)
+    }
+
+    /// Fail with `Errors::KeyTooLarge` or `Errors::ValueTooLarge` if KEY or VALUE exceeds the
+    /// limits set by `Options`.
+    pub(crate) fn check_entry_size(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() > self.options.max_key_size {
+            return Err(Errors::KeyTooLarge);
+        }
+        if value.len() > self.options.max_value_size {
+            return Err(Errors::ValueTooLarge);
+        }
         Ok(())
     }
 
-    /// Delete the entry with key KEY.
+    /// Delete the entry with key KEY, moving it into the trash if soft deletion is enabled.
     pub fn delete(&self, key: Bytes) -> Result<()> {
         if key.is_empty() {
             return Err(Errors::KeyIsEmpty);
         }
+        let _key_lock = self.key_locks.lock(&key);
+        self.delete_record(key)
+    }
 
+    /// Same as `delete`, where the key lock of KEY is already held.
+    pub(crate) fn delete_record(&self, key: Bytes) -> Result<()> {
         let pos = self.index.get(key.to_vec());
         if pos.is_none() {
             return Ok(());
         }
+        let _write_guard = self.write_fence.enter()?;
+        self.check_write_stall()?;
+        self.move_to_trash(&key)?;
 
-        let mut log_record = LogRecord {
-            key: encode_log_record_key(key.to_vec(), NON_TRANSACTION_SEQUENCE),
-            value: Default::default(),
-            record_type: LogRecordType::Deleted,
-        };
+        let mut log_record = LogRecord::new_tombstone(
+            encode_log_record_key(key.to_vec(), NON_TRANSACTION_SEQUENCE),
+            now_millis(),
+        );
 
         let pos = self.append_log_record(&mut log_record)?;
-        self.reclaim_size
-            .fetch_add(pos.size as usize, Ordering::SeqCst);
+        self.add_reclaim_size(&pos);
 
         if let Some(old_pos) = self.index.delete(key.to_vec()) {
-            self.reclaim_size
-                .fetch_add(old_pos.size as usize, Ordering::SeqCst);
+            self.add_reclaim_size(&old_pos);
         }
+        self.notify_change_sink();
 
         Ok(())
     }
@@ -291,71 +669,585 @@ impl Engine {
         }
 
         let log_record_pos = pos.unwrap();
-        self.get_value_by_position(&log_record_pos)
+        if self.expire_on_read(&key, log_record_pos) {
+            return Err(Errors::KeyNotFound);
+        }
+        match self.get_value_by_position(&log_record_pos) {
+            // The entry may have been moved by a partial merge, which removed its file.
+            Err(_) if self.index.get(key.to_vec()) != Some(log_record_pos) => self.get(key),
+            res => res,
+        }
+    }
+
+    /// Whether the database contains KEY, consistent with `get`: expired entries written by
+    /// `put_with_ttl` do not count. Only the index is consulted, as it keeps the expiry of each
+    /// entry, so no data file is read.
+    pub fn contains_key(&self, key: Bytes) -> Result<bool> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        Ok(self
+            .index
+            .get(key.to_vec())
+            .is_some_and(|log_record_pos| !log_record_pos.is_expired(now_millis())))
+    }
+
+    /// Whether the database contains each of KEYS, in the same order as KEYS.
+    pub fn exists_many(&self, keys: &[Bytes]) -> Result<Vec<bool>> {
+        keys.iter()
+            .map(|key| self.contains_key(key.clone()))
+            .collect()
+    }
+
+    /// Same as `get`, but also return the metadata of the entry, such as the time it was written.
+    pub fn get_with_metadata(&self, key: Bytes) -> Result<(Bytes, EntryMetadata)> {
+        let log_record = self.get_live_record(key)?;
+        let metadata = EntryMetadata::from_record(&log_record);
+        let value = self.blob_store.read_user_value(log_record)?;
+        Ok((value.into(), metadata))
+    }
+
+    /// Get the record holding the entry with key KEY, whose value is left in its blob file if it
+    /// is stored in one.
+    pub(crate) fn get_live_record(&self, key: Bytes) -> Result<LogRecord> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        let log_record_pos = self.index.get(key.to_vec()).ok_or(Errors::KeyNotFound)?;
+        if self.expire_on_read(&key, log_record_pos) {
+            return Err(Errors::KeyNotFound);
+        }
+
+        let active_file = self.active_file.read().unwrap();
+        let old_files = self.old_files();
+        let verify_crc = self.should_verify_crc(&active_file, &log_record_pos);
+        let log_record =
+            match self.read_live_record(&active_file, &old_files, &log_record_pos, verify_crc) {
+                Ok(log_record) => log_record,
+                // The entry may have been moved by a partial merge, which removed its file.
+                Err(_) if self.index.get(key.to_vec()) != Some(log_record_pos) => {
+                    drop(active_file);
+                    return self.get_live_record(key);
+                }
+                Err(e) => return Err(e),
+            };
+        if active_file.get_file_id() != log_record_pos.file_id {
+            self.touch_old_file(&old_files, log_record_pos.file_id);
+        }
+        Ok(log_record)
+    }
+
+    /// Same as `get`, but always verify the CRC of the record regardless of
+    /// `Options::read_checksum_policy`, failing with `Errors::InvalidLogRecordCRC` on bit rot.
+    pub fn get_checked(&self, key: Bytes) -> Result<Bytes> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        let log_record_pos = self.index.get(key.to_vec()).ok_or(Errors::KeyNotFound)?;
+        self.get_checked_at(key, log_record_pos)
+    }
+
+    /// Same as `get_checked`, reading the entry of KEY at LOG_RECORD_POS, as found in the index.
+    fn get_checked_at(&self, key: Bytes, log_record_pos: LogRecordPos) -> Result<Bytes> {
+        let active_file = self.active_file.read().unwrap();
+        let old_files = self.old_files();
+        let value = match self.read_value_with_crc(&active_file, &old_files, &log_record_pos, true)
+        {
+            Ok(value) => value,
+            // The entry may have been moved by a partial merge, which removed its file.
+            Err(_) if self.index.get(key.to_vec()) != Some(log_record_pos) => {
+                drop(active_file);
+                return self.get_checked(key);
+            }
+            Err(e) => return Err(e),
+        };
+        if active_file.get_file_id() != log_record_pos.file_id {
+            self.touch_old_file(&old_files, log_record_pos.file_id);
+        }
+        Ok(value)
     }
 
     pub(crate) fn get_value_by_position(&self, log_record_pos: &LogRecordPos) -> Result<Bytes> {
+        let value_cache = match &self.value_cache {
+            Some(value_cache) => value_cache,
+            None => return self.get_value_by_position_uncached(log_record_pos),
+        };
+        if let Some(value) = value_cache.get(log_record_pos, now_millis()) {
+            return Ok(value);
+        }
+
+        let active_file = self.active_file.read().unwrap();
+        let old_files = self.old_files();
+        let verify_crc = self.should_verify_crc(&active_file, log_record_pos);
+        let log_record =
+            self.read_live_record(&active_file, &old_files, log_record_pos, verify_crc)?;
+        let expire_at = log_record.expire_at();
+        let value: Bytes = self.blob_store.read_user_value(log_record)?.into();
+        value_cache.insert(log_record_pos, value.clone(), expire_at);
+        if active_file.get_file_id() != log_record_pos.file_id {
+            self.touch_old_file(&old_files, log_record_pos.file_id);
+        }
+        Ok(value)
+    }
+
+    /// Same as `get_value_by_position`, without going through the value cache.
+    fn get_value_by_position_uncached(&self, log_record_pos: &LogRecordPos) -> Result<Bytes> {
+        let active_file = self.active_file.read().unwrap();
+        let old_files = self.old_files.load();
+        let value = self.read_value(&active_file, &old_files, log_record_pos)?;
+        if active_file.get_file_id() != log_record_pos.file_id {
+            self.touch_old_file(&old_files, log_record_pos.file_id);
+        }
+        Ok(value)
+    }
+
+    /// Get the values at POSITIONS, in the same order as POSITIONS. The data files are locked
+    /// once, and positions are read in the order of file id and offset.
+    pub fn get_values_by_positions(&self, positions: &[LogRecordPos]) -> Result<Vec<Bytes>> {
+        self.read_values_by_positions(positions)?
+            .into_iter()
+            .map(|value| value.ok_or(Errors::KeyNotFound))
+            .collect()
+    }
+
+    /// Same as `get_values_by_positions`, but a position whose entry has expired gets None.
+    pub(crate) fn read_values_by_positions(
+        &self,
+        positions: &[LogRecordPos],
+    ) -> Result<Vec<Option<Bytes>>> {
+        let mut order: Vec<usize> = (0..positions.len()).collect();
+        order.sort_by_key(|i| (positions[*i].file_id, positions[*i].ofs));
+
         let active_file = self.active_file.read().unwrap();
-        let old_files = self.old_files.read().unwrap();
+        let old_files = self.old_files.load();
+        let mut values = vec![None; positions.len()];
+        for (n, i) in order.iter().enumerate() {
+            let log_record_pos = &positions[*i];
+            values[*i] = match self.read_value(&active_file, &old_files, log_record_pos) {
+                Ok(value) => Some(value),
+                Err(Errors::KeyNotFound) => None,
+                Err(e) => return Err(e),
+            };
+
+            // Record the access once all positions of the current file are read.
+            let is_last_of_file = match order.get(n + 1) {
+                Some(next) => positions[*next].file_id != log_record_pos.file_id,
+                None => true,
+            };
+            if is_last_of_file && active_file.get_file_id() != log_record_pos.file_id {
+                self.touch_old_file(&old_files, log_record_pos.file_id);
+            }
+        }
+        Ok(values)
+    }
 
+    /// Same as `read_values_by_positions`, for the entries of KEYS found at POSITIONS. If any of
+    /// them has been moved by a partial merge, which removed its file, the values are read by key
+    /// instead, and a key deleted since gets None.
+    pub(crate) fn read_values_of_keys<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+        positions: &[LogRecordPos],
+    ) -> Result<Vec<Option<Bytes>>> {
+        let err = match self.read_values_by_positions(positions) {
+            Ok(values) => return Ok(values),
+            Err(e) => e,
+        };
+        let is_moved = keys
+            .iter()
+            .zip(positions)
+            .any(|(key, pos)| self.index.get(key.as_ref().to_vec()) != Some(*pos));
+        if !is_moved {
+            return Err(err);
+        }
+        keys.iter()
+            .map(|key| match self.get(Bytes::copy_from_slice(key.as_ref())) {
+                Ok(value) => Ok(Some(value)),
+                Err(Errors::KeyNotFound) => Ok(None),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    /// Get the values of all KEYS, in the same order as KEYS. A key that does not exist gets
+    /// None.
+    pub fn multi_get(&self, keys: &[Bytes]) -> Result<Vec<Option<Bytes>>> {
+        let mut found = Vec::with_capacity(keys.len());
+        let mut positions = Vec::with_capacity(keys.len());
+        for (i, key) in keys.iter().enumerate() {
+            if key.is_empty() {
+                return Err(Errors::KeyIsEmpty);
+            }
+            if let Some(pos) = self.index.get(key.to_vec()) {
+                found.push(i);
+                positions.push(pos);
+            }
+        }
+
+        let found_keys: Vec<&Bytes> = found.iter().map(|i| &keys[*i]).collect();
+        let mut values = vec![None; keys.len()];
+        for (i, value) in found
+            .into_iter()
+            .zip(self.read_values_of_keys(&found_keys, &positions)?)
+        {
+            values[i] = value;
+        }
+        Ok(values)
+    }
+
+    /// Read the value at LOG_RECORD_POS from either ACTIVE_FILE or OLD_FILES.
+    pub(crate) fn read_value(
+        &self,
+        active_file: &DataFile,
+        old_files: &OldFiles,
+        log_record_pos: &LogRecordPos,
+    ) -> Result<Bytes> {
+        let verify_crc = self.should_verify_crc(active_file, log_record_pos);
+        self.read_value_with_crc(active_file, old_files, log_record_pos, verify_crc)
+    }
+
+    /// Whether reading the user value at LOG_RECORD_POS verifies its CRC, according to
+    /// `Options::read_checksum_policy`.
+    fn should_verify_crc(&self, active_file: &DataFile, log_record_pos: &LogRecordPos) -> bool {
+        match self.options.read_checksum_policy {
+            ChecksumPolicy::Always => true,
+            ChecksumPolicy::SealedOnly => active_file.get_file_id() != log_record_pos.file_id,
+            ChecksumPolicy::Never => false,
+        }
+    }
+
+    /// Same as `read_value`, but the CRC is verified only if VERIFY_CRC is set to TRUE.
+    fn read_value_with_crc(
+        &self,
+        active_file: &DataFile,
+        old_files: &OldFiles,
+        log_record_pos: &LogRecordPos,
+        verify_crc: bool,
+    ) -> Result<Bytes> {
+        let log_record =
+            self.read_live_record(active_file, old_files, log_record_pos, verify_crc)?;
+        Ok(self.blob_store.read_user_value(log_record)?.into())
+    }
+
+    /// Same as `read_log_record_at`, but fail with `Errors::KeyNotFound` if the record deletes
+    /// its key or has expired.
+    fn read_live_record(
+        &self,
+        active_file: &DataFile,
+        old_files: &OldFiles,
+        log_record_pos: &LogRecordPos,
+        verify_crc: bool,
+    ) -> Result<LogRecord> {
+        let log_record =
+            self.read_log_record_at(active_file, old_files, log_record_pos, verify_crc)?;
+        if log_record.record_type == LogRecordType::Deleted || log_record.is_expired(now_millis()) {
+            return Err(Errors::KeyNotFound);
+        }
+        Ok(log_record)
+    }
+
+    /// Read the log record at LOG_RECORD_POS from either ACTIVE_FILE or OLD_FILES, the CRC is
+    /// verified only if VERIFY_CRC is set to TRUE. The record is read at once, as its size is known
+    /// from LOG_RECORD_POS.
+    pub(crate) fn read_log_record_at(
+        &self,
+        active_file: &DataFile,
+        old_files: &OldFiles,
+        log_record_pos: &LogRecordPos,
+        verify_crc: bool,
+    ) -> Result<LogRecord> {
         // LOG_RECORD_POS may appears in either active file or closed files, so we need to check
         // both of them.
         let log_record = match active_file.get_file_id() == log_record_pos.file_id {
-            true => active_file.read_log_record(log_record_pos.ofs)?.0,
+            true => {
+                active_file
+                    .read_log_record_sized(
+                        log_record_pos.ofs,
+                        log_record_pos.size as usize,
+                        verify_crc,
+                    )?
+                    .0
+            }
             false => {
                 let data_file = old_files.get(&log_record_pos.file_id);
                 if data_file.is_none() {
                     return Err(Errors::DataFileNotFound);
                 }
-                data_file.unwrap().read_log_record(log_record_pos.ofs)?.0
+                data_file
+                    .unwrap()
+                    .read_log_record_sized(
+                        log_record_pos.ofs,
+                        log_record_pos.size as usize,
+                        verify_crc,
+                    )?
+                    .0
             }
         };
 
-        if log_record.record_type == LogRecordType::Deleted {
-            return Err(Errors::KeyNotFound);
-        }
-
-        Ok(log_record.value.into())
+        Ok(log_record)
     }
 
     /// Write to the active file by appending the file with LOG_RECORD.
     pub(crate) fn append_log_record(&self, log_record: &mut LogRecord) -> Result<LogRecordPos> {
-        let dir_path = self.options.dir_path.clone();
-
-        let encoded_record = log_record.encode();
-        let record_len = encoded_record.len() as u64;
+        let mut active_file = self.active_file.write().unwrap();
+        self.ensure_open()?;
+        let pos = self.write_log_record(&mut active_file, log_record)?;
+        self.sync_written(&active_file, pos.size as usize)?;
+        Ok(pos)
+    }
 
+    /// Append LOG_RECORDS to the active file in order under a single lock, syncing at most once
+    /// at the end. Return their positions.
+    pub(crate) fn append_log_records(
+        &self,
+        log_records: &[LogRecord],
+    ) -> Result<Vec<LogRecordPos>> {
         let mut active_file = self.active_file.write().unwrap();
+        self.ensure_open()?;
+        let positions = self.write_log_records(&mut active_file, log_records)?;
+        let written = positions.iter().map(|pos| pos.size as usize).sum();
+        self.sync_written(&active_file, written)?;
+        Ok(positions)
+    }
 
-        // When the current active file meets a size threshold, close it and create a new active
-        // file.
-        if active_file.get_write_ofs() + record_len > self.options.data_file_size {
-            // Persist the current active file to the disk.
-            active_file.sync()?;
-            let file_id = active_file.get_file_id();
+    /// Write all the pairs of PAIRS into the database under a single lock of the active file,
+    /// syncing at most once at the end. Readers are blocked until all pairs are written. Return
+    /// the number of pairs written.
+    pub fn put_many(&self, pairs: impl IntoIterator<Item = (Bytes, Bytes)>) -> Result<usize> {
+        let mut keys = Vec::new();
+        let mut log_records = Vec::new();
+        for (key, value) in pairs {
+            if key.is_empty() {
+                return Err(Errors::KeyIsEmpty);
+            }
+            self.check_entry_size(&key, &value)?;
+            log_records.push(LogRecord {
+                key: encode_log_record_key(key.to_vec(), NON_TRANSACTION_SEQUENCE),
+                value: value.to_vec(),
+                record_type: LogRecordType::Normal,
+                timestamp: Some(now_millis()),
+                user_flags: 0,
+            });
+            keys.push(key);
+        }
+        if log_records.is_empty() {
+            return Ok(0);
+        }
+        let _key_locks = self.key_locks.lock_all(keys.iter().map(|key| key.as_ref()));
+        let _write_guard = self.write_fence.enter()?;
+        self.check_write_stall()?;
+
+        let num = log_records.len();
+        let positions = self.append_log_records(&log_records)?;
+
+        for (log_record, pos) in log_records.into_iter().zip(positions) {
+            let (key, _) = parse_log_record_key(&log_record.key);
+            if let Some(old_pos) = self.index.put(key, pos) {
+                self.add_reclaim_size(&old_pos);
+            }
+        }
+        self.notify_change_sink();
+
+        Ok(num)
+    }
+
+    /// Write LOG_RECORD to ACTIVE_FILE, rotating it once it is full, without syncing.
+    fn write_log_record(
+        &self,
+        active_file: &mut DataFile,
+        log_record: &LogRecord,
+    ) -> Result<LogRecordPos> {
+        let positions = self.write_log_records(active_file, std::slice::from_ref(log_record))?;
+        Ok(positions[0])
+    }
+
+    /// Encode LOG_RECORD to be written to a data file, compressed according to
+    /// `Options::compression` and ended by `Options::checksum`. A large value is stored in a blob
+    /// file beforehand, and the record referring to it is encoded instead.
+    pub(crate) fn encode_log_record(&self, log_record: &LogRecord) -> Result<Vec<u8>> {
+        let blob_record = self.store_large_value(log_record)?;
+        Ok(blob_record
+            .as_ref()
+            .unwrap_or(log_record)
+            .encode_with_options(
+                self.options.compression,
+                self.options.compression_min_size,
+                self.options.checksum,
+            ))
+    }
+
+    /// Same as `encode_log_record`, appending the encoded record to BUF.
+    fn encode_log_record_into(&self, log_record: &LogRecord, buf: &mut Vec<u8>) -> Result<()> {
+        let blob_record = self.store_large_value(log_record)?;
+        blob_record
+            .as_ref()
+            .unwrap_or(log_record)
+            .encode_with_options_into(
+                self.options.compression,
+                self.options.compression_min_size,
+                self.options.checksum,
+                buf,
+            );
+        Ok(())
+    }
 
-            // Close the current active file, and insert it into the keydir.
-            let mut old_files = self.old_files.write().unwrap();
-            let old_file = DataFile::new(&dir_path, file_id, IOType::StandardFIO)?;
-            old_files.insert(file_id, old_file);
+    /// Write LOG_RECORDS to ACTIVE_FILE in order, rotating it once it is full, without syncing.
+    /// The records are encoded back to back into a buffer of the thread's pool, and those landing
+    /// in the same file are written by a single vectored write.
+    fn write_log_records(
+        &self,
+        active_file: &mut DataFile,
+        log_records: &[LogRecord],
+    ) -> Result<Vec<LogRecordPos>> {
+        let mut buf = take_buffer();
+        let mut encoded_records = Vec::with_capacity(log_records.len());
+        for log_record in log_records {
+            let start = buf.len();
+            self.encode_log_record_into(log_record, &mut buf)?;
+            encoded_records.push(start..buf.len());
+        }
+        let mut positions = Vec::with_capacity(log_records.len());
+        let (mut start, mut group_len) = (0, 0);
+        for (i, encoded_record) in encoded_records.iter().enumerate() {
+            let record_len = encoded_record.len() as u64;
+            // When the current active file meets a size threshold, close it and create a new
+            // active file.
+            if active_file.get_write_ofs() + group_len + record_len > self.options.data_file_size {
+                let range = start..i;
+                self.write_encoded_records(
+                    active_file,
+                    &log_records[range.clone()],
+                    &buf,
+                    &encoded_records[range],
+                    &mut positions,
+                )?;
+                self.rotate_active_file(active_file)?;
+                (start, group_len) = (i, 0);
+            }
+            group_len += record_len;
+        }
+        self.write_encoded_records(
+            active_file,
+            &log_records[start..],
+            &buf,
+            &encoded_records[start..],
+            &mut positions,
+        )?;
+        Ok(positions)
+    }
 
-            // Create a new active file.
-            let new_file = DataFile::new(&dir_path, file_id + 1, IOType::StandardFIO)?;
-            *active_file = new_file;
+    /// Write the records of BUF at the ranges ENCODED_RECORDS, the encoding of LOG_RECORDS, to
+    /// ACTIVE_FILE by a single vectored write, and push their positions to POSITIONS.
+    fn write_encoded_records(
+        &self,
+        active_file: &DataFile,
+        log_records: &[LogRecord],
+        buf: &[u8],
+        encoded_records: &[Range<usize>],
+        positions: &mut Vec<LogRecordPos>,
+    ) -> Result<()> {
+        if encoded_records.is_empty() {
+            return Ok(());
+        }
+        let mut write_ofs = active_file.get_write_ofs();
+        let bufs: Vec<IoSlice> = encoded_records
+            .iter()
+            .map(|range| IoSlice::new(&buf[range.clone()]))
+            .collect();
+        active_file.write_vectored(&bufs)?;
+
+        let file_id = active_file.get_file_id();
+        let mut active_summary = self.active_summary.lock().unwrap();
+        for (log_record, range) in log_records.iter().zip(encoded_records) {
+            let encoded_record = &buf[range.clone()];
+            active_summary.add(&log_record.key, log_record.record_type, encoded_record);
+            if self.options.prefix_extractor.is_some() && log_record.record_type.is_value() {
+                let (key, _) = parse_log_record_key(&log_record.key);
+                self.record_prefix(file_id, write_ofs, &key);
+            }
+            positions.push(LogRecordPos {
+                file_id,
+                ofs: write_ofs,
+                size: encoded_record.len() as u32,
+                expire_at: log_record.expire_at(),
+            });
+            write_ofs += encoded_record.len() as u64;
         }
+        Ok(())
+    }
+
+    /// Create the data file FILE_ID under DIR_PATH to be appended, see `new_data_file`.
+    pub(crate) fn new_data_file(
+        &self,
+        dir_path: &PathBuf,
+        file_id: u64,
+        io_type: IOType,
+    ) -> Result<DataFile> {
+        new_data_file(&self.options, dir_path, file_id, io_type)
+    }
+
+    /// Seal ACTIVE_FILE, and replace it with a new empty active file.
+    fn rotate_active_file(&self, active_file: &mut DataFile) -> Result<()> {
+        let dir_path = self.options.dir_path.clone();
+
+        // Persist the current active file to the disk.
+        active_file.sync()?;
+        let file_id = active_file.get_file_id();
+
+        // Close the current active file, and insert it into the keydir.
+        let end_ofs = self.write_active_footer(active_file)?;
+        self.manifest
+            .append(ManifestEdit::SealFile(file_id, end_ofs))?;
+        let old_file = DataFile::new_lazy(&dir_path, file_id, self.read_io_type)
+            .with_io_wrapper(self.options.io_wrapper.clone());
+        old_file.set_end_ofs(end_ofs);
+        self.update_old_files(|old_files| {
+            old_files.insert(file_id, Arc::new(old_file));
+        });
+
+        // Create a new active file.
+        let new_file = self.new_data_file(&dir_path, file_id + 1, self.io_type)?;
+        sync_dir(&dir_path)?;
+        self.manifest.append(ManifestEdit::NewFile(file_id + 1))?;
+        *active_file = new_file;
+        Ok(())
+    }
 
-        // write to the current active file.
-        let write_ofs = active_file.get_write_ofs();
-        active_file.write(&encoded_record)?;
+    /// Append the footer summarizing the records of ACTIVE_FILE, which is about to be sealed, and
+    /// return the logical end of the file. The records are scanned if they were not all appended
+    /// since the engine is opened, and a file whose records cannot be read is left without
+    /// footer.
+    pub(crate) fn write_active_footer(&self, active_file: &DataFile) -> Result<u64> {
+        let end_ofs = active_file.get_write_ofs();
+        let mut footer = std::mem::take(&mut *self.active_summary.lock().unwrap()).finish();
+        if footer.data_size != end_ofs {
+            footer = match FooterBuilder::scan(active_file, end_ofs) {
+                Ok(builder) => builder.finish(),
+                Err(e) => {
+                    warn!(
+                        "failed to summarize data file {}: {:?}",
+                        active_file.get_file_id(),
+                        e
+                    );
+                    return Ok(end_ofs);
+                }
+            };
+        }
+        if footer.record_count > 0 {
+            footer.write(active_file)?;
+            active_file.sync()?;
+        }
+        Ok(end_ofs)
+    }
 
+    /// Account for WRITTEN bytes just written to ACTIVE_FILE, and sync it if configured so.
+    fn sync_written(&self, active_file: &DataFile, written: usize) -> Result<()> {
         // Determine if we should perform sync
-        let previous = self
-            .bytes_write
-            .fetch_add(encoded_record.len(), Ordering::SeqCst);
+        let previous = self.bytes_write.fetch_add(written, Ordering::SeqCst);
         let mut need_sync = self.options.sync_writes;
         if !need_sync
             && self.options.bytes_per_sync > 0
-            && previous + encoded_record.len() >= self.options.bytes_per_sync
+            && previous + written >= self.options.bytes_per_sync
         {
             need_sync = true;
         }
@@ -363,66 +1255,61 @@ impl Engine {
             active_file.sync()?;
             self.bytes_write.store(0, Ordering::SeqCst);
         }
-
-        Ok(LogRecordPos {
-            file_id: active_file.get_file_id(),
-            ofs: write_ofs,
-            size: encoded_record.len() as u32,
-        })
+        Ok(())
     }
 
-    /// Indexing all the data files.
-    fn load_index_from_data_files(&self) -> Result<usize> {
+    /// Indexing the data files from START, the file id and offset of the first record not indexed
+    /// yet, or all the data files if START is None.
+    fn load_index_from_data_files(&self, start: Option<(u64, u64)>) -> Result<usize> {
         let mut current_sequence_number = NON_TRANSACTION_SEQUENCE;
         if self.file_ids.is_empty() {
             return Ok(current_sequence_number);
         }
 
-        // Obtain the id of the file that has not been merged.
-        let mut has_merge = false;
-        let mut non_merge_fid = 0;
-        let merge_fin_file = self.options.dir_path.join(MERGE_FIN_FILE_NAME);
-        if merge_fin_file.is_file() {
-            let merge_fin_file = DataFile::new_merge_fin_file(&self.options.dir_path)?;
-            let merge_fin_record = merge_fin_file.read_log_record(0)?;
-            let v = String::from_utf8(merge_fin_record.0.value).unwrap();
-
-            non_merge_fid = v.parse::<u32>().unwrap();
-            has_merge = true;
-        }
-
-        let mut transaction_records = HashMap::new();
+        let mut transaction_records = HashMap::new();
+        let mut replayed_commits = self.replayed_commits.lock().unwrap();
 
         let active_file = self.active_file.read().unwrap();
-        let old_files = self.old_files.read().unwrap();
+        let old_files = self.old_files();
+        let mut prefix_blooms = self.prefix_blooms.write().unwrap();
 
         for (i, file_id) in self.file_ids.iter().enumerate() {
-            // If the current has FILE_ID that less than NON_MERGE_FID, it indicates the current
-            // file has already been loaded to the indexer via hint file, so we skip it.
-            if has_merge && *file_id < non_merge_fid {
-                continue;
+            // Files before START have already been indexed, from the hint file or the keydir file.
+            let mut ofs = match start {
+                Some((start_fid, _)) if *file_id < start_fid => continue,
+                Some((start_fid, start_ofs)) if *file_id == start_fid => start_ofs,
+                _ => 0,
+            };
+
+            // All records of the file are scanned, so its prefix bloom filter is complete.
+            let is_scanned_whole = ofs == 0;
+            if self.options.prefix_extractor.is_some() && is_scanned_whole {
+                prefix_blooms
+                    .entry(*file_id)
+                    .or_insert_with(new_prefix_bloom);
             }
 
             // Read the file with id FILE_ID.
-            let mut ofs = 0;
+            let is_active = *file_id == active_file.get_file_id();
+            let data_file: &DataFile = match is_active {
+                true => &active_file,
+                false => old_files.get(file_id).unwrap(),
+            };
             loop {
-                let log_record_res = match *file_id == active_file.get_file_id() {
-                    true => active_file.read_log_record(ofs),
-                    false => {
-                        let data_file = old_files.get(file_id).unwrap();
-                        data_file.read_log_record(ofs)
-                    }
-                };
-
-                let (mut log_record, size) = match log_record_res {
+                let (mut log_record, size) = match data_file.read_log_record(ofs) {
                     Ok(result) => result,
                     Err(e) => {
                         if e == Errors::ReadDataFileEOF {
                             // This case indicates all content within the current file has been
                             // read. Therefore, we break the current loop and read the next file.
                             break;
-                        } else {
-                            return Err(e);
+                        }
+                        match self.recover_corrupted_record(data_file, is_active, ofs, e)? {
+                            Some(size) => {
+                                ofs += size as u64;
+                                continue;
+                            }
+                            None => break,
                         }
                     }
                 };
@@ -432,15 +1319,30 @@ impl Engine {
                     file_id: *file_id,
                     ofs,
                     size: size as u32,
+                    expire_at: log_record.expire_at(),
                 };
 
                 let (key, sequence_number) = parse_log_record_key(&log_record.key);
+                if let Some(extractor) = &self.options.prefix_extractor {
+                    if log_record.record_type.is_value() && is_scanned_whole {
+                        Self::record_loaded_prefix(
+                            &mut prefix_blooms,
+                            extractor.as_ref(),
+                            *file_id,
+                            &key,
+                        );
+                    }
+                }
                 if sequence_number == NON_TRANSACTION_SEQUENCE {
                     self.update_index(key, log_record.record_type, log_record_pos)?;
                 } else {
                     if log_record.record_type == LogRecordType::TxnFinished {
-                        let records: &Vec<TransactionRecord> =
-                            transaction_records.get(&sequence_number).unwrap();
+                        // Records of a transaction are never split by a keydir file, which is
+                        // written on close, still skip those unseen.
+                        let records: Vec<TransactionRecord> = transaction_records
+                            .remove(&sequence_number)
+                            .unwrap_or_default();
+                        replayed_commits.insert(sequence_number);
                         for txn_record in records.iter() {
                             self.update_index(
                                 txn_record.record.key.clone(),
@@ -448,7 +1350,6 @@ impl Engine {
                                 txn_record.pos,
                             )?;
                         }
-                        transaction_records.remove(&sequence_number);
                     } else {
                         log_record.key = key;
                         transaction_records
@@ -469,61 +1370,174 @@ impl Engine {
 
             if i == self.file_ids.len() - 1 {
                 active_file.set_write_ofs(ofs)
+            } else {
+                // Sealed files are reopened on demand, so do not hold their handles after loading.
+                data_file.close_io();
             }
         }
 
         Ok(current_sequence_number)
     }
 
-    pub(crate) fn load_index_from_hint_file(&self) -> Result<()> {
-        let hint_file_name = self.options.dir_path.join(HINT_FILE_NAME);
-
-        // Return if hint file does not exist.
-        if !hint_file_name.is_file() {
-            return Ok(());
+    /// Load the index from the hint file, return the id of the first data file it does not cover,
+    /// or None if all data files need to be scanned.
+    pub(crate) fn load_index_from_hint_file(&self) -> Result<Option<u64>> {
+        // Obtain the id of the file that has not been merged.
+        let merge_fin_file = self.options.dir_path.join(MERGE_FIN_FILE_NAME);
+        if !merge_fin_file.is_file() {
+            return Ok(None);
         }
+        let merge_fin_file = DataFile::new_merge_fin_file(&self.options.dir_path)?;
+        let merge_fin_record = merge_fin_file.read_log_record(0)?;
+        let v = String::from_utf8(merge_fin_record.0.value).unwrap();
+        let non_merge_fid = v.parse::<u64>().unwrap();
 
         // Load all log records from hint file to the indexer.
-        let hint_file = DataFile::new_hint_file(&self.options.dir_path)?;
-        let mut ofs = 0;
-        loop {
-            let (log_record, size) = match hint_file.read_log_record(ofs) {
-                Ok(result) => result,
-                Err(e) => {
-                    if e == Errors::ReadDataFileEOF {
-                        // This case indicates all content within the current file has been
-                        // read. Therefore, we break the current loop and read the next file.
-                        break;
-                    } else {
-                        return Err(e);
-                    }
-                }
+        let entries = match self.read_hint_file(non_merge_fid)? {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+        for (key, log_record_pos) in entries {
+            if let Some(extractor) = &self.options.prefix_extractor {
+                Self::record_loaded_prefix(
+                    &mut self.prefix_blooms.write().unwrap(),
+                    extractor.as_ref(),
+                    log_record_pos.file_id,
+                    &key,
+                );
+            }
+            self.index.put(key, log_record_pos);
+        }
+        Ok(Some(non_merge_fid))
+    }
+
+    /// Serve the index from the keydir file written by the last close, and index the records
+    /// appended since then. Return false if there is no keydir file matching the data files.
+    fn load_index_from_keydir(&mut self) -> Result<bool> {
+        let keydir = match KeydirFile::open(&self.options.dir_path) {
+            // The index must know the expiry of each entry, which older keydir files lack.
+            Some(keydir) if keydir.has_expiries() => keydir,
+            _ => return Ok(false),
+        };
+
+        // The data files may only have grown since the keydir file is written, as merges remove
+        // it. Records after its end are written by sessions without `persist_keydir`.
+        let (keydir_file_id, keydir_ofs) = (keydir.active_file_id(), keydir.active_ofs());
+        let is_tail_empty = {
+            let active_file = self.active_file.read().unwrap();
+            let keydir_file_size = match active_file.get_file_id() == keydir_file_id {
+                true => Some(active_file.file_size()),
+                false => self.old_files().get(&keydir_file_id).map(|f| f.file_size()),
             };
-            let log_record_pos = decode_log_record_pos(log_record.value);
-            self.index.put(log_record.key, log_record_pos);
-            ofs += size as u64;
+            if keydir_file_size.is_none_or(|size| size < keydir_ofs) {
+                return Ok(false);
+            }
+            active_file.get_file_id() == keydir_file_id && keydir_file_size == Some(keydir_ofs)
+        };
+
+        let delta = new_indexer(
+            self.options.index_type.clone(),
+            self.options.dir_path.clone(),
+            self.options.index_shard_num,
+        );
+        let mut sequence_number = keydir.sequence_number();
+        self.index = Box::new(SnapshotIndex::new(
+            Box::new(LayeredIndex::new(keydir, delta)),
+            self.snapshots.clone(),
+        ));
+        if is_tail_empty {
+            self.active_file.read().unwrap().set_write_ofs(keydir_ofs);
+        } else {
+            let tail_sequence_number =
+                self.load_index_from_data_files(Some((keydir_file_id, keydir_ofs)))?;
+            sequence_number = sequence_number.max(tail_sequence_number + 1);
         }
-        Ok(())
+        self.sequence_number
+            .store(sequence_number, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    /// Write SEQUENCE_NUMBER to the sequence number file. The record is written to a temporary
+    /// file which then replaces the old one, so a crash never leaves a partially written file.
+    fn save_sequence_number(&self, sequence_number: usize) -> Result<()> {
+        let dir_path = &self.options.dir_path;
+        let tmp_file_name = dir_path.join(SEQUENCE_NUMBER_TMP_FILE_NAME);
+        if tmp_file_name.is_file() {
+            fs::remove_file(&tmp_file_name).map_err(|_| Errors::FailedToWriteToDataFile)?;
+        }
+
+        let tmp_file = DataFile::new_sequence_number_file(dir_path, SEQUENCE_NUMBER_TMP_FILE_NAME)?;
+        let record = LogRecord {
+            key: SEQUENCE_NUMBER_KEY.as_bytes().to_vec(),
+            value: sequence_number.to_string().into_bytes(),
+            record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
+        };
+        tmp_file.write(&record.encode())?;
+        tmp_file.sync()?;
+        fs::rename(tmp_file_name, dir_path.join(SEQUENCE_NUMBER_FILE_NAME))
+            .map_err(|_| Errors::FailedToWriteToDataFile)
     }
 
-    fn load_sequence_number(&self) -> (bool, usize) {
+    /// Read the sequence number saved on the last close. Return None if the file is missing, or
+    /// if its record fails the checksum or cannot be parsed.
+    fn load_sequence_number(&self) -> Option<usize> {
         let file_name = self.options.dir_path.join(SEQUENCE_NUMBER_FILE_NAME);
         if !file_name.is_file() {
-            return (false, 0);
+            return None;
         }
-        let sequence_number_file =
-            DataFile::new_sequence_number_file(&self.options.dir_path).unwrap();
-        let record = match sequence_number_file.read_log_record(0) {
-            Ok(res) => res.0,
-            Err(e) => panic!("failed to read sequence number: {:?}", e),
+        let record =
+            DataFile::new_sequence_number_file(&self.options.dir_path, SEQUENCE_NUMBER_FILE_NAME)
+                .and_then(|file| file.read_log_record(0));
+        let sequence_number = match record {
+            Ok((record, _)) if record.key == SEQUENCE_NUMBER_KEY.as_bytes() => {
+                String::from_utf8(record.value)
+                    .ok()
+                    .and_then(|v| v.parse::<usize>().ok())
+            }
+            _ => None,
         };
-        let v = String::from_utf8(record.value).unwrap();
-        let sequence_number = v.parse::<usize>().unwrap();
-
-        // Clean up after loading.
-        fs::remove_file(file_name).unwrap();
+        if sequence_number.is_none() {
+            warn!("sequence number file is corrupted, scanning data files instead");
+        }
+        sequence_number
+    }
 
-        (true, sequence_number)
+    /// Scan all the data files for the largest sequence number committed by a transaction.
+    /// Records after the first unreadable one of each file are ignored.
+    fn scan_sequence_number(&self) -> usize {
+        let active_file = self.active_file.read().unwrap();
+        let old_files = self.old_files();
+        let mut max_sequence_number = NON_TRANSACTION_SEQUENCE;
+        let mut replayed_commits = self.replayed_commits.lock().unwrap();
+        // Prepared transactions left on disk are checked against the commits found here, which
+        // the footers do not hold, so the sealed files are scanned too.
+        let has_prepared = has_prepared_files(&self.options.dir_path);
+        for file_id in self.file_ids.iter() {
+            let data_file: &DataFile = match *file_id == active_file.get_file_id() {
+                true => &active_file,
+                false => old_files.get(file_id).unwrap(),
+            };
+            // The footer of a sealed file holds its highest sequence number.
+            let footer = DataFileFooter::read(data_file).filter(|footer| {
+                !has_prepared && data_file.get_end_ofs() == Some(footer.data_size)
+            });
+            if let Some(footer) = footer {
+                max_sequence_number = max_sequence_number.max(footer.max_sequence_number as usize);
+                continue;
+            }
+            let mut ofs = 0;
+            while let Ok((log_record, size)) = data_file.read_log_record(ofs) {
+                let (_, sequence_number) = parse_log_record_key(&log_record.key);
+                max_sequence_number = max_sequence_number.max(sequence_number);
+                if log_record.record_type == LogRecordType::TxnFinished {
+                    replayed_commits.insert(sequence_number);
+                }
+                ofs += size as u64;
+            }
+        }
+        max_sequence_number
     }
 
     fn update_index(
@@ -533,42 +1547,111 @@ impl Engine {
         log_record_pos: LogRecordPos,
     ) -> Result<()> {
         match record_type {
-            LogRecordType::Normal => {
+            LogRecordType::Normal | LogRecordType::Expiring | LogRecordType::Blob => {
                 if let Some(old_pos) = self.index.put(key.clone(), log_record_pos) {
-                    self.reclaim_size
-                        .fetch_add(old_pos.size as usize, Ordering::SeqCst);
+                    self.add_reclaim_size(&old_pos);
                 }
             }
             LogRecordType::Deleted => {
-                let mut size = log_record_pos.size;
+                self.add_reclaim_size(&log_record_pos);
                 if let Some(old_pos) = self.index.delete(key.clone()) {
-                    size += old_pos.size;
+                    self.add_reclaim_size(&old_pos);
                 }
-                self.reclaim_size.fetch_add(size as usize, Ordering::SeqCst);
             }
             _ => (),
         };
         Ok(())
     }
 
+    /// Record FILE_ID as the most recently read old file, and close the least recently read ones
+    /// once more than `max_open_files` old files are opened.
+    fn touch_old_file(&self, old_files: &OldFiles, file_id: u64) {
+        let mut open_files = self.open_files.lock().unwrap();
+        if open_files.back() == Some(&file_id) {
+            return;
+        }
+        if let Some(idx) = open_files.iter().position(|id| *id == file_id) {
+            open_files.remove(idx);
+        }
+        open_files.push_back(file_id);
+
+        while open_files.len() > self.options.max_open_files.max(1) {
+            let evicted = open_files.pop_front().unwrap();
+            if let Some(data_file) = old_files.get(&evicted) {
+                data_file.close_io();
+            }
+        }
+    }
+
+    /// Reopen the data files opened with `Options::startup_io_type` with the IO types they are
+    /// used with once the engine is started.
     fn reset_io_type(&self) {
-        let mut active_file = self.active_file.write().unwrap();
-        active_file.set_io_manager(&self.options.dir_path, IOType::StandardFIO);
-        let mut old_files = self.old_files.write().unwrap();
-        for (_, file) in old_files.iter_mut() {
-            file.set_io_manager(&self.options.dir_path, IOType::StandardFIO);
+        let startup_io_type = self.options.startup_io_type;
+        if startup_io_type != self.io_type {
+            let mut active_file = self.active_file.write().unwrap();
+            active_file.set_io_manager(&self.options.dir_path, self.io_type);
+        }
+        if startup_io_type == self.read_io_type {
+            return;
         }
+        self.update_old_files(|old_files| {
+            for (file_id, file) in old_files.iter_mut() {
+                let data_file =
+                    DataFile::new_lazy(&self.options.dir_path, *file_id, self.read_io_type)
+                        .with_io_wrapper(self.options.io_wrapper.clone());
+                if let Some(end_ofs) = file.get_end_ofs() {
+                    data_file.set_end_ofs(end_ofs);
+                }
+                *file = Arc::new(data_file);
+            }
+        });
+    }
+
+    /// Get the current snapshot of old files.
+    pub(crate) fn old_files(&self) -> Arc<OldFiles> {
+        self.old_files.load_full()
+    }
+
+    /// Publish a copy of the old files modified by F. Readers holding the previous snapshot are
+    /// not affected.
+    pub(crate) fn update_old_files<F>(&self, f: F)
+    where
+        F: FnOnce(&mut OldFiles),
+    {
+        let _update = self.old_files_update.lock().unwrap();
+        let mut new_old_files = OldFiles::clone(&self.old_files.load());
+        f(&mut new_old_files);
+        self.old_files.store(Arc::new(new_old_files));
     }
 }
 
 impl Drop for Engine {
     fn drop(&mut self) {
-        if let Err(e) = self.close() {
+        if self.is_closed() {
+            return;
+        }
+        if let Err(e) = self.shutdown() {
             log::error!("error while closing engine: {:?}", e);
         }
     }
 }
 
+/// Create the data file FILE_ID under DIR_PATH to be appended, whose disk space is reserved up to
+/// the size threshold if `Options::preallocate_data_files` is set.
+fn new_data_file(
+    opts: &Options,
+    dir_path: &PathBuf,
+    file_id: u64,
+    io_type: IOType,
+) -> Result<DataFile> {
+    let data_file =
+        DataFile::new(dir_path, file_id, io_type)?.with_io_wrapper(opts.io_wrapper.clone());
+    if opts.preallocate_data_files {
+        data_file.preallocate(opts.data_file_size)?;
+    }
+    Ok(data_file)
+}
+
 /// Fetch all data files under directory DIR_PATH.
 fn load_data_files(dir_path: &PathBuf, opts: &Options) -> Result<Vec<DataFile>> {
     let dir = fs::read_dir(dir_path);
@@ -576,7 +1659,7 @@ fn load_data_files(dir_path: &PathBuf, opts: &Options) -> Result<Vec<DataFile>>
         return Err(Errors::FailedToReadDatabaseDir);
     }
 
-    let mut file_ids = Vec::<u32>::new();
+    let mut file_ids = Vec::<u64>::new();
     let mut data_files = Vec::<DataFile>::new();
     for file in dir.unwrap() {
         if let Ok(entry) = file {
@@ -587,7 +1670,7 @@ fn load_data_files(dir_path: &PathBuf, opts: &Options) -> Result<Vec<DataFile>>
                     .split_once(".")
                     .unwrap()
                     .0
-                    .parse::<u32>()
+                    .parse::<u64>()
                     .map_err(|_| Errors::DataDirectoryCorrupted)?;
                 file_ids.push(file_id);
             }
@@ -596,7 +1679,10 @@ fn load_data_files(dir_path: &PathBuf, opts: &Options) -> Result<Vec<DataFile>>
 
     file_ids.sort();
     for file_id in file_ids {
-        data_files.push(DataFile::new(&dir_path, file_id, opts.startup_io_type)?);
+        data_files.push(
+            DataFile::new_lazy(&dir_path, file_id, opts.startup_io_type)
+                .with_io_wrapper(opts.io_wrapper.clone()),
+        );
     }
 
     Ok(data_files)
@@ -604,16 +1690,15 @@ fn load_data_files(dir_path: &PathBuf, opts: &Options) -> Result<Vec<DataFile>>
 
 /// Append the log record with the sequence number.
 pub(crate) fn encode_log_record_key(key: Vec<u8>, sequence_number: usize) -> Vec<u8> {
-    let mut encoded_key = BytesMut::new();
+    let mut encoded_key = Vec::with_capacity(length_delimiter_len(sequence_number) + key.len());
     encode_length_delimiter(sequence_number, &mut encoded_key).unwrap();
-    encoded_key.extend_from_slice(&key.to_vec());
-    encoded_key.to_vec()
+    encoded_key.extend_from_slice(&key);
+    encoded_key
 }
 
 /// Decode a encoded log record into the (key, sequence_number) pair.
 pub(crate) fn parse_log_record_key(key: &Vec<u8>) -> (Vec<u8>, usize) {
-    let mut buf = BytesMut::new();
-    buf.put_slice(key);
+    let mut buf = key.as_slice();
     let sequence_number = decode_length_delimiter(&mut buf).unwrap();
     (buf.to_vec(), sequence_number)
 }
@@ -628,24 +1713,49 @@ fn check_options(opts: &Options) -> Result<()> {
         return Err(Errors::DataFileSizeTooSmall);
     }
 
+    if opts.index_shard_num == 0 {
+        return Err(Errors::InvalidIndexShardNum);
+    }
+
     if opts.data_file_merge_ratio < 0 as f32 || opts.data_file_merge_ratio > 1 as f32 {
         return Err(Errors::InvalidMergeRatio);
     }
 
+    if let Some(auto_merge) = &opts.auto_merge {
+        if let Some((start, end)) = auto_merge.quiet_hours {
+            if start >= 24 || end >= 24 {
+                return Err(Errors::InvalidQuietHours);
+            }
+        }
+    }
+
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::{path::PathBuf, sync::atomic::Ordering, time::Duration};
 
     use bytes::Bytes;
 
     use crate::{
+        data::{
+            data_file::{get_data_file_name, DataFile, SEQUENCE_NUMBER_FILE_NAME},
+            footer::DataFileFooter,
+            log_record::LogRecordPos,
+        },
         db::Engine,
         errors::Errors,
-        options::Options,
-        utils::rand_kv::{get_test_key, get_test_value},
+        manifest::MANIFEST_FILE_NAME,
+        options::{
+            ChecksumPolicy, ChecksumType, CompressionType, IOType, IndexType, Options, PutOptions,
+            WriteBatchOptions,
+        },
+        utils::{
+            rand_kv::{get_test_key, get_test_value},
+            time::now_millis,
+        },
+        verify::IntegrityIssue,
     };
 
     #[test]
@@ -825,6 +1935,285 @@ mod tests {
         std::fs::remove_dir_all(opt.clone().dir_path).expect("failed to remove dir");
     }
 
+    #[test]
+    fn test_engine_multi_get() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-multi-get");
+        opts.data_file_size = 64 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..5000 {
+            let res = engine.put(get_test_key(i), get_test_value(i));
+            assert!(res.is_ok());
+        }
+
+        let keys = vec![
+            get_test_key(4000),
+            get_test_key(1),
+            Bytes::from("not exist"),
+            get_test_key(2500),
+        ];
+        let values = engine.multi_get(&keys).unwrap();
+        assert_eq!(values[0], Some(get_test_value(4000)));
+        assert_eq!(values[1], Some(get_test_value(1)));
+        assert_eq!(values[2], None);
+        assert_eq!(values[3], Some(get_test_value(2500)));
+
+        let res = engine.multi_get(&[Bytes::new()]);
+        assert_eq!(Errors::KeyIsEmpty, res.err().unwrap());
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_contains_key() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-contains-key");
+        opts.data_file_size = 64 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..100 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(engine
+            .put_with_ttl(
+                get_test_key(100),
+                get_test_value(100),
+                Duration::from_millis(100)
+            )
+            .is_ok());
+        assert!(engine.delete(get_test_key(2)).is_ok());
+
+        let keys = vec![
+            get_test_key(1),
+            get_test_key(2),
+            Bytes::from("not exist"),
+            get_test_key(100),
+        ];
+        assert!(engine.contains_key(get_test_key(1)).unwrap());
+        assert!(!engine.contains_key(Bytes::from("not exist")).unwrap());
+        assert_eq!(
+            engine.exists_many(&keys).unwrap(),
+            vec![true, false, false, true]
+        );
+        assert_eq!(
+            engine.contains_key(Bytes::new()).err(),
+            Some(Errors::KeyIsEmpty)
+        );
+
+        // An expired entry is not contained, same as with `get`, even before it is merged.
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(
+            engine.get(get_test_key(100)).err(),
+            Some(Errors::KeyNotFound)
+        );
+        assert!(!engine.contains_key(get_test_key(100)).unwrap());
+        assert_eq!(
+            engine.exists_many(&keys).unwrap(),
+            vec![true, false, false, false]
+        );
+
+        // The expiries are indexed again on restart.
+        assert!(engine
+            .put_with_ttl(
+                get_test_key(101),
+                get_test_value(101),
+                Duration::from_secs(60)
+            )
+            .is_ok());
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(!engine.contains_key(get_test_key(100)).unwrap());
+        assert!(engine.contains_key(get_test_key(101)).unwrap());
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_put_many() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-put-many");
+        opts.data_file_size = 64 * 1024;
+        opts.sync_writes = true;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let pairs = (0..5000).map(|i| (get_test_key(i), get_test_value(i)));
+        assert_eq!(engine.put_many(pairs).unwrap(), 5000);
+        assert_eq!(engine.get(get_test_key(0)).unwrap(), get_test_value(0));
+        assert_eq!(
+            engine.get(get_test_key(4999)).unwrap(),
+            get_test_value(4999)
+        );
+        assert!(engine.old_files().len() > 1);
+
+        // No pair is written if any key is empty.
+        let pairs = vec![
+            (get_test_key(5000), get_test_value(5000)),
+            (Bytes::new(), get_test_value(5001)),
+        ];
+        assert_eq!(engine.put_many(pairs).err(), Some(Errors::KeyIsEmpty));
+        assert_eq!(
+            engine.get(get_test_key(5000)).err(),
+            Some(Errors::KeyNotFound)
+        );
+
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 5000);
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_put_with_ttl() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-put-with-ttl");
+        opts.data_file_size = 64 * 1024;
+        opts.data_file_merge_ratio = 0.0;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..100 {
+            let res = match i % 2 {
+                0 => engine.put(get_test_key(i), get_test_value(i)),
+                _ => engine.put_with_ttl(
+                    get_test_key(i),
+                    get_test_value(i),
+                    Duration::from_millis(100),
+                ),
+            };
+            assert!(res.is_ok());
+        }
+        assert_eq!(engine.get(get_test_key(1)).unwrap(), get_test_value(1));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(engine.get(get_test_key(1)).err(), Some(Errors::KeyNotFound));
+        assert_eq!(engine.get(get_test_key(2)).unwrap(), get_test_value(2));
+        let values = engine
+            .multi_get(&[get_test_key(1), get_test_key(2)])
+            .unwrap();
+        assert_eq!(values, vec![None, Some(get_test_value(2))]);
+        let num = std::sync::atomic::AtomicUsize::new(0);
+        engine
+            .fold(|_, _| {
+                num.fetch_add(1, Ordering::SeqCst);
+                true
+            })
+            .unwrap();
+        assert_eq!(num.load(Ordering::SeqCst), 50);
+
+        // Merge discards the expired entries along with their index entries, except the one
+        // already dropped by the read above.
+        let stats = engine.merge().unwrap();
+        assert_eq!(stats.records_expired, 49);
+        assert_eq!(engine.list_keys().unwrap().len(), 50);
+        assert!(!engine.contains_key(get_test_key(3)).unwrap());
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 50);
+        assert_eq!(engine.get(get_test_key(3)).err(), Some(Errors::KeyNotFound));
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_concurrent_read_during_rotation() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-concurrent-read");
+        opts.data_file_size = 32 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+
+        let before = engine.old_files();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for i in 0..2000 {
+                        assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+                    }
+                });
+            }
+            s.spawn(|| {
+                for i in 2000..6000 {
+                    assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+                }
+            });
+        });
+
+        // Published snapshots are never modified.
+        assert!(engine.old_files().len() > before.len());
+        assert!(before.keys().all(|id| engine.old_files().contains_key(id)));
+
+        // Concurrent updates are applied one after the other, none of them is lost.
+        let file = before.values().next().unwrap();
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                let (engine, file) = (&engine, file.clone());
+                s.spawn(move || {
+                    engine.update_old_files(|old_files| {
+                        old_files.insert(u64::MAX - i, file);
+                    })
+                });
+            }
+        });
+        assert!((0..8).all(|i| engine.old_files().contains_key(&(u64::MAX - i))));
+        engine.update_old_files(|old_files| old_files.retain(|id, _| *id < u64::MAX - 8));
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_read_checksum_policy() {
+        let policies = [
+            (ChecksumPolicy::Always, false, false),
+            (ChecksumPolicy::SealedOnly, false, true),
+            (ChecksumPolicy::Never, true, true),
+        ];
+        for (policy, sealed_ok, active_ok) in policies {
+            let mut opts = Options::default();
+            opts.dir_path = PathBuf::from("/tmp/bitcask-rs-read-checksum-policy");
+            opts.data_file_size = 32 * 1024;
+            opts.read_checksum_policy = policy;
+            let engine = Engine::open(opts.clone()).expect("failed to open engine");
+            for i in 0..1000 {
+                assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+            }
+            let sealed_pos = engine.index.get(get_test_key(0).to_vec()).unwrap();
+            let active_pos = engine.index.get(get_test_key(999).to_vec()).unwrap();
+            assert_ne!(sealed_pos.file_id, active_pos.file_id);
+
+            // Flip the last byte of the value in both records.
+            for pos in [sealed_pos, active_pos] {
+                let file_name = get_data_file_name(&opts.dir_path, pos.file_id);
+                let mut content = std::fs::read(&file_name).unwrap();
+                let idx = (pos.ofs + pos.size as u64) as usize - 5;
+                content[idx] ^= 0xff;
+                std::fs::write(&file_name, content).unwrap();
+            }
+
+            let res = engine.get(get_test_key(0));
+            assert_eq!(res.is_ok(), sealed_ok);
+            if !sealed_ok {
+                assert_eq!(res.err().unwrap(), Errors::InvalidLogRecordCRC);
+            }
+            assert_eq!(engine.get(get_test_key(999)).is_ok(), active_ok);
+
+            // Checked reads always verify the CRC.
+            for key in [get_test_key(0), get_test_key(999)] {
+                assert_eq!(
+                    engine.get_checked(key).err(),
+                    Some(Errors::InvalidLogRecordCRC)
+                );
+            }
+
+            std::mem::drop(engine);
+            std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+        }
+    }
+
     #[test]
     fn test_engine_filelock() {
         let mut opts = Options::default();
@@ -843,6 +2232,80 @@ mod tests {
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     }
 
+    #[test]
+    fn test_engine_close_idempotent() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-close-idempotent");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine
+            .put(get_test_key(1), get_test_value(1))
+            .expect("failed to put");
+
+        assert!(engine.close().is_ok());
+        assert!(engine.is_closed());
+        assert!(engine.close().is_ok());
+        assert_eq!(
+            engine.put(get_test_key(2), get_test_value(2)).err(),
+            Some(Errors::EngineClosed)
+        );
+        assert_eq!(engine.merge().err(), Some(Errors::EngineClosed));
+
+        // The directory lock is released, and dropping the closed engine leaves it alone.
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        std::mem::drop(engine);
+        assert!(Engine::open(opts.clone()).is_err());
+        assert!(engine2.get(get_test_key(1)).is_ok());
+        std::mem::drop(engine2);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_shutdown_drains_writers() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-shutdown-drain");
+        let engine =
+            std::sync::Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+
+        let writers = (0..4)
+            .map(|t| {
+                let engine = engine.clone();
+                std::thread::spawn(move || {
+                    let mut written = Vec::new();
+                    for i in 0.. {
+                        let key = get_test_key(t * 100000 + i);
+                        match engine.put(key.clone(), get_test_value(i)) {
+                            Ok(()) => written.push(key),
+                            Err(e) => {
+                                assert_eq!(e, Errors::EngineClosed);
+                                break;
+                            }
+                        }
+                    }
+                    written
+                })
+            })
+            .collect::<Vec<_>>();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(engine.shutdown().is_ok());
+        assert!(engine.shutdown().is_ok());
+
+        // Every acknowledged write is persisted, and stays readable after shutdown.
+        let written = writers
+            .into_iter()
+            .flat_map(|writer| writer.join().unwrap())
+            .collect::<Vec<_>>();
+        assert!(!written.is_empty());
+        assert!(engine.get(written[0].clone()).is_ok());
+        std::mem::drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.stat().unwrap().key_num, written.len());
+        std::mem::drop(engine);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
     #[test]
     fn test_engine_stat() {
         let mut opts = Options::default();
@@ -864,7 +2327,798 @@ mod tests {
 
         let stat = engine.stat().unwrap();
         assert!(stat.reclaim_size > 0);
+        assert_eq!(stat.key_num, 7000);
+
+        // The index holds at least the keys and their positions.
+        let usage = engine.index_memory_usage();
+        assert_eq!(usage.entry_num, 7000);
+        assert_eq!(stat.index_memory_size, usage.estimated_bytes);
+        let key_size = get_test_key(0).len();
+        assert!(stat.index_memory_size > 7000 * (key_size + std::mem::size_of::<LogRecordPos>()));
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
 
+    #[test]
+    fn test_engine_persist_keydir() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-persist-keydir");
+        opts.persist_keydir = true;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=10000 {
+            let res = engine.put(get_test_key(i), get_test_value(i));
+            assert!(res.is_ok());
+        }
+        for i in 0..=100 {
+            let res = engine.delete(get_test_key(i));
+            assert!(res.is_ok());
+        }
+        std::mem::drop(engine);
+
+        // The sequence number file is consumed once open from the keydir file too.
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(!opts.dir_path.join(SEQUENCE_NUMBER_FILE_NAME).exists());
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine2.get(get_test_key(50)).err().unwrap()
+        );
+        assert_eq!(engine2.get(get_test_key(500)).unwrap(), get_test_value(500));
+        assert!(engine2.delete(get_test_key(200)).is_ok());
+        assert!(engine2.put(get_test_key(300), Bytes::from("new")).is_ok());
+        assert!(engine2.put(get_test_key(20000), Bytes::from("new")).is_ok());
+        assert_eq!(engine2.list_keys().unwrap().len(), 9900);
+        std::mem::drop(engine2);
+
+        // Data files are modified without updating the keydir, which must be ignored then.
+        let mut opts2 = opts.clone();
+        opts2.persist_keydir = false;
+        let engine3 = Engine::open(opts2.clone()).expect("failed to open engine");
+        assert!(engine3.put(get_test_key(400), Bytes::from("new")).is_ok());
+        std::mem::drop(engine3);
+
+        let engine4 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine4.get(get_test_key(200)).err().unwrap()
+        );
+        assert_eq!(engine4.get(get_test_key(300)).unwrap(), Bytes::from("new"));
+        assert_eq!(engine4.get(get_test_key(400)).unwrap(), Bytes::from("new"));
+        assert_eq!(engine4.list_keys().unwrap().len(), 9900);
+
+        std::mem::drop(engine4);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_persist_keydir_replay_tail() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-persist-keydir-tail");
+        opts.data_file_size = 32 * 1024;
+        opts.persist_keydir = true;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        std::mem::drop(engine);
+
+        // Records are appended over several data files without updating the keydir.
+        let mut opts2 = opts.clone();
+        opts2.persist_keydir = false;
+        let engine2 = Engine::open(opts2.clone()).expect("failed to open engine");
+        for i in 500..2000 {
+            assert!(engine2.put(get_test_key(i), Bytes::from("new")).is_ok());
+        }
+        for i in 0..100 {
+            assert!(engine2.delete(get_test_key(i)).is_ok());
+        }
+        let wb = engine2
+            .new_write_batch(crate::options::WriteBatchOptions::default())
+            .unwrap();
+        assert!(wb.put(get_test_key(3000), Bytes::from("batch")).is_ok());
+        assert!(wb.commit().is_ok());
+        std::mem::drop(engine2);
+
+        let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine3.get(get_test_key(50)).err().unwrap()
+        );
+        assert_eq!(engine3.get(get_test_key(200)).unwrap(), get_test_value(200));
+        assert_eq!(engine3.get(get_test_key(700)).unwrap(), Bytes::from("new"));
+        assert_eq!(
+            engine3.get(get_test_key(3000)).unwrap(),
+            Bytes::from("batch")
+        );
+        assert_eq!(engine3.list_keys().unwrap().len(), 1901);
+
+        // Sequence numbers keep increasing after the batch replayed from the tail.
+        let wb = engine3
+            .new_write_batch(crate::options::WriteBatchOptions::default())
+            .unwrap();
+        assert!(wb.put(get_test_key(3001), Bytes::from("batch")).is_ok());
+        assert!(wb.commit().is_ok());
+        std::mem::drop(engine3);
+
+        let engine4 = Engine::open(opts2.clone()).expect("failed to open engine");
+        assert_eq!(
+            engine4.get(get_test_key(3001)).unwrap(),
+            Bytes::from("batch")
+        );
+        assert_eq!(engine4.list_keys().unwrap().len(), 1902);
+
+        std::mem::drop(engine4);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_hash_index() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-hash-index");
+        opts.index_type = IndexType::Hash;
+        opts.persist_keydir = true;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..100 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        let keys = engine.list_keys().unwrap();
+        assert_eq!(keys.len(), 900);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        std::mem::drop(engine);
+
+        // The index is restored from the keydir file, then from the data files.
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine2.get(get_test_key(500)).unwrap(), get_test_value(500));
+        assert_eq!(engine2.list_keys().unwrap().len(), 900);
+        std::mem::drop(engine2);
+
+        opts.persist_keydir = false;
+        let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine3.get(get_test_key(50)).err().unwrap()
+        );
+        assert_eq!(engine3.get(get_test_key(500)).unwrap(), get_test_value(500));
+        assert_eq!(engine3.list_keys().unwrap().len(), 900);
+
+        std::mem::drop(engine3);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_sharded_index() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-sharded-index");
+        opts.index_shard_num = 0;
+        assert_eq!(
+            Engine::open(opts.clone()).err(),
+            Some(Errors::InvalidIndexShardNum)
+        );
+
+        opts.index_shard_num = 8;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let engine = &engine;
+                s.spawn(move || {
+                    for i in (t * 250)..((t + 1) * 250) {
+                        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+                    }
+                });
+            }
+        });
+        let keys = engine.list_keys().unwrap();
+        assert_eq!(keys.len(), 1000);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        std::mem::drop(engine);
+
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine2.get(get_test_key(999)).unwrap(), get_test_value(999));
+        assert_eq!(engine2.list_keys().unwrap().len(), 1000);
+
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_lazy_open_files() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-lazy-open");
+        opts.data_file_size = 64 * 1024;
+        opts.max_open_files = 2;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=10000 {
+            let res = engine.put(get_test_key(i), get_test_value(i));
+            assert!(res.is_ok());
+        }
+        std::mem::drop(engine);
+
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        let count_opened = |engine: &Engine| {
+            let old_files = engine.old_files();
+            assert!(old_files.len() > 2);
+            old_files.values().filter(|f| f.is_open()).count()
+        };
+        assert_eq!(count_opened(&engine2), 0);
+
+        for i in 0..=10000 {
+            let res = engine2.get(get_test_key(i));
+            assert_eq!(res.unwrap(), get_test_value(i));
+        }
+        assert!(count_opened(&engine2) <= 2);
+
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_memory_mapped_io() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-mmap-io");
+        opts.data_file_size = 64 * 1024;
+        opts.startup_io_type = IOType::MemoryMapped;
+        opts.io_type = IOType::MemoryMapped;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=5000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(engine.delete(get_test_key(0)).is_ok());
+        assert!(engine.sync().is_ok());
+        assert!(engine.old_files().len() > 1);
+        for i in 1..=5000 {
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+        std::mem::drop(engine);
+
+        // Data files hold no trailing bytes past the records, and keep being appended to.
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine2.get(get_test_key(0)).is_err());
+        assert!(engine2.put(get_test_key(0), get_test_value(0)).is_ok());
+        for i in 0..=5000 {
+            assert_eq!(engine2.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_read_io_type() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-read-io-type");
+        opts.data_file_size = 64 * 1024;
+        opts.read_io_type = Some(IOType::MemoryMapped);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=5000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+
+        // The sealed files are memory-mapped, while the active file is appended as usual.
+        let is_mapped = |engine: &Engine| {
+            engine
+                .old_files()
+                .values()
+                .all(|f| f.get_io_type() == IOType::MemoryMapped)
+        };
+        assert!(engine.old_files().len() > 1);
+        assert!(is_mapped(&engine));
+        assert!(engine.active_file.read().unwrap().get_io_type() == IOType::StandardFIO);
+        for i in 0..=5000 {
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+        std::mem::drop(engine);
+
+        // The files opened at startup are switched to their IO type once started.
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(is_mapped(&engine2));
+        assert!(engine2.active_file.read().unwrap().get_io_type() == IOType::StandardFIO);
+        for i in 0..=5000 {
+            assert_eq!(engine2.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_get_with_metadata() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-get-with-metadata");
+        opts.data_file_merge_ratio = 0.0;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let before = now_millis();
+        assert!(engine.put(get_test_key(0), get_test_value(0)).is_ok());
+        assert!(engine
+            .put_with_ttl(get_test_key(1), get_test_value(1), Duration::from_secs(60))
+            .is_ok());
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert!(wb.put(get_test_key(2), get_test_value(2)).is_ok());
+        assert!(wb.commit().is_ok());
+        let after = now_millis();
+
+        let (value, metadata) = engine.get_with_metadata(get_test_key(0)).unwrap();
+        assert_eq!(value, get_test_value(0));
+        let timestamp = metadata.timestamp.unwrap();
+        assert!(before <= timestamp && timestamp <= after);
+        assert_eq!(metadata.expire_at, None);
+        let (value, metadata) = engine.get_with_metadata(get_test_key(1)).unwrap();
+        assert_eq!(value, get_test_value(1));
+        assert!(metadata.expire_at.unwrap() >= before + 60_000);
+        let (_, metadata) = engine.get_with_metadata(get_test_key(2)).unwrap();
+        assert!(metadata.timestamp.unwrap() >= before);
+        assert_eq!(
+            engine.get_with_metadata(get_test_key(3)).err(),
+            Some(Errors::KeyNotFound)
+        );
+
+        // Timestamps are kept by merges.
+        let timestamps: Vec<_> = (0..3)
+            .map(|i| engine.get_with_metadata(get_test_key(i)).unwrap().1)
+            .collect();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(engine.delete(get_test_key(3)).is_ok());
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        for (i, metadata) in timestamps.iter().enumerate() {
+            let (value, merged) = engine2.get_with_metadata(get_test_key(i as i32)).unwrap();
+            assert_eq!(value, get_test_value(i as i32));
+            assert_eq!(&merged, metadata);
+        }
+
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_put_with_options() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-put-with-options");
+        opts.data_file_merge_ratio = 0.0;
+        opts.large_value_threshold = 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let flagged = |user_flags: u8| PutOptions {
+            user_flags,
+            ..Default::default()
+        };
+        assert!(engine
+            .put_with_options(get_test_key(0), get_test_value(0), flagged(0x21))
+            .is_ok());
+        let options = PutOptions {
+            ttl: Some(Duration::from_secs(60)),
+            user_flags: 0x42,
+        };
+        assert!(engine
+            .put_with_options(get_test_key(1), get_test_value(1), options)
+            .is_ok());
+        let large_value = Bytes::from(vec![7; 4096]);
+        assert!(engine
+            .put_with_options(get_test_key(2), large_value.clone(), flagged(0x84))
+            .is_ok());
+        assert!(engine.put(get_test_key(3), get_test_value(3)).is_ok());
+
+        let check = |engine: &Engine| {
+            let (value, metadata) = engine.get_with_metadata(get_test_key(0)).unwrap();
+            assert_eq!(value, get_test_value(0));
+            assert_eq!(metadata.user_flags, 0x21);
+            let (value, metadata) = engine.get_with_metadata(get_test_key(1)).unwrap();
+            assert_eq!(value, get_test_value(1));
+            assert_eq!(metadata.user_flags, 0x42);
+            assert!(metadata.expire_at.is_some());
+            let (value, metadata) = engine.get_with_metadata(get_test_key(2)).unwrap();
+            assert_eq!(value, large_value);
+            assert_eq!(metadata.user_flags, 0x84);
+            let (_, metadata) = engine.get_with_metadata(get_test_key(3)).unwrap();
+            assert_eq!(metadata.user_flags, 0);
+        };
+        check(&engine);
+
+        // User flags are kept by merges, whatever the checksum.
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+        opts.checksum = ChecksumType::XxHash64;
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        check(&engine2);
+        assert!(engine2.merge().is_ok());
+        std::mem::drop(engine2);
+        let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+        check(&engine3);
+
+        // Overwriting the entry clears its flags.
+        assert!(engine3.put(get_test_key(0), get_test_value(0)).is_ok());
+        let (_, metadata) = engine3.get_with_metadata(get_test_key(0)).unwrap();
+        assert_eq!(metadata.user_flags, 0);
+
+        std::mem::drop(engine3);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_compression() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-compression");
+        opts.data_file_merge_ratio = 0.0;
+        opts.compression = CompressionType::Lz4;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let value =
+            |i: i32| Bytes::from(format!("{{\"id\":{},\"name\":\"Hamlet\"}}", i).repeat(50));
+        for i in 0..100 {
+            assert!(engine.put(get_test_key(i), value(i)).is_ok());
+        }
+        assert!(engine
+            .put_with_ttl(get_test_key(100), value(100), Duration::from_secs(60))
+            .is_ok());
+        // Short values are left uncompressed.
+        assert!(engine.put(get_test_key(101), get_test_value(101)).is_ok());
+        let written = engine.active_file.read().unwrap().get_write_ofs();
+        assert!(written < 101 * value(0).len() as u64 / 5);
+        for i in 0..=100 {
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), value(i));
+        }
+        assert_eq!(engine.get(get_test_key(101)).unwrap(), get_test_value(101));
+        std::mem::drop(engine);
+
+        // Compressed values are read whatever the compression, and merges recompress them.
+        opts.compression = CompressionType::Zstd;
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=100 {
+            assert_eq!(engine2.get(get_test_key(i)).unwrap(), value(i));
+        }
+        assert!(engine2.merge().is_ok());
+        std::mem::drop(engine2);
+
+        opts.compression = CompressionType::None;
+        let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+        let merged = std::fs::metadata(get_data_file_name(&opts.dir_path, 1)).unwrap();
+        assert!(merged.len() < written);
+        for i in 0..=100 {
+            assert_eq!(engine3.get(get_test_key(i)).unwrap(), value(i));
+        }
+        assert!(engine3
+            .get_with_metadata(get_test_key(100))
+            .unwrap()
+            .1
+            .expire_at
+            .is_some());
+
+        std::mem::drop(engine3);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_checksum() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-checksum");
+        opts.data_file_merge_ratio = 0.0;
+        opts.checksum = ChecksumType::XxHash64;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..100 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        std::mem::drop(engine);
+
+        // Records are read whatever the checksum, and merges rewrite them with the current one.
+        opts.checksum = ChecksumType::None;
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine2.put(get_test_key(100), get_test_value(100)).is_ok());
+        for i in 0..=100 {
+            assert_eq!(engine2.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+        assert!(engine2.merge().is_ok());
+        std::mem::drop(engine2);
+
+        opts.checksum = ChecksumType::XxHash64;
+        let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=100 {
+            assert_eq!(engine3.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+        assert!(engine3.put(get_test_key(101), get_test_value(101)).is_ok());
+        assert!(engine3.put(get_test_key(102), get_test_value(102)).is_ok());
+        let pos = engine3.index.get(get_test_key(101).to_vec()).unwrap();
+        std::mem::drop(engine3);
+
+        // A corrupted value before the end of the file fails the xxHash64 checksum on startup.
+        let file_name = get_data_file_name(&opts.dir_path, pos.file_id);
+        let mut content = std::fs::read(&file_name).unwrap();
+        content[(pos.ofs + pos.size as u64) as usize - 10] ^= 0xff;
+        std::fs::write(&file_name, content).unwrap();
+        let res = Engine::open(opts.clone());
+        assert_eq!(res.err().unwrap(), Errors::InvalidLogRecordCRC);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_preallocate_data_files() {
+        use std::os::unix::fs::MetadataExt;
+
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-preallocate");
+        opts.data_file_size = 64 * 1024;
+        opts.preallocate_data_files = true;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine.put(get_test_key(0), get_test_value(0)).is_ok());
+
+        // The space is reserved while the size only covers the records.
+        let (active_file_id, write_ofs) = {
+            let active_file = engine.active_file.read().unwrap();
+            (active_file.get_file_id(), active_file.get_write_ofs())
+        };
+        let metadata =
+            std::fs::metadata(get_data_file_name(&opts.dir_path, active_file_id)).unwrap();
+        assert_eq!(metadata.len(), write_ofs);
+        assert!(metadata.blocks() * 512 >= opts.data_file_size);
+
+        for i in 1..=3000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(engine.old_files().len() > 1);
+        std::mem::drop(engine);
+
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=3000 {
+            assert_eq!(engine2.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_direct_io() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-direct-io");
+        opts.data_file_size = 64 * 1024;
+        opts.io_type = IOType::Direct;
+        opts.data_file_merge_ratio = 0.0;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=3000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..=1000 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine2.get(get_test_key(1000)).is_err());
+        for i in 1001..=3000 {
+            assert_eq!(engine2.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_buffered_io() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-buffered-io");
+        opts.data_file_size = 64 * 1024;
+        opts.io_type = IOType::BufferedFIO(4 * 1024);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=3000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+            // Entries still in the buffer are readable.
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+        assert!(engine.old_files().len() > 1);
+        std::mem::drop(engine);
+
+        // Buffered entries are written once the engine is closed.
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=3000 {
+            assert_eq!(engine2.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_data_file_footer() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-footer-engine");
+        opts.data_file_size = 32 * 1024;
+        opts.data_file_merge_ratio = 0.0;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+
+        // Every sealed file ends with the footer of its records.
+        let old_files = engine.old_files();
+        assert!(old_files.len() > 1);
+        for data_file in old_files.values() {
+            let footer = DataFileFooter::read(data_file).unwrap();
+            assert!(footer.record_count > 0);
+            assert_eq!(Some(footer.data_size), data_file.get_end_ofs());
+        }
+
+        // A footer left on the active file by a crash while sealing it is dropped on startup.
+        engine
+            .write_active_footer(&engine.active_file.read().unwrap())
+            .unwrap();
+        std::mem::drop(engine);
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(DataFileFooter::read(&engine2.active_file.read().unwrap()).is_none());
+        assert!(engine2
+            .put(get_test_key(2000), get_test_value(2000))
+            .is_ok());
+        for i in 0..=2000 {
+            assert_eq!(engine2.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+        assert!(engine2.merge().is_ok());
+        std::mem::drop(engine2);
+
+        // Without the MANIFEST, the merged files end where their footer tells.
+        std::fs::remove_file(opts.dir_path.join(MANIFEST_FILE_NAME)).unwrap();
+        let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..=2000 {
+            assert_eq!(engine3.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+        let old_files = engine3.old_files();
+        for data_file in old_files.values() {
+            let footer = DataFileFooter::read(data_file).unwrap();
+            assert_eq!(Some(footer.data_size), data_file.get_end_ofs());
+        }
+        assert!(engine3.verify().is_ok());
+
+        // A footer not matching the records is reported by the full verification.
+        let file_id = *old_files.keys().min().unwrap();
+        let data_file = DataFile::new(&opts.dir_path, file_id, IOType::StandardFIO).unwrap();
+        let mut footer = DataFileFooter::read(&data_file).unwrap();
+        data_file.truncate(footer.data_size).unwrap();
+        footer.max_key = b"tampered".to_vec();
+        footer.write(&data_file).unwrap();
+        assert_eq!(
+            engine3.verify().issues,
+            vec![IntegrityIssue::FooterMismatch { file_id }]
+        );
+
+        std::mem::drop(engine3);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_entry_size_limits() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-size-limits");
+        opts.max_key_size = 32;
+        opts.max_value_size = 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let (long_key, long_value) = (Bytes::from(vec![b'k'; 33]), Bytes::from(vec![0; 1025]));
+        assert!(engine
+            .put(Bytes::from(vec![b'k'; 32]), Bytes::from(vec![0; 1024]))
+            .is_ok());
+        assert_eq!(
+            engine.put(long_key.clone(), get_test_value(0)).err(),
+            Some(Errors::KeyTooLarge)
+        );
+        assert_eq!(
+            engine
+                .put_with_ttl(get_test_key(0), long_value.clone(), Duration::from_secs(60))
+                .err(),
+            Some(Errors::ValueTooLarge)
+        );
+        assert_eq!(
+            engine
+                .put_many(vec![
+                    (get_test_key(1), get_test_value(1)),
+                    (get_test_key(2), long_value.clone()),
+                ])
+                .err(),
+            Some(Errors::ValueTooLarge)
+        );
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .unwrap();
+        assert_eq!(
+            wb.put(long_key, get_test_value(3)).err(),
+            Some(Errors::KeyTooLarge)
+        );
+
+        // Nothing is written by the rejected writes.
+        assert_eq!(engine.list_keys().unwrap().len(), 1);
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_value_cache() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-value-cache");
+        opts.data_file_size = 32 * 1024;
+        opts.cache_size = 1024 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+
+        // The second read of a value is served by the cache.
+        for _ in 0..2 {
+            for i in 0..1000 {
+                assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+            }
+        }
+        let stat = engine.stat().unwrap();
+        assert_eq!((stat.cache_hits, stat.cache_misses), (1000, 1000));
+
+        // Overwritten, deleted and expired entries are never read from the cache.
+        assert!(engine.put(get_test_key(0), get_test_value(1000)).is_ok());
+        assert_eq!(engine.get(get_test_key(0)).unwrap(), get_test_value(1000));
+        assert!(engine.delete(get_test_key(1)).is_ok());
+        assert_eq!(engine.get(get_test_key(1)).err(), Some(Errors::KeyNotFound));
+        assert!(engine
+            .put_with_ttl(
+                get_test_key(2),
+                get_test_value(2),
+                Duration::from_millis(50)
+            )
+            .is_ok());
+        assert_eq!(engine.get(get_test_key(2)).unwrap(), get_test_value(2));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(engine.get(get_test_key(2)).err(), Some(Errors::KeyNotFound));
+
+        // Values stay readable once their files are merged.
+        let file_ids: Vec<u64> = engine.old_files().keys().copied().collect();
+        assert!(engine.merge_files(&file_ids).is_ok());
+        for i in 3..1000 {
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_read_moved_entries() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-read-moved");
+        opts.data_file_size = 32 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+
+        // Positions read from the index before a partial merge refer to removed files.
+        let keys: Vec<Bytes> = (0..1000).map(get_test_key).collect();
+        let positions: Vec<LogRecordPos> = keys
+            .iter()
+            .map(|key| engine.index.get(key.to_vec()).unwrap())
+            .collect();
+        assert!(engine.delete(get_test_key(0)).is_ok());
+        let file_ids: Vec<u64> = engine.old_files().keys().copied().collect();
+        assert!(engine.merge_files(&file_ids).is_ok());
+        assert!(engine.read_values_by_positions(&positions).is_err());
+        let values = engine.read_values_of_keys(&keys, &positions).unwrap();
+        assert_eq!(values[0], None);
+        for (i, value) in values.into_iter().enumerate().skip(1) {
+            assert_eq!(value, Some(get_test_value(i as i32)));
+        }
+
+        assert_eq!(
+            engine
+                .get_checked_at(get_test_key(1), positions[1])
+                .unwrap(),
+            get_test_value(1)
+        );
+        assert_eq!(
+            engine.get_checked_at(get_test_key(0), positions[0]).err(),
+            Some(Errors::KeyNotFound)
+        );
+
+        std::mem::drop(engine);
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     }
 }
@@ -2,7 +2,7 @@ use std::result;
 
 pub type Result<T> = result::Result<T, Errors>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Errors {
     DataFileNotFound,
     DirPathIsEmpty,
@@ -18,6 +18,8 @@ pub enum Errors {
     KeyNotFound,
     IndexUpdateFailed,
     InvalidLogRecordCRC,
+    InvalidLogRecordHeader,
+    TruncatedLogRecord,
     ReadDataFileEOF,
     ReadDataFileFailed,
     ExceedMaxBatchNum,
@@ -27,4 +29,30 @@ pub enum Errors {
     InvalidMergeRatio,
     MergeRationUnreached,
     MergeNoEnoughSpace,
+    BulkLoadKeysUnsorted,
+    WriteStalled,
+    EngineClosed,
+    UnsupportedIndexType,
+    DatabaseDirNotEmpty,
+    InvalidEngineName,
+    EngineNotFound,
+    SourceReadFailed,
+    SourceWriteFailed,
+    SinkDeliveryFailed,
+    InvalidSampleRate,
+    TrashDisabled,
+    VersionMismatch,
+    ValueMismatch,
+    ValueNotInteger,
+    IntegerOverflow,
+    InvalidQuietHours,
+    InvalidIndexShardNum,
+    SavepointNotFound,
+    KeyLockTimeout,
+    TransactionNotPrepared,
+    UnsupportedFormatVersion,
+    DecompressionFailed,
+    BlobFileCorrupted,
+    KeyTooLarge,
+    ValueTooLarge,
 }
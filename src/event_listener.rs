@@ -0,0 +1,133 @@
+//! Event listeners let applications react to what happens to entries besides their own writes,
+//! for instance cascading deletions or emitting audit events once data ages out.
+//!
+//! An entry written with a TTL is reported once it expires, when either a read through `get`
+//! or `get_with_metadata` finds it expired, or a merge purges it. Both drop the entry from the
+//! index, so it is reported once per session. As the expired record stays in the data files
+//! until merged, an entry may be reported again after a restart.
+
+use crate::{
+    data::log_record::{LogRecord, LogRecordPos},
+    db::{Engine, EntryMetadata},
+    utils::time::now_millis,
+};
+
+/// Receives the events of the engine. All events are ignored by default.
+pub trait EventListener: Sync + Send {
+    /// Called once the entry with key KEY has expired, where METADATA is the metadata of its
+    /// last value.
+    fn on_expired(&self, _key: &[u8], _metadata: &EntryMetadata) {}
+}
+
+impl Engine {
+    /// Drop the entry of KEY at LOG_RECORD_POS from the index if it has expired, as found by a
+    /// read, and return whether it has. Its record is only read if an event listener is
+    /// configured, and the entry is left to merge if the read fails.
+    pub(crate) fn expire_on_read(&self, key: &[u8], log_record_pos: LogRecordPos) -> bool {
+        if !log_record_pos.is_expired(now_millis()) {
+            return false;
+        }
+        let log_record = match &self.options.event_listener {
+            Some(_) => {
+                let active_file = self.active_file.read().unwrap();
+                let old_files = self.old_files();
+                match self.read_log_record_at(&active_file, &old_files, &log_record_pos, true) {
+                    Ok(log_record) => Some(log_record),
+                    Err(_) => return true,
+                }
+            }
+            None => None,
+        };
+        self.drop_expired_entry(key, log_record_pos, log_record.as_ref());
+        true
+    }
+
+    /// Drop the expired entry of KEY at LOG_RECORD_POS from the index, unless the key is written
+    /// again meanwhile, and notify the event listener with LOG_RECORD, the record of the entry.
+    pub(crate) fn drop_expired_entry(
+        &self,
+        key: &[u8],
+        log_record_pos: LogRecordPos,
+        log_record: Option<&LogRecord>,
+    ) {
+        if !self.index.compare_and_delete(key.to_vec(), log_record_pos) {
+            return;
+        }
+        self.add_reclaim_size(&log_record_pos);
+        if let (Some(listener), Some(log_record)) = (&self.options.event_listener, log_record) {
+            listener.on_expired(key, &EntryMetadata::from_record(log_record));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        errors::Errors,
+        options::{Options, PutOptions},
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    /// Records the expired entries it is notified of.
+    #[derive(Default)]
+    struct TestListener {
+        expired: Mutex<Vec<(Vec<u8>, EntryMetadata)>>,
+    }
+
+    impl EventListener for TestListener {
+        fn on_expired(&self, key: &[u8], metadata: &EntryMetadata) {
+            self.expired.lock().unwrap().push((key.to_vec(), *metadata));
+        }
+    }
+
+    #[test]
+    fn test_event_listener_on_expired() {
+        let listener = Arc::new(TestListener::default());
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-event-listener");
+        opts.data_file_merge_ratio = 0.0;
+        opts.event_listener = Some(listener.clone());
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let options = PutOptions {
+            ttl: Some(Duration::from_millis(100)),
+            user_flags: 7,
+        };
+        for i in 0..2 {
+            assert!(engine
+                .put_with_options(get_test_key(i), get_test_value(i), options.clone())
+                .is_ok());
+        }
+        assert!(engine.put(get_test_key(2), get_test_value(2)).is_ok());
+        let (_, written) = engine.get_with_metadata(get_test_key(0)).unwrap();
+        thread::sleep(Duration::from_millis(150));
+
+        // The read finding the entry expired reports it, later reads do not.
+        assert_eq!(engine.get(get_test_key(0)).err(), Some(Errors::KeyNotFound));
+        assert_eq!(engine.get(get_test_key(0)).err(), Some(Errors::KeyNotFound));
+        assert_eq!(
+            *listener.expired.lock().unwrap(),
+            vec![(get_test_key(0).to_vec(), written)]
+        );
+
+        // The merge reports the expired entries no read found.
+        assert_eq!(engine.merge().unwrap().records_expired, 1);
+        let expired = listener.expired.lock().unwrap().clone();
+        assert_eq!(expired.len(), 2);
+        assert_eq!(expired[1].0, get_test_key(1).to_vec());
+        assert_eq!(expired[1].1.user_flags, 7);
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
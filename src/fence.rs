@@ -0,0 +1,79 @@
+//! Write fencing for shutdown. Every write enters the fence for its whole duration, including the
+//! index update after the append, so shutdown can wait for all in-flight writes to complete
+//! before persisting the state of the engine. Writes entering a fenced engine are rejected.
+//!
+//! Writes are counted by epoch, so partial merge can also wait for the writes started before a
+//! point in time, without waiting for the later ones.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Condvar, Mutex,
+};
+
+use crate::errors::{Errors, Result};
+
+/// The in-flight writes, counted by the parity of the epoch they entered in.
+#[derive(Default)]
+struct InFlight {
+    epoch: usize,
+    writes: [usize; 2],
+}
+
+/// Counts the in-flight writes, and rejects new ones once fenced.
+#[derive(Default)]
+pub(crate) struct WriteFence {
+    fenced: AtomicBool,
+    in_flight: Mutex<InFlight>,
+    drained: Condvar,
+}
+
+/// An in-flight write, which leaves the fence when dropped.
+pub(crate) struct FenceGuard<'a> {
+    fence: &'a WriteFence,
+    slot: usize,
+}
+
+impl WriteFence {
+    /// Enter the fence for a write, fail with `Errors::EngineClosed` if it is fenced.
+    pub(crate) fn enter(&self) -> Result<FenceGuard<'_>> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if self.fenced.load(Ordering::SeqCst) {
+            return Err(Errors::EngineClosed);
+        }
+        let slot = in_flight.epoch % 2;
+        in_flight.writes[slot] += 1;
+        Ok(FenceGuard { fence: self, slot })
+    }
+
+    /// Reject new writes, and wait for the in-flight ones to complete.
+    pub(crate) fn fence_and_drain(&self) {
+        let in_flight = self.in_flight.lock().unwrap();
+        self.fenced.store(true, Ordering::SeqCst);
+        let _in_flight = self
+            .drained
+            .wait_while(in_flight, |in_flight| in_flight.writes != [0, 0])
+            .unwrap();
+    }
+
+    /// Wait for the writes in flight when called to complete, writes entering later are not
+    /// waited for. Callers are serialized by the merge lock.
+    pub(crate) fn wait_for_earlier_writes(&self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let slot = in_flight.epoch % 2;
+        in_flight.epoch += 1;
+        let _in_flight = self
+            .drained
+            .wait_while(in_flight, |in_flight| in_flight.writes[slot] > 0)
+            .unwrap();
+    }
+}
+
+impl Drop for FenceGuard<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.fence.in_flight.lock().unwrap();
+        in_flight.writes[self.slot] -= 1;
+        if in_flight.writes[self.slot] == 0 {
+            self.fence.drained.notify_all();
+        }
+    }
+}
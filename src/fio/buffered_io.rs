@@ -0,0 +1,145 @@
+//! Buffered appends over `FileIO`. Small appends are coalesced in memory and written to the file
+//! at once when the buffer is full, when the file is synced, which includes the rotation of the
+//! active file, and when it is closed. Buffered bytes are still visible to reads, but they are
+//! lost if the process crashes before they are written.
+
+use std::{path::PathBuf, sync::Mutex};
+
+use crate::errors::Result;
+
+use super::{file_io::FileIO, IOManager};
+
+/// Standard file IO with an append buffer, where:
+/// - `inner` is the underlying file.
+/// - `buffer` stores the bytes appended and not written to the file yet, along with the length
+///   of the file.
+/// - `buffer_size` is the number of bytes buffered before they are written.
+pub struct BufferedIO {
+    inner: FileIO,
+    buffer: Mutex<(Vec<u8>, u64)>,
+    buffer_size: usize,
+}
+
+impl BufferedIO {
+    pub fn new(file_name: PathBuf, buffer_size: usize) -> Result<Self> {
+        let inner = FileIO::new(file_name)?;
+        let len = inner.size();
+        Ok(BufferedIO {
+            inner,
+            buffer: Mutex::new((Vec::with_capacity(buffer_size), len)),
+            buffer_size,
+        })
+    }
+
+    /// Write the bytes of BUFFER to the file, whose length is LEN.
+    fn flush(&self, buffer: &mut Vec<u8>, len: &mut u64) -> Result<()> {
+        let mut written = 0;
+        while written < buffer.len() {
+            written += self.inner.write(&buffer[written..])?;
+        }
+        *len += buffer.len() as u64;
+        buffer.clear();
+        Ok(())
+    }
+}
+
+impl IOManager for BufferedIO {
+    fn read(&self, buf: &mut [u8], ofs: u64) -> Result<usize> {
+        let state = self.buffer.lock().unwrap();
+        if ofs + buf.len() as u64 <= state.1 {
+            // The file only grows, so the bytes are read without holding the buffer.
+            drop(state);
+            return self.inner.read(buf, ofs);
+        }
+        let (buffer, len) = (&state.0, state.1);
+
+        // The bytes past the end of the file are served from the buffer.
+        let file_part = len.saturating_sub(ofs) as usize;
+        let mut size = 0;
+        if file_part > 0 {
+            size = self.inner.read(&mut buf[..file_part], ofs)?;
+            if size < file_part {
+                return Ok(size);
+            }
+        }
+        let buffer_start = ofs.saturating_sub(len) as usize;
+        if buffer_start < buffer.len() {
+            let n = (buf.len() - file_part).min(buffer.len() - buffer_start);
+            buf[file_part..file_part + n].copy_from_slice(&buffer[buffer_start..buffer_start + n]);
+            size += n;
+        }
+        Ok(size)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let (buffer, len) = &mut *buffer;
+        buffer.extend_from_slice(buf);
+        if buffer.len() >= self.buffer_size {
+            self.flush(buffer, len)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let (buffer, len) = &mut *buffer;
+        self.flush(buffer, len)?;
+        self.inner.sync()
+    }
+
+    fn size(&self) -> u64 {
+        let buffer = self.buffer.lock().unwrap();
+        buffer.1 + buffer.0.len() as u64
+    }
+
+    fn preallocate(&self, len: u64) -> Result<()> {
+        self.inner.preallocate(len)
+    }
+}
+
+impl Drop for BufferedIO {
+    fn drop(&mut self) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let (buffer, len) = &mut *buffer;
+        if let Err(e) = self.flush(buffer, len) {
+            log::error!("failed to write buffered appends: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_buffered_io() {
+        let path = PathBuf::from("/tmp/buffered-io-test.data");
+        let _ = fs::remove_file(path.clone());
+
+        let buffered_io = BufferedIO::new(path.clone(), 8).expect("failed to open buffered io");
+        assert_eq!(buffered_io.write(b"hello").unwrap(), 5);
+        assert_eq!(fs::metadata(path.clone()).unwrap().len(), 0);
+        assert_eq!(buffered_io.size(), 5);
+
+        // Reads see the buffered bytes, across the end of the file.
+        assert_eq!(buffered_io.write(b" world").unwrap(), 6);
+        assert_eq!(fs::metadata(path.clone()).unwrap().len(), 11);
+        assert!(buffered_io.write(b"!!").is_ok());
+        let mut buf = [0u8; 6];
+        assert_eq!(buffered_io.read(&mut buf, 7).unwrap(), 6);
+        assert_eq!(&buf, b"orld!!");
+        assert_eq!(buffered_io.read(&mut buf, 10).unwrap(), 3);
+        assert_eq!(&buf[..3], b"d!!");
+
+        assert!(buffered_io.sync().is_ok());
+        assert_eq!(fs::metadata(path.clone()).unwrap().len(), 13);
+        assert!(buffered_io.write(b"?").is_ok());
+        std::mem::drop(buffered_io);
+        assert_eq!(fs::read(path.clone()).unwrap(), b"hello world!!?");
+
+        assert!(fs::remove_file(path.clone()).is_ok());
+    }
+}
@@ -0,0 +1,201 @@
+//! Direct IO, which bypasses the page cache of the OS, so large writes such as bulk loads and
+//! merges do not evict the pages serving foreground reads. Transfers must be aligned to the block
+//! size in offset, length and memory, so reads are widened to the blocks around them, and appends
+//! rewrite the last partial block of the file along with the new bytes, before the file is
+//! truncated back to its length.
+
+use std::{
+    fs::{File, OpenOptions},
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use crate::errors::{Errors, Result};
+
+use super::{preallocate_file, IOManager};
+
+/// Alignment of the transfers, which covers the logical block size of common devices.
+const BLOCK_SIZE: usize = 4096;
+
+/// Direct IO, where:
+/// - `file` is the file opened for direct IO, whose length is kept to the bytes written.
+/// - `tail` stores the bytes of the last partial block along with the length of the file.
+pub struct DirectIO {
+    file: File,
+    tail: Mutex<(Vec<u8>, u64)>,
+}
+
+impl DirectIO {
+    pub fn new(file_name: PathBuf) -> Result<Self> {
+        let mut open_options = OpenOptions::new();
+        open_options.create(true).read(true).write(true);
+        #[cfg(target_os = "linux")]
+        open_options.custom_flags(libc::O_DIRECT);
+        match open_options.open(file_name) {
+            Ok(file) => {
+                let len = file
+                    .metadata()
+                    .map_err(|_| Errors::FailedToOpenDataFile)?
+                    .len();
+                let direct_io = DirectIO {
+                    file,
+                    tail: Mutex::new((Vec::new(), len)),
+                };
+                let tail_start = align_down(len);
+                let mut tail = vec![0u8; (len - tail_start) as usize];
+                direct_io.read(&mut tail, tail_start)?;
+                direct_io.tail.lock().unwrap().0 = tail;
+                Ok(direct_io)
+            }
+            Err(e) => {
+                eprintln!("[DirectIO: new] Failed to open data file, {}", e);
+                Err(Errors::FailedToOpenDataFile)
+            }
+        }
+    }
+}
+
+impl IOManager for DirectIO {
+    fn read(&self, buf: &mut [u8], ofs: u64) -> Result<usize> {
+        let len = self.tail.lock().unwrap().1;
+        let end = len.min(ofs + buf.len() as u64);
+        if ofs >= end {
+            return Ok(0);
+        }
+
+        let block_start = align_down(ofs);
+        let mut aligned = AlignedBuf::new((align_up(end) - block_start) as usize);
+        let want = (end - block_start) as usize;
+        let mut read = 0;
+        while read < want {
+            let n = self
+                .file
+                .read_at(
+                    &mut aligned.as_mut_slice()[read..],
+                    block_start + read as u64,
+                )
+                .map_err(|_| Errors::FailedToReadFromDataFile)?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+
+        let start = (ofs - block_start) as usize;
+        let size = read.min(want).saturating_sub(start);
+        buf[..size].copy_from_slice(&aligned.as_slice()[start..start + size]);
+        Ok(size)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        let mut tail = self.tail.lock().unwrap();
+        let (tail_bytes, len) = &mut *tail;
+        let block_start = *len - tail_bytes.len() as u64;
+        let total = tail_bytes.len() + buf.len();
+
+        let mut aligned = AlignedBuf::new(align_up(total as u64) as usize);
+        aligned.as_mut_slice()[..tail_bytes.len()].copy_from_slice(tail_bytes);
+        aligned.as_mut_slice()[tail_bytes.len()..total].copy_from_slice(buf);
+        self.file
+            .write_all_at(aligned.as_slice(), block_start)
+            .map_err(|_| Errors::FailedToWriteToDataFile)?;
+        // Drop the padding of the last block.
+        self.file
+            .set_len(*len + buf.len() as u64)
+            .map_err(|_| Errors::FailedToWriteToDataFile)?;
+
+        *tail_bytes = aligned.as_slice()[align_down(total as u64) as usize..total].to_vec();
+        *len += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file
+            .sync_all()
+            .map_err(|_| Errors::FailedToSyncToDataFile)
+    }
+
+    fn size(&self) -> u64 {
+        self.tail.lock().unwrap().1
+    }
+
+    fn preallocate(&self, len: u64) -> Result<()> {
+        preallocate_file(&self.file, len)
+    }
+}
+
+/// A zeroed buffer whose memory is aligned to the block size.
+struct AlignedBuf {
+    buf: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let buf = vec![0u8; len + BLOCK_SIZE];
+        let start = buf.as_ptr().align_offset(BLOCK_SIZE);
+        Self { buf, start, len }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buf[self.start..self.start + self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..self.start + self.len]
+    }
+}
+
+fn align_down(ofs: u64) -> u64 {
+    ofs - ofs % BLOCK_SIZE as u64
+}
+
+fn align_up(ofs: u64) -> u64 {
+    align_down(ofs + BLOCK_SIZE as u64 - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::fio::file_io::FileIO;
+
+    use super::*;
+
+    #[test]
+    fn test_direct_io_write_read() {
+        let path = PathBuf::from("/tmp/direct-io-test.data");
+        let _ = fs::remove_file(path.clone());
+
+        let direct_io = DirectIO::new(path.clone()).expect("failed to open direct io");
+        assert_eq!(direct_io.write(b"hello ").unwrap(), 6);
+        // Writes across block boundaries keep the bytes written before.
+        let large = vec![b'a'; BLOCK_SIZE * 2 + 100];
+        assert_eq!(direct_io.write(&large).unwrap(), large.len());
+        assert_eq!(direct_io.write(b"world").unwrap(), 5);
+        assert!(direct_io.sync().is_ok());
+        let len = 11 + large.len() as u64;
+        assert_eq!(direct_io.size(), len);
+
+        let mut buf = [0u8; 10];
+        assert_eq!(direct_io.read(&mut buf, 0).unwrap(), 10);
+        assert_eq!(&buf, b"hello aaaa");
+        assert_eq!(direct_io.read(&mut buf, len - 5).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(direct_io.read(&mut buf, len).unwrap(), 0);
+
+        // Reopening picks up the last partial block.
+        std::mem::drop(direct_io);
+        assert_eq!(fs::metadata(path.clone()).unwrap().len(), len);
+        let direct_io = DirectIO::new(path.clone()).expect("failed to open direct io");
+        assert!(direct_io.write(b"!").is_ok());
+        let fio = FileIO::new(path.clone()).expect("failed to open file io");
+        let mut buf = [0u8; 6];
+        assert_eq!(fio.read(&mut buf, len - 5).unwrap(), 6);
+        assert_eq!(&buf, b"world!");
+
+        assert!(fs::remove_file(path.clone()).is_ok());
+    }
+}
@@ -0,0 +1,266 @@
+//! Fault injection for durability tests. A `FaultInjector` installed as `Options::io_wrapper`
+//! wraps the IO managers of the data files of an engine, and makes their operations fail or
+//! misbehave on demand:
+//! - short writes, where each write only writes part of its buffer;
+//! - delayed syncs, where each sync is slowed down;
+//! - an IO error returned by the Nth operation;
+//! - a crash once a number of bytes are written, where the write crossing it is torn and every
+//!   following write and sync fails, as if the process died there.
+//!
+//! The faults are shared by all the files wrapped by the same injector, and can be changed while
+//! the engine is running.
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::errors::{Errors, Result};
+
+use super::{IOManager, IOWrapper};
+
+/// Injects the configured faults into the IO managers it wraps.
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    state: Arc<Mutex<FaultState>>,
+}
+
+/// The faults injected, where:
+/// - `short_write_len` is the maximum number of bytes written by a single write, if set.
+/// - `sync_delay` is slept by each sync, if set.
+/// - `ops` counts the operations since `fail_nth_op`, whose `fail_op`th one fails.
+/// - `written` counts the bytes written since `crash_at_offset`, up to `crash_ofs`.
+/// - `crashed` is set once the crash offset is reached.
+#[derive(Default)]
+struct FaultState {
+    short_write_len: Option<usize>,
+    sync_delay: Option<Duration>,
+    ops: usize,
+    fail_op: Option<usize>,
+    written: u64,
+    crash_ofs: Option<u64>,
+    crashed: bool,
+}
+
+/// An IO manager whose operations are altered by a `FaultInjector`.
+pub struct FaultIO {
+    inner: Box<dyn IOManager>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write at most MAX_LEN bytes per write from now on.
+    pub fn short_writes(&self, max_len: usize) {
+        self.state.lock().unwrap().short_write_len = Some(max_len.max(1));
+    }
+
+    /// Sleep for DELAY before each sync from now on.
+    pub fn delay_syncs(&self, delay: Duration) {
+        self.state.lock().unwrap().sync_delay = Some(delay);
+    }
+
+    /// Fail the Nth read, write or sync from now on, counting from 1.
+    pub fn fail_nth_op(&self, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.ops = 0;
+        state.fail_op = Some(n);
+    }
+
+    /// Crash once OFS more bytes are written: the write crossing it only writes the bytes before
+    /// it, and every write and sync fails from then on.
+    pub fn crash_at_offset(&self, ofs: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.written = 0;
+        state.crash_ofs = Some(ofs);
+    }
+
+    /// Whether the crash offset has been reached.
+    pub fn crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+
+    /// Stop injecting any fault, and recover from the crash, if any.
+    pub fn clear(&self) {
+        *self.state.lock().unwrap() = FaultState::default();
+    }
+}
+
+impl IOWrapper for FaultInjector {
+    fn wrap(&self, _file_name: &Path, io_manager: Box<dyn IOManager>) -> Box<dyn IOManager> {
+        Box::new(FaultIO {
+            inner: io_manager,
+            state: self.state.clone(),
+        })
+    }
+}
+
+impl FaultState {
+    /// Count an operation, fail with ERR if it is the one to fail.
+    fn count_op(&mut self, err: Errors) -> Result<()> {
+        self.ops += 1;
+        if self.fail_op == Some(self.ops) {
+            self.fail_op = None;
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+impl IOManager for FaultIO {
+    fn read(&self, buf: &mut [u8], ofs: u64) -> Result<usize> {
+        self.state
+            .lock()
+            .unwrap()
+            .count_op(Errors::FailedToReadFromDataFile)?;
+        self.inner.read(buf, ofs)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        // The state is held along the write, so the crash offset is exact across files.
+        let mut state = self.state.lock().unwrap();
+        state.count_op(Errors::FailedToWriteToDataFile)?;
+        if state.crashed {
+            return Err(Errors::FailedToWriteToDataFile);
+        }
+
+        let mut len = buf.len();
+        if let Some(short_write_len) = state.short_write_len {
+            len = len.min(short_write_len);
+        }
+        if let Some(crash_ofs) = state.crash_ofs {
+            let left = crash_ofs.saturating_sub(state.written);
+            if len as u64 >= left {
+                let torn = left as usize;
+                if torn > 0 {
+                    self.inner.write(&buf[..torn])?;
+                }
+                state.written += torn as u64;
+                state.crashed = true;
+                return Err(Errors::FailedToWriteToDataFile);
+            }
+        }
+
+        let size = self.inner.write(&buf[..len])?;
+        state.written += size as u64;
+        Ok(size)
+    }
+
+    fn sync(&self) -> Result<()> {
+        let sync_delay = {
+            let mut state = self.state.lock().unwrap();
+            state.count_op(Errors::FailedToSyncToDataFile)?;
+            if state.crashed {
+                return Err(Errors::FailedToSyncToDataFile);
+            }
+            state.sync_delay
+        };
+        if let Some(sync_delay) = sync_delay {
+            thread::sleep(sync_delay);
+        }
+        self.inner.sync()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn preallocate(&self, len: u64) -> Result<()> {
+        self.inner.preallocate(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::Instant};
+
+    use bytes::Bytes;
+
+    use crate::{
+        db::Engine,
+        fio::file_io::FileIO,
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_fault_io() {
+        let path = PathBuf::from("/tmp/fault-io-test.data");
+        let _ = fs::remove_file(path.clone());
+        let injector = FaultInjector::new();
+        let fault_io = injector.wrap(&path, Box::new(FileIO::new(path.clone()).unwrap()));
+
+        injector.short_writes(3);
+        assert_eq!(fault_io.write(b"hello").unwrap(), 3);
+        injector.clear();
+
+        injector.fail_nth_op(2);
+        assert!(fault_io.write(b"lo").is_ok());
+        assert_eq!(fault_io.sync().err(), Some(Errors::FailedToSyncToDataFile));
+        assert!(fault_io.sync().is_ok());
+
+        injector.delay_syncs(Duration::from_millis(20));
+        let now = Instant::now();
+        assert!(fault_io.sync().is_ok());
+        assert!(now.elapsed() >= Duration::from_millis(20));
+
+        injector.crash_at_offset(3);
+        assert!(fault_io.write(b" wo").is_err());
+        assert!(injector.crashed());
+        assert!(fault_io.write(b"rld").is_err());
+        assert!(fault_io.sync().is_err());
+        let mut buf = [0u8; 8];
+        assert_eq!(fault_io.read(&mut buf, 0).unwrap(), 8);
+        assert_eq!(&buf, b"hello wo");
+
+        assert!(fs::remove_file(path.clone()).is_ok());
+    }
+
+    #[test]
+    fn test_engine_fault_injection() {
+        let injector = FaultInjector::new();
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-fault-injection");
+        // A failed earlier run leaves its data behind, start from an empty directory.
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.io_wrapper = Some(Arc::new(injector.clone()));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        // Short writes are completed by the engine.
+        injector.short_writes(7);
+        for i in 0..10 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        injector.clear();
+
+        // The acknowledged writes survive a crash, the torn one is discarded.
+        injector.crash_at_offset(1000);
+        let mut acknowledged = 10;
+        while engine
+            .put(get_test_key(acknowledged), get_test_value(acknowledged))
+            .is_ok()
+        {
+            acknowledged += 1;
+        }
+        assert!(injector.crashed());
+        std::mem::drop(engine);
+
+        opts.io_wrapper = None;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..acknowledged {
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+        assert!(engine.get(get_test_key(acknowledged)).is_err());
+        assert!(engine.put(get_test_key(0), Bytes::from("value")).is_ok());
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     fs::{File, OpenOptions},
-    io::Write,
+    io::{IoSlice, Write},
     os::unix::fs::FileExt,
     path::PathBuf,
     sync::{Arc, RwLock},
@@ -8,7 +8,7 @@ use std::{
 
 use crate::{
     errors::{Errors, Result},
-    fio::IOManager,
+    fio::{preallocate_file, IOManager},
 };
 
 pub struct FileIO {
@@ -47,6 +47,25 @@ impl IOManager for FileIO {
         file.write(buf).map_err(|_| Errors::FailedToWriteToDataFile)
     }
 
+    fn write_vectored(&self, bufs: &[IoSlice]) -> Result<usize> {
+        let mut file = self.file.write().unwrap();
+        let mut bufs = bufs.to_vec();
+        let mut bufs = &mut bufs[..];
+        let mut written = 0;
+        // A single call may write only part of the buffers, continue from where it stopped.
+        while !bufs.is_empty() {
+            let size = file
+                .write_vectored(bufs)
+                .map_err(|_| Errors::FailedToWriteToDataFile)?;
+            if size == 0 {
+                return Err(Errors::FailedToWriteToDataFile);
+            }
+            IoSlice::advance_slices(&mut bufs, size);
+            written += size;
+        }
+        Ok(written)
+    }
+
     fn sync(&self) -> Result<()> {
         let file = self.file.read().unwrap();
         file.sync_all().map_err(|_| Errors::FailedToSyncToDataFile)
@@ -56,6 +75,10 @@ impl IOManager for FileIO {
         let file = self.file.read().unwrap();
         file.metadata().unwrap().len()
     }
+
+    fn preallocate(&self, len: u64) -> Result<()> {
+        preallocate_file(&self.file.read().unwrap(), len)
+    }
 }
 
 #[cfg(test)]
@@ -82,6 +105,24 @@ mod tests {
         assert!(std::fs::remove_file(path.clone()).is_ok());
     }
 
+    #[test]
+    fn test_file_io_write_vectored() {
+        let path = PathBuf::from("/tmp/a-vectored.data");
+        let fio = FileIO::new(path.clone()).expect("failed to open file io");
+
+        let bufs = [
+            IoSlice::new(b"hello "),
+            IoSlice::new(b""),
+            IoSlice::new(b"world"),
+        ];
+        assert_eq!(fio.write_vectored(&bufs).unwrap(), 11);
+        let mut buf = [0u8; 11];
+        assert_eq!(fio.read(&mut buf, 0).unwrap(), 11);
+        assert_eq!(&buf, b"hello world");
+
+        assert!(std::fs::remove_file(path.clone()).is_ok());
+    }
+
     #[test]
     fn test_file_io_read() {
         let path = PathBuf::from("/tmp/b.data");
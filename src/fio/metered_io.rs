@@ -0,0 +1,243 @@
+//! Metering of the IO of the data files. Every engine wraps the IO managers of its data files
+//! beneath `Options::io_wrapper`, and counts the operations, bytes, errors and time spent in the
+//! file system, which are reported by `Engine::stat`. Comparing the time spent in IO with the
+//! latency of the engine tells whether slowness comes from the disk.
+
+use std::{
+    io::IoSlice,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::errors::Result;
+
+use super::{IOManager, IOWrapper};
+
+/// Counters of one kind of IO operation, where:
+/// - `count` is the number of operations, including the failed ones.
+/// - `bytes` is the number of bytes read or written.
+/// - `errors` is the number of failed operations.
+/// - `latency` is the total time spent in the operations.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IOOpStat {
+    pub count: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    pub latency: Duration,
+}
+
+/// Counters of the IO of the data files of an engine.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IOStat {
+    pub read: IOOpStat,
+    pub write: IOOpStat,
+    pub sync: IOOpStat,
+}
+
+#[derive(Default)]
+struct OpCounters {
+    count: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    nanos: AtomicU64,
+}
+
+/// The IO counters shared by the data files of an engine.
+#[derive(Default)]
+pub(crate) struct IOMetrics {
+    read: OpCounters,
+    write: OpCounters,
+    sync: OpCounters,
+}
+
+/// Wraps the IO managers with `MeteredIO` beneath `inner`, if set.
+pub(crate) struct MeteredWrapper {
+    metrics: Arc<IOMetrics>,
+    inner: Option<Arc<dyn IOWrapper>>,
+}
+
+/// An IO manager whose operations are counted into `IOMetrics`.
+struct MeteredIO {
+    inner: Box<dyn IOManager>,
+    metrics: Arc<IOMetrics>,
+}
+
+impl OpCounters {
+    /// Count an operation started at START, which read or written BYTES, or failed.
+    fn record(&self, start: Instant, bytes: Option<u64>) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        match bytes {
+            Some(bytes) => self.bytes.fetch_add(bytes, Ordering::Relaxed),
+            None => self.errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    fn stat(&self) -> IOOpStat {
+        IOOpStat {
+            count: self.count.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency: Duration::from_nanos(self.nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl IOMetrics {
+    pub(crate) fn stat(&self) -> IOStat {
+        IOStat {
+            read: self.read.stat(),
+            write: self.write.stat(),
+            sync: self.sync.stat(),
+        }
+    }
+}
+
+impl MeteredWrapper {
+    pub(crate) fn new(metrics: Arc<IOMetrics>, inner: Option<Arc<dyn IOWrapper>>) -> Self {
+        Self { metrics, inner }
+    }
+}
+
+impl IOWrapper for MeteredWrapper {
+    fn wrap(&self, file_name: &Path, io_manager: Box<dyn IOManager>) -> Box<dyn IOManager> {
+        let metered = Box::new(MeteredIO {
+            inner: io_manager,
+            metrics: self.metrics.clone(),
+        });
+        match &self.inner {
+            Some(inner) => inner.wrap(file_name, metered),
+            None => metered,
+        }
+    }
+}
+
+impl IOManager for MeteredIO {
+    fn read(&self, buf: &mut [u8], ofs: u64) -> Result<usize> {
+        let start = Instant::now();
+        let res = self.inner.read(buf, ofs);
+        let size = res.as_ref().ok().map(|size| *size as u64);
+        self.metrics.read.record(start, size);
+        res
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        let start = Instant::now();
+        let res = self.inner.write(buf);
+        let size = res.as_ref().ok().map(|size| *size as u64);
+        self.metrics.write.record(start, size);
+        res
+    }
+
+    fn write_vectored(&self, bufs: &[IoSlice]) -> Result<usize> {
+        let start = Instant::now();
+        let res = self.inner.write_vectored(bufs);
+        let size = res.as_ref().ok().map(|size| *size as u64);
+        self.metrics.write.record(start, size);
+        res
+    }
+
+    fn sync(&self) -> Result<()> {
+        let start = Instant::now();
+        let res = self.inner.sync();
+        self.metrics
+            .sync
+            .record(start, res.as_ref().ok().map(|_| 0));
+        res
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn preallocate(&self, len: u64) -> Result<()> {
+        self.inner.preallocate(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::{
+        db::Engine,
+        errors::Errors,
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    /// An IO manager failing every operation.
+    struct FailingIO;
+
+    impl IOManager for FailingIO {
+        fn read(&self, _buf: &mut [u8], _ofs: u64) -> Result<usize> {
+            Err(Errors::FailedToReadFromDataFile)
+        }
+
+        fn write(&self, _buf: &[u8]) -> Result<usize> {
+            Err(Errors::FailedToWriteToDataFile)
+        }
+
+        fn sync(&self) -> Result<()> {
+            Err(Errors::FailedToSyncToDataFile)
+        }
+
+        fn size(&self) -> u64 {
+            0
+        }
+    }
+
+    #[test]
+    fn test_metered_io_errors() {
+        let metrics = Arc::new(IOMetrics::default());
+        let wrapper = MeteredWrapper::new(metrics.clone(), None);
+        let io = wrapper.wrap(Path::new("failing"), Box::new(FailingIO));
+        assert!(io.write(b"hello").is_err());
+        assert!(io.read(&mut [0u8; 5], 0).is_err());
+        assert!(io.sync().is_err());
+
+        let stat = metrics.stat();
+        for op in [stat.read, stat.write, stat.sync] {
+            assert_eq!(op.count, 1);
+            assert_eq!(op.errors, 1);
+            assert_eq!(op.bytes, 0);
+        }
+    }
+
+    #[test]
+    fn test_engine_io_stat() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-io-stat");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..100 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(engine.sync().is_ok());
+        for i in 0..100 {
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+
+        let io = engine.stat().unwrap().io;
+        assert_eq!(io.write.count, 100);
+        assert_eq!(io.write.errors, 0);
+        assert_eq!(
+            io.write.bytes,
+            engine.active_file.read().unwrap().get_write_ofs()
+        );
+        assert!(io.read.count >= 100);
+        assert!(io.read.bytes >= io.write.bytes);
+        assert!(io.sync.count >= 1);
+        assert!(io.write.latency > Duration::ZERO);
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -1,17 +1,25 @@
 use std::{
-    fs::OpenOptions,
+    fs::{File, OpenOptions},
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::RwLock,
 };
 
-use memmap2::Mmap;
+use memmap2::{MmapMut, MmapOptions};
 
 use crate::errors::{Errors, Result};
 
 use super::IOManager;
 
+/// Minimum size of the mapping, which grows by doubling as the file is appended to.
+const MIN_MAP_LEN: usize = 64 * 1024;
+
+/// Memory mapped IO, where:
+/// - `file` is the mapped file, whose length is kept to the bytes written.
+/// - `map` stores the mapping along with the length of the file. The mapping may extend past
+///   the end of the file, and is remapped once a write goes past its end.
 pub struct MMapIO {
-    map: Arc<Mutex<Mmap>>,
+    file: File,
+    map: RwLock<(MmapMut, usize)>,
 }
 
 impl MMapIO {
@@ -22,11 +30,17 @@ impl MMapIO {
             .write(true)
             .open(file_name)
         {
-            Ok(file) => Ok(MMapIO {
-                map: Arc::new(Mutex::new(unsafe {
-                    Mmap::map(&file).expect("failed to map the file")
-                })),
-            }),
+            Ok(file) => {
+                let len = file
+                    .metadata()
+                    .map_err(|_| Errors::FailedToOpenDataFile)?
+                    .len() as usize;
+                let map = map_file(&file, len.max(MIN_MAP_LEN))?;
+                Ok(MMapIO {
+                    file,
+                    map: RwLock::new((map, len)),
+                })
+            }
             Err(e) => {
                 eprintln!("[FileIO: new] Failed to open data file, {}", e);
                 Err(Errors::FailedToOpenDataFile)
@@ -35,11 +49,18 @@ impl MMapIO {
     }
 }
 
+/// Map the first MAP_LEN bytes of FILE, which may extend past its end.
+fn map_file(file: &File, map_len: usize) -> Result<MmapMut> {
+    unsafe { MmapOptions::new().len(map_len).map_mut(file) }
+        .map_err(|_| Errors::FailedToOpenDataFile)
+}
+
 impl IOManager for MMapIO {
     fn read(&self, buf: &mut [u8], ofs: u64) -> Result<usize> {
-        let map = self.map.lock().unwrap();
+        let map = self.map.read().unwrap();
+        let (map, len) = (&map.0, map.1);
         let end = ofs + buf.len() as u64;
-        if end > map.len() as u64 {
+        if end > len as u64 {
             return Err(Errors::ReadDataFileEOF);
         }
         let val = &map[ofs as usize..end as usize];
@@ -49,15 +70,34 @@ impl IOManager for MMapIO {
     }
 
     fn write(&self, buf: &[u8]) -> Result<usize> {
-        unimplemented!()
+        let mut map = self.map.write().unwrap();
+        let (ofs, end) = (map.1, map.1 + buf.len());
+        if end > map.0.len() {
+            let map_len = end.max(map.0.len() * 2);
+            map.0 = map_file(&self.file, map_len)?;
+        }
+        // The file is extended first, as the pages of the mapping past its end are not backed.
+        self.file
+            .set_len(end as u64)
+            .map_err(|_| Errors::FailedToWriteToDataFile)?;
+        map.0[ofs..end].copy_from_slice(buf);
+        map.1 = end;
+
+        Ok(buf.len())
     }
 
     fn sync(&self) -> Result<()> {
-        unimplemented!()
+        let map = self.map.read().unwrap();
+        map.0
+            .flush_range(0, map.1)
+            .map_err(|_| Errors::FailedToSyncToDataFile)?;
+        self.file
+            .sync_all()
+            .map_err(|_| Errors::FailedToSyncToDataFile)
     }
 
     fn size(&self) -> u64 {
-        self.map.lock().unwrap().len() as u64
+        self.map.read().unwrap().1 as u64
     }
 }
 
@@ -98,4 +138,41 @@ mod tests {
         let remove_res = fs::remove_file(path.clone());
         assert!(remove_res.is_ok());
     }
+
+    #[test]
+    fn test_mmap_write() {
+        let path = PathBuf::from("/tmp/mmap-write-test.data");
+        let _ = fs::remove_file(path.clone());
+
+        let mmap_io = MMapIO::new(path.clone()).expect("failed to open mmap io");
+        assert!(mmap_io.write(b"hello ").is_ok());
+        // Writes past the end of the mapping remap the file.
+        let large = vec![b'a'; MIN_MAP_LEN * 3];
+        assert_eq!(mmap_io.write(&large).unwrap(), large.len());
+        assert!(mmap_io.write(b"world").is_ok());
+        assert!(mmap_io.sync().is_ok());
+        assert_eq!(mmap_io.size(), (11 + large.len()) as u64);
+
+        let mut buf = [0u8; 5];
+        assert!(mmap_io.read(&mut buf, 6 + large.len() as u64).is_ok());
+        assert_eq!(&buf, b"world");
+        assert_eq!(
+            mmap_io.read(&mut buf, 7 + large.len() as u64).err(),
+            Some(Errors::ReadDataFileEOF)
+        );
+
+        // The file holds exactly the bytes written.
+        std::mem::drop(mmap_io);
+        assert_eq!(
+            fs::metadata(path.clone()).unwrap().len(),
+            (11 + large.len()) as u64
+        );
+        let fio = FileIO::new(path.clone()).expect("failed to open file io");
+        let mut buf = [0u8; 6];
+        assert!(fio.read(&mut buf, 0).is_ok());
+        assert_eq!(&buf, b"hello ");
+
+        let remove_res = fs::remove_file(path.clone());
+        assert!(remove_res.is_ok());
+    }
 }
@@ -1,11 +1,22 @@
+pub mod buffered_io;
+pub mod direct_io;
+#[cfg(feature = "fault-injection")]
+pub mod fault_io;
 pub mod file_io;
+pub mod metered_io;
 pub mod mmap;
+pub mod rate_limited_io;
 
-use std::path::PathBuf;
+use std::{
+    cell::Cell,
+    fs::File,
+    io::IoSlice,
+    path::{Path, PathBuf},
+};
 
-use crate::errors::Result;
+use crate::errors::{Errors, Result};
 
-use self::{file_io::FileIO, mmap::MMapIO};
+use self::{buffered_io::BufferedIO, direct_io::DirectIO, file_io::FileIO, mmap::MMapIO};
 
 use super::options::IOType;
 
@@ -17,17 +28,118 @@ pub trait IOManager: Sync + Send {
     /// Write to file SELF with content in BUF.
     fn write(&self, buf: &[u8]) -> Result<usize>;
 
+    /// Write to file SELF with the contents of BUFS in order, return the number of bytes
+    /// written. Fall back to writing the buffers one by one by default.
+    fn write_vectored(&self, bufs: &[IoSlice]) -> Result<usize> {
+        let mut written = 0;
+        for buf in bufs {
+            let mut buf_written = 0;
+            while buf_written < buf.len() {
+                buf_written += self.write(&buf[buf_written..])?;
+            }
+            written += buf_written;
+        }
+        Ok(written)
+    }
+
     /// Synchronize data.
     fn sync(&self) -> Result<()>;
 
     /// Get the size of current data file.
     fn size(&self) -> u64;
+
+    /// Reserve the disk space of the first LEN bytes of file SELF, without changing its size.
+    /// Nothing is reserved by default.
+    fn preallocate(&self, _len: u64) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Wraps the IO managers of the data files of an engine, so that their operations can be
+/// observed or altered.
+pub trait IOWrapper: Sync + Send {
+    /// Wrap IO_MANAGER, which is just opened for the file FILE_NAME.
+    fn wrap(&self, file_name: &Path, io_manager: Box<dyn IOManager>) -> Box<dyn IOManager>;
+}
+
+/// What the IO of the current thread is issued for, so IO wrappers can treat background work
+/// differently from the foreground reads and writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IOPurpose {
+    Foreground,
+    Merge,
+}
+
+thread_local! {
+    static IO_PURPOSE: Cell<IOPurpose> = const { Cell::new(IOPurpose::Foreground) };
+}
+
+/// Get the purpose of the IO issued by the current thread.
+pub fn current_io_purpose() -> IOPurpose {
+    IO_PURPOSE.with(|purpose| purpose.get())
+}
+
+/// Sets the purpose of the IO issued by the current thread, which is restored when dropped.
+pub(crate) struct IOPurposeGuard {
+    prev: IOPurpose,
+}
+
+impl IOPurposeGuard {
+    pub(crate) fn new(purpose: IOPurpose) -> Self {
+        Self {
+            prev: IO_PURPOSE.with(|current| current.replace(purpose)),
+        }
+    }
+}
+
+impl Drop for IOPurposeGuard {
+    fn drop(&mut self) {
+        IO_PURPOSE.with(|current| current.set(self.prev));
+    }
+}
+
+/// Reserve the disk space of the first LEN bytes of FILE without changing its size, so later
+/// appends neither fragment the file nor run out of space. Nothing is reserved where the platform
+/// or the file system does not support it.
+pub(crate) fn preallocate_file(file: &File, len: u64) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                0,
+                len as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(Errors::FailedToWriteToDataFile);
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, len);
+    Ok(())
+}
+
+/// Synchronize the entries of directory DIR_PATH, so that files created, renamed or removed in
+/// it survive a crash.
+pub fn sync_dir(dir_path: &Path) -> Result<()> {
+    File::open(dir_path)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|_| Errors::FailedToSyncToDataFile)
 }
 
 /// Initialize IOMANAGER according to the file type.
-pub fn new_io_manager(file_name: PathBuf, io_type: IOType) -> Box<dyn IOManager> {
+pub fn new_io_manager(file_name: PathBuf, io_type: IOType) -> Result<Box<dyn IOManager>> {
     match io_type {
-        IOType::StandardFIO => Box::new(FileIO::new(file_name).unwrap()),
-        IOType::MemoryMapped => Box::new(MMapIO::new(file_name).unwrap()),
+        IOType::StandardFIO => Ok(Box::new(FileIO::new(file_name)?)),
+        IOType::MemoryMapped => Ok(Box::new(MMapIO::new(file_name)?)),
+        IOType::Direct => Ok(Box::new(DirectIO::new(file_name)?)),
+        IOType::BufferedFIO(buffer_size) => Ok(Box::new(BufferedIO::new(file_name, buffer_size)?)),
     }
 }
@@ -0,0 +1,168 @@
+//! Throttling of the bytes read and written by the data files of an engine. An `IORateLimiter`
+//! installed as `Options::io_wrapper` makes every read and write wait for its bytes in a token
+//! bucket, either one shared by all the IO of the engine, or one per `IOPurpose`, so background
+//! merges cannot take the disk bandwidth of the foreground reads and writes.
+
+use std::{io::IoSlice, path::Path, sync::Arc};
+
+use crate::{errors::Result, rate_limit::TokenBucket};
+
+use super::{current_io_purpose, IOManager, IOPurpose, IOWrapper};
+
+/// Throttles the IO managers it wraps, where:
+/// - `foreground` limits the IO issued for foreground reads and writes.
+/// - `merge` limits the IO issued by merges. It may be the same bucket as `foreground`.
+#[derive(Clone)]
+pub struct IORateLimiter {
+    foreground: Arc<TokenBucket>,
+    merge: Arc<TokenBucket>,
+}
+
+/// An IO manager whose reads and writes are throttled by an `IORateLimiter`.
+pub struct RateLimitedIO {
+    inner: Box<dyn IOManager>,
+    limiter: IORateLimiter,
+}
+
+impl IORateLimiter {
+    /// Limit all the IO to BYTES_PER_SEC, unlimited if set to 0.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bucket = Arc::new(TokenBucket::new(bytes_per_sec));
+        Self {
+            foreground: bucket.clone(),
+            merge: bucket,
+        }
+    }
+
+    /// Limit the foreground IO to FOREGROUND_BYTES_PER_SEC, and the IO of merges to
+    /// MERGE_BYTES_PER_SEC independently, either unlimited if set to 0.
+    pub fn per_purpose(foreground_bytes_per_sec: u64, merge_bytes_per_sec: u64) -> Self {
+        Self {
+            foreground: Arc::new(TokenBucket::new(foreground_bytes_per_sec)),
+            merge: Arc::new(TokenBucket::new(merge_bytes_per_sec)),
+        }
+    }
+
+    /// Wait until BYTES can be read or written by the current thread.
+    fn acquire(&self, bytes: u64) {
+        match current_io_purpose() {
+            IOPurpose::Foreground => self.foreground.acquire(bytes),
+            IOPurpose::Merge => self.merge.acquire(bytes),
+        }
+    }
+}
+
+impl IOWrapper for IORateLimiter {
+    fn wrap(&self, _file_name: &Path, io_manager: Box<dyn IOManager>) -> Box<dyn IOManager> {
+        Box::new(RateLimitedIO {
+            inner: io_manager,
+            limiter: self.clone(),
+        })
+    }
+}
+
+impl IOManager for RateLimitedIO {
+    fn read(&self, buf: &mut [u8], ofs: u64) -> Result<usize> {
+        self.limiter.acquire(buf.len() as u64);
+        self.inner.read(buf, ofs)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        self.limiter.acquire(buf.len() as u64);
+        self.inner.write(buf)
+    }
+
+    fn write_vectored(&self, bufs: &[IoSlice]) -> Result<usize> {
+        self.limiter
+            .acquire(bufs.iter().map(|buf| buf.len() as u64).sum());
+        self.inner.write_vectored(bufs)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn preallocate(&self, len: u64) -> Result<()> {
+        self.inner.preallocate(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        db::Engine,
+        fio::{file_io::FileIO, IOPurposeGuard},
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limited_io() {
+        let path = PathBuf::from("/tmp/rate-limited-io-test.data");
+        let _ = fs::remove_file(path.clone());
+        let limiter = IORateLimiter::per_purpose(0, 100_000);
+        let io = limiter.wrap(&path, Box::new(FileIO::new(path.clone()).unwrap()));
+        let buf = vec![0u8; 10_000];
+
+        // Foreground IO is unlimited, while merges are throttled.
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(io.write(&buf).unwrap(), buf.len());
+        }
+        assert!(now.elapsed() < Duration::from_millis(200));
+
+        let now = Instant::now();
+        {
+            let _io_purpose = IOPurposeGuard::new(IOPurpose::Merge);
+            for _ in 0..6 {
+                assert_eq!(io.write(&buf).unwrap(), buf.len());
+            }
+        }
+        assert!(now.elapsed() >= Duration::from_millis(400));
+        assert_eq!(current_io_purpose(), IOPurpose::Foreground);
+
+        assert!(fs::remove_file(path.clone()).is_ok());
+    }
+
+    #[test]
+    fn test_engine_rate_limited_io() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-rate-limited-io");
+        opts.data_file_size = 32 * 1024;
+        opts.data_file_merge_ratio = 0.0;
+        opts.io_wrapper = Some(Arc::new(IORateLimiter::per_purpose(0, 200_000)));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let now = Instant::now();
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(now.elapsed() < Duration::from_secs(1));
+
+        // The merge reads and rewrites about 100KB.
+        let now = Instant::now();
+        assert!(engine.merge().is_ok());
+        assert!(now.elapsed() >= Duration::from_millis(300));
+
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,176 @@
+//! The hint file lists the positions of the live records of the data files before the
+//! non-merged file id recorded by the merge-fin file, so startup only scans the files after it.
+//!
+//! The hint file is only trusted if it is newer than every data file it covers, and all its
+//! positions refer to these files. Otherwise, it is ignored and all data files are scanned.
+//! `Engine::rebuild_hint_file` regenerates it from the current index without a merge.
+
+use std::{fs, path::Path, time::SystemTime};
+
+use log::warn;
+
+use crate::{
+    data::{
+        data_file::{get_data_file_name, DataFile, HINT_FILE_NAME, MERGE_FIN_FILE_NAME},
+        log_record::{decode_log_record_pos, LogRecord, LogRecordPos, LogRecordType},
+    },
+    db::Engine,
+    errors::{Errors, Result},
+    fio::sync_dir,
+    options::{IndexType, IteratorOptions},
+};
+
+const HINT_TMP_FILE_NAME: &str = "hint-index.tmp";
+const MERGE_FIN_TMP_FILE_NAME: &str = "merge-finished.tmp";
+
+/// Positions listed by a hint file, by key.
+pub(crate) type HintEntries = Vec<(Vec<u8>, LogRecordPos)>;
+
+impl Engine {
+    /// Regenerate the hint file from the current index, covering all sealed data files, so the
+    /// next startup only scans the active file.
+    pub fn rebuild_hint_file(&self) -> Result<()> {
+        let _merge_lock = self
+            .merge_lock
+            .try_lock()
+            .map_err(|_| Errors::MergeInProgress)?;
+        self.ensure_open()?;
+        if self.options.index_type == IndexType::BPTree {
+            return Err(Errors::UnsupportedIndexType);
+        }
+
+        // Records of the sealed files never move while merge is held off, and sealed files are
+        // never written to.
+        let non_merge_fid = self.active_file.read().unwrap().get_file_id();
+        let dir_path = &self.options.dir_path;
+
+        let _ = fs::remove_file(dir_path.join(HINT_TMP_FILE_NAME));
+        let hint_file = DataFile::new_tmp_file(dir_path, HINT_TMP_FILE_NAME)?;
+        let mut index_iter = self.index.iterator(IteratorOptions::default());
+        while let Some((key, pos)) = index_iter.next() {
+            if pos.file_id < non_merge_fid {
+                hint_file.write_hint_record(key.clone(), *pos)?;
+            }
+        }
+        hint_file.sync()?;
+
+        let _ = fs::remove_file(dir_path.join(MERGE_FIN_TMP_FILE_NAME));
+        let merge_fin_file = DataFile::new_tmp_file(dir_path, MERGE_FIN_TMP_FILE_NAME)?;
+        let merge_fin_record = LogRecord {
+            key: MERGE_FIN_FILE_NAME.as_bytes().to_vec(),
+            value: non_merge_fid.to_string().into_bytes(),
+            record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
+        };
+        merge_fin_file.write(&merge_fin_record.encode())?;
+        merge_fin_file.sync()?;
+
+        // The hint file is replaced first, so a crash in between leaves a hint file covering more
+        // files than the merge-fin file records, which are then scanned again.
+        rename(dir_path, HINT_TMP_FILE_NAME, HINT_FILE_NAME)?;
+        rename(dir_path, MERGE_FIN_TMP_FILE_NAME, MERGE_FIN_FILE_NAME)?;
+        sync_dir(dir_path)
+    }
+
+    /// Read the hint file covering the data files before NON_MERGE_FID, return its positions by
+    /// key, or None if it is missing or stale.
+    pub(crate) fn read_hint_file(&self, non_merge_fid: u64) -> Result<Option<HintEntries>> {
+        let dir_path = &self.options.dir_path;
+        let hint_mtime = match modified_time(&dir_path.join(HINT_FILE_NAME)) {
+            Some(mtime) => mtime,
+            None => {
+                warn!("hint file is missing, scanning all data files");
+                return Ok(None);
+            }
+        };
+        for file_id in self.file_ids.iter().filter(|id| **id < non_merge_fid) {
+            let data_file_mtime = modified_time(&get_data_file_name(dir_path, *file_id));
+            if data_file_mtime.is_some_and(|mtime| mtime > hint_mtime) {
+                warn!(
+                    "hint file is older than data file {}, scanning all data files",
+                    file_id
+                );
+                return Ok(None);
+            }
+        }
+
+        let hint_file = DataFile::new_hint_file(dir_path)?;
+        let mut entries = Vec::new();
+        let mut ofs = 0;
+        loop {
+            let (log_record, size) = match hint_file.read_log_record(ofs) {
+                Ok(result) => result,
+                Err(Errors::ReadDataFileEOF) => break,
+                Err(e) => return Err(e),
+            };
+            let pos = decode_log_record_pos(log_record.value);
+            if pos.file_id >= non_merge_fid || !self.file_ids.contains(&pos.file_id) {
+                warn!(
+                    "hint file refers to data file {} it does not cover, scanning all data files",
+                    pos.file_id
+                );
+                return Ok(None);
+            }
+            entries.push((log_record.key, pos));
+            ofs += size as u64;
+        }
+        Ok(Some(entries))
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn rename(dir_path: &Path, from: &str, to: &str) -> Result<()> {
+    fs::rename(dir_path.join(from), dir_path.join(to)).map_err(|_| Errors::FailedToWriteToDataFile)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_rebuild_hint_file() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-rebuild-hint-file");
+        opts.data_file_size = 32 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..100 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        assert!(engine.rebuild_hint_file().is_ok());
+        let non_merge_fid = engine.active_file.read().unwrap().get_file_id();
+        assert!(non_merge_fid > 1);
+        assert!(engine.put(get_test_key(1000), get_test_value(1000)).is_ok());
+        std::mem::drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 901);
+        assert_eq!(engine.get(get_test_key(0)).err(), Some(Errors::KeyNotFound));
+        assert_eq!(engine.get(get_test_key(500)).unwrap(), get_test_value(500));
+        let entries = engine.read_hint_file(non_merge_fid).unwrap().unwrap();
+        assert!(!entries.is_empty());
+        std::mem::drop(engine);
+
+        // A missing hint file falls back to scanning all data files.
+        fs::remove_file(opts.dir_path.join(HINT_FILE_NAME)).unwrap();
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 901);
+        assert!(engine.read_hint_file(non_merge_fid).unwrap().is_none());
+        std::mem::drop(engine);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,191 @@
+//! Index keys are copied into large append-only chunks instead of being allocated one by one,
+//! which reduces the allocator overhead and fragmentation when the keydir holds tens of millions
+//! of entries. Chunks are never reallocated nor freed before the arena is dropped, so an
+//! `ArenaKey` stays valid as long as the arena that allocated it.
+//!
+//! Space of deleted keys is not reused, it is reported as `dead_bytes` in `ArenaStats`. Indexers
+//! copy their live keys into a new arena in `Indexer::compact` once the dead keys take more space
+//! than the live ones, which frees the old arena.
+
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{self, AtomicUsize},
+        Mutex,
+    },
+};
+
+/// Size of each chunk. Keys larger than this are stored in their own chunk.
+const ARENA_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Statistics of a key arena.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ArenaStats {
+    /// Number of chunks allocated.
+    pub chunk_num: usize,
+
+    /// Total capacity of all chunks.
+    pub reserved_bytes: usize,
+
+    /// Bytes occupied by keys, including deleted ones.
+    pub allocated_bytes: usize,
+
+    /// Bytes occupied by deleted keys, which cannot be reused.
+    pub dead_bytes: usize,
+}
+
+pub struct KeyArena {
+    chunks: Mutex<Vec<Vec<u8>>>,
+    reserved_bytes: AtomicUsize,
+    allocated_bytes: AtomicUsize,
+    dead_bytes: AtomicUsize,
+}
+
+/// A key stored in a `KeyArena`. It must not be used after the arena is dropped.
+#[derive(Clone, Copy)]
+pub struct ArenaKey {
+    ptr: *const u8,
+    len: usize,
+}
+
+// SAFETY: an `ArenaKey` is an immutable view of bytes which are never modified nor moved.
+unsafe impl Send for ArenaKey {}
+unsafe impl Sync for ArenaKey {}
+
+impl KeyArena {
+    pub fn new() -> Self {
+        Self {
+            chunks: Mutex::new(Vec::new()),
+            reserved_bytes: AtomicUsize::new(0),
+            allocated_bytes: AtomicUsize::new(0),
+            dead_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Copy KEY into the arena.
+    pub fn alloc(&self, key: &[u8]) -> ArenaKey {
+        let mut chunks = self.chunks.lock().unwrap();
+        let has_room = match chunks.last() {
+            Some(chunk) => chunk.capacity() - chunk.len() >= key.len(),
+            None => false,
+        };
+        if !has_room {
+            let capacity = ARENA_CHUNK_SIZE.max(key.len());
+            chunks.push(Vec::with_capacity(capacity));
+            self.reserved_bytes
+                .fetch_add(capacity, atomic::Ordering::Relaxed);
+        }
+
+        // The chunk has enough capacity, so extending it never moves the existing keys.
+        let chunk = chunks.last_mut().unwrap();
+        let start = chunk.len();
+        chunk.extend_from_slice(key);
+        self.allocated_bytes
+            .fetch_add(key.len(), atomic::Ordering::Relaxed);
+        ArenaKey {
+            ptr: chunk[start..].as_ptr(),
+            len: key.len(),
+        }
+    }
+
+    /// Mark KEY as deleted.
+    pub fn release(&self, key: &ArenaKey) {
+        self.dead_bytes
+            .fetch_add(key.len, atomic::Ordering::Relaxed);
+    }
+
+    /// Whether deleted keys take more space than live ones, so the live keys are worth copying
+    /// into a new arena.
+    pub fn should_compact(&self) -> bool {
+        let allocated_bytes = self.allocated_bytes.load(atomic::Ordering::Relaxed);
+        let dead_bytes = self.dead_bytes.load(atomic::Ordering::Relaxed);
+        dead_bytes > allocated_bytes - dead_bytes
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        ArenaStats {
+            chunk_num: self.chunks.lock().unwrap().len(),
+            reserved_bytes: self.reserved_bytes.load(atomic::Ordering::Relaxed),
+            allocated_bytes: self.allocated_bytes.load(atomic::Ordering::Relaxed),
+            dead_bytes: self.dead_bytes.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for KeyArena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ArenaKey {
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: the bytes are owned by a chunk that lives as long as the arena.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Borrow<[u8]> for ArenaKey {
+    fn borrow(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl PartialEq for ArenaKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for ArenaKey {}
+
+// Hashed as its bytes, so that a map keyed by `ArenaKey` can be queried by `[u8]`.
+impl Hash for ArenaKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state)
+    }
+}
+
+impl PartialOrd for ArenaKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ArenaKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_slice().cmp(other.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_arena_alloc() {
+        let arena = KeyArena::new();
+        let key1 = arena.alloc(b"aa");
+        let key2 = arena.alloc(b"bbb");
+        let key3 = arena.alloc(&vec![7u8; ARENA_CHUNK_SIZE + 1]);
+        let key4 = arena.alloc(b"c");
+        assert_eq!(key1.as_slice(), b"aa");
+        assert_eq!(key2.as_slice(), b"bbb");
+        assert_eq!(key3.as_slice().len(), ARENA_CHUNK_SIZE + 1);
+        assert_eq!(key4.as_slice(), b"c");
+        assert!(key1 < key2);
+
+        arena.release(&key2);
+        let stats = arena.stats();
+        assert_eq!(stats.chunk_num, 3);
+        assert_eq!(stats.allocated_bytes, ARENA_CHUNK_SIZE + 7);
+        assert_eq!(stats.dead_bytes, 3);
+        assert_eq!(stats.reserved_bytes, ARENA_CHUNK_SIZE * 3 + 1);
+
+        assert!(!arena.should_compact());
+        arena.release(&key3);
+        assert!(arena.should_compact());
+    }
+}
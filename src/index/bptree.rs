@@ -1,22 +1,59 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::VecDeque,
+    fs,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
 
 use bytes::Bytes;
 use jammdb::DB;
 
 use crate::{
     data::log_record::{decode_log_record_pos, LogRecordPos},
-    errors::Result,
+    errors::{Errors, Result},
     index::Indexer,
     options::IteratorOptions,
+    utils::bloom::BloomFilter,
 };
 
-use super::IndexIterator;
+use super::{
+    bounded_range, preceding_range, remaining_range, IndexIterator, KeyRange,
+    INDEX_ITERATOR_BATCH_SIZE,
+};
 
 const BPTREE_INDEX_FILE_NAME: &str = "bptree-index";
 const BPTREE_BUCKET_NAME: &str = "bitcask-index";
+const BPTREE_BLOOM_FILE_NAME: &str = "bptree-bloom";
+const BPTREE_BLOOM_TMP_FILE_NAME: &str = "bptree-bloom.tmp";
+
+/// The bloom filter of the keys is sized for at least this many keys.
+const MIN_BLOOM_KEY_NUM: usize = 64 * 1024;
 
+/// BPlusTree indexer stored in a jammdb database, where:
+/// - `dir_path` is the directory holding the database, and the bloom filter of its keys.
+/// - `filter` holds the keys of the tree, consulted first so lookups of missing keys rarely
+///   read the tree.
 pub struct BPTree {
     tree: Arc<DB>,
+    dir_path: PathBuf,
+    filter: RwLock<KeyFilter>,
+}
+
+/// Bloom filter of the keys inserted into the tree, never missing a committed key. Keys inserted
+/// while the filter is rebuilt are recorded into `pending`, to be inserted into the new one.
+struct KeyFilter {
+    bloom: BloomFilter,
+    pending: Option<Vec<Vec<u8>>>,
+}
+
+impl KeyFilter {
+    fn insert(&mut self, key: &[u8]) {
+        self.bloom.insert(key);
+        if let Some(pending) = &mut self.pending {
+            pending.push(key.to_vec());
+        }
+    }
 }
 
 impl BPTree {
@@ -28,7 +65,64 @@ impl BPTree {
         tx.get_or_create_bucket(BPTREE_BUCKET_NAME).unwrap();
         tx.commit().unwrap();
 
-        Self { tree: tree.clone() }
+        let bloom = take_bloom_file(&dir_path);
+        let is_restored = bloom.is_some();
+        let bptree = Self {
+            tree: tree.clone(),
+            dir_path,
+            filter: RwLock::new(KeyFilter {
+                bloom: bloom.unwrap_or_else(|| BloomFilter::new(0, 1)),
+                pending: None,
+            }),
+        };
+        if !is_restored {
+            bptree.rebuild_filter();
+        }
+        bptree
+    }
+
+    /// Rebuild the bloom filter from the keys of the tree, sized for twice as many keys, which
+    /// also drops the keys deleted since the filter was built.
+    fn rebuild_filter(&self) {
+        // Keys committed after the transaction begins are recorded as pending, since they are
+        // inserted into the filter after their commit.
+        let tx = {
+            let mut filter = self.filter.write().unwrap();
+            filter.pending = Some(Vec::new());
+            self.tree.tx(false).expect("failed to begin tx")
+        };
+        let mut bloom = {
+            let bucket = tx.get_bucket(BPTREE_BUCKET_NAME).unwrap();
+            let key_num = bucket.cursor().count();
+            let mut bloom = BloomFilter::with_capacity((key_num * 2).max(MIN_BLOOM_KEY_NUM));
+            for data in bucket.cursor() {
+                bloom.insert(data.key());
+            }
+            bloom
+        };
+        // Writers growing the database wait for the read transactions, so this one ends before
+        // the filter is locked.
+        drop(tx);
+
+        let mut filter = self.filter.write().unwrap();
+        for key in filter.pending.take().unwrap_or_default() {
+            bloom.insert(&key);
+        }
+        filter.bloom = bloom;
+    }
+
+    /// Get an iterator with OPTIONS through the keys in RANGE.
+    fn new_iterator(&self, range: KeyRange, options: IteratorOptions) -> Box<dyn IndexIterator> {
+        Box::new(BPTreeIterator {
+            tree: self.tree.clone(),
+            range,
+            bound: Bound::Unbounded,
+            items: VecDeque::new(),
+            current: None,
+            exhausted: false,
+            checkpoints: None,
+            options,
+        })
     }
 }
 
@@ -45,14 +139,18 @@ impl Indexer for BPTree {
 
         // Put the new value
         bucket
-            .put(key, pos.encode())
+            .put(key.clone(), pos.encode())
             .expect("failed to put value in bptree");
         tx.commit().unwrap();
+        self.filter.write().unwrap().insert(&key);
 
         result
     }
 
     fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        if !self.filter.read().unwrap().bloom.may_contain(&key) {
+            return None;
+        }
         let tx = self.tree.tx(false).expect("failed to begin tx");
         let bucket = tx.get_bucket(BPTREE_BUCKET_NAME).unwrap();
         bucket
@@ -71,6 +169,34 @@ impl Indexer for BPTree {
         result
     }
 
+    fn compare_and_put(&self, key: Vec<u8>, expected: LogRecordPos, pos: LogRecordPos) -> bool {
+        let tx = self.tree.tx(true).expect("failed to begin tx");
+        let bucket = tx.get_bucket(BPTREE_BUCKET_NAME).unwrap();
+        match bucket.get_kv(&key) {
+            Some(kv) if decode_log_record_pos(kv.value().to_vec()) == expected => {}
+            _ => return false,
+        }
+        bucket
+            .put(key, pos.encode())
+            .expect("failed to put value in bptree");
+        tx.commit().unwrap();
+        true
+    }
+
+    fn compare_and_delete(&self, key: Vec<u8>, expected: LogRecordPos) -> bool {
+        let tx = self.tree.tx(true).expect("failed to begin tx");
+        let bucket = tx.get_bucket(BPTREE_BUCKET_NAME).unwrap();
+        match bucket.get_kv(&key) {
+            Some(kv) if decode_log_record_pos(kv.value().to_vec()) == expected => {}
+            _ => return false,
+        }
+        bucket
+            .delete(key)
+            .expect("failed to delete value in bptree");
+        tx.commit().unwrap();
+        true
+    }
+
     fn list_keys(&self) -> Result<Vec<Bytes>> {
         let tx = self.tree.tx(false).expect("failed to begin tx");
         let bucket = tx.get_bucket(BPTREE_BUCKET_NAME).unwrap();
@@ -82,69 +208,211 @@ impl Indexer for BPTree {
     }
 
     fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
-        let mut items = Vec::new();
-        let tx = self.tree.tx(false).expect("failed to begin tx");
-        let bucket = tx.get_bucket(BPTREE_BUCKET_NAME).unwrap();
+        self.new_iterator(bounded_range(&options), options)
+    }
 
-        for data in bucket.cursor() {
-            let key = data.key().to_vec();
-            let pos = decode_log_record_pos(data.kv().value().to_vec());
-            items.push((key, pos));
-        }
+    fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Box<dyn IndexIterator> {
+        self.new_iterator((start, end), IteratorOptions::default())
+    }
 
-        if options.reverse {
-            items.reverse();
-        }
+    fn persist(&self) -> Result<()> {
+        let mut buf = self.filter.read().unwrap().bloom.encode();
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        let tmp_file_name = self.dir_path.join(BPTREE_BLOOM_TMP_FILE_NAME);
+        fs::write(&tmp_file_name, buf).map_err(|_| Errors::FailedToWriteToDataFile)?;
+        fs::rename(tmp_file_name, self.dir_path.join(BPTREE_BLOOM_FILE_NAME))
+            .map_err(|_| Errors::FailedToWriteToDataFile)
+    }
 
-        Box::new(BPTreeIterator {
-            items,
-            curr_index: 0,
-            options,
-        })
+    fn compact(&self) {
+        self.rebuild_filter();
+    }
+}
+
+/// Read the bloom filter persisted under DIR_PATH by the last shutdown, and remove it, so a crash
+/// afterwards never leaves a filter missing the keys inserted since. Return None if it is missing,
+/// corrupted, or hashed by another function than this version's, so the filter is rebuilt.
+fn take_bloom_file(dir_path: &Path) -> Option<BloomFilter> {
+    let file_name = dir_path.join(BPTREE_BLOOM_FILE_NAME);
+    let buf = fs::read(&file_name).ok()?;
+    fs::remove_file(&file_name).ok()?;
+    let (encoded, crc) = buf.split_at(buf.len().checked_sub(4)?);
+    if crc32fast::hash(encoded) != u32::from_le_bytes(crc.try_into().ok()?) {
+        return None;
     }
+    BloomFilter::decode(encoded)
 }
 
-/// Iterator for BPlusTree, where:
-/// - `items` stores the key and log record position.
-/// - `curr_index` indicates the position of iterator.
+/// Iterator for BPlusTree, which reads the tree in batches of one transaction each, where:
+/// - `tree` is the database holding the tree iterated through.
+/// - `range` is the range of keys iterated through.
+/// - `bound` is the lower bound of the keys left to read, or their upper bound in reverse.
+/// - `items` stores the keys and log record positions read but not returned yet.
+/// - `current` stores the item returned by the last call to `next`.
+/// - `exhausted` indicates that all keys left are in `items`.
+/// - `checkpoints` stores the first key of each batch left to read in reverse, in key order.
 /// - `options` determines how to iterate through the BPlusTree instance.
+///
+/// The tree can only be walked forwards, so a reverse iterator first walks the keys left to
+/// split them into batches, then reads the batches backwards.
 pub struct BPTreeIterator {
-    items: Vec<(Vec<u8>, LogRecordPos)>,
-    curr_index: usize,
+    tree: Arc<DB>,
+    range: KeyRange,
+    bound: Bound<Vec<u8>>,
+    items: VecDeque<(Vec<u8>, LogRecordPos)>,
+    current: Option<(Vec<u8>, LogRecordPos)>,
+    exhausted: bool,
+    checkpoints: Option<Vec<Vec<u8>>>,
     options: IteratorOptions,
 }
 
+impl BPTreeIterator {
+    /// Restart the iterator from BOUND.
+    fn reset(&mut self, bound: Bound<Vec<u8>>) {
+        self.bound = bound;
+        self.items.clear();
+        self.exhausted = false;
+        self.checkpoints = None;
+    }
+
+    /// Read the next batch of items from the tree.
+    fn fill(&mut self) {
+        self.exhausted = true;
+        let (lower, upper) = match remaining_range(&self.bound, &self.range, &self.options) {
+            Some(range) => range,
+            None => return,
+        };
+
+        if !self.options.reverse {
+            let mut items = VecDeque::new();
+            let is_complete = self.scan(&lower, &upper, |key, data| {
+                if items.len() == INDEX_ITERATOR_BATCH_SIZE {
+                    return false;
+                }
+                items.push_back((key.to_vec(), decode_log_record_pos(data.to_vec())));
+                true
+            });
+            self.items = items;
+            self.exhausted = is_complete;
+            if let Some((key, _)) = self.items.back() {
+                self.bound = Bound::Excluded(key.clone());
+            }
+            return;
+        }
+
+        if self.checkpoints.is_none() {
+            let mut checkpoints = Vec::new();
+            let mut num = 0;
+            self.scan(&lower, &upper, |key, _| {
+                if num % INDEX_ITERATOR_BATCH_SIZE == 0 {
+                    checkpoints.push(key.to_vec());
+                }
+                num += 1;
+                true
+            });
+            self.checkpoints = Some(checkpoints);
+        }
+        let checkpoints = self.checkpoints.as_mut().unwrap();
+        let start = match checkpoints.pop() {
+            Some(start) => start,
+            None => return,
+        };
+        self.exhausted = checkpoints.is_empty();
+
+        let mut items = VecDeque::new();
+        self.scan(&Bound::Included(start.clone()), &upper, |key, data| {
+            items.push_front((key.to_vec(), decode_log_record_pos(data.to_vec())));
+            true
+        });
+        self.items = items;
+        self.bound = Bound::Excluded(start);
+    }
+
+    /// Invoke F with each key between LOWER and UPPER and its encoded position in key order,
+    /// until F returns false. Return whether all the keys are visited.
+    fn scan<F>(&self, lower: &Bound<Vec<u8>>, upper: &Bound<Vec<u8>>, mut f: F) -> bool
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let tx = self.tree.tx(false).expect("failed to begin tx");
+        let bucket = tx.get_bucket(BPTREE_BUCKET_NAME).unwrap();
+        if bucket.cursor().next().is_none() {
+            return true;
+        }
+
+        // The cursor stops just before the sought key if it is absent, which is skipped then.
+        let mut cursor = bucket.cursor();
+        if let Bound::Included(start) | Bound::Excluded(start) = lower {
+            cursor.seek(start);
+            if cursor
+                .current()
+                .is_none_or(|data| data.key() < start.as_slice())
+            {
+                cursor.next();
+            }
+        }
+        for data in cursor {
+            let key = data.key();
+            let is_below = match lower {
+                Bound::Included(start) => key < start.as_slice(),
+                Bound::Excluded(start) => key <= start.as_slice(),
+                Bound::Unbounded => false,
+            };
+            if is_below {
+                continue;
+            }
+            let is_above = match upper {
+                Bound::Included(end) => key > end.as_slice(),
+                Bound::Excluded(end) => key >= end.as_slice(),
+                Bound::Unbounded => false,
+            };
+            if is_above {
+                break;
+            }
+            if !f(key, data.kv().value()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 impl IndexIterator for BPTreeIterator {
     fn rewind(&mut self) {
-        self.curr_index = 0;
+        self.reset(Bound::Unbounded);
     }
 
     fn seek(&mut self, key: Vec<u8>) {
-        self.curr_index = match self.items.binary_search_by(|(x, _)| {
-            if self.options.reverse {
-                x.cmp(&key).reverse()
-            } else {
-                x.cmp(&key)
-            }
-        }) {
-            Ok(equal_val) => equal_val,
-            Err(insert_val) => insert_val,
-        };
+        self.reset(Bound::Included(key));
     }
 
     fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
-        if self.curr_index >= self.items.len() {
-            return None;
+        if self.items.is_empty() && !self.exhausted {
+            self.fill();
         }
+        self.current = self.items.pop_front();
+        self.current.as_ref().map(|(key, pos)| (key, pos))
+    }
 
-        while let Some(item) = self.items.get(self.curr_index) {
-            self.curr_index += 1;
-            let prefix = &self.options.prefix;
-            if prefix.is_empty() || item.0.starts_with(&prefix) {
-                return Some((&item.0, &item.1));
-            }
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        let next_key = self.items.front().map(|(key, _)| key);
+        let mut item = None;
+        if let Some(((lower, upper), options)) =
+            preceding_range(next_key, &self.bound, &self.range, &self.options)
+        {
+            // The previous item of a forward iterator is the last one of the preceding range,
+            // which can only be found by walking through it.
+            self.scan(&lower, &upper, |key, data| {
+                item = Some((key.to_vec(), decode_log_record_pos(data.to_vec())));
+                options.reverse
+            });
         }
-        None
+        if let Some((key, _)) = &item {
+            self.reset(Bound::Included(key.clone()));
+        }
+        self.current = item;
+        self.current.as_ref().map(|(key, pos)| (key, pos))
     }
 }
 
@@ -152,6 +420,8 @@ impl IndexIterator for BPTreeIterator {
 mod tests {
     use std::fs;
 
+    use std::ops::Bound;
+
     use super::*;
 
     #[test]
@@ -166,6 +436,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res1.is_none());
@@ -175,6 +446,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res2.is_none());
@@ -184,6 +456,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res3.is_none());
@@ -193,6 +466,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res4.is_none());
@@ -203,6 +477,7 @@ mod tests {
                 file_id: 77,
                 ofs: 11,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res5.is_some());
@@ -228,6 +503,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         let v2 = bpt.get(b"ccbde".to_vec());
@@ -239,6 +515,7 @@ mod tests {
                 file_id: 125,
                 ofs: 77773,
                 size: 11,
+                expire_at: None,
             },
         );
         let v3 = bpt.get(b"ccbde".to_vec());
@@ -262,6 +539,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         let r2 = bpt.delete(b"ccbde".to_vec());
@@ -291,6 +569,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         bpt.put(
@@ -299,6 +578,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         bpt.put(
@@ -307,6 +587,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         bpt.put(
@@ -315,6 +596,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
 
@@ -336,6 +618,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         bpt.put(
@@ -344,6 +627,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         bpt.put(
@@ -352,6 +636,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
         bpt.put(
@@ -360,6 +645,7 @@ mod tests {
                 file_id: 123,
                 ofs: 883,
                 size: 11,
+                expire_at: None,
             },
         );
 
@@ -372,4 +658,182 @@ mod tests {
 
         fs::remove_dir_all(path.clone()).unwrap();
     }
+
+    #[test]
+    fn test_bptree_iterator_batches() {
+        let path = PathBuf::from("/tmp/bptree-iterator-batches");
+        fs::create_dir_all(path.clone()).unwrap();
+        let bpt = BPTree::new(path.clone());
+        let keys: Vec<Vec<u8>> = (0..2000)
+            .map(|i| format!("key-{:05}", i * 2).into_bytes())
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            bpt.put(
+                key.clone(),
+                LogRecordPos {
+                    file_id: 1,
+                    ofs: i as u64,
+                    size: 11,
+                    expire_at: None,
+                },
+            );
+        }
+
+        let collect = |iter: &mut Box<dyn IndexIterator>| {
+            let mut keys = Vec::new();
+            while let Some((key, _)) = iter.next() {
+                keys.push(key.clone());
+            }
+            keys
+        };
+
+        let mut iter = bpt.iterator(IteratorOptions::default());
+        assert_eq!(collect(&mut iter), keys);
+        iter.rewind();
+        assert_eq!(collect(&mut iter).len(), 2000);
+
+        // Seek to every absent key in between, including those at the edge of tree pages.
+        for i in (0..2000).step_by(7) {
+            iter.seek(format!("key-{:05}", i * 2 + 1).into_bytes());
+            assert_eq!(
+                iter.next().map(|(key, _)| key.clone()),
+                keys.get(i + 1).cloned()
+            );
+        }
+        iter.seek(b"key-99999".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut opts = IteratorOptions::default();
+        opts.reverse = true;
+        let mut iter = bpt.iterator(opts);
+        iter.seek(b"key-01001".to_vec());
+        let reversed: Vec<Vec<u8>> = keys[..=500].iter().rev().cloned().collect();
+        assert_eq!(collect(&mut iter), reversed);
+
+        // Reverse iterators read the keys in batches as well.
+        iter.rewind();
+        let reversed: Vec<Vec<u8>> = keys.iter().rev().cloned().collect();
+        assert_eq!(collect(&mut iter), reversed);
+        iter.seek(b"key-00001".to_vec());
+        assert_eq!(collect(&mut iter), vec![keys[0].clone()]);
+
+        let mut opts = IteratorOptions::default();
+        opts.prefix = b"key-012".to_vec();
+        let mut iter = bpt.iterator(opts);
+        assert_eq!(collect(&mut iter), keys[600..650].to_vec());
+        assert_eq!(iter.prev().unwrap().0, &keys[649]);
+        assert_eq!(iter.prev().unwrap().0, &keys[648]);
+
+        // Step backwards through the whole tree, across its pages.
+        let mut opts = IteratorOptions::default();
+        opts.reverse = true;
+        let mut iter = bpt.iterator(opts);
+        assert!(iter.prev().is_none());
+        while iter.next().is_some() {}
+        let mut stepped = Vec::new();
+        while let Some((key, _)) = iter.prev() {
+            stepped.push(key.clone());
+        }
+        assert_eq!(stepped, keys);
+
+        fs::remove_dir_all(path.clone()).unwrap();
+    }
+
+    #[test]
+    fn test_bptree_range() {
+        let path = PathBuf::from("/tmp/bptree-range");
+        fs::create_dir_all(path.clone()).unwrap();
+        let index = BPTree::new(path.clone());
+        for i in 0..100u64 {
+            let pos = LogRecordPos {
+                file_id: 1,
+                ofs: i,
+                size: 11,
+                expire_at: None,
+            };
+            index.put(format!("key-{:03}", i).into_bytes(), pos);
+        }
+
+        let mut iter = index.range(
+            Bound::Excluded(b"key-010".to_vec()),
+            Bound::Included(b"key-020".to_vec()),
+        );
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(key.clone());
+        }
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys[0], b"key-011".to_vec());
+        assert_eq!(keys[9], b"key-020".to_vec());
+
+        // Seeking never moves the iterator out of the range.
+        iter.seek(b"key-000".to_vec());
+        assert_eq!(iter.next().unwrap().0, &b"key-011".to_vec());
+        iter.seek(b"key-015".to_vec());
+        assert_eq!(iter.next().unwrap().0, &b"key-015".to_vec());
+        iter.seek(b"key-050".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut iter = index.range(Bound::Included(b"key-099".to_vec()), Bound::Unbounded);
+        assert_eq!(iter.next().unwrap().0, &b"key-099".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut iter = index.range(
+            Bound::Included(b"key-020".to_vec()),
+            Bound::Excluded(b"key-010".to_vec()),
+        );
+        assert!(iter.next().is_none());
+
+        fs::remove_dir_all(path.clone()).unwrap();
+    }
+
+    #[test]
+    fn test_bptree_key_filter() {
+        let path = PathBuf::from("/tmp/bptree-key-filter");
+        fs::create_dir_all(path.clone()).unwrap();
+        let pos = LogRecordPos {
+            file_id: 1,
+            ofs: 10,
+            size: 11,
+            expire_at: None,
+        };
+        let bpt = BPTree::new(path.clone());
+        for i in 0..100 {
+            bpt.put(std::format!("key-{:03}", i).into_bytes(), pos);
+        }
+        assert!(bpt.get(b"key-100".to_vec()).is_none());
+        assert!(bpt.delete(b"key-000".to_vec()).is_some());
+
+        // The filter persisted on shutdown is consumed by the next open.
+        assert!(bpt.persist().is_ok());
+        std::mem::drop(bpt);
+        assert!(path.join(BPTREE_BLOOM_FILE_NAME).is_file());
+        let bpt = BPTree::new(path.clone());
+        assert!(!path.join(BPTREE_BLOOM_FILE_NAME).exists());
+        for i in 1..100 {
+            assert!(bpt.get(std::format!("key-{:03}", i).into_bytes()) == Some(pos));
+        }
+
+        // Rebuilding the filter drops the deleted keys.
+        assert!(bpt.filter.read().unwrap().bloom.may_contain(b"key-000"));
+        bpt.compact();
+        assert!(!bpt.filter.read().unwrap().bloom.may_contain(b"key-000"));
+        assert!(bpt.get(b"key-000".to_vec()).is_none());
+        std::mem::drop(bpt);
+
+        // A filter missing after a crash is rebuilt from the tree.
+        let bpt = BPTree::new(path.clone());
+        assert!(bpt.get(b"key-050".to_vec()) == Some(pos));
+        std::mem::drop(bpt);
+
+        // So is an empty filter written by a version hashing the keys otherwise.
+        let mut buf = BloomFilter::new(0, 1).encode();
+        buf[0] = 0;
+        buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+        fs::write(path.join(BPTREE_BLOOM_FILE_NAME), buf).unwrap();
+        let bpt = BPTree::new(path.clone());
+        assert!(bpt.get(b"key-050".to_vec()) == Some(pos));
+
+        fs::remove_dir_all(path.clone()).unwrap();
+    }
 }
@@ -1,5 +1,6 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
+    ops::Bound,
     sync::{Arc, RwLock},
 };
 
@@ -8,112 +9,236 @@ use bytes::Bytes;
 use crate::{
     data::log_record::LogRecordPos,
     errors::Result,
-    index::{IndexIterator, Indexer},
+    index::{
+        arena::{ArenaKey, KeyArena},
+        bounded_range, preceding_range, remaining_range, IndexIterator, IndexMemoryUsage, Indexer,
+        KeyRange, INDEX_ITERATOR_BATCH_SIZE,
+    },
     options::IteratorOptions,
 };
 
+/// Estimated memory of a tree entry, as tree nodes are not always full.
+const BTREE_ENTRY_SIZE: usize = size_of::<(ArenaKey, LogRecordPos)>() * 3 / 2;
+
+/// BTree indexer, where keys are stored in `arena` and `tree` holds references to them. `tree`
+/// is declared first so it is dropped before `arena`, which is only replaced by `compact` while
+/// `tree` is locked for writing.
 pub struct BTree {
-    tree: Arc<RwLock<BTreeMap<Vec<u8>, LogRecordPos>>>,
+    tree: Arc<RwLock<BTreeMap<ArenaKey, LogRecordPos>>>,
+    arena: Arc<RwLock<KeyArena>>,
 }
 
 impl BTree {
     pub fn new() -> Self {
         Self {
             tree: Arc::new(RwLock::new(BTreeMap::new())),
+            arena: Arc::new(RwLock::new(KeyArena::new())),
         }
     }
+
+    /// Get an iterator with OPTIONS through the keys in RANGE.
+    fn new_iterator(&self, range: KeyRange, options: IteratorOptions) -> Box<dyn IndexIterator> {
+        Box::new(BTreeIterator {
+            tree: self.tree.clone(),
+            _arena: self.arena.clone(),
+            range,
+            bound: Bound::Unbounded,
+            items: VecDeque::new(),
+            current: None,
+            exhausted: false,
+            options,
+        })
+    }
 }
 
 impl Indexer for BTree {
     fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> Option<LogRecordPos> {
         let mut tree = self.tree.write().unwrap();
-        tree.insert(key, pos)
+        if let Some(old_pos) = tree.get_mut(key.as_slice()) {
+            return Some(std::mem::replace(old_pos, pos));
+        }
+        tree.insert(self.arena.read().unwrap().alloc(&key), pos);
+        None
     }
 
     fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
         let tree = self.tree.read().unwrap();
-        tree.get(&key).copied()
+        tree.get(key.as_slice()).copied()
     }
 
     fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos> {
         let mut tree = self.tree.write().unwrap();
-        tree.remove(&key)
+        let (arena_key, pos) = tree.remove_entry(key.as_slice())?;
+        self.arena.read().unwrap().release(&arena_key);
+        Some(pos)
+    }
+
+    fn compare_and_put(&self, key: Vec<u8>, expected: LogRecordPos, pos: LogRecordPos) -> bool {
+        let mut tree = self.tree.write().unwrap();
+        match tree.get_mut(key.as_slice()) {
+            Some(old_pos) if *old_pos == expected => {
+                *old_pos = pos;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn compare_and_delete(&self, key: Vec<u8>, expected: LogRecordPos) -> bool {
+        let mut tree = self.tree.write().unwrap();
+        if tree.get(key.as_slice()) != Some(&expected) {
+            return false;
+        }
+        if let Some((arena_key, _)) = tree.remove_entry(key.as_slice()) {
+            self.arena.read().unwrap().release(&arena_key);
+        }
+        true
     }
 
     fn list_keys(&self) -> Result<Vec<Bytes>> {
         let read_guard = self.tree.read().unwrap();
         let mut keys = Vec::with_capacity(read_guard.len());
         for (k, _) in read_guard.iter() {
-            keys.push(Bytes::copy_from_slice(&k));
+            keys.push(Bytes::copy_from_slice(k.as_slice()));
         }
         Ok(keys)
     }
 
     fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
-        let read_guard = self.tree.read().unwrap();
-        let mut items = Vec::with_capacity(read_guard.len());
+        self.new_iterator(bounded_range(&options), options)
+    }
 
-        for (key, value) in read_guard.iter() {
-            items.push((key.clone(), value.clone()));
-        }
-        if options.reverse {
-            items.reverse();
+    fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Box<dyn IndexIterator> {
+        self.new_iterator((start, end), IteratorOptions::default())
+    }
+
+    fn memory_usage(&self) -> IndexMemoryUsage {
+        IndexMemoryUsage::with_arena(
+            self.tree.read().unwrap().len(),
+            BTREE_ENTRY_SIZE,
+            self.arena.read().unwrap().stats(),
+        )
+    }
+
+    fn compact(&self) {
+        let mut tree = self.tree.write().unwrap();
+        let mut arena = self.arena.write().unwrap();
+        if !arena.should_compact() {
+            return;
         }
-        Box::new(BTreeIterator {
-            items,
-            curr_index: 0,
-            options,
-        })
+        let new_arena = KeyArena::new();
+        *tree = tree
+            .iter()
+            .map(|(key, pos)| (new_arena.alloc(key.as_slice()), *pos))
+            .collect();
+        *arena = new_arena;
     }
 }
 
-/// Iterator for BTree, where:
-/// - `items` stores the key and log record position.
-/// - `curr_index` indicates the position of iterator.
+/// Iterator for BTree, which reads the tree in batches instead of copying it at once, where:
+/// - `tree` is the tree iterated through, and `_arena` keeps its keys valid.
+/// - `range` is the range of keys iterated through.
+/// - `bound` is the lower bound of the keys left to read, or their upper bound in reverse.
+/// - `items` stores the keys and log record positions read but not returned yet.
+/// - `current` stores the item returned by the last call to `next`.
+/// - `exhausted` indicates that all keys left are in `items`.
 /// - `options` determines how to iterate through the BTree instance.
 pub struct BTreeIterator {
-    items: Vec<(Vec<u8>, LogRecordPos)>,
-    curr_index: usize,
+    tree: Arc<RwLock<BTreeMap<ArenaKey, LogRecordPos>>>,
+    _arena: Arc<RwLock<KeyArena>>,
+    range: KeyRange,
+    bound: Bound<Vec<u8>>,
+    items: VecDeque<(Vec<u8>, LogRecordPos)>,
+    current: Option<(Vec<u8>, LogRecordPos)>,
+    exhausted: bool,
     options: IteratorOptions,
 }
 
+impl BTreeIterator {
+    /// Restart the iterator from BOUND.
+    fn reset(&mut self, bound: Bound<Vec<u8>>) {
+        self.bound = bound;
+        self.items.clear();
+        self.exhausted = false;
+    }
+
+    /// Read the next batch of items from the tree.
+    fn fill(&mut self) {
+        let (lower, upper) = match remaining_range(&self.bound, &self.range, &self.options) {
+            Some(range) => range,
+            None => {
+                self.exhausted = true;
+                return;
+            }
+        };
+        self.items = self.read(
+            &(lower, upper),
+            self.options.reverse,
+            INDEX_ITERATOR_BATCH_SIZE,
+        );
+
+        self.exhausted = self.items.len() < INDEX_ITERATOR_BATCH_SIZE;
+        if let Some((key, _)) = self.items.back() {
+            self.bound = Bound::Excluded(key.clone());
+        }
+    }
+
+    /// Read at most LIMIT items in RANGE from the tree, in descending key order if REVERSE.
+    fn read(
+        &self,
+        range: &KeyRange,
+        reverse: bool,
+        limit: usize,
+    ) -> VecDeque<(Vec<u8>, LogRecordPos)> {
+        let tree = self.tree.read().unwrap();
+        let range = tree.range::<[u8], _>((
+            range.0.as_ref().map(|key| key.as_slice()),
+            range.1.as_ref().map(|key| key.as_slice()),
+        ));
+        let entries: Box<dyn Iterator<Item = _>> = match reverse {
+            true => Box::new(range.rev()),
+            false => Box::new(range),
+        };
+        entries
+            .take(limit)
+            .map(|(key, pos)| (key.as_slice().to_vec(), *pos))
+            .collect()
+    }
+}
+
 impl IndexIterator for BTreeIterator {
     fn rewind(&mut self) {
-        self.curr_index = 0;
+        self.reset(Bound::Unbounded);
     }
 
     fn seek(&mut self, key: Vec<u8>) {
-        self.curr_index = match self.items.binary_search_by(|(x, _)| {
-            if self.options.reverse {
-                x.cmp(&key).reverse()
-            } else {
-                x.cmp(&key)
-            }
-        }) {
-            Ok(equal_val) => equal_val,
-            Err(insert_val) => insert_val,
-        };
+        self.reset(Bound::Included(key));
     }
 
     fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
-        if self.curr_index >= self.items.len() {
-            return None;
+        if self.items.is_empty() && !self.exhausted {
+            self.fill();
         }
+        self.current = self.items.pop_front();
+        self.current.as_ref().map(|(key, pos)| (key, pos))
+    }
 
-        while let Some(item) = self.items.get(self.curr_index) {
-            self.curr_index += 1;
-            let prefix = &self.options.prefix;
-            if prefix.is_empty() || item.0.starts_with(&prefix) {
-                return Some((&item.0, &item.1));
-            }
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        let next_key = self.items.front().map(|(key, _)| key);
+        self.current = preceding_range(next_key, &self.bound, &self.range, &self.options)
+            .and_then(|(range, options)| self.read(&range, options.reverse, 1).pop_front());
+        if let Some((key, _)) = &self.current {
+            self.reset(Bound::Included(key.clone()));
         }
-        None
+        self.current.as_ref().map(|(key, pos)| (key, pos))
     }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use std::ops::Bound;
+
     use super::*;
 
     #[test]
@@ -125,6 +250,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res1.is_none());
@@ -135,6 +261,7 @@ mod tests {
                 file_id: 11,
                 ofs: 22,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res2.is_none());
@@ -145,6 +272,7 @@ mod tests {
                 file_id: 1144,
                 ofs: 22122,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res3.is_some());
@@ -162,6 +290,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res1.is_none());
@@ -171,6 +300,7 @@ mod tests {
                 file_id: 11,
                 ofs: 22,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res2.is_none());
@@ -195,6 +325,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res1.is_none());
@@ -204,6 +335,7 @@ mod tests {
                 file_id: 11,
                 ofs: 22,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res2.is_none());
@@ -224,6 +356,65 @@ mod tests {
         assert!(del3.is_none());
     }
 
+    #[test]
+    fn test_btree_compare_and_delete() {
+        let bt = BTree::new();
+        let pos = LogRecordPos {
+            file_id: 1,
+            ofs: 10,
+            size: 11,
+            expire_at: None,
+        };
+        bt.put("aa".as_bytes().to_vec(), pos);
+
+        let stale = LogRecordPos { ofs: 0, ..pos };
+        assert!(!bt.compare_and_delete("aa".as_bytes().to_vec(), stale));
+        assert!(bt.get("aa".as_bytes().to_vec()) == Some(pos));
+
+        assert!(bt.compare_and_delete("aa".as_bytes().to_vec(), pos));
+        assert!(bt.get("aa".as_bytes().to_vec()).is_none());
+        assert!(!bt.compare_and_delete("aa".as_bytes().to_vec(), pos));
+    }
+
+    #[test]
+    fn test_btree_memory_usage() {
+        let bt = BTree::new();
+        let pos = LogRecordPos {
+            file_id: 1,
+            ofs: 10,
+            size: 11,
+            expire_at: None,
+        };
+        bt.put("aa".as_bytes().to_vec(), pos);
+        bt.put("bbb".as_bytes().to_vec(), pos);
+        bt.put("aa".as_bytes().to_vec(), pos);
+        bt.delete("bbb".as_bytes().to_vec());
+
+        let usage = bt.memory_usage();
+        assert_eq!(usage.entry_num, 1);
+        let arena = usage.arena.unwrap();
+        assert_eq!(arena.allocated_bytes, 5);
+        assert_eq!(arena.dead_bytes, 3);
+        assert_eq!(
+            usage.estimated_bytes,
+            BTREE_ENTRY_SIZE + arena.reserved_bytes
+        );
+
+        // The dead keys outweigh the live one, so compacting copies it into a new arena.
+        let mut iter = bt.iterator(IteratorOptions::default());
+        bt.compact();
+        let arena = bt.memory_usage().arena.unwrap();
+        assert_eq!(arena.allocated_bytes, 2);
+        assert_eq!(arena.dead_bytes, 0);
+        assert!(bt.get("aa".as_bytes().to_vec()) == Some(pos));
+        assert_eq!(
+            iter.next().map(|(key, _)| key.clone()),
+            Some(b"aa".to_vec())
+        );
+        bt.compact();
+        assert_eq!(bt.memory_usage().arena.unwrap().allocated_bytes, 2);
+    }
+
     #[test]
     fn test_btree_iterator_seek() {
         let bt = BTree::new();
@@ -239,6 +430,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
         let mut iter2 = bt.iterator(IteratorOptions::default());
@@ -257,6 +449,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
         bt.put(
@@ -265,6 +458,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
         bt.put(
@@ -273,6 +467,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
 
@@ -315,6 +510,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
         let mut iter_opt1 = IteratorOptions::default();
@@ -328,6 +524,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
         bt.put(
@@ -336,6 +533,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
         bt.put(
@@ -344,6 +542,7 @@ mod tests {
                 file_id: 1,
                 ofs: 10,
                 size: 11,
+                expire_at: None,
             },
         );
 
@@ -361,4 +560,191 @@ mod tests {
             assert!(item.0.len() > 0);
         }
     }
+
+    #[test]
+    fn test_btree_iterator_batches() {
+        let bt = BTree::new();
+        let keys: Vec<Vec<u8>> = (0..1000)
+            .map(|i| format!("key-{:05}", i * 2).into_bytes())
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            bt.put(
+                key.clone(),
+                LogRecordPos {
+                    file_id: 1,
+                    ofs: i as u64,
+                    size: 11,
+                    expire_at: None,
+                },
+            );
+        }
+
+        let mut iter = bt.iterator(IteratorOptions::default());
+        iter.seek(b"key-00401".to_vec());
+        assert_eq!(iter.next().unwrap().0, &keys[201]);
+
+        // Keys written after the iterator is created are seen once it reads past them.
+        bt.put(
+            b"key-01001".to_vec(),
+            LogRecordPos {
+                file_id: 2,
+                ofs: 0,
+                size: 11,
+                expire_at: None,
+            },
+        );
+        let mut num = 1;
+        while iter.next().is_some() {
+            num += 1;
+        }
+        assert_eq!(num, 800);
+
+        let mut opts = IteratorOptions::default();
+        opts.reverse = true;
+        opts.prefix = b"key-0".to_vec();
+        let mut iter = bt.iterator(opts);
+        let mut reversed = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            reversed.push(key.clone());
+        }
+        let mut expected = keys.clone();
+        expected.push(b"key-01001".to_vec());
+        expected.sort();
+        expected.reverse();
+        assert_eq!(reversed, expected);
+    }
+
+    #[test]
+    fn test_btree_iterator_bounds() {
+        let bt = BTree::new();
+        for i in 0..3000 {
+            bt.put(
+                format!("user:{:04}:", i).into_bytes(),
+                LogRecordPos {
+                    file_id: 1,
+                    ofs: i as u64,
+                    size: 11,
+                    expire_at: None,
+                },
+            );
+        }
+
+        let mut opts = IteratorOptions::default();
+        opts.lower_bound = Some(b"user:1000:".to_vec());
+        opts.upper_bound = Some(b"user:2000:".to_vec());
+        let mut iter = bt.iterator(opts.clone());
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(key.clone());
+        }
+        assert_eq!(keys.len(), 1000);
+        assert_eq!(keys.first().unwrap(), b"user:1000:");
+        assert_eq!(keys.last().unwrap(), b"user:1999:");
+        assert_eq!(iter.prev().unwrap().0, b"user:1999:");
+
+        // Seeking outside of the bounds stays within them.
+        iter.seek(b"user:0".to_vec());
+        assert_eq!(iter.next().unwrap().0, b"user:1000:");
+        iter.seek(b"user:3".to_vec());
+        assert!(iter.next().is_none());
+
+        opts.reverse = true;
+        opts.prefix = b"user:19".to_vec();
+        let mut iter = bt.iterator(opts);
+        assert_eq!(iter.next().unwrap().0, b"user:1999:");
+        let mut num = 1;
+        while iter.next().is_some() {
+            num += 1;
+        }
+        assert_eq!(num, 100);
+    }
+
+    #[test]
+    fn test_btree_iterator_prev() {
+        let bt = BTree::new();
+        let keys: Vec<Vec<u8>> = (0..600)
+            .map(|i| format!("key-{:05}", i).into_bytes())
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            bt.put(
+                key.clone(),
+                LogRecordPos {
+                    file_id: 1,
+                    ofs: i as u64,
+                    size: 11,
+                    expire_at: None,
+                },
+            );
+        }
+
+        let mut iter = bt.iterator(IteratorOptions::default());
+        assert!(iter.prev().is_none());
+        for key in keys.iter().take(300) {
+            assert_eq!(iter.next().unwrap().0, key);
+        }
+        // Step back across the batch read ahead by the iterator.
+        for key in keys[..300].iter().rev() {
+            assert_eq!(iter.prev().unwrap().0, key);
+        }
+        assert!(iter.prev().is_none());
+        assert_eq!(iter.next().unwrap().0, &keys[0]);
+
+        while iter.next().is_some() {}
+        assert_eq!(iter.prev().unwrap().0, &keys[599]);
+        assert_eq!(iter.next().unwrap().0, &keys[599]);
+
+        let mut opts = IteratorOptions::default();
+        opts.reverse = true;
+        let mut iter = bt.iterator(opts);
+        iter.seek(b"key-00100".to_vec());
+        assert_eq!(iter.next().unwrap().0, &keys[100]);
+        assert_eq!(iter.next().unwrap().0, &keys[99]);
+        assert_eq!(iter.prev().unwrap().0, &keys[99]);
+        assert_eq!(iter.prev().unwrap().0, &keys[100]);
+        assert_eq!(iter.prev().unwrap().0, &keys[101]);
+    }
+
+    #[test]
+    fn test_btree_range() {
+        let index = BTree::new();
+        for i in 0..100u64 {
+            let pos = LogRecordPos {
+                file_id: 1,
+                ofs: i,
+                size: 11,
+                expire_at: None,
+            };
+            index.put(format!("key-{:03}", i).into_bytes(), pos);
+        }
+
+        let mut iter = index.range(
+            Bound::Excluded(b"key-010".to_vec()),
+            Bound::Included(b"key-020".to_vec()),
+        );
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(key.clone());
+        }
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys[0], b"key-011".to_vec());
+        assert_eq!(keys[9], b"key-020".to_vec());
+
+        // Seeking never moves the iterator out of the range.
+        iter.seek(b"key-000".to_vec());
+        assert_eq!(iter.next().unwrap().0, &b"key-011".to_vec());
+        iter.seek(b"key-015".to_vec());
+        assert_eq!(iter.next().unwrap().0, &b"key-015".to_vec());
+        iter.seek(b"key-050".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut iter = index.range(Bound::Included(b"key-099".to_vec()), Bound::Unbounded);
+        assert_eq!(iter.next().unwrap().0, &b"key-099".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut iter = index.range(
+            Bound::Included(b"key-020".to_vec()),
+            Bound::Excluded(b"key-010".to_vec()),
+        );
+        assert!(iter.next().is_none());
+    }
 }
@@ -0,0 +1,410 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    ops::{Bound, RangeBounds},
+    sync::RwLock,
+};
+
+use bytes::Bytes;
+
+use crate::{data::log_record::LogRecordPos, errors::Result, options::IteratorOptions};
+
+use super::{
+    arena::{ArenaKey, KeyArena},
+    bounded_range, remaining_range, IndexIterator, IndexMemoryUsage, Indexer,
+};
+
+/// Number of shards of the hash indexer, each guarded by its own lock.
+const HASH_INDEX_SHARD_NUM: usize = 16;
+
+/// Estimated memory of a hash map entry, with its control byte and a load factor of 7/8.
+const HASH_INDEX_ENTRY_SIZE: usize = (size_of::<(ArenaKey, LogRecordPos)>() + 1) * 8 / 7;
+
+/// Hash indexer for point-lookup-heavy workloads, where keys are stored in `arena` and `shards`
+/// hold references to them. Keys are sorted on each ordered iteration, see
+/// `IteratorOptions::unordered`. `shards` is declared first so it is dropped before `arena`, which
+/// is only replaced by `compact` while all shards are locked for writing.
+pub struct HashIndex {
+    shards: Vec<RwLock<HashMap<ArenaKey, LogRecordPos>>>,
+    arena: RwLock<KeyArena>,
+    hasher: RandomState,
+}
+
+impl HashIndex {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..HASH_INDEX_SHARD_NUM)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            arena: RwLock::new(KeyArena::new()),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &RwLock<HashMap<ArenaKey, LogRecordPos>> {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash % HASH_INDEX_SHARD_NUM]
+    }
+}
+
+impl Default for HashIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Indexer for HashIndex {
+    fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> Option<LogRecordPos> {
+        let mut shard = self.shard(&key).write().unwrap();
+        if let Some(old_pos) = shard.get_mut(key.as_slice()) {
+            return Some(std::mem::replace(old_pos, pos));
+        }
+        shard.insert(self.arena.read().unwrap().alloc(&key), pos);
+        None
+    }
+
+    fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        let shard = self.shard(&key).read().unwrap();
+        shard.get(key.as_slice()).copied()
+    }
+
+    fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        let mut shard = self.shard(&key).write().unwrap();
+        let (arena_key, pos) = shard.remove_entry(key.as_slice())?;
+        self.arena.read().unwrap().release(&arena_key);
+        Some(pos)
+    }
+
+    fn compare_and_put(&self, key: Vec<u8>, expected: LogRecordPos, pos: LogRecordPos) -> bool {
+        let mut shard = self.shard(&key).write().unwrap();
+        match shard.get_mut(key.as_slice()) {
+            Some(old_pos) if *old_pos == expected => {
+                *old_pos = pos;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn compare_and_delete(&self, key: Vec<u8>, expected: LogRecordPos) -> bool {
+        let mut shard = self.shard(&key).write().unwrap();
+        if shard.get(key.as_slice()) != Some(&expected) {
+            return false;
+        }
+        if let Some((arena_key, _)) = shard.remove_entry(key.as_slice()) {
+            self.arena.read().unwrap().release(&arena_key);
+        }
+        true
+    }
+
+    fn list_keys(&self) -> Result<Vec<Bytes>> {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            keys.extend(shard.keys().map(|k| Bytes::copy_from_slice(k.as_slice())));
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
+        // Only the keys within the prefix are collected and sorted.
+        let mut items = Vec::new();
+        let remaining = remaining_range(&Bound::Unbounded, &bounded_range(&options), &options);
+        if let Some((lower, upper)) = remaining {
+            let range = (
+                lower.as_ref().map(|key| key.as_slice()),
+                upper.as_ref().map(|key| key.as_slice()),
+            );
+            for shard in &self.shards {
+                let shard = shard.read().unwrap();
+                items.extend(
+                    shard
+                        .iter()
+                        .filter(|(k, _)| RangeBounds::<[u8]>::contains(&range, k.as_slice()))
+                        .map(|(k, v)| (k.as_slice().to_vec(), *v)),
+                );
+            }
+        }
+        if !options.unordered {
+            items.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+            if options.reverse {
+                items.reverse();
+            }
+        }
+        Box::new(HashIndexIterator {
+            items,
+            curr_index: 0,
+            lower_bound: None,
+            options,
+        })
+    }
+
+    fn memory_usage(&self) -> IndexMemoryUsage {
+        let entry_num = self
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum();
+        let arena_stats = self.arena.read().unwrap().stats();
+        IndexMemoryUsage::with_arena(entry_num, HASH_INDEX_ENTRY_SIZE, arena_stats)
+    }
+
+    fn compact(&self) {
+        let mut shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.write().unwrap())
+            .collect();
+        let mut arena = self.arena.write().unwrap();
+        if !arena.should_compact() {
+            return;
+        }
+        let new_arena = KeyArena::new();
+        for shard in shards.iter_mut() {
+            **shard = shard
+                .iter()
+                .map(|(key, pos)| (new_arena.alloc(key.as_slice()), *pos))
+                .collect();
+        }
+        *arena = new_arena;
+    }
+}
+
+/// Iterator for the hash indexer, where:
+/// - `items` stores the key and log record position.
+/// - `curr_index` indicates the position of iterator.
+/// - `lower_bound` is the key sought by an unordered iterator, before which keys are skipped.
+/// - `options` determines how to iterate through the hash indexer instance.
+pub struct HashIndexIterator {
+    items: Vec<(Vec<u8>, LogRecordPos)>,
+    curr_index: usize,
+    lower_bound: Option<Vec<u8>>,
+    options: IteratorOptions,
+}
+
+impl HashIndexIterator {
+    /// Whether KEY is before the key sought, in the iteration order.
+    fn is_before_bound(&self, key: &[u8]) -> bool {
+        match &self.lower_bound {
+            Some(bound) if self.options.reverse => key > bound.as_slice(),
+            Some(bound) => key < bound.as_slice(),
+            None => false,
+        }
+    }
+}
+
+impl IndexIterator for HashIndexIterator {
+    fn rewind(&mut self) {
+        self.curr_index = 0;
+        self.lower_bound = None;
+    }
+
+    fn seek(&mut self, key: Vec<u8>) {
+        if self.options.unordered {
+            self.curr_index = 0;
+            self.lower_bound = Some(key);
+            return;
+        }
+        self.curr_index = match self.items.binary_search_by(|(x, _)| {
+            if self.options.reverse {
+                x.cmp(&key).reverse()
+            } else {
+                x.cmp(&key)
+            }
+        }) {
+            Ok(equal_val) => equal_val,
+            Err(insert_val) => insert_val,
+        };
+    }
+
+    fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        while self.curr_index < self.items.len() {
+            let index = self.curr_index;
+            self.curr_index += 1;
+            if !self.is_before_bound(&self.items[index].0) {
+                let item = &self.items[index];
+                return Some((&item.0, &item.1));
+            }
+        }
+        None
+    }
+
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        let index = self.items[..self.curr_index]
+            .iter()
+            .rposition(|(key, _)| !self.is_before_bound(key))?;
+        self.curr_index = index;
+        let item = &self.items[index];
+        Some((&item.0, &item.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use super::*;
+
+    fn pos(file_id: u64, ofs: u64) -> LogRecordPos {
+        LogRecordPos {
+            file_id,
+            ofs,
+            size: 11,
+            expire_at: None,
+        }
+    }
+
+    #[test]
+    fn test_hash_index_put_get_delete() {
+        let index = HashIndex::new();
+        assert!(index.put("".as_bytes().to_vec(), pos(1, 10)).is_none());
+        assert!(index.put("aa".as_bytes().to_vec(), pos(11, 22)).is_none());
+
+        let old_pos = index.put("aa".as_bytes().to_vec(), pos(1144, 22122));
+        assert_eq!(old_pos.unwrap().file_id, 11);
+        assert_eq!(index.get("aa".as_bytes().to_vec()).unwrap().ofs, 22122);
+        assert_eq!(index.get("".as_bytes().to_vec()).unwrap().ofs, 10);
+        assert!(index.get("not exist".as_bytes().to_vec()).is_none());
+
+        assert_eq!(index.delete("".as_bytes().to_vec()).unwrap().file_id, 1);
+        assert!(index.delete("".as_bytes().to_vec()).is_none());
+        assert!(index.get("".as_bytes().to_vec()).is_none());
+
+        assert!(!index.compare_and_put("aa".as_bytes().to_vec(), pos(11, 22), pos(2, 0)));
+        assert!(index.compare_and_put("aa".as_bytes().to_vec(), pos(1144, 22122), pos(2, 0)));
+        assert!(!index.compare_and_delete("aa".as_bytes().to_vec(), pos(1144, 22122)));
+        assert!(index.compare_and_delete("aa".as_bytes().to_vec(), pos(2, 0)));
+        assert!(index.get("aa".as_bytes().to_vec()).is_none());
+
+        let usage = index.memory_usage();
+        assert_eq!(usage.entry_num, 0);
+        assert_eq!(usage.arena.unwrap().dead_bytes, 2);
+    }
+
+    #[test]
+    fn test_hash_index_compact() {
+        let index = HashIndex::new();
+        for i in 0..100 {
+            index.put(format!("key-{:03}", i).into_bytes(), pos(1, i));
+        }
+        for i in 0..60 {
+            index.delete(format!("key-{:03}", i).into_bytes());
+        }
+
+        // The dead keys outweigh the live ones, so compacting copies these into a new arena.
+        index.compact();
+        let arena = index.memory_usage().arena.unwrap();
+        assert_eq!(arena.allocated_bytes, 40 * 7);
+        assert_eq!(arena.dead_bytes, 0);
+        for i in 0..100 {
+            let key = format!("key-{:03}", i).into_bytes();
+            assert_eq!(index.get(key).map(|pos| pos.ofs), (i >= 60).then_some(i));
+        }
+        assert_eq!(index.list_keys().unwrap().len(), 40);
+    }
+
+    #[test]
+    fn test_hash_index_iterator() {
+        let index = HashIndex::new();
+        for (i, key) in ["ccde", "bbed", "aaed", "cadd"].iter().enumerate() {
+            index.put(key.as_bytes().to_vec(), pos(1, i as u64));
+        }
+        let keys = index.list_keys().unwrap();
+        assert_eq!(keys, vec!["aaed", "bbed", "cadd", "ccde"]);
+
+        let mut iter1 = index.iterator(IteratorOptions::default());
+        iter1.seek("b".as_bytes().to_vec());
+        assert_eq!(iter1.next().unwrap().0, &b"bbed".to_vec());
+        assert_eq!(iter1.next().unwrap().0, &b"cadd".to_vec());
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.reverse = true;
+        let mut iter2 = index.iterator(iter_opts);
+        iter2.seek("bz".as_bytes().to_vec());
+        assert_eq!(iter2.next().unwrap().0, &b"bbed".to_vec());
+        assert_eq!(iter2.next().unwrap().0, &b"aaed".to_vec());
+        assert!(iter2.next().is_none());
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.unordered = true;
+        let mut iter3 = index.iterator(iter_opts);
+        iter3.seek("c".as_bytes().to_vec());
+        let mut sought = Vec::new();
+        while let Some((key, _)) = iter3.next() {
+            sought.push(key.clone());
+        }
+        sought.sort();
+        assert_eq!(sought, vec![b"cadd".to_vec(), b"ccde".to_vec()]);
+
+        iter3.rewind();
+        let mut num = 0;
+        while iter3.next().is_some() {
+            num += 1;
+        }
+        assert_eq!(num, 4);
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.lower_bound = Some(b"bbed".to_vec());
+        iter_opts.upper_bound = Some(b"ccde".to_vec());
+        let mut iter4 = index.iterator(iter_opts);
+        assert_eq!(iter4.next().unwrap().0, &b"bbed".to_vec());
+        assert_eq!(iter4.next().unwrap().0, &b"cadd".to_vec());
+        assert!(iter4.next().is_none());
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.prefix = b"c".to_vec();
+        let mut iter5 = index.iterator(iter_opts);
+        iter5.seek(b"a".to_vec());
+        assert_eq!(iter5.next().unwrap().0, &b"cadd".to_vec());
+        assert_eq!(iter5.next().unwrap().0, &b"ccde".to_vec());
+        assert!(iter5.next().is_none());
+        assert_eq!(iter5.prev().unwrap().0, &b"ccde".to_vec());
+        assert_eq!(iter5.prev().unwrap().0, &b"cadd".to_vec());
+        assert!(iter5.prev().is_none());
+    }
+
+    #[test]
+    fn test_hash_index_range() {
+        let index = HashIndex::new();
+        for i in 0..100u64 {
+            let pos = LogRecordPos {
+                file_id: 1,
+                ofs: i,
+                size: 11,
+                expire_at: None,
+            };
+            index.put(format!("key-{:03}", i).into_bytes(), pos);
+        }
+
+        let mut iter = index.range(
+            Bound::Excluded(b"key-010".to_vec()),
+            Bound::Included(b"key-020".to_vec()),
+        );
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(key.clone());
+        }
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys[0], b"key-011".to_vec());
+        assert_eq!(keys[9], b"key-020".to_vec());
+
+        // Seeking never moves the iterator out of the range.
+        iter.seek(b"key-000".to_vec());
+        assert_eq!(iter.next().unwrap().0, &b"key-011".to_vec());
+        iter.seek(b"key-015".to_vec());
+        assert_eq!(iter.next().unwrap().0, &b"key-015".to_vec());
+        iter.seek(b"key-050".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut iter = index.range(Bound::Included(b"key-099".to_vec()), Bound::Unbounded);
+        assert_eq!(iter.next().unwrap().0, &b"key-099".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut iter = index.range(
+            Bound::Included(b"key-020".to_vec()),
+            Bound::Excluded(b"key-010".to_vec()),
+        );
+        assert!(iter.next().is_none());
+    }
+}
@@ -0,0 +1,552 @@
+//! The keydir file is an on-disk copy of the in-memory index, written on close, which can be
+//! memory-mapped and binary-searched directly on the next startup. So the engine is readable
+//! right after `open`, while the in-memory index is rebuilt from the keydir in background.
+//!
+//! The keydir file is formatted as follows, where all integers are little-endian:
+//! ```text
+//!  +-------+-------+----------------+------------+-----------------+-----+---------+---------+
+//!  | magic | count | active_file_id | active_ofs | sequence_number | CRC | offsets | entries |
+//!  +-------+-------+----------------+------------+-----------------+-----+---------+---------+
+//! ```
+//! - `offsets` contains COUNT u64, the offset of each entry relative to the start of `entries`.
+//! - each entry is `| key_size (u32) | key | file_id (u64) | ofs (u64) | size (u32) |
+//!   expire_at (u64) |`, where `expire_at` is 0 if the record never expires, and entries are
+//!   sorted by key.
+//! - keydir files written before expiries were recorded carry the `SDBKEYD2` magic, and their
+//!   entries end after `size`. They are still readable, but the engine scans the data files
+//!   instead, as it cannot tell which entries expire.
+//! - keydir files written before file ids were widened carry the `SDBKEYD1` magic, where
+//!   `active_file_id` and the `file_id` of each entry are u32, and entries end after `size`.
+//! - `active_file_id` and `active_ofs` record the end of data files when the keydir is written.
+//!   Records appended after them are replayed on startup, while merges remove the keydir file.
+
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex, RwLock, Weak},
+    thread,
+};
+
+use bytes::Bytes;
+use memmap2::Mmap;
+
+use crate::{
+    data::log_record::LogRecordPos,
+    errors::{Errors, Result},
+    index::{btree::BTree, IndexIterator, IndexMemoryUsage, Indexer},
+    options::IteratorOptions,
+};
+
+pub const KEYDIR_FILE_NAME: &str = "keydir-index";
+const KEYDIR_MAGIC: &[u8; 8] = b"SDBKEYD3";
+const KEYDIR_MAGIC_V2: &[u8; 8] = b"SDBKEYD2";
+const KEYDIR_MAGIC_V1: &[u8; 8] = b"SDBKEYD1";
+
+/// Number of entries moved from the keydir file to the in-memory index per lock acquisition.
+const KEYDIR_LOAD_BATCH: usize = 1024;
+
+/// A read-only, memory-mapped keydir file.
+pub struct KeydirFile {
+    map: Mmap,
+    count: usize,
+
+    /// Size of the encoded file ids, 4 bytes for the keydir files with the `SDBKEYD1` magic.
+    file_id_len: usize,
+
+    /// Whether the entries end with their expiry, false for the keydir files written before it.
+    has_expiries: bool,
+    active_file_id: u64,
+    active_ofs: u64,
+    sequence_number: usize,
+}
+
+impl KeydirFile {
+    /// Open and validate the keydir file under DIR_PATH, return None if there is no valid one.
+    pub fn open(dir_path: &Path) -> Option<KeydirFile> {
+        let file = File::open(dir_path.join(KEYDIR_FILE_NAME)).ok()?;
+        let map = unsafe { Mmap::map(&file).ok()? };
+        let (file_id_len, has_expiries) = match map.get(..8)? {
+            magic if magic == KEYDIR_MAGIC => (8, true),
+            magic if magic == KEYDIR_MAGIC_V2 => (8, false),
+            magic if magic == KEYDIR_MAGIC_V1 => (4, false),
+            _ => return None,
+        };
+        let header_size = header_size(file_id_len);
+        if map.len() < header_size {
+            return None;
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&map[..header_size - 4]);
+        if hasher.finalize() != read_u32(&map, header_size - 4)? {
+            return None;
+        }
+
+        let count = read_u64(&map, 8)? as usize;
+        let entries_start = header_size.checked_add(count.checked_mul(8)?)?;
+        if entries_start > map.len() {
+            return None;
+        }
+
+        let keydir = KeydirFile {
+            count,
+            file_id_len,
+            has_expiries,
+            active_file_id: read_file_id(&map, 16, file_id_len)?,
+            active_ofs: read_u64(&map, 16 + file_id_len)?,
+            sequence_number: read_u64(&map, 24 + file_id_len)? as usize,
+            map,
+        };
+        if count > 0 {
+            keydir.entry(count - 1)?;
+        }
+        Some(keydir)
+    }
+
+    /// Write all entries yielded by ITER to the keydir file under DIR_PATH. The entries must be
+    /// sorted by key.
+    pub fn write(
+        dir_path: &Path,
+        iter: &mut dyn IndexIterator,
+        active_file_id: u64,
+        active_ofs: u64,
+        sequence_number: usize,
+    ) -> Result<()> {
+        let mut offsets = Vec::new();
+        let mut entries = Vec::new();
+        while let Some((key, pos)) = iter.next() {
+            offsets.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+            entries.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            entries.extend_from_slice(key);
+            entries.extend_from_slice(&pos.file_id.to_le_bytes());
+            entries.extend_from_slice(&pos.ofs.to_le_bytes());
+            entries.extend_from_slice(&pos.size.to_le_bytes());
+            entries.extend_from_slice(&pos.expire_at.unwrap_or(0).to_le_bytes());
+        }
+
+        let mut header = Vec::with_capacity(header_size(8));
+        header.extend_from_slice(KEYDIR_MAGIC);
+        header.extend_from_slice(&((offsets.len() / 8) as u64).to_le_bytes());
+        header.extend_from_slice(&active_file_id.to_le_bytes());
+        header.extend_from_slice(&active_ofs.to_le_bytes());
+        header.extend_from_slice(&(sequence_number as u64).to_le_bytes());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        header.extend_from_slice(&hasher.finalize().to_le_bytes());
+
+        // Write to a temporary file first, so a crash never leaves a partially written keydir.
+        let tmp_path = dir_path.join(std::format!("{}.tmp", KEYDIR_FILE_NAME));
+        let mut file = File::create(&tmp_path).map_err(|_| Errors::FailedToOpenDataFile)?;
+        for buf in [&header, &offsets, &entries] {
+            file.write_all(buf)
+                .map_err(|_| Errors::FailedToWriteToDataFile)?;
+        }
+        file.sync_all()
+            .map_err(|_| Errors::FailedToSyncToDataFile)?;
+        fs::rename(tmp_path, dir_path.join(KEYDIR_FILE_NAME))
+            .map_err(|_| Errors::FailedToWriteToDataFile)
+    }
+
+    /// Remove the keydir file under DIR_PATH if any, used when the data files are rewritten.
+    pub fn remove(dir_path: &Path) -> Result<()> {
+        let path = dir_path.join(KEYDIR_FILE_NAME);
+        if path.is_file() {
+            fs::remove_file(path).map_err(|_| Errors::FailedToWriteToDataFile)?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn active_file_id(&self) -> u64 {
+        self.active_file_id
+    }
+
+    pub fn active_ofs(&self) -> u64 {
+        self.active_ofs
+    }
+
+    pub fn sequence_number(&self) -> usize {
+        self.sequence_number
+    }
+
+    /// Whether the entries record the expiry of their records.
+    pub fn has_expiries(&self) -> bool {
+        self.has_expiries
+    }
+
+    /// Get the I-th entry of the keydir.
+    pub fn entry(&self, i: usize) -> Option<(&[u8], LogRecordPos)> {
+        let header_size = header_size(self.file_id_len);
+        let entries_start = header_size + self.count * 8;
+        let ofs = entries_start + read_u64(&self.map, header_size + i * 8)? as usize;
+        let key_size = read_u32(&self.map, ofs)? as usize;
+        let key_end = (ofs + 4).checked_add(key_size)?;
+        let expiry_len = if self.has_expiries { 8 } else { 0 };
+        if key_end + self.file_id_len + 12 + expiry_len > self.map.len() {
+            return None;
+        }
+        let expire_at = match self.has_expiries {
+            true => Some(read_u64(&self.map, key_end + self.file_id_len + 12)?),
+            false => None,
+        };
+        let pos = LogRecordPos {
+            file_id: read_file_id(&self.map, key_end, self.file_id_len)?,
+            ofs: read_u64(&self.map, key_end + self.file_id_len)?,
+            size: read_u32(&self.map, key_end + self.file_id_len + 8)?,
+            expire_at: expire_at.filter(|expire_at| *expire_at > 0),
+        };
+        Some((&self.map[ofs + 4..key_end], pos))
+    }
+
+    /// Binary search KEY in the keydir.
+    pub fn get(&self, key: &[u8]) -> Option<LogRecordPos> {
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (k, pos) = self.entry(mid)?;
+            match k.cmp(key) {
+                std::cmp::Ordering::Equal => return Some(pos),
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+            }
+        }
+        None
+    }
+}
+
+/// Size of the header, with file ids encoded in FILE_ID_LEN bytes.
+fn header_size(file_id_len: usize) -> usize {
+    36 + file_id_len
+}
+
+/// Read a file id encoded in FILE_ID_LEN bytes at OFS of BUF.
+fn read_file_id(buf: &[u8], ofs: usize, file_id_len: usize) -> Option<u64> {
+    match file_id_len {
+        4 => read_u32(buf, ofs).map(u64::from),
+        _ => read_u64(buf, ofs),
+    }
+}
+
+fn read_u32(buf: &[u8], ofs: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(ofs..ofs + 4)?.try_into().ok()?))
+}
+
+fn read_u64(buf: &[u8], ofs: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(buf.get(ofs..ofs + 8)?.try_into().ok()?))
+}
+
+/// An indexer serving lookups from a memory-mapped keydir file while its entries are moved into
+/// an in-memory indexer in background. Once all entries are moved, the keydir file is unmapped and
+/// all requests go to the in-memory indexer directly.
+pub struct LayeredIndex {
+    inner: Arc<LayeredIndexInner>,
+}
+
+struct LayeredIndexInner {
+    base: RwLock<Option<Arc<KeydirFile>>>,
+    delta: Box<dyn Indexer>,
+
+    /// Keys of the keydir file that were overwritten or deleted since startup. The lock is also
+    /// held by every modification, so the background loading never overwrites a newer position.
+    shadowed: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl LayeredIndex {
+    /// Serve lookups from BASE, and start moving its entries to DELTA in background.
+    pub fn new(base: KeydirFile, delta: Box<dyn Indexer>) -> Self {
+        let inner = Arc::new(LayeredIndexInner {
+            base: RwLock::new(Some(Arc::new(base))),
+            delta,
+            shadowed: Mutex::new(HashSet::new()),
+        });
+
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || LayeredIndexInner::load_in_background(weak));
+        Self { inner }
+    }
+
+    /// Whether entries of the keydir file are still being moved to memory.
+    pub fn is_loading(&self) -> bool {
+        self.inner.base.read().unwrap().is_some()
+    }
+
+    /// Snapshot all live entries, with entries from memory taking precedence over the keydir.
+    fn snapshot(&self, base: &KeydirFile) -> BTree {
+        let shadowed = self.inner.shadowed.lock().unwrap();
+        let tree = BTree::new();
+        for i in 0..base.len() {
+            if let Some((key, pos)) = base.entry(i) {
+                if !shadowed.contains(key) {
+                    tree.put(key.to_vec(), pos);
+                }
+            }
+        }
+        let mut iter = self.inner.delta.iterator(IteratorOptions::default());
+        while let Some((key, pos)) = iter.next() {
+            tree.put(key.clone(), *pos);
+        }
+        tree
+    }
+
+    fn base(&self) -> Option<Arc<KeydirFile>> {
+        self.inner.base.read().unwrap().clone()
+    }
+}
+
+impl LayeredIndexInner {
+    fn load_in_background(weak: Weak<LayeredIndexInner>) {
+        let mut next = 0;
+        loop {
+            // Stop as soon as the engine is dropped.
+            let inner = match weak.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+            let mut shadowed = inner.shadowed.lock().unwrap();
+            let base = match inner.base.read().unwrap().clone() {
+                Some(base) => base,
+                None => return,
+            };
+
+            let end = base.len().min(next + KEYDIR_LOAD_BATCH);
+            for i in next..end {
+                if let Some((key, pos)) = base.entry(i) {
+                    if !shadowed.contains(key) && inner.delta.get(key.to_vec()).is_none() {
+                        inner.delta.put(key.to_vec(), pos);
+                    }
+                }
+            }
+            next = end;
+
+            if next >= base.len() {
+                *inner.base.write().unwrap() = None;
+                shadowed.clear();
+                return;
+            }
+        }
+    }
+}
+
+impl Indexer for LayeredIndex {
+    fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> Option<LogRecordPos> {
+        let mut shadowed = self.inner.shadowed.lock().unwrap();
+        let old_pos = self.inner.delta.put(key.clone(), pos);
+        if old_pos.is_some() || shadowed.contains(&key) {
+            return old_pos;
+        }
+        let base_pos = self.base().and_then(|base| base.get(&key));
+        if base_pos.is_some() {
+            shadowed.insert(key);
+        }
+        base_pos
+    }
+
+    fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        let base = match self.base() {
+            Some(base) => base,
+            None => return self.inner.delta.get(key),
+        };
+        let shadowed = self.inner.shadowed.lock().unwrap();
+        if let Some(pos) = self.inner.delta.get(key.clone()) {
+            return Some(pos);
+        }
+        if shadowed.contains(&key) {
+            return None;
+        }
+        base.get(&key)
+    }
+
+    fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        let mut shadowed = self.inner.shadowed.lock().unwrap();
+        let old_pos = self.inner.delta.delete(key.clone());
+        if shadowed.contains(&key) {
+            return old_pos;
+        }
+        let base_pos = self.base().and_then(|base| base.get(&key));
+        if base_pos.is_some() {
+            shadowed.insert(key);
+        }
+        old_pos.or(base_pos)
+    }
+
+    fn compare_and_put(&self, key: Vec<u8>, expected: LogRecordPos, pos: LogRecordPos) -> bool {
+        let mut shadowed = self.inner.shadowed.lock().unwrap();
+        if self.inner.delta.get(key.clone()).is_some() {
+            return self.inner.delta.compare_and_put(key, expected, pos);
+        }
+        if shadowed.contains(&key) {
+            return false;
+        }
+        match self.base().and_then(|base| base.get(&key)) {
+            Some(base_pos) if base_pos == expected => {
+                self.inner.delta.put(key.clone(), pos);
+                shadowed.insert(key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn compare_and_delete(&self, key: Vec<u8>, expected: LogRecordPos) -> bool {
+        let mut shadowed = self.inner.shadowed.lock().unwrap();
+        // A key in the delta already shadows its base entry, if any.
+        if self.inner.delta.get(key.clone()).is_some() {
+            return self.inner.delta.compare_and_delete(key, expected);
+        }
+        if shadowed.contains(&key) {
+            return false;
+        }
+        match self.base().and_then(|base| base.get(&key)) {
+            Some(base_pos) if base_pos == expected => {
+                shadowed.insert(key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn list_keys(&self) -> Result<Vec<Bytes>> {
+        match self.base() {
+            Some(base) => self.snapshot(&base).list_keys(),
+            None => self.inner.delta.list_keys(),
+        }
+    }
+
+    fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
+        match self.base() {
+            Some(base) => self.snapshot(&base).iterator(options),
+            None => self.inner.delta.iterator(options),
+        }
+    }
+
+    fn memory_usage(&self) -> IndexMemoryUsage {
+        self.inner.delta.memory_usage()
+    }
+
+    fn compact(&self) {
+        self.inner.delta.compact()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn write_test_keydir(dir_path: &Path, n: u32) {
+        let bt = BTree::new();
+        for i in 0..n {
+            bt.put(
+                std::format!("key-{:05}", i).into_bytes(),
+                LogRecordPos {
+                    file_id: i as u64,
+                    ofs: i as u64 * 10,
+                    size: 10,
+                    expire_at: (i % 2 == 1).then_some(i as u64 * 1000),
+                },
+            );
+        }
+        let mut iter = bt.iterator(IteratorOptions::default());
+        KeydirFile::write(dir_path, iter.as_mut(), 7, 1234, 3).unwrap();
+    }
+
+    #[test]
+    fn test_keydir_file_get() {
+        let dir_path = PathBuf::from("/tmp/keydir-file-get");
+        fs::create_dir_all(dir_path.clone()).unwrap();
+        write_test_keydir(&dir_path, 1000);
+
+        let keydir = KeydirFile::open(&dir_path).unwrap();
+        assert_eq!(keydir.len(), 1000);
+        assert_eq!(keydir.active_file_id(), 7);
+        assert_eq!(keydir.active_ofs(), 1234);
+        assert_eq!(keydir.sequence_number(), 3);
+        assert!(keydir.has_expiries());
+
+        let pos = keydir.get(b"key-00123").unwrap();
+        assert_eq!(pos.file_id, 123);
+        assert_eq!(pos.ofs, 1230);
+        assert_eq!(pos.expire_at, Some(123_000));
+        assert_eq!(keydir.get(b"key-00124").unwrap().expire_at, None);
+        assert!(keydir.get(b"key-01000").is_none());
+        assert!(keydir.get(b"").is_none());
+
+        assert!(KeydirFile::remove(&dir_path).is_ok());
+        assert!(KeydirFile::open(&dir_path).is_none());
+        assert!(KeydirFile::remove(&dir_path).is_ok());
+        fs::remove_dir_all(dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_keydir_file_v1() {
+        let dir_path = PathBuf::from("/tmp/keydir-file-v1");
+        fs::create_dir_all(dir_path.clone()).unwrap();
+
+        // A keydir written with u32 file ids, holding a single entry.
+        let mut header = Vec::new();
+        header.extend_from_slice(KEYDIR_MAGIC_V1);
+        header.extend_from_slice(&1u64.to_le_bytes());
+        header.extend_from_slice(&7u32.to_le_bytes());
+        header.extend_from_slice(&1234u64.to_le_bytes());
+        header.extend_from_slice(&3u64.to_le_bytes());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        header.extend_from_slice(&hasher.finalize().to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&3u32.to_le_bytes());
+        header.extend_from_slice(b"key");
+        header.extend_from_slice(&5u32.to_le_bytes());
+        header.extend_from_slice(&50u64.to_le_bytes());
+        header.extend_from_slice(&10u32.to_le_bytes());
+        fs::write(dir_path.join(KEYDIR_FILE_NAME), header).unwrap();
+
+        let keydir = KeydirFile::open(&dir_path).unwrap();
+        assert_eq!(keydir.len(), 1);
+        assert_eq!(keydir.active_file_id(), 7);
+        assert_eq!(keydir.active_ofs(), 1234);
+        assert_eq!(keydir.sequence_number(), 3);
+        assert!(!keydir.has_expiries());
+        let pos = keydir.get(b"key").unwrap();
+        assert_eq!((pos.file_id, pos.ofs, pos.size), (5, 50, 10));
+        fs::remove_dir_all(dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_layered_index() {
+        let dir_path = PathBuf::from("/tmp/keydir-layered-index");
+        fs::create_dir_all(dir_path.clone()).unwrap();
+        write_test_keydir(&dir_path, 5000);
+
+        let pos = LogRecordPos {
+            file_id: 100,
+            ofs: 0,
+            size: 1,
+            expire_at: None,
+        };
+        let index = LayeredIndex::new(KeydirFile::open(&dir_path).unwrap(), Box::new(BTree::new()));
+        assert!(index.get(b"key-00001".to_vec()).is_some());
+        assert_eq!(index.put(b"key-00002".to_vec(), pos).unwrap().file_id, 2);
+        assert_eq!(index.delete(b"key-00003".to_vec()).unwrap().file_id, 3);
+        assert!(index.put(b"new-key".to_vec(), pos).is_none());
+
+        while index.is_loading() {
+            thread::yield_now();
+        }
+        assert_eq!(index.get(b"key-00001".to_vec()).unwrap().file_id, 1);
+        assert_eq!(index.get(b"key-00002".to_vec()).unwrap().file_id, 100);
+        assert!(index.get(b"key-00003".to_vec()).is_none());
+        assert_eq!(index.list_keys().unwrap().len(), 5000);
+
+        fs::remove_dir_all(dir_path).unwrap();
+    }
+}
@@ -1,8 +1,12 @@
+pub mod arena;
 pub mod bptree;
 pub mod btree;
+pub mod hash;
+pub mod keydir;
+pub mod sharded_btree;
 pub mod skiplist;
 
-use std::path::PathBuf;
+use std::{ops::Bound, path::PathBuf};
 
 use bytes::Bytes;
 
@@ -12,6 +16,8 @@ use crate::{
     options::{IndexType, IteratorOptions},
 };
 
+use self::arena::ArenaStats;
+
 /// Interface for data indexing abstraction.
 pub trait Indexer: Sync + Send {
     /// Write KEY to INDEXER at position POS.
@@ -23,22 +29,85 @@ pub trait Indexer: Sync + Send {
     /// Delete the index associate with key KEY in the INDEXER.
     fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos>;
 
+    /// Move KEY to position POS only if it is currently at EXPECTED, return whether it is moved.
+    fn compare_and_put(&self, key: Vec<u8>, expected: LogRecordPos, pos: LogRecordPos) -> bool;
+
+    /// Delete KEY only if it is currently at EXPECTED, return whether it is deleted.
+    fn compare_and_delete(&self, key: Vec<u8>, expected: LogRecordPos) -> bool;
+
     /// Get all keys contained in the engine.
     fn list_keys(&self) -> Result<Vec<Bytes>>;
 
     /// Get the index iterator.
     fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator>;
+
+    /// Get the index iterator through the keys between START and END in ascending order. `seek`
+    /// and `rewind` never move the iterator out of the range.
+    fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Box<dyn IndexIterator> {
+        Box::new(RangeIterator::new(
+            self.iterator(IteratorOptions::default()),
+            (start, end),
+        ))
+    }
+
+    /// Get the memory consumed by the indexer. Disk-resident indexers report nothing.
+    fn memory_usage(&self) -> IndexMemoryUsage {
+        IndexMemoryUsage::default()
+    }
+
+    /// Persist the state kept in memory alongside the index, called when the engine is shut
+    /// down.
+    fn persist(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Rebuild the state derived from the keys of the index, such as the arena holding the keys
+    /// once most of them are deleted, called once a merge completes.
+    fn compact(&self) {}
+}
+
+/// Memory consumed by an indexer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IndexMemoryUsage {
+    /// Number of entries held in memory.
+    pub entry_num: usize,
+
+    /// Statistics of the arena storing the keys, if any.
+    pub arena: Option<ArenaStats>,
+
+    /// Estimated bytes of memory held by the entries and their keys.
+    pub estimated_bytes: usize,
 }
 
-pub fn new_indexer(index_type: IndexType, dir_path: PathBuf) -> Box<dyn Indexer> {
+impl IndexMemoryUsage {
+    /// Usage of ENTRY_NUM entries of ENTRY_SIZE bytes each, with keys stored in an arena of ARENA.
+    pub(crate) fn with_arena(entry_num: usize, entry_size: usize, arena: ArenaStats) -> Self {
+        Self {
+            entry_num,
+            arena: Some(arena),
+            estimated_bytes: entry_num * entry_size + arena.reserved_bytes,
+        }
+    }
+}
+
+pub fn new_indexer(index_type: IndexType, dir_path: PathBuf, shard_num: usize) -> Box<dyn Indexer> {
     match index_type {
+        IndexType::BTree if shard_num > 1 => Box::new(sharded_btree::ShardedBTree::new(shard_num)),
         IndexType::BTree => Box::new(btree::BTree::new()),
         IndexType::BPTree => Box::new(bptree::BPTree::new(dir_path)),
         IndexType::SkipList => Box::new(skiplist::SkipList::new()),
+        IndexType::Hash => Box::new(hash::HashIndex::new()),
     }
 }
 
-/// Interface for indexer iterator.
+/// Range of keys, given by its lower and upper bounds.
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Number of entries read at once by the iterators reading the index lazily.
+pub(crate) const INDEX_ITERATOR_BATCH_SIZE: usize = 256;
+
+/// Interface for indexer iterator. Implementations may read the index lazily, in which case the
+/// writes made after the iterator is created can be observed.
 pub trait IndexIterator: Sync + Send {
     /// Start the iterator to the beginning of all items.
     fn rewind(&mut self);
@@ -48,4 +117,276 @@ pub trait IndexIterator: Sync + Send {
 
     /// Go to the next item of the iterator.
     fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)>;
+
+    /// Go back to the previous item of the iterator, so `prev` right after `next` returns the
+    /// same item. Return None without moving at the beginning of all items.
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)>;
+}
+
+/// Iterator through the keys of `inner` within `range`, for indexers unable to bound their own
+/// iterators. `current` stores the item returned by the last call to `next`.
+pub struct RangeIterator {
+    inner: Box<dyn IndexIterator>,
+    range: KeyRange,
+    current: Option<(Vec<u8>, LogRecordPos)>,
+}
+
+impl RangeIterator {
+    pub fn new(inner: Box<dyn IndexIterator>, range: KeyRange) -> Self {
+        let mut iter = Self {
+            inner,
+            range,
+            current: None,
+        };
+        iter.rewind();
+        iter
+    }
+}
+
+impl IndexIterator for RangeIterator {
+    fn rewind(&mut self) {
+        match &self.range.0 {
+            Bound::Included(start) | Bound::Excluded(start) => self.inner.seek(start.clone()),
+            Bound::Unbounded => self.inner.rewind(),
+        }
+    }
+
+    fn seek(&mut self, key: Vec<u8>) {
+        match &self.range.0 {
+            Bound::Included(start) | Bound::Excluded(start) if key < *start => self.rewind(),
+            _ => self.inner.seek(key),
+        }
+    }
+
+    fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        self.current = None;
+        while let Some((key, pos)) = self.inner.next() {
+            if matches!(&self.range.0, Bound::Excluded(start) if key == start) {
+                continue;
+            }
+            let is_above = match &self.range.1 {
+                Bound::Included(end) => key > end,
+                Bound::Excluded(end) => key >= end,
+                Bound::Unbounded => false,
+            };
+            if !is_above {
+                self.current = Some((key.clone(), *pos));
+            }
+            break;
+        }
+        self.current.as_ref().map(|(key, pos)| (key, pos))
+    }
+
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        self.current = None;
+        while let Some((key, pos)) = self.inner.prev() {
+            let is_below = match &self.range.0 {
+                Bound::Included(start) => key < start,
+                Bound::Excluded(start) => key <= start,
+                Bound::Unbounded => false,
+            };
+            if is_below {
+                // Stay at the beginning of the range.
+                self.inner.next();
+                break;
+            }
+            let is_above = match &self.range.1 {
+                Bound::Included(end) => key > end,
+                Bound::Excluded(end) => key >= end,
+                Bound::Unbounded => false,
+            };
+            if !is_above {
+                self.current = Some((key.clone(), *pos));
+                break;
+            }
+        }
+        self.current.as_ref().map(|(key, pos)| (key, pos))
+    }
+}
+
+/// Get the range of keys before the position of a lazy iterator with OPTIONS through RANGE,
+/// along with the options to read them backwards. The iterator is positioned right before
+/// NEXT_KEY if any, or else at BOUND as given to `remaining_range`.
+pub(crate) fn preceding_range(
+    next_key: Option<&Vec<u8>>,
+    bound: &Bound<Vec<u8>>,
+    range: &KeyRange,
+    options: &IteratorOptions,
+) -> Option<(KeyRange, IteratorOptions)> {
+    let boundary = match (next_key, bound) {
+        (Some(key), _) | (None, Bound::Included(key)) => Bound::Excluded(key.clone()),
+        (None, Bound::Excluded(key)) => Bound::Included(key.clone()),
+        (None, Bound::Unbounded) => return None,
+    };
+    let options = IteratorOptions {
+        reverse: !options.reverse,
+        ..options.clone()
+    };
+    remaining_range(&boundary, range, &options).map(|range| (range, options))
+}
+
+/// Get the range of keys between the `lower_bound` and `upper_bound` of OPTIONS, starting right
+/// after its `cursor` if any.
+pub(crate) fn bounded_range(options: &IteratorOptions) -> KeyRange {
+    let mut lower = match &options.lower_bound {
+        Some(key) => Bound::Included(key.clone()),
+        None => Bound::Unbounded,
+    };
+    let mut upper = match &options.upper_bound {
+        Some(key) => Bound::Excluded(key.clone()),
+        None => Bound::Unbounded,
+    };
+    if let Some(key) = &options.cursor {
+        match options.reverse {
+            true => upper = tighter_bound(upper, Bound::Excluded(key.clone()), true),
+            false => lower = tighter_bound(lower, Bound::Excluded(key.clone()), false),
+        }
+    }
+    (lower, upper)
+}
+
+/// Get the range of keys left to an iterator with OPTIONS through RANGE, where BOUND is the lower
+/// bound of the keys left, or their upper bound if iterating in reverse. The range is narrowed to
+/// the keys with the prefix of OPTIONS. Return None if the range is empty.
+pub(crate) fn remaining_range(
+    bound: &Bound<Vec<u8>>,
+    range: &KeyRange,
+    options: &IteratorOptions,
+) -> Option<KeyRange> {
+    let prefix = &options.prefix;
+    let prefix_lower = match prefix.is_empty() {
+        true => Bound::Unbounded,
+        false => Bound::Included(prefix.clone()),
+    };
+    let prefix_upper = match prefix_successor(prefix) {
+        Some(successor) => Bound::Excluded(successor),
+        None => Bound::Unbounded,
+    };
+
+    let (lower, upper) = match options.reverse {
+        true => (
+            prefix_lower,
+            tighter_bound(bound.clone(), prefix_upper, true),
+        ),
+        false => (
+            tighter_bound(bound.clone(), prefix_lower, false),
+            prefix_upper,
+        ),
+    };
+    let lower = tighter_bound(lower, range.0.clone(), false);
+    let upper = tighter_bound(upper, range.1.clone(), true);
+    let is_empty = match (&lower, &upper) {
+        (Bound::Included(l), Bound::Included(u)) => l > u,
+        (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u)) => {
+            l >= u
+        }
+        _ => false,
+    };
+    (!is_empty).then_some((lower, upper))
+}
+
+/// Get the smallest key greater than all keys with PREFIX, or None if there is no such key.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < u8::MAX {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+    None
+}
+
+/// Get the tighter of the lower bounds A and B, or of the upper bounds if IS_UPPER.
+fn tighter_bound(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>, is_upper: bool) -> Bound<Vec<u8>> {
+    let (a_key, b_key) = match (&a, &b) {
+        (Bound::Unbounded, _) => return b,
+        (_, Bound::Unbounded) => return a,
+        (
+            Bound::Included(a_key) | Bound::Excluded(a_key),
+            Bound::Included(b_key) | Bound::Excluded(b_key),
+        ) => (a_key, b_key),
+    };
+    match a_key.cmp(b_key) {
+        std::cmp::Ordering::Equal if matches!(a, Bound::Excluded(_)) => a,
+        std::cmp::Ordering::Equal => b,
+        std::cmp::Ordering::Less if is_upper => a,
+        std::cmp::Ordering::Greater if !is_upper => a,
+        _ => b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remaining_range() {
+        let full = (Bound::Unbounded, Bound::Unbounded);
+        let mut options = IteratorOptions::default();
+        assert_eq!(
+            remaining_range(&Bound::Unbounded, &full, &options),
+            Some((Bound::Unbounded, Bound::Unbounded))
+        );
+        assert_eq!(
+            remaining_range(&Bound::Excluded(b"b".to_vec()), &full, &options),
+            Some((Bound::Excluded(b"b".to_vec()), Bound::Unbounded))
+        );
+
+        options.prefix = b"a\xff".to_vec();
+        assert_eq!(
+            remaining_range(&Bound::Included(b"a".to_vec()), &full, &options),
+            Some((
+                Bound::Included(b"a\xff".to_vec()),
+                Bound::Excluded(b"b".to_vec())
+            ))
+        );
+        assert_eq!(
+            remaining_range(&Bound::Excluded(b"b".to_vec()), &full, &options),
+            None
+        );
+
+        options.reverse = true;
+        assert_eq!(
+            remaining_range(&Bound::Included(b"c".to_vec()), &full, &options),
+            Some((
+                Bound::Included(b"a\xff".to_vec()),
+                Bound::Excluded(b"b".to_vec())
+            ))
+        );
+        assert_eq!(
+            remaining_range(&Bound::Included(b"a\xff".to_vec()), &full, &options),
+            Some((
+                Bound::Included(b"a\xff".to_vec()),
+                Bound::Included(b"a\xff".to_vec())
+            ))
+        );
+        assert_eq!(
+            remaining_range(&Bound::Excluded(b"a\xff".to_vec()), &full, &options),
+            None
+        );
+
+        options.prefix = vec![u8::MAX];
+        assert_eq!(
+            remaining_range(&Bound::Unbounded, &full, &options),
+            Some((Bound::Included(vec![u8::MAX]), Bound::Unbounded))
+        );
+
+        let range = (
+            Bound::Excluded(b"b".to_vec()),
+            Bound::Included(b"d".to_vec()),
+        );
+        options = IteratorOptions::default();
+        assert_eq!(
+            remaining_range(&Bound::Included(b"a".to_vec()), &range, &options),
+            Some((
+                Bound::Excluded(b"b".to_vec()),
+                Bound::Included(b"d".to_vec())
+            ))
+        );
+        assert_eq!(
+            remaining_range(&Bound::Excluded(b"d".to_vec()), &range, &options),
+            None
+        );
+    }
 }
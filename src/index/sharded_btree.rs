@@ -0,0 +1,358 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher, ops::Bound};
+
+use bytes::Bytes;
+
+use crate::{data::log_record::LogRecordPos, errors::Result, options::IteratorOptions};
+
+use super::{arena::ArenaStats, btree::BTree, IndexIterator, IndexMemoryUsage, Indexer};
+
+/// BTree indexer split into shards by key hash, each with its own lock and key arena, so that
+/// concurrent writers of different keys rarely wait for each other.
+pub struct ShardedBTree {
+    shards: Vec<BTree>,
+    hasher: RandomState,
+}
+
+impl ShardedBTree {
+    pub fn new(shard_num: usize) -> Self {
+        Self {
+            shards: (0..shard_num).map(|_| BTree::new()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &BTree {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+}
+
+impl Indexer for ShardedBTree {
+    fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> Option<LogRecordPos> {
+        self.shard(&key).put(key, pos)
+    }
+
+    fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        self.shard(&key).get(key)
+    }
+
+    fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        self.shard(&key).delete(key)
+    }
+
+    fn compare_and_put(&self, key: Vec<u8>, expected: LogRecordPos, pos: LogRecordPos) -> bool {
+        self.shard(&key).compare_and_put(key, expected, pos)
+    }
+
+    fn compare_and_delete(&self, key: Vec<u8>, expected: LogRecordPos) -> bool {
+        self.shard(&key).compare_and_delete(key, expected)
+    }
+
+    fn list_keys(&self) -> Result<Vec<Bytes>> {
+        let mut iter = self.iterator(IteratorOptions::default());
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(Bytes::copy_from_slice(key));
+        }
+        Ok(keys)
+    }
+
+    fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
+        let children = self
+            .shards
+            .iter()
+            .map(|shard| shard.iterator(options.clone()))
+            .collect();
+        Box::new(MergingIterator::new(children, options.reverse))
+    }
+
+    fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Box<dyn IndexIterator> {
+        let children = self
+            .shards
+            .iter()
+            .map(|shard| shard.range(start.clone(), end.clone()))
+            .collect();
+        Box::new(MergingIterator::new(children, false))
+    }
+
+    fn memory_usage(&self) -> IndexMemoryUsage {
+        let mut usage = IndexMemoryUsage {
+            entry_num: 0,
+            arena: Some(ArenaStats::default()),
+            estimated_bytes: 0,
+        };
+        for shard in &self.shards {
+            let shard_usage = shard.memory_usage();
+            usage.entry_num += shard_usage.entry_num;
+            usage.estimated_bytes += shard_usage.estimated_bytes;
+            if let (Some(total), Some(arena)) = (usage.arena.as_mut(), shard_usage.arena) {
+                total.chunk_num += arena.chunk_num;
+                total.reserved_bytes += arena.reserved_bytes;
+                total.allocated_bytes += arena.allocated_bytes;
+                total.dead_bytes += arena.dead_bytes;
+            }
+        }
+        usage
+    }
+
+    fn compact(&self) {
+        for shard in &self.shards {
+            shard.compact();
+        }
+    }
+}
+
+/// Iterator merging the sorted iterators of each shard, where:
+/// - `children` are the iterators of the shards, in the same direction.
+/// - `heads` stores the next item of each child.
+/// - `current` stores the item returned by the last call to `next`.
+/// - `reverse` indicates whether the children iterate in descending key order.
+pub struct MergingIterator {
+    children: Vec<Box<dyn IndexIterator>>,
+    heads: Vec<Option<(Vec<u8>, LogRecordPos)>>,
+    current: Option<(Vec<u8>, LogRecordPos)>,
+    reverse: bool,
+}
+
+impl MergingIterator {
+    fn new(children: Vec<Box<dyn IndexIterator>>, reverse: bool) -> Self {
+        let mut iter = Self {
+            heads: vec![None; children.len()],
+            children,
+            current: None,
+            reverse,
+        };
+        iter.fill_heads();
+        iter
+    }
+
+    fn fill_heads(&mut self) {
+        for i in 0..self.children.len() {
+            self.fill_head(i);
+        }
+    }
+
+    fn fill_head(&mut self, i: usize) {
+        self.heads[i] = self.children[i]
+            .next()
+            .map(|(key, pos)| (key.clone(), *pos));
+    }
+}
+
+impl IndexIterator for MergingIterator {
+    fn rewind(&mut self) {
+        for child in self.children.iter_mut() {
+            child.rewind();
+        }
+        self.fill_heads();
+    }
+
+    fn seek(&mut self, key: Vec<u8>) {
+        for child in self.children.iter_mut() {
+            child.seek(key.clone());
+        }
+        self.fill_heads();
+    }
+
+    fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        // Keys are unique across shards, so the first key in the iteration order is taken.
+        let mut next: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let key = match head {
+                Some((key, _)) => key,
+                None => continue,
+            };
+            let is_first = match next.and_then(|j| self.heads[j].as_ref()) {
+                Some((first, _)) if self.reverse => key > first,
+                Some((first, _)) => key < first,
+                None => true,
+            };
+            if is_first {
+                next = Some(i);
+            }
+        }
+
+        let i = next?;
+        self.current = self.heads[i].take();
+        self.fill_head(i);
+        self.current.as_ref().map(|(key, pos)| (key, pos))
+    }
+
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        // Put back the prefetched heads, so every child is positioned like this iterator.
+        for (child, head) in self.children.iter_mut().zip(self.heads.iter()) {
+            if head.is_some() {
+                child.prev();
+            }
+        }
+        let candidates: Vec<_> = self
+            .children
+            .iter_mut()
+            .map(|child| child.prev().map(|(key, pos)| (key.clone(), *pos)))
+            .collect();
+
+        // The previous item is the last candidate in the iteration order.
+        let mut prev: Option<usize> = None;
+        for (i, candidate) in candidates.iter().enumerate() {
+            let key = match candidate {
+                Some((key, _)) => key,
+                None => continue,
+            };
+            let is_last = match prev.and_then(|j| candidates[j].as_ref()) {
+                Some((last, _)) if self.reverse => key < last,
+                Some((last, _)) => key > last,
+                None => true,
+            };
+            if is_last {
+                prev = Some(i);
+            }
+        }
+        for (i, candidate) in candidates.iter().enumerate() {
+            if candidate.is_some() && Some(i) != prev {
+                self.children[i].next();
+            }
+        }
+        self.fill_heads();
+
+        self.current = prev.and_then(|i| candidates[i].clone());
+        self.current.as_ref().map(|(key, pos)| (key, pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use super::*;
+
+    fn pos(file_id: u64, ofs: u64) -> LogRecordPos {
+        LogRecordPos {
+            file_id,
+            ofs,
+            size: 11,
+            expire_at: None,
+        }
+    }
+
+    #[test]
+    fn test_sharded_btree_put_get_delete() {
+        let index = ShardedBTree::new(4);
+        for i in 0..100u64 {
+            let key = format!("key-{:03}", i).into_bytes();
+            assert!(index.put(key, pos(1, i)).is_none());
+        }
+        assert_eq!(index.put(b"key-010".to_vec(), pos(2, 0)).unwrap().ofs, 10);
+        assert_eq!(index.get(b"key-010".to_vec()).unwrap().file_id, 2);
+        assert!(index.delete(b"key-020".to_vec()).is_some());
+        assert!(index.get(b"key-020".to_vec()).is_none());
+        assert!(index.compare_and_delete(b"key-030".to_vec(), pos(1, 30)));
+
+        let usage = index.memory_usage();
+        assert_eq!(usage.entry_num, 98);
+        assert_eq!(usage.arena.unwrap().allocated_bytes, 700);
+
+        let keys = index.list_keys().unwrap();
+        assert_eq!(keys.len(), 98);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_sharded_btree_iterator() {
+        let index = ShardedBTree::new(3);
+        for (i, key) in ["ccde", "bbed", "aaed", "cadd", "bbaa"].iter().enumerate() {
+            index.put(key.as_bytes().to_vec(), pos(1, i as u64));
+        }
+
+        let mut iter1 = index.iterator(IteratorOptions::default());
+        iter1.seek("b".as_bytes().to_vec());
+        assert_eq!(iter1.next().unwrap().0, &b"bbaa".to_vec());
+        assert_eq!(iter1.next().unwrap().0, &b"bbed".to_vec());
+        assert_eq!(iter1.next().unwrap().0, &b"cadd".to_vec());
+        assert_eq!(iter1.next().unwrap().0, &b"ccde".to_vec());
+        assert!(iter1.next().is_none());
+
+        iter1.rewind();
+        assert_eq!(iter1.next().unwrap().0, &b"aaed".to_vec());
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.reverse = true;
+        iter_opts.prefix = b"bb".to_vec();
+        let mut iter2 = index.iterator(iter_opts);
+        assert_eq!(iter2.next().unwrap().0, &b"bbed".to_vec());
+        assert_eq!(iter2.next().unwrap().0, &b"bbaa".to_vec());
+        assert!(iter2.next().is_none());
+    }
+
+    #[test]
+    fn test_sharded_btree_iterator_prev() {
+        let index = ShardedBTree::new(3);
+        let keys: Vec<Vec<u8>> = (0..50)
+            .map(|i| format!("key-{:03}", i).into_bytes())
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            index.put(key.clone(), pos(1, i as u64));
+        }
+
+        let mut iter = index.iterator(IteratorOptions::default());
+        assert!(iter.prev().is_none());
+        for key in keys.iter().take(20) {
+            assert_eq!(iter.next().unwrap().0, key);
+        }
+        for key in keys[..20].iter().rev() {
+            assert_eq!(iter.prev().unwrap().0, key);
+        }
+        assert!(iter.prev().is_none());
+        assert_eq!(iter.next().unwrap().0, &keys[0]);
+        assert_eq!(iter.next().unwrap().0, &keys[1]);
+
+        while iter.next().is_some() {}
+        assert_eq!(iter.prev().unwrap().0, &keys[49]);
+        assert_eq!(iter.prev().unwrap().0, &keys[48]);
+        assert_eq!(iter.next().unwrap().0, &keys[48]);
+        assert_eq!(iter.next().unwrap().0, &keys[49]);
+    }
+
+    #[test]
+    fn test_sharded_btree_range() {
+        let index = ShardedBTree::new(4);
+        for i in 0..100u64 {
+            let pos = LogRecordPos {
+                file_id: 1,
+                ofs: i,
+                size: 11,
+                expire_at: None,
+            };
+            index.put(format!("key-{:03}", i).into_bytes(), pos);
+        }
+
+        let mut iter = index.range(
+            Bound::Excluded(b"key-010".to_vec()),
+            Bound::Included(b"key-020".to_vec()),
+        );
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(key.clone());
+        }
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys[0], b"key-011".to_vec());
+        assert_eq!(keys[9], b"key-020".to_vec());
+
+        // Seeking never moves the iterator out of the range.
+        iter.seek(b"key-000".to_vec());
+        assert_eq!(iter.next().unwrap().0, &b"key-011".to_vec());
+        iter.seek(b"key-015".to_vec());
+        assert_eq!(iter.next().unwrap().0, &b"key-015".to_vec());
+        iter.seek(b"key-050".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut iter = index.range(Bound::Included(b"key-099".to_vec()), Bound::Unbounded);
+        assert_eq!(iter.next().unwrap().0, &b"key-099".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut iter = index.range(
+            Bound::Included(b"key-020".to_vec()),
+            Bound::Excluded(b"key-010".to_vec()),
+        );
+        assert!(iter.next().is_none());
+    }
+}
@@ -1,61 +1,133 @@
-use std::sync::Arc;
+use std::{
+    ops::Bound,
+    sync::{Arc, RwLock},
+};
 
+use arc_swap::ArcSwap;
 use bytes::Bytes;
 use crossbeam_skiplist::SkipMap;
 
 use crate::{data::log_record::LogRecordPos, errors::Result, options::IteratorOptions};
 
-use super::{IndexIterator, Indexer};
+use super::{
+    arena::{ArenaKey, KeyArena},
+    bounded_range, remaining_range, IndexIterator, IndexMemoryUsage, Indexer, KeyRange,
+};
 
+/// Estimated memory of a skiplist entry, including its node header and links.
+const SKIPLIST_ENTRY_SIZE: usize = size_of::<(ArenaKey, LogRecordPos)>() + 4 * size_of::<usize>();
+
+/// Skiplist indexer, where keys are stored in an arena and the skiplist holds references to them.
+/// Both are replaced at once by `compact`, so readers never lock, while writers take `write_lock`
+/// for reading so that none of their writes is lost while the skiplist is rebuilt.
 pub struct SkipList {
-    skl: Arc<SkipMap<Vec<u8>, LogRecordPos>>,
+    state: ArcSwap<ArenaSkipMap>,
+    write_lock: RwLock<()>,
+}
+
+/// A skiplist along with the arena storing its keys. `skl` is declared first so it is dropped
+/// before `arena`.
+struct ArenaSkipMap {
+    skl: SkipMap<ArenaKey, LogRecordPos>,
+    arena: KeyArena,
 }
 
 impl SkipList {
     pub fn new() -> Self {
         Self {
-            skl: Arc::new(SkipMap::new()),
+            state: ArcSwap::from_pointee(ArenaSkipMap {
+                skl: SkipMap::new(),
+                arena: KeyArena::new(),
+            }),
+            write_lock: RwLock::new(()),
         }
     }
+
+    /// Collect the items of the skiplist in RANGE.
+    fn collect(&self, range: &KeyRange, options: &IteratorOptions) -> Vec<(Vec<u8>, LogRecordPos)> {
+        let mut items = Vec::new();
+        if let Some((start, end)) = remaining_range(&Bound::Unbounded, range, options) {
+            let range = (
+                start.as_ref().map(|key| key.as_slice()),
+                end.as_ref().map(|key| key.as_slice()),
+            );
+            for e in self.state.load().skl.range::<[u8], _>(range) {
+                items.push((e.key().as_slice().to_vec(), *e.value()));
+            }
+        }
+        items
+    }
 }
 
 impl Indexer for SkipList {
     fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> Option<LogRecordPos> {
-        let mut result = None;
-        if let Some(entry) = self.skl.get(&key) {
-            result = Some(*entry.value());
+        let _write_lock = self.write_lock.read().unwrap();
+        let state = self.state.load();
+        // Reuse the key stored in arena if the key already exists.
+        if let Some(entry) = state.skl.get(key.as_slice()) {
+            let result = Some(*entry.value());
+            state.skl.insert(*entry.key(), pos);
+            return result;
         }
-        self.skl.insert(key, pos);
-        result
+        state.skl.insert(state.arena.alloc(&key), pos);
+        None
     }
 
     fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
-        match self.skl.get(&key) {
-            Some(e) => Some(*e.value()),
-            None => None,
-        }
+        self.state
+            .load()
+            .skl
+            .get(key.as_slice())
+            .map(|e| *e.value())
     }
 
     fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos> {
-        match self.skl.remove(&key) {
-            Some(entry) => Some(*entry.value()),
-            None => None,
+        let _write_lock = self.write_lock.read().unwrap();
+        let state = self.state.load();
+        let entry = state.skl.remove(key.as_slice())?;
+        state.arena.release(entry.key());
+        Some(*entry.value())
+    }
+
+    fn compare_and_put(&self, key: Vec<u8>, expected: LogRecordPos, pos: LogRecordPos) -> bool {
+        let _write_lock = self.write_lock.read().unwrap();
+        let state = self.state.load();
+        let entry = match state.skl.get(key.as_slice()) {
+            Some(entry) if *entry.value() == expected => entry,
+            _ => return false,
+        };
+        let entry = state
+            .skl
+            .compare_insert(*entry.key(), pos, |old_pos| *old_pos == expected);
+        *entry.value() == pos
+    }
+
+    fn compare_and_delete(&self, key: Vec<u8>, expected: LogRecordPos) -> bool {
+        let _write_lock = self.write_lock.read().unwrap();
+        let state = self.state.load();
+        let entry = match state.skl.get(key.as_slice()) {
+            Some(entry) if *entry.value() == expected => entry,
+            _ => return false,
+        };
+        // The entry is not removed if it has been replaced concurrently.
+        if !entry.remove() {
+            return false;
         }
+        state.arena.release(entry.key());
+        true
     }
 
     fn list_keys(&self) -> Result<Vec<bytes::Bytes>> {
-        let mut keys = Vec::with_capacity(self.skl.len());
-        for e in self.skl.iter() {
-            keys.push(Bytes::copy_from_slice(e.key()))
+        let state = self.state.load();
+        let mut keys = Vec::with_capacity(state.skl.len());
+        for e in state.skl.iter() {
+            keys.push(Bytes::copy_from_slice(e.key().as_slice()))
         }
         Ok(keys)
     }
 
     fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
-        let mut items = Vec::with_capacity(self.skl.len());
-        for e in self.skl.iter() {
-            items.push((e.key().clone(), e.value().clone()));
-        }
+        let mut items = self.collect(&bounded_range(&options), &options);
         if options.reverse {
             items.reverse();
         }
@@ -65,6 +137,36 @@ impl Indexer for SkipList {
             options,
         })
     }
+
+    fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Box<dyn IndexIterator> {
+        let options = IteratorOptions::default();
+        let items = self.collect(&(start, end), &options);
+        Box::new(SkipListIterator {
+            items,
+            curr_index: 0,
+            options,
+        })
+    }
+
+    fn memory_usage(&self) -> IndexMemoryUsage {
+        let state = self.state.load();
+        IndexMemoryUsage::with_arena(state.skl.len(), SKIPLIST_ENTRY_SIZE, state.arena.stats())
+    }
+
+    fn compact(&self) {
+        let _write_lock = self.write_lock.write().unwrap();
+        let state = self.state.load();
+        if !state.arena.should_compact() {
+            return;
+        }
+        let arena = KeyArena::new();
+        let skl = SkipMap::new();
+        for e in state.skl.iter() {
+            skl.insert(arena.alloc(e.key().as_slice()), *e.value());
+        }
+        // Readers still holding the old skiplist keep its arena alive until they are done.
+        self.state.store(Arc::new(ArenaSkipMap { skl, arena }));
+    }
 }
 
 /// Iterator for skiplist, where:
@@ -96,23 +198,23 @@ impl IndexIterator for SkipListIterator {
     }
 
     fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
-        if self.curr_index >= self.items.len() {
-            return None;
-        }
+        // Items are collected within the prefix only, so none of them is skipped.
+        let item = self.items.get(self.curr_index)?;
+        self.curr_index += 1;
+        Some((&item.0, &item.1))
+    }
 
-        while let Some(item) = self.items.get(self.curr_index) {
-            self.curr_index += 1;
-            let prefix = &self.options.prefix;
-            if prefix.is_empty() || item.0.starts_with(&prefix) {
-                return Some((&item.0, &item.1));
-            }
-        }
-        None
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        self.curr_index = self.curr_index.checked_sub(1)?;
+        let item = &self.items[self.curr_index];
+        Some((&item.0, &item.1))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::ops::Bound;
+
     use super::*;
 
     #[test]
@@ -124,6 +226,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res1.is_none());
@@ -133,6 +236,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res2.is_none());
@@ -142,6 +246,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res3.is_none());
@@ -151,6 +256,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res4.is_none());
@@ -161,6 +267,7 @@ mod tests {
                 file_id: 93,
                 ofs: 22,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res5.is_some());
@@ -182,6 +289,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res1.is_none());
@@ -194,6 +302,7 @@ mod tests {
                 file_id: 11,
                 ofs: 990,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res2.is_some());
@@ -214,6 +323,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res1.is_none());
@@ -241,6 +351,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res1.is_none());
@@ -250,6 +361,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res2.is_none());
@@ -259,6 +371,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res3.is_none());
@@ -268,6 +381,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res4.is_none());
@@ -286,6 +400,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res1.is_none());
@@ -295,6 +410,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res2.is_none());
@@ -304,6 +420,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res3.is_none());
@@ -313,6 +430,7 @@ mod tests {
                 file_id: 1123,
                 ofs: 1232,
                 size: 11,
+                expire_at: None,
             },
         );
         assert!(res4.is_none());
@@ -325,4 +443,182 @@ mod tests {
             assert!(!key.is_empty());
         }
     }
+
+    #[test]
+    fn test_skl_iterator_seek() {
+        let skl = SkipList::new();
+        let pos = LogRecordPos {
+            file_id: 1,
+            ofs: 10,
+            size: 11,
+            expire_at: None,
+        };
+
+        let mut iter1 = skl.iterator(IteratorOptions::default());
+        iter1.seek("aa".as_bytes().to_vec());
+        assert!(iter1.next().is_none());
+
+        for key in ["ccde", "bbed", "aaed", "cadd"] {
+            skl.put(key.as_bytes().to_vec(), pos);
+        }
+
+        let mut iter2 = skl.iterator(IteratorOptions::default());
+        iter2.seek("b".as_bytes().to_vec());
+        assert_eq!(iter2.next().unwrap().0, &b"bbed".to_vec());
+        assert_eq!(iter2.next().unwrap().0, &b"cadd".to_vec());
+
+        let mut iter3 = skl.iterator(IteratorOptions::default());
+        iter3.seek("zzz".as_bytes().to_vec());
+        assert!(iter3.next().is_none());
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.reverse = true;
+        let mut iter4 = skl.iterator(iter_opts);
+        iter4.seek("bz".as_bytes().to_vec());
+        assert_eq!(iter4.next().unwrap().0, &b"bbed".to_vec());
+        assert_eq!(iter4.next().unwrap().0, &b"aaed".to_vec());
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.reverse = true;
+        iter_opts.lower_bound = Some(b"b".to_vec());
+        iter_opts.upper_bound = Some(b"cc".to_vec());
+        let mut iter5 = skl.iterator(iter_opts);
+        assert_eq!(iter5.next().unwrap().0, &b"cadd".to_vec());
+        assert_eq!(iter5.next().unwrap().0, &b"bbed".to_vec());
+        assert!(iter5.next().is_none());
+        assert!(iter4.next().is_none());
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.prefix = b"ca".to_vec();
+        let mut iter5 = skl.iterator(iter_opts);
+        assert_eq!(iter5.next().unwrap().0, &b"cadd".to_vec());
+        assert!(iter5.next().is_none());
+    }
+
+    #[test]
+    fn test_skl_memory_usage() {
+        let skl = SkipList::new();
+        let pos = LogRecordPos {
+            file_id: 1,
+            ofs: 10,
+            size: 11,
+            expire_at: None,
+        };
+        skl.put("aa".as_bytes().to_vec(), pos);
+        skl.put("bbb".as_bytes().to_vec(), pos);
+        skl.put("aa".as_bytes().to_vec(), pos);
+        skl.delete("bbb".as_bytes().to_vec());
+
+        let usage = skl.memory_usage();
+        assert_eq!(usage.entry_num, 1);
+        let arena = usage.arena.unwrap();
+        assert_eq!(arena.allocated_bytes, 5);
+        assert_eq!(arena.dead_bytes, 3);
+
+        // The dead keys outweigh the live one, so compacting copies it into a new arena.
+        let mut iter = skl.iterator(IteratorOptions::default());
+        skl.compact();
+        let arena = skl.memory_usage().arena.unwrap();
+        assert_eq!(arena.allocated_bytes, 2);
+        assert_eq!(arena.dead_bytes, 0);
+        assert!(skl.get("aa".as_bytes().to_vec()) == Some(pos));
+        assert_eq!(
+            iter.next().map(|(key, _)| key.clone()),
+            Some(b"aa".to_vec())
+        );
+        skl.put("bbb".as_bytes().to_vec(), pos);
+        assert_eq!(skl.list_keys().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_skl_compare_and_delete() {
+        let skl = SkipList::new();
+        let pos = LogRecordPos {
+            file_id: 1,
+            ofs: 10,
+            size: 11,
+            expire_at: None,
+        };
+        skl.put("aa".as_bytes().to_vec(), pos);
+
+        let stale = LogRecordPos { ofs: 0, ..pos };
+        assert!(!skl.compare_and_delete("aa".as_bytes().to_vec(), stale));
+        assert!(skl.get("aa".as_bytes().to_vec()) == Some(pos));
+
+        assert!(skl.compare_and_delete("aa".as_bytes().to_vec(), pos));
+        assert!(skl.get("aa".as_bytes().to_vec()).is_none());
+        assert!(!skl.compare_and_delete("aa".as_bytes().to_vec(), pos));
+    }
+
+    #[test]
+    fn test_skl_concurrent_put() {
+        let skl = SkipList::new();
+        std::thread::scope(|s| {
+            for t in 0..4u64 {
+                let skl = &skl;
+                s.spawn(move || {
+                    for i in 0..1000u64 {
+                        let key = format!("key-{:02}-{:04}", t, i);
+                        let pos = LogRecordPos {
+                            file_id: t,
+                            ofs: i,
+                            size: 11,
+                            expire_at: None,
+                        };
+                        assert!(skl.put(key.into_bytes(), pos).is_none());
+                    }
+                });
+            }
+        });
+
+        let keys = skl.list_keys().unwrap();
+        assert_eq!(keys.len(), 4000);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        let pos = skl.get(b"key-03-0999".to_vec()).unwrap();
+        assert_eq!((pos.file_id, pos.ofs), (3, 999));
+    }
+
+    #[test]
+    fn test_skl_range() {
+        let index = SkipList::new();
+        for i in 0..100u64 {
+            let pos = LogRecordPos {
+                file_id: 1,
+                ofs: i,
+                size: 11,
+                expire_at: None,
+            };
+            index.put(format!("key-{:03}", i).into_bytes(), pos);
+        }
+
+        let mut iter = index.range(
+            Bound::Excluded(b"key-010".to_vec()),
+            Bound::Included(b"key-020".to_vec()),
+        );
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(key.clone());
+        }
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys[0], b"key-011".to_vec());
+        assert_eq!(keys[9], b"key-020".to_vec());
+
+        // Seeking never moves the iterator out of the range.
+        iter.seek(b"key-000".to_vec());
+        assert_eq!(iter.next().unwrap().0, &b"key-011".to_vec());
+        iter.seek(b"key-015".to_vec());
+        assert_eq!(iter.next().unwrap().0, &b"key-015".to_vec());
+        iter.seek(b"key-050".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut iter = index.range(Bound::Included(b"key-099".to_vec()), Bound::Unbounded);
+        assert_eq!(iter.next().unwrap().0, &b"key-099".to_vec());
+        assert!(iter.next().is_none());
+
+        let mut iter = index.range(
+            Bound::Included(b"key-020".to_vec()),
+            Bound::Excluded(b"key-010".to_vec()),
+        );
+        assert!(iter.next().is_none());
+    }
 }
@@ -1,22 +1,46 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc,
+};
+use std::thread;
 
 use bytes::Bytes;
-use std::sync::RwLock;
-
-use crate::{db::Engine, errors::Result, index::IndexIterator, options::IteratorOptions};
-
+use std::sync::{Mutex, RwLock};
+
+use crate::{
+    data::log_record::LogRecordPos,
+    db::Engine,
+    errors::{Errors, Result},
+    index::{btree::BTree, IndexIterator, Indexer},
+    options::IteratorOptions,
+    snapshot::Snapshot,
+};
+
+/// Number of values read at once by `fold`.
+const FOLD_BATCH_SIZE: usize = 128;
+
+/// Iterator through the entries of the engine, which sees the engine as of its creation
+/// regardless of the later writes and merges.
 pub struct Iterator<'a> {
     index_iter: Arc<RwLock<Box<dyn IndexIterator>>>,
-    engine: &'a Engine,
+    snapshot: Snapshot<'a>,
+    limit: Option<usize>,
+    page: Mutex<Page>,
+}
+
+/// Entries returned by `next` since the iterator is created, rewound or sought, where:
+/// - `returned` is the number of entries returned.
+/// - `last_key` is the key of the last entry returned.
+#[derive(Default)]
+struct Page {
+    returned: usize,
+    last_key: Option<Vec<u8>>,
 }
 
 impl Engine {
     /// Get the iterator instance.
     pub fn iter(&self, options: IteratorOptions) -> Iterator {
-        Iterator {
-            index_iter: Arc::new(RwLock::new(self.index.iterator(options))),
-            engine: self,
-        }
+        self.snapshot().iter(options)
     }
 
     /// Get all the keys contained in the engine.
@@ -30,34 +54,191 @@ impl Engine {
         Self: Sized,
         F: Fn(Bytes, Bytes) -> bool,
     {
-        let iter = self.iter(IteratorOptions::default());
-        while let Some((key, value)) = iter.next() {
-            if !f(key, value) {
-                break;
+        // Values are read in batches to reduce the locking and seeking overhead.
+        let mut index_iter = self.index.iterator(IteratorOptions::default());
+        loop {
+            let mut keys = Vec::with_capacity(FOLD_BATCH_SIZE);
+            let mut positions = Vec::with_capacity(FOLD_BATCH_SIZE);
+            while keys.len() < FOLD_BATCH_SIZE {
+                match index_iter.next() {
+                    Some((key, pos)) => {
+                        keys.push(key.clone());
+                        positions.push(*pos);
+                    }
+                    None => break,
+                }
+            }
+            if keys.is_empty() {
+                return Ok(());
+            }
+
+            let values = self.read_values_of_keys(&keys, &positions)?;
+            for (key, value) in keys.into_iter().zip(values) {
+                let value = match value {
+                    Some(value) => value,
+                    None => continue,
+                };
+                if !f(Bytes::from(key), value) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Same as `fold`, but the keys are split into chunks folded by THREAD_NUM threads, so F is
+    /// invoked concurrently and in no particular order. Once F returns false, the remaining
+    /// chunks are skipped, while the ones being folded by other threads may still be completed.
+    pub fn par_fold<F>(&self, thread_num: usize, f: F) -> Result<()>
+    where
+        Self: Sized,
+        F: Fn(Bytes, Bytes) -> bool + Sync,
+    {
+        let thread_num = thread_num.max(1);
+        let stopped = AtomicBool::new(false);
+        let (sender, receiver) = mpsc::sync_channel::<Vec<(Vec<u8>, LogRecordPos)>>(thread_num);
+        let receiver = Mutex::new(receiver);
+
+        // Each thread reads the values of a chunk at once, in the order of the data files. Chunks
+        // are still received once stopped, so the current thread is never blocked sending them.
+        let fold_chunks = || -> Result<()> {
+            let mut res = Ok(());
+            loop {
+                let chunk = match receiver.lock().unwrap().recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => return res,
+                };
+                if stopped.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let (keys, positions): (Vec<&Vec<u8>>, Vec<LogRecordPos>) =
+                    chunk.iter().map(|(key, pos)| (key, *pos)).unzip();
+                let values = match self.read_values_of_keys(&keys, &positions) {
+                    Ok(values) => values,
+                    Err(e) => {
+                        stopped.store(true, Ordering::SeqCst);
+                        res = Err(e);
+                        continue;
+                    }
+                };
+                for ((key, _), value) in chunk.into_iter().zip(values) {
+                    let value = match value {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    if !f(Bytes::from(key), value) {
+                        stopped.store(true, Ordering::SeqCst);
+                        break;
+                    }
+                }
+            }
+        };
+
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..thread_num).map(|_| scope.spawn(fold_chunks)).collect();
+
+            // The keys are split in the current thread, which only walks the index.
+            let mut index_iter = self.index.iterator(IteratorOptions::default());
+            let mut chunk = Vec::with_capacity(FOLD_BATCH_SIZE);
+            while !stopped.load(Ordering::SeqCst) {
+                match index_iter.next() {
+                    Some((key, pos)) => chunk.push((key.clone(), *pos)),
+                    None => break,
+                }
+                if chunk.len() == FOLD_BATCH_SIZE {
+                    let full = std::mem::replace(&mut chunk, Vec::with_capacity(FOLD_BATCH_SIZE));
+                    if sender.send(full).is_err() {
+                        break;
+                    }
+                }
             }
+            if !chunk.is_empty() && !stopped.load(Ordering::SeqCst) {
+                let _ = sender.send(chunk);
+            }
+            drop(sender);
+
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().unwrap())
+        })
+    }
+}
+
+impl<'a> Snapshot<'a> {
+    /// Get an iterator through the entries of the snapshot.
+    pub fn iter(&self, options: IteratorOptions) -> Iterator<'a> {
+        let engine = self.engine();
+        let limit = options.limit;
+        // Skip the indexer entirely if no data file contains the prefix.
+        let index_iter = match engine.may_contain_prefix(&options.prefix) {
+            true => self.iterator(engine.index.as_ref(), options),
+            false => BTree::new().iterator(options),
+        };
+        Iterator {
+            index_iter: Arc::new(RwLock::new(index_iter)),
+            snapshot: self.clone(),
+            limit,
+            page: Mutex::new(Page::default()),
         }
-        Ok(())
     }
 }
 
 impl Iterator<'_> {
+    /// Get the sequence number of the next transaction when the iterator is created. Only the
+    /// transactions with a lower sequence number are visible to the iterator.
+    pub fn sequence_number(&self) -> usize {
+        self.snapshot.sequence_number()
+    }
+
+    /// Get the cursor taken at the last entry returned by `next`, which resumes the iteration
+    /// right after it through `IteratorOptions::cursor`. Return None if `next` has not returned
+    /// any entry since the iterator is created, rewound or sought.
+    pub fn cursor(&self) -> Option<Vec<u8>> {
+        self.page.lock().unwrap().last_key.clone()
+    }
+
     pub fn rewind(&self) {
         let mut index_iter = self.index_iter.write().unwrap();
         index_iter.rewind();
+        *self.page.lock().unwrap() = Page::default();
     }
 
     pub fn seek(&self, key: Vec<u8>) {
         let mut index_iter = self.index_iter.write().unwrap();
         index_iter.seek(key);
+        *self.page.lock().unwrap() = Page::default();
     }
 
     pub fn next(&self) -> Option<(Bytes, Bytes)> {
         let mut index_iter = self.index_iter.write().unwrap();
-        if let Some(item) = index_iter.next() {
-            let value = self
-                .engine
-                .get_value_by_position(item.1)
-                .expect("failed to get value from data file");
+        let mut page = self.page.lock().unwrap();
+        if self.limit.is_some_and(|limit| page.returned >= limit) {
+            return None;
+        }
+        while let Some(item) = index_iter.next() {
+            // Expired entries are skipped.
+            let value = match self.snapshot.get_value_by_position(item.1) {
+                Ok(value) => value,
+                Err(Errors::KeyNotFound) => continue,
+                Err(e) => panic!("failed to get value from data file: {:?}", e),
+            };
+            page.returned += 1;
+            page.last_key = Some(item.0.to_vec());
+            return Some((Bytes::from(item.0.to_vec()), value));
+        }
+        None
+    }
+
+    /// Step back to the previous (key, value) pair, which is the one returned by the last call
+    /// to `next` if called right after it.
+    pub fn prev(&self) -> Option<(Bytes, Bytes)> {
+        let mut index_iter = self.index_iter.write().unwrap();
+        while let Some(item) = index_iter.prev() {
+            // Expired entries are skipped.
+            let value = match self.snapshot.get_value_by_position(item.1) {
+                Ok(value) => value,
+                Err(Errors::KeyNotFound) => continue,
+                Err(e) => panic!("failed to get value from data file: {:?}", e),
+            };
             return Some((Bytes::from(item.0.to_vec()), value));
         }
         None
@@ -68,7 +249,13 @@ impl Iterator<'_> {
 mod tests {
     use std::path::PathBuf;
 
-    use crate::{options::Options, utils};
+    use crate::{
+        options::Options,
+        utils::{
+            self,
+            rand_kv::{get_test_key, get_test_value},
+        },
+    };
 
     use super::*;
 
@@ -122,6 +309,46 @@ mod tests {
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     }
 
+    #[test]
+    fn test_par_fold() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-par-fold");
+        opts.data_file_size = 64 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..5000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..1000 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+
+        let folded = Mutex::new(Vec::new());
+        engine
+            .par_fold(4, |key, value| {
+                assert_eq!(key, value);
+                folded.lock().unwrap().push(key);
+                true
+            })
+            .unwrap();
+        let mut folded = folded.into_inner().unwrap();
+        folded.sort();
+        assert_eq!(folded.len(), 4000);
+        assert!(folded
+            .iter()
+            .enumerate()
+            .all(|(i, key)| *key == get_test_key(i as i32 + 1000)));
+
+        // Folding stops early once the function returns false.
+        let num = std::sync::atomic::AtomicUsize::new(0);
+        engine
+            .par_fold(4, |_, _| num.fetch_add(1, Ordering::SeqCst) < 10)
+            .unwrap();
+        assert!(num.load(Ordering::SeqCst) < 4000);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
     #[test]
     fn test_iterator_seek() {
         let mut opts = Options::default();
@@ -167,6 +394,204 @@ mod tests {
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     }
 
+    #[test]
+    fn test_iterator_prev() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-iter-prev");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for key in ["aacc", "bbac", "ccde", "eecc"] {
+            let put_res = engine.put(Bytes::from(key), utils::rand_kv::get_test_value(10));
+            assert!(put_res.is_ok());
+        }
+
+        let iter1 = engine.iter(IteratorOptions::default());
+        assert!(iter1.prev().is_none());
+        assert_eq!(Bytes::from("aacc"), iter1.next().unwrap().0);
+        assert_eq!(Bytes::from("bbac"), iter1.next().unwrap().0);
+        assert_eq!(Bytes::from("ccde"), iter1.next().unwrap().0);
+        assert_eq!(Bytes::from("ccde"), iter1.prev().unwrap().0);
+        assert_eq!(Bytes::from("bbac"), iter1.prev().unwrap().0);
+        assert_eq!(Bytes::from("aacc"), iter1.prev().unwrap().0);
+        assert!(iter1.prev().is_none());
+        assert_eq!(Bytes::from("aacc"), iter1.next().unwrap().0);
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.reverse = true;
+        let iter2 = engine.iter(iter_opts);
+        while iter2.next().is_some() {}
+        assert_eq!(Bytes::from("aacc"), iter2.prev().unwrap().0);
+        assert_eq!(Bytes::from("bbac"), iter2.prev().unwrap().0);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_iterator_bounds() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-iter-bounds");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..30 {
+            let key = Bytes::from(format!("user:{:04}:", i * 100));
+            let put_res = engine.put(key, utils::rand_kv::get_test_value(10));
+            assert!(put_res.is_ok());
+        }
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.lower_bound = Some(b"user:1000:".to_vec());
+        iter_opts.upper_bound = Some(b"user:2000:".to_vec());
+        let iter = engine.iter(iter_opts);
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(key);
+        }
+        assert_eq!(keys.len(), 10);
+        assert_eq!(Bytes::from("user:1000:"), keys[0]);
+        assert_eq!(Bytes::from("user:1900:"), keys[9]);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_iterator_snapshot() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-iter-snapshot");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..100 {
+            let put_res = engine.put(get_test_key(i), get_test_value(i));
+            assert!(put_res.is_ok());
+        }
+        let iter = engine.iter(IteratorOptions::default());
+
+        // Writes made after the iterator is created are not visible to it.
+        for i in 0..50 {
+            assert!(engine.put(get_test_key(i), Bytes::from("new")).is_ok());
+        }
+        for i in 50..60 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        for i in 100..200 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        let mut num = 0;
+        while let Some((key, value)) = iter.next() {
+            assert_eq!(key, get_test_key(num));
+            assert_eq!(value, get_test_value(num));
+            num += 1;
+        }
+        assert_eq!(num, 100);
+        assert_eq!(iter.prev().unwrap().0, get_test_key(99));
+        std::mem::drop(iter);
+
+        let iter = engine.iter(IteratorOptions::default());
+        assert_eq!(iter.next().unwrap().1, Bytes::from("new"));
+        let mut num = 1;
+        while iter.next().is_some() {
+            num += 1;
+        }
+        assert_eq!(num, 190);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_iterator_snapshot_merge_files() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-iter-snapshot-merge-files");
+        opts.data_file_size = 16 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        let iter = engine.iter(IteratorOptions::default());
+        for i in 0..1000 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+
+        // The merged files are kept while the iterator is open.
+        let mut file_ids: Vec<u64> = engine.old_files().keys().copied().collect();
+        file_ids.sort();
+        assert!(engine.merge_files(&file_ids).is_ok());
+        assert!(engine.get(get_test_key(0)).is_err());
+        let mut num = 0;
+        while let Some((key, value)) = iter.next() {
+            assert_eq!(key, get_test_key(num));
+            assert_eq!(value, get_test_value(num));
+            num += 1;
+        }
+        assert_eq!(num, 2000);
+
+        let obsolete_path = opts.dir_path.join("obsolete");
+        assert!(obsolete_path.is_dir());
+        std::mem::drop(iter);
+        assert!(!obsolete_path.exists());
+        for i in 1000..2000 {
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_iterator_pagination() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-iter-pagination");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..250 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut iter_opts = IteratorOptions::default();
+            iter_opts.limit = Some(100);
+            iter_opts.cursor = cursor;
+            let iter = engine.iter(iter_opts);
+            let mut page_size = 0;
+            while let Some((key, _)) = iter.next() {
+                keys.push(key);
+                page_size += 1;
+            }
+            assert!(page_size <= 100);
+            cursor = iter.cursor();
+            if page_size < 100 {
+                break;
+            }
+            // Pages are resumed from their cursor regardless of the writes in between.
+            assert!(engine.put(get_test_key(0), Bytes::from("new")).is_ok());
+        }
+        assert_eq!(keys.len(), 250);
+        assert!(keys
+            .iter()
+            .enumerate()
+            .all(|(i, key)| *key == get_test_key(i as i32)));
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.reverse = true;
+        iter_opts.limit = Some(10);
+        iter_opts.cursor = Some(get_test_key(100).to_vec());
+        let iter = engine.iter(iter_opts);
+        assert_eq!(iter.next().unwrap().0, get_test_key(99));
+        let mut num = 1;
+        while iter.next().is_some() {
+            num += 1;
+        }
+        assert_eq!(num, 10);
+        assert_eq!(iter.cursor(), Some(get_test_key(90).to_vec()));
+
+        // Rewinding starts a new page from the cursor.
+        iter.rewind();
+        assert!(iter.cursor().is_none());
+        assert_eq!(iter.next().unwrap().0, get_test_key(99));
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
     #[test]
     fn test_iterator_prefix() {
         let mut opts = Options::default();
@@ -0,0 +1,216 @@
+//! Per-key locks for applications. `Engine::lock_key` serializes read-modify-write on a key
+//! between the callers holding its guard, without blocking the writes of other keys. Locks only
+//! exclude each other, the plain writes of a locked key are not blocked.
+//!
+//! The keys of a guard are acquired all at once, so a single guard never waits while holding
+//! some of its keys. Callers holding several guards at a time should rather use `lock_keys`, or
+//! `try_lock_key` whose timeout breaks deadlocks.
+//!
+//! The engine serializes its own read-modify-write primitives, such as `Engine::incr` and
+//! `Engine::compare_and_swap`, with striped `KeyLocks` instead, which plain writes also take for
+//! their key, and write batches for all their keys while committing. These are never held by
+//! applications, so the primitives may be called while holding a `KeyGuard`.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+};
+
+/// Number of locks serializing the writes of the engine to the same key, keys are spread over
+/// them by hash.
+const KEY_LOCK_NUM: usize = 64;
+
+/// Locks serializing the read-modify-write primitives of the engine and the write batches on the
+/// same key.
+pub(crate) struct KeyLocks {
+    locks: Vec<Mutex<()>>,
+}
+
+impl KeyLocks {
+    pub(crate) fn new() -> Self {
+        Self {
+            locks: (0..KEY_LOCK_NUM).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    /// Lock KEY, along with the other keys sharing its lock.
+    pub(crate) fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.locks[stripe(key)].lock().unwrap()
+    }
+
+    /// Lock all of KEYS. Locks are taken in ascending order, so callers never deadlock.
+    pub(crate) fn lock_all<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let stripes: BTreeSet<usize> = keys.into_iter().map(stripe).collect();
+        stripes
+            .into_iter()
+            .map(|i| self.locks[i].lock().unwrap())
+            .collect()
+    }
+
+    /// Lock every key, for writes not known key by key in advance.
+    pub(crate) fn lock_every(&self) -> Vec<MutexGuard<'_, ()>> {
+        self.locks.iter().map(|lock| lock.lock().unwrap()).collect()
+    }
+}
+
+fn stripe(key: &[u8]) -> usize {
+    crc32fast::hash(key) as usize % KEY_LOCK_NUM
+}
+
+/// The keys locked by the applications.
+#[derive(Default)]
+pub(crate) struct KeyLockTable {
+    locked: Mutex<HashSet<Vec<u8>>>,
+    released: Condvar,
+}
+
+/// Keys locked by `Engine::lock_key`, which are released when dropped.
+pub struct KeyGuard<'a> {
+    table: &'a KeyLockTable,
+    keys: Vec<Vec<u8>>,
+}
+
+impl KeyLockTable {
+    /// Lock all of KEYS at once, waiting until none of them is locked. Give up with
+    /// `Errors::KeyLockTimeout` once TIMEOUT is elapsed, if any.
+    fn lock(&self, keys: Vec<Vec<u8>>, timeout: Option<Duration>) -> Result<KeyGuard<'_>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut locked = self.locked.lock().unwrap();
+        while keys.iter().any(|key| locked.contains(key)) {
+            locked = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Errors::KeyLockTimeout);
+                    }
+                    self.released
+                        .wait_timeout(locked, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.released.wait(locked).unwrap(),
+            };
+        }
+        locked.extend(keys.iter().cloned());
+        Ok(KeyGuard { table: self, keys })
+    }
+}
+
+impl Drop for KeyGuard<'_> {
+    fn drop(&mut self) {
+        let mut locked = self.table.locked.lock().unwrap();
+        for key in &self.keys {
+            locked.remove(key);
+        }
+        self.table.released.notify_all();
+    }
+}
+
+impl Engine {
+    /// Lock KEY until the returned guard is dropped, waiting while another guard holds it.
+    pub fn lock_key(&self, key: Bytes) -> Result<KeyGuard<'_>> {
+        self.lock_keys(vec![key])
+    }
+
+    /// Lock all of KEYS at once until the returned guard is dropped, waiting while any of them
+    /// is held by another guard. Duplicated keys are locked once.
+    pub fn lock_keys(&self, keys: Vec<Bytes>) -> Result<KeyGuard<'_>> {
+        self.key_lock_table.lock(dedup_keys(keys)?, None)
+    }
+
+    /// Lock KEY as `lock_key`, but fail with `Errors::KeyLockTimeout` if it is still held by
+    /// another guard after TIMEOUT.
+    pub fn try_lock_key(&self, key: Bytes, timeout: Duration) -> Result<KeyGuard<'_>> {
+        self.key_lock_table
+            .lock(dedup_keys(vec![key])?, Some(timeout))
+    }
+}
+
+/// Get the distinct KEYS, fail with `Errors::KeyIsEmpty` if any of them is empty.
+fn dedup_keys(keys: Vec<Bytes>) -> Result<Vec<Vec<u8>>> {
+    if keys.iter().any(|key| key.is_empty()) {
+        return Err(Errors::KeyIsEmpty);
+    }
+    let mut keys: Vec<Vec<u8>> = keys.into_iter().map(|key| key.to_vec()).collect();
+    keys.sort();
+    keys.dedup();
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, sync::Arc, thread};
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_engine_lock_key() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-lock-key");
+        let engine = Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+        assert!(engine.put(get_test_key(0), Bytes::from("0")).is_ok());
+
+        // Read-modify-write under the lock is never lost.
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let _guard = engine.lock_key(get_test_key(0)).unwrap();
+                        let value = engine.get(get_test_key(0)).unwrap();
+                        let num: u32 = String::from_utf8(value.to_vec()).unwrap().parse().unwrap();
+                        engine
+                            .put(get_test_key(0), Bytes::from((num + 1).to_string()))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(engine.get(get_test_key(0)).unwrap(), Bytes::from("400"));
+
+        // Held keys time out, while the other keys stay available.
+        let guard = engine
+            .lock_keys(vec![get_test_key(1), get_test_key(2), get_test_key(1)])
+            .unwrap();
+        assert_eq!(
+            engine
+                .try_lock_key(get_test_key(2), Duration::from_millis(50))
+                .err(),
+            Some(Errors::KeyLockTimeout)
+        );
+        assert!(engine
+            .try_lock_key(get_test_key(3), Duration::from_millis(50))
+            .is_ok());
+        assert!(engine.put(get_test_key(1), get_test_value(1)).is_ok());
+        std::mem::drop(guard);
+        assert!(engine
+            .try_lock_key(get_test_key(2), Duration::from_millis(50))
+            .is_ok());
+        assert_eq!(
+            engine.lock_key(Bytes::new()).err(),
+            Some(Errors::KeyIsEmpty)
+        );
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
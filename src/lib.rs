@@ -1,10 +1,43 @@
+pub mod auto_merge;
+pub mod backup;
 pub mod batch;
+pub mod blob;
+pub mod bulk_load;
+pub mod cached_store;
+pub mod change_sink;
+pub mod clone;
+pub mod compaction_filter;
+pub mod counter;
 pub mod data;
 pub mod db;
 pub mod errors;
+pub mod event_listener;
+pub mod fence;
 pub mod fio;
+pub mod hint;
 pub mod index;
 pub mod iterator;
+pub mod key_lock;
+pub mod lock;
+pub mod manager;
+pub mod manifest;
 pub mod merge;
+pub mod merge_policy;
+pub mod merge_stats;
 pub mod options;
+pub mod partial_merge;
+pub mod periodic_sync;
+pub mod prefix;
+pub mod prepared;
+pub mod rate_limit;
+pub mod reclaim;
+pub mod recovery;
+pub mod size_report;
+pub mod snapshot;
+pub mod stall;
+pub mod trash;
+pub mod ttl;
 pub mod utils;
+pub mod value_cache;
+pub mod verify;
+pub mod version;
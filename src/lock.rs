@@ -0,0 +1,293 @@
+//! The directory lock. The `flock` file is locked exclusively by the engine owning the directory,
+//! and records the owner as `pid host timestamp pid_namespace`. On a local file system, the lock
+//! is released with the process holding it, so failing to lock means the directory is in use. On
+//! a network file system such as NFS, the lock may outlive its process, so a lock left behind by
+//! a dead process of the same host and PID namespace is taken over instead of failing with
+//! `Errors::DatabaseInUse`.
+
+use std::{
+    fs::{self, File},
+    io::{Seek, SeekFrom, Write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+    process,
+};
+
+use fs2::FileExt;
+use log::warn;
+
+use crate::{
+    db::LOCK_FILE_NAME,
+    errors::{Errors, Result},
+    utils::time::now_millis,
+};
+
+/// The host recorded when the hostname is unknown, which is never trusted to tell whether the
+/// owner is on this host.
+const UNKNOWN_HOST: &str = "localhost";
+
+/// The owner of a directory lock, where `locked_at` is in milliseconds since the epoch, and
+/// `pid_ns` names the PID namespace of `pid`, None if unknown or written by an older version.
+#[derive(Debug, PartialEq)]
+pub(crate) struct LockOwner {
+    pub(crate) pid: u32,
+    pub(crate) host: String,
+    pub(crate) locked_at: u64,
+    pub(crate) pid_ns: Option<String>,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        Self {
+            pid: process::id(),
+            host: hostname(),
+            locked_at: now_millis(),
+            pid_ns: pid_namespace(),
+        }
+    }
+
+    /// Whether the owner is known to be dead, which can only be told for a process on this host,
+    /// in the PID namespace of the current process.
+    fn is_dead(&self) -> bool {
+        self.host != UNKNOWN_HOST
+            && self.host == hostname()
+            && self.pid_ns.is_some()
+            && self.pid_ns == pid_namespace()
+            && self.pid != process::id()
+            && Path::new("/proc").is_dir()
+            && !Path::new(&std::format!("/proc/{}", self.pid)).exists()
+    }
+
+    fn encode(&self) -> String {
+        let pid_ns = self.pid_ns.as_deref().unwrap_or("-");
+        std::format!("{} {} {} {}\n", self.pid, self.host, self.locked_at, pid_ns)
+    }
+
+    fn decode(content: &str) -> Option<Self> {
+        let mut fields = content.split_whitespace();
+        Some(Self {
+            pid: fields.next()?.parse().ok()?,
+            host: fields.next()?.to_string(),
+            locked_at: fields.next()?.parse().ok()?,
+            pid_ns: fields.next().filter(|ns| *ns != "-").map(str::to_string),
+        })
+    }
+}
+
+/// Lock the directory DIR_PATH exclusively, return the locked file which keeps the lock until it
+/// is unlocked or dropped.
+pub(crate) fn lock_dir(dir_path: &Path) -> Result<File> {
+    lock_dir_with_takeover(dir_path, locks_outlive_process(dir_path))
+}
+
+/// Same as `lock_dir`, where a lock left behind by a dead process is taken over only if
+/// TAKEOVER is set, that is if the file system may keep locks after their process exits.
+fn lock_dir_with_takeover(dir_path: &Path, takeover: bool) -> Result<File> {
+    let lock_path = dir_path.join(LOCK_FILE_NAME);
+    let lock_file = open_lock_file(&lock_path)?;
+    if lock_file.try_lock_exclusive().is_ok() {
+        write_owner(&lock_file, &LockOwner::current())?;
+        return Ok(lock_file);
+    }
+    if !takeover {
+        return Err(Errors::DatabaseInUse);
+    }
+
+    let owner = match read_owner(&lock_path) {
+        Some(owner) if owner.is_dead() => owner,
+        _ => return Err(Errors::DatabaseInUse),
+    };
+    // Another process may have taken the lock over since it was read.
+    if read_owner(&lock_path).as_ref() != Some(&owner) {
+        return Err(Errors::DatabaseInUse);
+    }
+    warn!(
+        "taking over the lock of {:?} left by dead process {} since {}",
+        dir_path, owner.pid, owner.locked_at
+    );
+
+    // The stale lock is held on the old file, so lock a new one in its place.
+    fs::remove_file(&lock_path).map_err(|_| Errors::DatabaseInUse)?;
+    let lock_file = open_lock_file(&lock_path)?;
+    lock_file
+        .try_lock_exclusive()
+        .map_err(|_| Errors::DatabaseInUse)?;
+    let new_owner = LockOwner::current();
+    write_owner(&lock_file, &new_owner)?;
+
+    // A process racing the takeover may have replaced the file, in which case it owns the
+    // directory, and the lock is given up.
+    let is_ours = fs::metadata(&lock_path)
+        .and_then(|path_meta| {
+            let file_meta = lock_file.metadata()?;
+            Ok(path_meta.dev() == file_meta.dev() && path_meta.ino() == file_meta.ino())
+        })
+        .unwrap_or(false);
+    if !is_ours || read_owner(&lock_path) != Some(new_owner) {
+        return Err(Errors::DatabaseInUse);
+    }
+    Ok(lock_file)
+}
+
+fn open_lock_file(lock_path: &Path) -> Result<File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)
+        .map_err(|_| Errors::FailedToOpenDataFile)
+}
+
+fn read_owner(lock_path: &Path) -> Option<LockOwner> {
+    fs::read_to_string(lock_path)
+        .ok()
+        .and_then(|content| LockOwner::decode(&content))
+}
+
+fn write_owner(mut lock_file: &File, owner: &LockOwner) -> Result<()> {
+    lock_file
+        .set_len(0)
+        .and_then(|_| lock_file.seek(SeekFrom::Start(0)))
+        .and_then(|_| lock_file.write_all(owner.encode().as_bytes()))
+        .map_err(|_| Errors::FailedToWriteToDataFile)
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| UNKNOWN_HOST.to_string())
+}
+
+/// Get the name of the PID namespace of the current process, such as `pid:[4026531836]`, or None
+/// if unknown.
+fn pid_namespace() -> Option<String> {
+    fs::read_link("/proc/self/ns/pid")
+        .ok()
+        .and_then(|ns| ns.to_str().map(str::to_string))
+        .filter(|ns| !ns.contains(char::is_whitespace))
+}
+
+/// Whether the file system of DIR_PATH may keep a lock after the process holding it exits, as
+/// network file systems do. Local file systems release the locks of a process when it exits.
+fn locks_outlive_process(dir_path: &Path) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        const NFS_SUPER_MAGIC: i64 = 0x6969;
+        const SMB_SUPER_MAGIC: i64 = 0x517b;
+        const CIFS_SUPER_MAGIC: i64 = 0xff53_4d42;
+        const SMB2_SUPER_MAGIC: i64 = 0xfe53_4d42;
+
+        let Ok(path) = CString::new(dir_path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+            return false;
+        }
+        matches!(
+            stat.f_type as i64,
+            NFS_SUPER_MAGIC | SMB_SUPER_MAGIC | CIFS_SUPER_MAGIC | SMB2_SUPER_MAGIC
+        )
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = dir_path;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{db::Engine, options::Options};
+
+    use super::*;
+
+    #[test]
+    fn test_lock_dir_stale() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-lock-stale");
+        fs::create_dir_all(&opts.dir_path).unwrap();
+
+        // A lock held on behalf of a live process is respected.
+        let lock_path = opts.dir_path.join(LOCK_FILE_NAME);
+        let stale_file = open_lock_file(&lock_path).unwrap();
+        stale_file.try_lock_exclusive().unwrap();
+        let mut owner = LockOwner::current();
+        fs::write(&lock_path, owner.encode()).unwrap();
+        assert_eq!(
+            Engine::open(opts.clone()).err(),
+            Some(Errors::DatabaseInUse)
+        );
+
+        // On a local file system, the lock is released with its process, so a lock held while
+        // the owner is dead means the owner is unknown to this host, and it is respected.
+        owner.pid = u32::MAX;
+        fs::write(&lock_path, owner.encode()).unwrap();
+        assert_eq!(
+            Engine::open(opts.clone()).err(),
+            Some(Errors::DatabaseInUse)
+        );
+
+        // Neither is it taken over where locks outlive their process, unless the owner is known
+        // to be dead on this host and in this PID namespace.
+        for stale in [
+            LockOwner {
+                host: UNKNOWN_HOST.to_string(),
+                ..LockOwner::current()
+            },
+            LockOwner {
+                pid_ns: Some("pid:[0]".to_string()),
+                ..LockOwner::current()
+            },
+            LockOwner {
+                pid_ns: None,
+                ..LockOwner::current()
+            },
+        ] {
+            let stale = LockOwner {
+                pid: u32::MAX,
+                ..stale
+            };
+            fs::write(&lock_path, stale.encode()).unwrap();
+            assert_eq!(
+                lock_dir_with_takeover(&opts.dir_path, true).err(),
+                Some(Errors::DatabaseInUse)
+            );
+        }
+
+        // A lock left by a dead process is taken over there.
+        fs::write(&lock_path, owner.encode()).unwrap();
+        let lock_file = lock_dir_with_takeover(&opts.dir_path, true);
+        if !owner.is_dead() {
+            // Without /proc, no process is known to be dead.
+            assert_eq!(lock_file.err(), Some(Errors::DatabaseInUse));
+        } else {
+            let lock_file = lock_file.expect("failed to take over the lock");
+            let owner = LockOwner::decode(&fs::read_to_string(&lock_path).unwrap()).unwrap();
+            assert_eq!(owner.pid, process::id());
+            assert_eq!(
+                Engine::open(opts.clone()).err(),
+                Some(Errors::DatabaseInUse)
+            );
+            std::mem::drop(lock_file);
+        }
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        // Owners written before the PID namespace was recorded are decoded as unknown.
+        let old_owner = LockOwner::decode("42 yorick 7\n").unwrap();
+        assert_eq!(old_owner.pid_ns, None);
+        assert!(!old_owner.is_dead());
+
+        std::mem::drop(engine);
+        std::mem::drop(stale_file);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,189 @@
+//! Hosting many small databases in one process, for instance one per tenant. Each database lives
+//! in its own subdirectory of a common root, and is opened with the same options. The manager
+//! owns the lifecycle of the engines, and runs maintenance and statistics across all of them.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    db::{Engine, Stat},
+    errors::{Errors, Result},
+    options::Options,
+};
+
+/// A set of engines opened under a common root directory.
+///
+/// - `options` is the configuration shared by all engines, where `dir_path` is the root
+///   directory.
+/// - `engines` are the opened engines by name.
+pub struct EngineManager {
+    options: Options,
+    engines: RwLock<HashMap<String, Arc<Engine>>>,
+}
+
+impl EngineManager {
+    /// Create a manager opening engines under `OPTS.dir_path` with configuration OPTS.
+    pub fn new(opts: Options) -> Self {
+        Self {
+            options: opts,
+            engines: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Open the engine named NAME, or return it if it is already opened.
+    pub fn open(&self, name: &str) -> Result<Arc<Engine>> {
+        if let Some(engine) = self.get(name) {
+            return Ok(engine);
+        }
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(Errors::InvalidEngineName);
+        }
+
+        let mut engines = self.engines.write().unwrap();
+        if let Some(engine) = engines.get(name) {
+            return Ok(engine.clone());
+        }
+        let mut opts = self.options.clone();
+        opts.dir_path = self.options.dir_path.join(name);
+        let engine = Arc::new(Engine::open(opts)?);
+        engines.insert(name.to_string(), engine.clone());
+        Ok(engine)
+    }
+
+    /// Get the opened engine named NAME.
+    pub fn get(&self, name: &str) -> Option<Arc<Engine>> {
+        self.engines.read().unwrap().get(name).cloned()
+    }
+
+    /// Names of all opened engines, in ascending order.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.engines.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Close the engine named NAME. Handles of the engine still held by the application fail
+    /// with `Errors::EngineClosed` on writes afterwards.
+    pub fn close(&self, name: &str) -> Result<()> {
+        let engine = self.engines.write().unwrap().remove(name);
+        match engine {
+            Some(engine) => engine.close(),
+            None => Err(Errors::EngineNotFound),
+        }
+    }
+
+    /// Close all opened engines, return the first error encountered.
+    pub fn close_all(&self) -> Result<()> {
+        let engines: Vec<Arc<Engine>> =
+            self.engines.write().unwrap().drain().map(|e| e.1).collect();
+        let mut res = Ok(());
+        for engine in engines {
+            if let Err(e) = engine.close() {
+                res = res.and(Err(e));
+            }
+        }
+        res
+    }
+
+    /// Persist the active files of all opened engines.
+    pub fn sync_all(&self) -> Result<()> {
+        for engine in self.engines() {
+            engine.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Merge every opened engine whose garbage reaches the configured merge ratio, one at a
+    /// time, so merges of different engines never compete for the disk. Return the names of
+    /// the merged engines.
+    pub fn merge_all(&self) -> Result<Vec<String>> {
+        let mut merged = Vec::new();
+        for name in self.names() {
+            let engine = match self.get(&name) {
+                Some(engine) => engine,
+                None => continue,
+            };
+            match engine.merge() {
+                Ok(_) => merged.push(name),
+                Err(Errors::MergeRationUnreached) | Err(Errors::MergeInProgress) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Statistics summed over all opened engines.
+    pub fn stat(&self) -> Result<Stat> {
+        let mut total = Stat::default();
+        for engine in self.engines() {
+            let stat = engine.stat()?;
+            total.key_num += stat.key_num;
+            total.data_file_num += stat.data_file_num;
+            total.reclaim_size += stat.reclaim_size;
+            total.disk_size += stat.disk_size;
+            total.index_memory_size += stat.index_memory_size;
+        }
+        Ok(total)
+    }
+
+    fn engines(&self) -> Vec<Arc<Engine>> {
+        self.engines.read().unwrap().values().cloned().collect()
+    }
+}
+
+impl Drop for EngineManager {
+    fn drop(&mut self) {
+        if let Err(e) = self.close_all() {
+            log::error!("error while closing engines: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::utils::rand_kv::{get_test_key, get_test_value};
+
+    use super::*;
+
+    #[test]
+    fn test_engine_manager() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-manager");
+        let manager = EngineManager::new(opts.clone());
+
+        let engine1 = manager.open("tenant-1").unwrap();
+        let engine2 = manager.open("tenant-2").unwrap();
+        assert!(Arc::ptr_eq(&engine1, &manager.open("tenant-1").unwrap()));
+        assert_eq!(manager.open("../x").err(), Some(Errors::InvalidEngineName));
+        assert_eq!(manager.names(), vec!["tenant-1", "tenant-2"]);
+
+        for i in 0..10 {
+            assert!(engine1.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(engine2.put(get_test_key(0), get_test_value(0)).is_ok());
+        assert!(manager.sync_all().is_ok());
+        assert_eq!(manager.stat().unwrap().key_num, 11);
+
+        assert!(manager.close("tenant-2").is_ok());
+        assert_eq!(
+            manager.close("tenant-2").err(),
+            Some(Errors::EngineNotFound)
+        );
+        assert!(engine2.is_closed());
+        assert_eq!(manager.names(), vec!["tenant-1"]);
+
+        // A closed engine can be opened again with its data.
+        let engine2 = manager.open("tenant-2").unwrap();
+        assert!(engine2.get(get_test_key(0)).is_ok());
+
+        assert!(manager.close_all().is_ok());
+        assert!(engine1.is_closed() && engine2.is_closed());
+        assert!(manager.names().is_empty());
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,462 @@
+//! The MANIFEST is an append-only log of the lifecycle of data files: creations, seals, removals
+//! by partial merge and merge installations, which remove the merged files. Each edit is encoded
+//! as a log record, so a torn or corrupted edit is detected by its CRC and ignored together with
+//! everything after it.
+//!
+//! On startup, the replayed MANIFEST tells which data files must exist, so a missing data file is
+//! detected instead of silently losing its records. A merge is installed by appending a
+//! `MergeCommitted` edit listing the merged files before touching any file, which makes the edit
+//! the commit point: once it is persisted, an interrupted installation is always completed on
+//! the next startup from the recorded file list, regardless of which files were already moved.
+//!
+//! The MANIFEST is rewritten as a snapshot of the live files on each startup, so it never grows
+//! beyond the edits of a single run.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use bytes::BytesMut;
+use prost::encoding::{decode_varint, encode_varint};
+
+use crate::{
+    data::{
+        data_file::{get_data_file_name, DataFile},
+        log_record::{LogRecord, LogRecordType},
+    },
+    errors::{Errors, Result},
+};
+
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const MANIFEST_TMP_FILE_NAME: &str = "MANIFEST.tmp";
+
+const NEW_FILE_TAG: &[u8] = b"new-file";
+const SEAL_FILE_TAG: &[u8] = b"seal-file";
+const REMOVE_FILE_TAG: &[u8] = b"remove-file";
+const MERGE_COMMITTED_TAG: &[u8] = b"merge-committed";
+const MERGE_INSTALLED_TAG: &[u8] = b"merge-installed";
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ManifestEdit {
+    /// A data file is created as the active file.
+    NewFile(u64),
+
+    /// A data file is sealed with the given size, it is never written again.
+    SealFile(u64, u64),
+
+    /// A sealed data file is removed by a partial merge, which is recorded before the file is
+    /// deleted.
+    RemoveFile(u64),
+
+    /// A merge is committed. Data files with id less than NON_MERGE_FID are removed, and
+    /// replaced by the merged files FILE_IDS once the merge is installed.
+    MergeCommitted {
+        non_merge_fid: u64,
+        file_ids: Vec<u64>,
+    },
+
+    /// The last committed merge is fully installed.
+    MergeInstalled,
+}
+
+/// The state of data files replayed from the MANIFEST.
+#[derive(Default)]
+struct ManifestState {
+    /// Live data files, and the size of each sealed file if known.
+    files: BTreeMap<u64, Option<u64>>,
+
+    /// The committed but not yet installed merge.
+    pending_merge: Option<(u64, Vec<u64>)>,
+}
+
+pub(crate) struct Manifest {
+    dir_path: PathBuf,
+    file: Mutex<DataFile>,
+    state: Mutex<ManifestState>,
+
+    /// Whether the MANIFEST existed on opening.
+    existed: bool,
+}
+
+impl ManifestEdit {
+    fn encode(&self) -> Vec<u8> {
+        let mut value = BytesMut::new();
+        let tag = match self {
+            ManifestEdit::NewFile(file_id) => {
+                encode_varint(*file_id, &mut value);
+                NEW_FILE_TAG
+            }
+            ManifestEdit::SealFile(file_id, size) => {
+                encode_varint(*file_id, &mut value);
+                encode_varint(*size, &mut value);
+                SEAL_FILE_TAG
+            }
+            ManifestEdit::RemoveFile(file_id) => {
+                encode_varint(*file_id, &mut value);
+                REMOVE_FILE_TAG
+            }
+            ManifestEdit::MergeCommitted {
+                non_merge_fid,
+                file_ids,
+            } => {
+                encode_varint(*non_merge_fid, &mut value);
+                for file_id in file_ids {
+                    encode_varint(*file_id, &mut value);
+                }
+                MERGE_COMMITTED_TAG
+            }
+            ManifestEdit::MergeInstalled => MERGE_INSTALLED_TAG,
+        };
+        LogRecord {
+            key: tag.to_vec(),
+            value: value.to_vec(),
+            record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
+        }
+        .encode()
+    }
+
+    fn decode(log_record: &LogRecord) -> Option<ManifestEdit> {
+        let mut buf = log_record.value.as_slice();
+        let mut values = Vec::new();
+        while !buf.is_empty() {
+            values.push(decode_varint(&mut buf).ok()?);
+        }
+
+        let edit = match log_record.key.as_slice() {
+            NEW_FILE_TAG => ManifestEdit::NewFile(*values.first()?),
+            SEAL_FILE_TAG => ManifestEdit::SealFile(*values.first()?, *values.get(1)?),
+            REMOVE_FILE_TAG => ManifestEdit::RemoveFile(*values.first()?),
+            MERGE_COMMITTED_TAG => ManifestEdit::MergeCommitted {
+                non_merge_fid: *values.first()?,
+                file_ids: values[1..].to_vec(),
+            },
+            MERGE_INSTALLED_TAG => ManifestEdit::MergeInstalled,
+            _ => return None,
+        };
+        Some(edit)
+    }
+}
+
+impl ManifestState {
+    fn apply(&mut self, edit: &ManifestEdit) {
+        match edit {
+            ManifestEdit::NewFile(file_id) => {
+                self.files.insert(*file_id, None);
+            }
+            ManifestEdit::SealFile(file_id, size) => {
+                self.files.insert(*file_id, Some(*size));
+            }
+            ManifestEdit::RemoveFile(file_id) => {
+                self.files.remove(file_id);
+            }
+            ManifestEdit::MergeCommitted {
+                non_merge_fid,
+                file_ids,
+            } => {
+                self.files.retain(|file_id, _| file_id >= non_merge_fid);
+                for file_id in file_ids {
+                    self.files.insert(*file_id, None);
+                }
+                self.pending_merge = Some((*non_merge_fid, file_ids.clone()));
+            }
+            ManifestEdit::MergeInstalled => self.pending_merge = None,
+        }
+    }
+}
+
+impl Manifest {
+    /// Open the MANIFEST under DIR_PATH and replay its edits, it is created if not exists.
+    pub(crate) fn open(dir_path: &Path) -> Result<Manifest> {
+        let file_name = dir_path.join(MANIFEST_FILE_NAME);
+        let existed = file_name.is_file();
+        let file = DataFile::new_manifest_file(dir_path, MANIFEST_FILE_NAME)?;
+
+        let mut state = ManifestState::default();
+        let mut ofs = 0;
+        loop {
+            let (log_record, size) = match file.read_log_record(ofs) {
+                Ok(result) => result,
+                Err(Errors::ReadDataFileEOF) => break,
+                Err(e) => {
+                    log::warn!(
+                        "ignored MANIFEST after corrupted edit at offset {}: {:?}",
+                        ofs,
+                        e
+                    );
+                    break;
+                }
+            };
+            match ManifestEdit::decode(&log_record) {
+                Some(edit) => state.apply(&edit),
+                None => {
+                    log::warn!("ignored MANIFEST after unknown edit at offset {}", ofs);
+                    break;
+                }
+            }
+            ofs += size as u64;
+        }
+
+        Ok(Manifest {
+            dir_path: dir_path.to_path_buf(),
+            file: Mutex::new(file),
+            state: Mutex::new(state),
+            existed,
+        })
+    }
+
+    /// Persist EDIT.
+    pub(crate) fn append(&self, edit: ManifestEdit) -> Result<()> {
+        let file = self.file.lock().unwrap();
+        file.write(&edit.encode())?;
+        file.sync()?;
+        self.state.lock().unwrap().apply(&edit);
+        Ok(())
+    }
+
+    /// Get the committed merge which is not installed yet, as the non-merged file id and the
+    /// merged files.
+    pub(crate) fn pending_merge(&self) -> Option<(u64, Vec<u64>)> {
+        self.state.lock().unwrap().pending_merge.clone()
+    }
+
+    /// Get the ids of all live data files, or None if the MANIFEST did not exist on opening.
+    pub(crate) fn live_file_ids(&self) -> Option<Vec<u64>> {
+        if !self.existed {
+            return None;
+        }
+        Some(self.state.lock().unwrap().files.keys().copied().collect())
+    }
+
+    /// Check the data files FILE_IDS found on disk against the MANIFEST. A live file which is
+    /// missing or shorter than its sealed size fails the check, while a file unknown to the
+    /// MANIFEST, which is created right before a crash, is kept and adopted by the next snapshot.
+    pub(crate) fn check_files(&self, file_ids: &[u64]) -> Result<()> {
+        let live_file_ids = match self.live_file_ids() {
+            Some(live_file_ids) => live_file_ids,
+            None => return Ok(()),
+        };
+
+        let missing: Vec<&u64> = live_file_ids
+            .iter()
+            .filter(|file_id| !file_ids.contains(file_id))
+            .collect();
+        if !missing.is_empty() {
+            log::error!(
+                "data files {:?} recorded by the MANIFEST are missing",
+                missing
+            );
+            return Err(Errors::DataDirectoryCorrupted);
+        }
+
+        for file_id in file_ids {
+            let sealed_size = match self.sealed_size(*file_id) {
+                Some(sealed_size) => sealed_size,
+                None => continue,
+            };
+            let file_name = get_data_file_name(&self.dir_path, *file_id);
+            let size = fs::metadata(file_name).map(|m| m.len()).unwrap_or(0);
+            if size < sealed_size {
+                log::error!(
+                    "data file {} is truncated from {} to {} bytes",
+                    file_id,
+                    sealed_size,
+                    size
+                );
+                return Err(Errors::DataDirectoryCorrupted);
+            }
+        }
+
+        let extra: Vec<&u64> = file_ids
+            .iter()
+            .filter(|file_id| !live_file_ids.contains(file_id))
+            .collect();
+        if !extra.is_empty() {
+            log::warn!("data files {:?} are not recorded by the MANIFEST", extra);
+        }
+        Ok(())
+    }
+
+    /// Get the size of the sealed file FILE_ID recorded by the MANIFEST.
+    pub(crate) fn sealed_size(&self, file_id: u64) -> Option<u64> {
+        *self.state.lock().unwrap().files.get(&file_id)?
+    }
+
+    /// Replace the MANIFEST with a snapshot where SEALED_FILES, given as (file id, size) pairs,
+    /// are sealed and ACTIVE_FILE_ID is the active file.
+    pub(crate) fn rewrite(&self, sealed_files: &[(u64, u64)], active_file_id: u64) -> Result<()> {
+        let mut state = ManifestState::default();
+        let tmp_file = DataFile::new_manifest_file(&self.dir_path, MANIFEST_TMP_FILE_NAME)?;
+        tmp_file.truncate(0)?;
+        let mut edits = Vec::new();
+        for (file_id, size) in sealed_files {
+            edits.push(ManifestEdit::NewFile(*file_id));
+            edits.push(ManifestEdit::SealFile(*file_id, *size));
+        }
+        edits.push(ManifestEdit::NewFile(active_file_id));
+        for edit in edits {
+            tmp_file.write(&edit.encode())?;
+            state.apply(&edit);
+        }
+        tmp_file.sync()?;
+
+        let mut file = self.file.lock().unwrap();
+        fs::rename(
+            self.dir_path.join(MANIFEST_TMP_FILE_NAME),
+            self.dir_path.join(MANIFEST_FILE_NAME),
+        )
+        .map_err(|_| Errors::FailedToWriteToDataFile)?;
+        *file = DataFile::new_manifest_file(&self.dir_path, MANIFEST_FILE_NAME)?;
+        *self.state.lock().unwrap() = state;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::{
+        data::data_file::MERGE_FIN_FILE_NAME,
+        db::Engine,
+        merge::get_merge_path,
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_manifest_replay() {
+        let dir_path = PathBuf::from("/tmp/bitcask-rs-manifest-replay");
+        fs::create_dir_all(&dir_path).unwrap();
+
+        let manifest = Manifest::open(&dir_path).unwrap();
+        assert!(manifest.live_file_ids().is_none());
+        manifest.append(ManifestEdit::NewFile(1)).unwrap();
+        manifest.append(ManifestEdit::SealFile(1, 100)).unwrap();
+        manifest.append(ManifestEdit::NewFile(2)).unwrap();
+        manifest.append(ManifestEdit::SealFile(2, 200)).unwrap();
+        manifest.append(ManifestEdit::NewFile(3)).unwrap();
+        manifest
+            .append(ManifestEdit::MergeCommitted {
+                non_merge_fid: 3,
+                file_ids: vec![1],
+            })
+            .unwrap();
+        std::mem::drop(manifest);
+
+        // A torn edit at the end is ignored.
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(dir_path.join(MANIFEST_FILE_NAME))
+            .unwrap();
+        file.write_all(&ManifestEdit::NewFile(4).encode()[..5])
+            .unwrap();
+        std::mem::drop(file);
+
+        let manifest = Manifest::open(&dir_path).unwrap();
+        assert_eq!(manifest.live_file_ids(), Some(vec![1, 3]));
+        assert_eq!(manifest.pending_merge(), Some((3, vec![1])));
+        manifest.append(ManifestEdit::MergeInstalled).unwrap();
+        assert_eq!(manifest.pending_merge(), None);
+
+        manifest.rewrite(&[(3, 300)], 5).unwrap();
+        std::mem::drop(manifest);
+        let manifest = Manifest::open(&dir_path).unwrap();
+        assert_eq!(manifest.live_file_ids(), Some(vec![3, 5]));
+        assert_eq!(manifest.sealed_size(3), Some(300));
+        assert_eq!(manifest.sealed_size(5), None);
+        assert_eq!(manifest.pending_merge(), None);
+
+        manifest.append(ManifestEdit::RemoveFile(3)).unwrap();
+        std::mem::drop(manifest);
+        let manifest = Manifest::open(&dir_path).unwrap();
+        assert_eq!(manifest.live_file_ids(), Some(vec![5]));
+
+        fs::remove_dir_all(dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_manifest_missing_data_file() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-manifest-missing");
+        opts.data_file_size = 32 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        std::mem::drop(engine);
+
+        fs::remove_file(get_data_file_name(&opts.dir_path, 2)).unwrap();
+        let res = Engine::open(opts.clone());
+        assert_eq!(res.err().unwrap(), Errors::DataDirectoryCorrupted);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_manifest_interrupted_merge_install() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-manifest-merge");
+        opts.data_file_size = 32 * 1024;
+        opts.data_file_merge_ratio = 0.0;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i + 1)).is_ok());
+        }
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+
+        // Commit the merge and move a single merged file, as if the installation is interrupted.
+        let merge_path = get_merge_path(&opts.dir_path);
+        let merge_fin_file = DataFile::new_merge_fin_file(&merge_path).unwrap();
+        let v = String::from_utf8(merge_fin_file.read_log_record(0).unwrap().0.value).unwrap();
+        let non_merge_fid = v.parse::<u64>().unwrap();
+        let mut file_ids: Vec<u64> = (0..non_merge_fid)
+            .filter(|file_id| get_data_file_name(&merge_path, *file_id).is_file())
+            .collect();
+        file_ids.retain(|file_id| {
+            fs::metadata(get_data_file_name(&merge_path, *file_id))
+                .unwrap()
+                .len()
+                > 0
+        });
+        assert!(file_ids.len() > 1);
+        let manifest = Manifest::open(&opts.dir_path).unwrap();
+        manifest
+            .append(ManifestEdit::MergeCommitted {
+                non_merge_fid,
+                file_ids: file_ids.clone(),
+            })
+            .unwrap();
+        std::mem::drop(manifest);
+        fs::rename(
+            get_data_file_name(&merge_path, file_ids[0]),
+            get_data_file_name(&opts.dir_path, file_ids[0]),
+        )
+        .unwrap();
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(!merge_path.exists());
+        assert!(opts.dir_path.join(MERGE_FIN_FILE_NAME).is_file());
+        assert_eq!(engine.list_keys().unwrap().len(), 2000);
+        for i in 0..2000 {
+            let value = if i < 1000 { i + 1 } else { i };
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(value));
+        }
+        std::mem::drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 2000);
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
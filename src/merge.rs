@@ -1,27 +1,51 @@
 //! The main focus of merge is to clean redundancy on disk caused by using bitcask.
 //! On merging the data file of bitcask instance A, we do the followings:
-//! 1. Create a tmp directory and a new bitcask instance B.
+//! 1. Create a tmp directory inside A's directory and a new bitcask instance B, which locks it.
 //! 2. Fetch all the log records from A's data file directory and add the record into the B's
 //!     merge directory by checking LogRecordType with the indexer.
 //! 3. After merge completes, create a hint file next to each data files, which is just a
 //!     data file but instead of storing the value, it contains the position and size of the
 //!     values within the corresponding data file.
-
-use std::{fs, path::PathBuf, sync::atomic::Ordering};
+//!
+//! With `Options::merge_parallelism`, data files are rewritten by several threads instead, each
+//! into a merged file of the same id.
+
+use fs2::FileExt;
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::SystemTime,
+};
 
 use crate::{
     batch::NON_TRANSACTION_SEQUENCE,
+    blob::{decode_blob_record, remove_blob_garbage},
+    compaction_filter::FilterDecision,
     data::{
         data_file::{
-            get_data_file_name, DataFile, DATA_FILE_NAME_SUFFIX, MERGE_FIN_FILE_NAME,
-            SEQUENCE_NUMBER_FILE_NAME,
+            get_data_file_name, parse_data_file_id, DataFile, BLOB_GARBAGE_FILE_NAME,
+            HINT_FILE_NAME, MERGE_FIN_FILE_NAME, RECLAIM_STAT_FILE_NAME, SEQUENCE_NUMBER_FILE_NAME,
         },
-        log_record::{LogRecord, LogRecordType},
+        footer::FooterBuilder,
+        log_record::{LogRecord, LogRecordPos, LogRecordType},
     },
     db::{encode_log_record_key, parse_log_record_key, Engine, LOCK_FILE_NAME},
     errors::{Errors, Result},
+    fio::{sync_dir, IOPurpose, IOPurposeGuard},
+    index::keydir::KeydirFile,
+    lock::lock_dir,
+    manifest::{Manifest, ManifestEdit, MANIFEST_FILE_NAME},
+    merge_stats::{MergeCounters, MergeStats},
     options::{IOType, Options},
-    utils,
+    rate_limit::RateLimiter,
+    reclaim::drop_merged_reclaim_stats,
+    utils::{self, time::now_millis},
 };
 
 const MERGE_DIR_NAME: &str = "merge";
@@ -30,47 +54,139 @@ const MERGE_FIN_KEY: &[u8] = "merge-finished".as_bytes();
 impl Engine {
     /// Atomically merge the data file under the current bitcask working directory. During the
     /// merge process, we clean all the deleted log record and construct a hint file used to
-    /// speed up the engine startup time.
-    pub fn merge(&self) -> Result<()> {
+    /// speed up the engine startup time. Return the statistics of the merge.
+    pub fn merge(&self) -> Result<MergeStats> {
         if self.is_empty_engine() {
-            return Ok(());
+            return Ok(MergeStats::default());
         }
+        let _io_purpose = IOPurposeGuard::new(IOPurpose::Merge);
 
         let _merge_lock = self
             .merge_lock
             .try_lock()
             .map_err(|_| Errors::MergeInProgress)?;
+        self.ensure_open()?;
+        self.purge_trash()?;
 
-        let reclaim_size = self.reclaim_size.load(Ordering::SeqCst);
-        let total_size = utils::file::dir_disk_size(&self.options.dir_path);
-        if (reclaim_size as f32) / (total_size as f32) < self.options.data_file_merge_ratio {
+        let metrics = self.merge_metrics();
+        if !self.should_merge(&metrics) {
             return Err(Errors::MergeRationUnreached);
         }
 
         let available_size = utils::file::available_disk_size();
-        if total_size - reclaim_size as u64 > available_size {
+        if metrics.disk_size.saturating_sub(metrics.reclaim_size) > available_size {
             return Err(Errors::MergeNoEnoughSpace);
         }
 
         let merge_path = get_merge_path(&self.options.dir_path);
-        if merge_path.is_dir() {
-            fs::remove_dir_all(merge_path.clone()).unwrap();
-        }
+        remove_merge_dir(&merge_path)?;
         fs::create_dir_all(merge_path.clone()).map_err(|_| Errors::FailedToCreateDatabaseDir)?;
 
-        // Obtain all the live files
-        let merge_files = self.get_merge_files()?;
-        let mut merge_engine_opts = Options::default();
-        merge_engine_opts.dir_path = merge_path.clone();
-        merge_engine_opts.data_file_size = self.options.data_file_size;
-        let merge_engine = Engine::open(merge_engine_opts)?;
+        // Blob files created from now on are referred to by records written after the merged
+        // files, and are never taken for garbage by this merge.
+        let blob_watermark = self.blob_store.next_id();
+
+        // Obtain all the live files. No transaction is split between the merged files and the
+        // new active file, so shipping all the changes of the merged files never leaves one
+        // pending.
+        let merge_files = {
+            let _batch_commit_lock = self.batch_commit_lock.lock().unwrap();
+            self.get_merge_files()?
+        };
+        let counters = Arc::new(MergeCounters::new(merge_files.len()));
+        *self.merge_counters.lock().unwrap() = Some(counters.clone());
+        let res = self.write_merged_files(&merge_path, &merge_files, blob_watermark, &counters);
+        *self.merge_counters.lock().unwrap() = None;
+        res?;
+        self.index.compact();
+
+        Ok(counters.snapshot())
+    }
+
+    /// Rewrite MERGE_FILES into the merge directory MERGE_PATH along with the hint file, and
+    /// mark the merge as completed, counting its progress into COUNTERS. The blob files below
+    /// BLOB_WATERMARK no longer referred to are recorded as garbage.
+    fn write_merged_files(
+        &self,
+        merge_path: &Path,
+        merge_files: &[DataFile],
+        blob_watermark: u64,
+        counters: &MergeCounters,
+    ) -> Result<()> {
+        self.ship_changes()?;
 
         // Create the hint file.
-        let hint_file = DataFile::new_hint_file(&merge_path)?;
-        for data_file in &merge_files {
+        let hint_file = DataFile::new_hint_file(&merge_path.to_path_buf())?;
+        let live_blobs = Mutex::new(HashSet::new());
+        if self.options.merge_parallelism > 1 {
+            self.merge_in_parallel(merge_path, merge_files, &hint_file, &live_blobs, counters)?;
+        } else {
+            self.merge_sequentially(merge_path, merge_files, &hint_file, &live_blobs, counters)?;
+        }
+        hint_file.sync()?;
+
+        // The hint file is distrusted on startup if older than any merged file, whose footer is
+        // written after its hint records.
+        File::options()
+            .write(true)
+            .open(merge_path.join(HINT_FILE_NAME))
+            .and_then(|file| file.set_modified(SystemTime::now()))
+            .map_err(|_| Errors::FailedToWriteToDataFile)?;
+        // Append the data file with a fin_record indicating merge process is completed.
+        let non_merge_file_id = merge_files.last().unwrap().get_file_id() + 1;
+
+        let mut live_blobs = live_blobs.into_inner().unwrap();
+        self.collect_blobs_since(non_merge_file_id, &mut live_blobs)?;
+        self.blob_store
+            .write_garbage(merge_path, blob_watermark, &live_blobs)?;
+        let merge_fin_file = DataFile::new_merge_fin_file(&merge_path.to_path_buf())?;
+        let merge_fin_record = LogRecord {
+            key: MERGE_FIN_KEY.to_vec(),
+            value: non_merge_file_id.to_string().into_bytes(),
+            record_type: LogRecordType::Normal,
+            timestamp: None,
+            user_flags: 0,
+        };
+
+        let encoded_record = merge_fin_record.encode();
+        merge_fin_file.write(&encoded_record)?;
+        merge_fin_file.sync()?;
+        sync_dir(merge_path)?;
+
+        // Garbage of the merged files is discarded once the merged files are installed on the
+        // next startup, so it no longer counts towards the next merge nor write stalls.
+        self.discard_reclaim_sizes(non_merge_file_id);
+
+        Ok(())
+    }
+
+    /// Rewrite the live records of MERGE_FILES into a merge engine under MERGE_PATH, one file
+    /// after another, recording their new positions into HINT_FILE, and the blob files still
+    /// referred to into LIVE_BLOBS.
+    fn merge_sequentially(
+        &self,
+        merge_path: &Path,
+        merge_files: &[DataFile],
+        hint_file: &DataFile,
+        live_blobs: &Mutex<HashSet<u64>>,
+        counters: &MergeCounters,
+    ) -> Result<()> {
+        let mut merge_engine_opts = Options::default();
+        merge_engine_opts.dir_path = merge_path.to_path_buf();
+        merge_engine_opts.data_file_size = self.options.data_file_size;
+        merge_engine_opts.compression = self.options.compression;
+        merge_engine_opts.compression_min_size = self.options.compression_min_size;
+        merge_engine_opts.checksum = self.options.checksum;
+        merge_engine_opts.large_value_threshold = self.options.large_value_threshold;
+        let mut merge_engine = Engine::open(merge_engine_opts)?;
+        merge_engine.blob_store = self.blob_store.clone();
+
+        let now = now_millis();
+        let rate_limiter = RateLimiter::new(self.options.merge_rate_limit);
+        for data_file in merge_files {
             let mut ofs = 0;
             loop {
-                let (mut log_record, size) = match data_file.read_log_record(ofs) {
+                let (log_record, size) = match data_file.read_log_record(ofs) {
                     Ok(result) => result,
                     Err(e) => {
                         if e == Errors::ReadDataFileEOF {
@@ -82,46 +198,221 @@ impl Engine {
                         }
                     }
                 };
+                rate_limiter.acquire(size as u64);
 
                 // Write live log records to the data file,
                 // create a hint file next to each data file.
-                let (key, _) = parse_log_record_key(&log_record.key);
-                if let Some(index_pos) = self.index.get(key.clone()) {
-                    if index_pos.file_id == data_file.get_file_id() && index_pos.ofs == ofs {
-                        log_record.key =
-                            encode_log_record_key(key.clone(), NON_TRANSACTION_SEQUENCE);
-                        let log_record_pos = merge_engine.append_log_record(&mut log_record)?;
-                        hint_file.write_hint_record(key.clone(), log_record_pos)?;
+                if let Some((key, mut log_record)) =
+                    self.live_merge_record(data_file.get_file_id(), ofs, log_record, now, counters)?
+                {
+                    if let Some(blob_ref) = decode_blob_record(&log_record) {
+                        live_blobs.lock().unwrap().insert(blob_ref.id);
+                    }
+                    let log_record_pos = merge_engine.append_log_record(&mut log_record)?;
+                    if log_record.record_type != LogRecordType::Deleted {
+                        hint_file.write_hint_record(key, log_record_pos)?;
                     }
+                    rate_limiter.acquire(log_record_pos.size as u64);
+                    counters.record(size as u64, Some(log_record_pos.size as u64));
+                } else {
+                    counters.record(size as u64, None);
                 }
 
                 ofs += size as u64;
             }
+            self.merged_file(counters);
+        }
+
+        // The last merged file is sealed like the others once installed.
+        {
+            let active_file = merge_engine.active_file.read().unwrap();
+            merge_engine.write_active_footer(&active_file)?;
         }
 
         // Synchronize all the metadata to the disk
-        merge_engine.sync()?;
-        hint_file.sync()?;
+        merge_engine.sync()
+    }
 
-        // Append the data file with a fin_record indicating merge process is completed.
-        let non_merge_file_id = merge_files.last().unwrap().get_file_id() + 1;
-        let merge_fin_file = DataFile::new_merge_fin_file(&merge_path)?;
-        let merge_fin_record = LogRecord {
-            key: MERGE_FIN_KEY.to_vec(),
-            value: non_merge_file_id.to_string().into_bytes(),
-            record_type: LogRecordType::Normal,
+    /// Rewrite the live records of MERGE_FILES into files under MERGE_PATH with `Options::
+    /// merge_parallelism` threads, recording their new positions into HINT_FILE. Each file is
+    /// rewritten into a merged file of the same id, which is never larger. The blob files still
+    /// referred to are recorded into LIVE_BLOBS.
+    fn merge_in_parallel(
+        &self,
+        merge_path: &Path,
+        merge_files: &[DataFile],
+        hint_file: &DataFile,
+        live_blobs: &Mutex<HashSet<u64>>,
+        counters: &MergeCounters,
+    ) -> Result<()> {
+        // The merge directory is locked by the merge engine in sequential mode.
+        let _merge_dir_lock = lock_dir(merge_path)?;
+
+        let now = now_millis();
+        let rate_limiter = RateLimiter::new(self.options.merge_rate_limit);
+        let hint_file = Mutex::new(hint_file);
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let merge_file = |data_file: &DataFile| -> Result<()> {
+            let file_id = data_file.get_file_id();
+            let merged_file =
+                self.new_data_file(&merge_path.to_path_buf(), file_id, self.options.io_type)?;
+            let mut summary = FooterBuilder::default();
+            let mut ofs = 0;
+            loop {
+                let (log_record, size) = match data_file.read_log_record(ofs) {
+                    Ok(result) => result,
+                    Err(Errors::ReadDataFileEOF) => break,
+                    Err(e) => return Err(e),
+                };
+                rate_limiter.acquire(size as u64);
+
+                if let Some((key, log_record)) =
+                    self.live_merge_record(file_id, ofs, log_record, now, counters)?
+                {
+                    if let Some(blob_ref) = decode_blob_record(&log_record) {
+                        live_blobs.lock().unwrap().insert(blob_ref.id);
+                    }
+                    let encoded_record = self.encode_log_record(&log_record)?;
+                    let log_record_pos = LogRecordPos {
+                        file_id,
+                        ofs: merged_file.get_write_ofs(),
+                        size: encoded_record.len() as u32,
+                        expire_at: log_record.expire_at(),
+                    };
+                    merged_file.write(&encoded_record)?;
+                    summary.add(&log_record.key, log_record.record_type, &encoded_record);
+                    if log_record.record_type != LogRecordType::Deleted {
+                        hint_file
+                            .lock()
+                            .unwrap()
+                            .write_hint_record(key, log_record_pos)?;
+                    }
+                    rate_limiter.acquire(log_record_pos.size as u64);
+                    counters.record(size as u64, Some(log_record_pos.size as u64));
+                } else {
+                    counters.record(size as u64, None);
+                }
+
+                ofs += size as u64;
+            }
+            let footer = summary.finish();
+            if footer.record_count > 0 {
+                footer.write(&merged_file)?;
+            }
+            merged_file.sync()?;
+            self.merged_file(counters);
+            Ok(())
         };
 
-        let encoded_record = merge_fin_record.encode();
-        merge_fin_file.write(&encoded_record)?;
-        merge_fin_file.sync()?;
+        let worker_num = self.options.merge_parallelism.min(merge_files.len());
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..worker_num)
+                .map(|_| {
+                    scope.spawn(|| -> Result<()> {
+                        let _io_purpose = IOPurposeGuard::new(IOPurpose::Merge);
+                        while !failed.load(Ordering::SeqCst) {
+                            let i = next.fetch_add(1, Ordering::SeqCst);
+                            let data_file = match merge_files.get(i) {
+                                Some(data_file) => data_file,
+                                None => break,
+                            };
+                            if let Err(e) = merge_file(data_file) {
+                                failed.store(true, Ordering::SeqCst);
+                                return Err(e);
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().unwrap())
+        })
+    }
 
-        Ok(())
+    /// Get the record to write into the merged files for LOG_RECORD read at OFS of data file
+    /// FILE_ID along with its key, or None if it is dropped by merge. Expired records are
+    /// counted into COUNTERS.
+    fn live_merge_record(
+        &self,
+        file_id: u64,
+        ofs: u64,
+        mut log_record: LogRecord,
+        now: u64,
+        counters: &MergeCounters,
+    ) -> Result<Option<(Vec<u8>, LogRecord)>> {
+        let (key, _) = parse_log_record_key(&log_record.key);
+        let index_pos = self.index.get(key.clone());
+
+        // A deletion record is committed if the key is absent or written again after it, while
+        // a deletion record followed by the live entry belongs to an aborted transaction.
+        if log_record.record_type == LogRecordType::Deleted {
+            let committed = index_pos.is_none_or(|pos| (pos.file_id, pos.ofs) > (file_id, ofs));
+            if !committed || !self.is_tombstone_retained(&log_record, now) {
+                return Ok(None);
+            }
+            log_record.key = encode_log_record_key(key.clone(), NON_TRANSACTION_SEQUENCE);
+            return Ok(Some((key, log_record)));
+        }
+
+        let index_pos = match index_pos {
+            Some(index_pos) if index_pos.file_id == file_id && index_pos.ofs == ofs => index_pos,
+            _ => return Ok(None),
+        };
+
+        // Expired records are dropped along with their index entries, as if the key was deleted,
+        // unless the key is written again meanwhile.
+        if log_record.is_expired(now) {
+            self.drop_expired_entry(&key, index_pos, Some(&log_record));
+            counters.expired();
+            return Ok(None);
+        }
+        if let Some(filter) = &self.options.compaction_filter {
+            let value = match decode_blob_record(&log_record) {
+                Some(blob_ref) => self.blob_store.read(&blob_ref)?,
+                None => log_record.user_value().to_vec(),
+            };
+            match filter.filter(&key, &value) {
+                FilterDecision::Keep => {}
+                FilterDecision::Remove => return Ok(None),
+                FilterDecision::ChangeValue(value) => {
+                    // The rewritten record keeps the time it was written and its user flags.
+                    let (timestamp, user_flags) = (log_record.timestamp, log_record.user_flags);
+                    log_record = match log_record.expire_at() {
+                        Some(expire_at) => LogRecord::new_expiring(key.clone(), &value, expire_at),
+                        None => LogRecord {
+                            key: key.clone(),
+                            value,
+                            record_type: LogRecordType::Normal,
+                            timestamp: None,
+                            user_flags: 0,
+                        },
+                    };
+                    log_record.timestamp = timestamp;
+                    log_record.user_flags = user_flags;
+                }
+            }
+        }
+        log_record.key = encode_log_record_key(key.clone(), NON_TRANSACTION_SEQUENCE);
+        Ok(Some((key, log_record)))
+    }
+
+    /// Whether the deletion record LOG_RECORD is kept by merges at NOW milliseconds since the
+    /// epoch, as it is within `Options::tombstone_ttl`.
+    pub(crate) fn is_tombstone_retained(&self, log_record: &LogRecord, now: u64) -> bool {
+        match (self.options.tombstone_ttl, log_record.deleted_at()) {
+            (Some(ttl), Some(deleted_at)) => {
+                now.saturating_sub(deleted_at) < ttl.as_millis() as u64
+            }
+            _ => false,
+        }
     }
 
     fn is_empty_engine(&self) -> bool {
         let active_file = self.active_file.read().unwrap();
-        let old_files = self.old_files.read().unwrap();
+        let old_files = self.old_files();
         if active_file.get_write_ofs() == 0 && old_files.len() == 0 {
             return true;
         }
@@ -130,29 +421,45 @@ impl Engine {
 
     /// Get the list of all data files. Close and replace the current active file with a new one.
     fn get_merge_files(&self) -> Result<Vec<DataFile>> {
-        // Get all the file id of all old files.
-        let mut old_files = self.old_files.write().unwrap();
-        let mut merge_file_ids: Vec<u32> = old_files.iter().map(|(k, _)| *k).collect();
-
         // Get the file id of active file, and close the current active file.
         let mut active_file = self.active_file.write().unwrap();
+
+        // Get all the file id of all old files.
+        let mut merge_file_ids: Vec<u64> = self.old_files().keys().copied().collect();
+
         active_file.sync()?;
         let active_file_id = active_file.get_file_id();
-        let new_active_file = DataFile::new(
+        let end_ofs = self.write_active_footer(&active_file)?;
+        self.manifest
+            .append(ManifestEdit::SealFile(active_file_id, end_ofs))?;
+        let new_active_file = self.new_data_file(
             &self.options.dir_path,
             active_file_id + 1,
             IOType::StandardFIO,
         )?;
+        self.manifest
+            .append(ManifestEdit::NewFile(active_file_id + 1))?;
         *active_file = new_active_file;
-        let old_file = DataFile::new(&self.options.dir_path, active_file_id, IOType::StandardFIO)?;
-        old_files.insert(active_file_id, old_file);
+        let old_file =
+            DataFile::new_lazy(&self.options.dir_path, active_file_id, self.read_io_type)
+                .with_io_wrapper(self.options.io_wrapper.clone());
+        old_file.set_end_ofs(end_ofs);
+        self.update_old_files(|old_files| {
+            old_files.insert(active_file_id, Arc::new(old_file));
+        });
 
         merge_file_ids.push(active_file_id);
         merge_file_ids.sort();
 
+        // The merged files are read up to their logical end, before their footer.
+        let old_files = self.old_files();
         let mut merge_files = Vec::new();
         for fid in &merge_file_ids {
-            let data_file = DataFile::new(&self.options.dir_path, *fid, IOType::StandardFIO)?;
+            let data_file = DataFile::new_lazy(&self.options.dir_path, *fid, IOType::StandardFIO)
+                .with_io_wrapper(self.options.io_wrapper.clone());
+            if let Some(end_ofs) = old_files.get(fid).and_then(|f| f.get_end_ofs()) {
+                data_file.set_end_ofs(end_ofs);
+            }
             merge_files.push(data_file);
         }
 
@@ -161,88 +468,133 @@ impl Engine {
 }
 
 /// Append DIR_PATH with "merge" suffix, which is the default directory name used for merge process.
-fn get_merge_path(dir_path: &PathBuf) -> PathBuf {
-    let file_name = dir_path.file_name().unwrap();
-    let merge_path = std::format!("{}-{}", file_name.to_str().unwrap(), MERGE_DIR_NAME);
-    let parent = dir_path.parent().unwrap();
-    parent.to_path_buf().join(merge_path)
+/// Get the merge directory of the engine under DIR_PATH. It is placed inside DIR_PATH, which is
+/// locked by the engine, so it is never shared with another engine.
+pub(crate) fn get_merge_path(dir_path: &Path) -> PathBuf {
+    dir_path.join(MERGE_DIR_NAME)
 }
 
-/// Load all data file from the merge directory to DIR_PATH.
-pub(crate) fn load_merge_files(dir_path: &PathBuf) -> Result<()> {
-    let merge_path = get_merge_path(dir_path);
-
-    // If the directory does not exists, it indicates no merge happened, return.
+/// Remove the merge directory MERGE_PATH. The merge engine holds the lock of the directory while
+/// merging, so fail with `Errors::MergeInProgress` rather than deleting the files of a running
+/// merge.
+fn remove_merge_dir(merge_path: &Path) -> Result<()> {
     if !merge_path.is_dir() {
         return Ok(());
     }
+    if let Ok(lock_file) = File::open(merge_path.join(LOCK_FILE_NAME)) {
+        if lock_file.try_lock_exclusive().is_err() {
+            return Err(Errors::MergeInProgress);
+        }
+    }
+    fs::remove_dir_all(merge_path).map_err(|_| Errors::FailedToReadDatabaseDir)
+}
 
-    // Check if the merge-fin file exists.
-    let mut merge_file_names = Vec::new();
-    let mut merge_finished = false;
-    let dir = fs::read_dir(merge_path.clone()).map_err(|_| Errors::FailedToReadDatabaseDir)?;
-    for file in dir {
-        if let Ok(entry) = file {
+/// Load all data file from the merge directory to DIR_PATH. The merge is committed to MANIFEST
+/// before any file is moved, and an interrupted installation of a committed merge is completed.
+pub(crate) fn load_merge_files(dir_path: &PathBuf, manifest: &Manifest) -> Result<()> {
+    let merge_path = get_merge_path(dir_path);
+
+    if manifest.pending_merge().is_none() {
+        // If the directory does not exists, it indicates no merge happened, return.
+        if !merge_path.is_dir() {
+            return Ok(());
+        }
+
+        // Merge-fin file does not exist indicates merge process is not completed due to a
+        // undesired behavior, for instance, system shutdown. So we deletes the whole merge
+        // directory to discard the merge process.
+        if !merge_path.join(MERGE_FIN_FILE_NAME).is_file() {
+            return remove_merge_dir(&merge_path);
+        }
+
+        let merge_fin_file = DataFile::new_merge_fin_file(&merge_path)?;
+        let merge_fin_record = merge_fin_file.read_log_record(0)?;
+        let v = String::from_utf8(merge_fin_record.0.value).unwrap();
+        let non_merge_fid = v.parse::<u64>().unwrap();
+        manifest.append(ManifestEdit::MergeCommitted {
+            non_merge_fid,
+            file_ids: list_merged_file_ids(&merge_path)?,
+        })?;
+    }
+    let (non_merge_fid, merged_file_ids) = manifest.pending_merge().unwrap();
+
+    // Positions recorded by the keydir file become invalid once the merged files are installed.
+    KeydirFile::remove(dir_path)?;
+    drop_merged_reclaim_stats(dir_path, non_merge_fid)?;
+
+    // Move merged data file to the current bitcask working directory, which replace the
+    // non-merged files with the same id.
+    if merge_path.is_dir() {
+        let dir = fs::read_dir(merge_path.clone()).map_err(|_| Errors::FailedToReadDatabaseDir)?;
+        for entry in dir.flatten() {
             let file_os_str = entry.file_name();
             let file_name = file_os_str.to_str().unwrap();
-            if file_name.ends_with(MERGE_FIN_FILE_NAME) {
-                merge_finished = true;
-            }
 
             // Ignore the file indicates the sequence number. It is possible to have a new
             // transaction happens during the merge process, so the old sequence number file
             // is outdated.
-            if file_name.ends_with(SEQUENCE_NUMBER_FILE_NAME) || file_name.ends_with(LOCK_FILE_NAME)
+            if file_name.ends_with(SEQUENCE_NUMBER_FILE_NAME)
+                || file_name.ends_with(LOCK_FILE_NAME)
+                || file_name.ends_with(RECLAIM_STAT_FILE_NAME)
+                || file_name.starts_with(MANIFEST_FILE_NAME)
+                || file_name == BLOB_GARBAGE_FILE_NAME
             {
                 continue;
             }
 
-            // Skip empty files.
-            let meta = entry.metadata().unwrap();
-            if file_name.ends_with(DATA_FILE_NAME_SUFFIX) && meta.len() == 0 {
-                continue;
+            // Skip empty files, which are not committed.
+            if let Some(file_id) = parse_data_file_id(file_name) {
+                if !merged_file_ids.contains(&file_id) {
+                    continue;
+                }
             }
 
-            merge_file_names.push(entry.file_name());
+            fs::rename(entry.path(), dir_path.join(file_name)).unwrap();
         }
-    }
-
-    // Merge-fin file does not exist indicates merge process is not completed due to a undesired
-    // behavior, for instance, system shutdown. So we deletes the whole merge directory to
-    // discard the merge process.
-    if !merge_finished {
-        fs::remove_dir_all(merge_path.clone()).unwrap();
-        return Ok(());
+        sync_dir(dir_path)?;
     }
 
     // Delete all non-merged file.
-    let merge_fin_file = DataFile::new_merge_fin_file(&merge_path)?;
-    let merge_fin_record = merge_fin_file.read_log_record(0)?;
-    let v = String::from_utf8(merge_fin_record.0.value).unwrap();
-    let non_merge_fid = v.parse::<u32>().unwrap();
     for file_id in 0..non_merge_fid {
         let file = get_data_file_name(dir_path, file_id);
-        if file.is_file() {
+        if !merged_file_ids.contains(&file_id) && file.is_file() {
             fs::remove_file(file).unwrap();
         }
     }
 
-    // Move merged data file to the current bitcask working directory.
-    for file_name in merge_file_names {
-        let from = merge_path.join(file_name.clone());
-        let to = dir_path.join(file_name.clone());
-        fs::rename(from, to).unwrap();
-    }
-
-    fs::remove_dir_all(merge_path.clone()).unwrap();
+    // The blob files only referred to by the deleted files are garbage now.
+    remove_blob_garbage(dir_path, &merge_path)?;
+    remove_merge_dir(&merge_path)?;
+    manifest.append(ManifestEdit::MergeInstalled)?;
 
     Ok(())
 }
 
+/// Get the ids of all non-empty data files under MERGE_PATH.
+fn list_merged_file_ids(merge_path: &Path) -> Result<Vec<u64>> {
+    let mut file_ids = Vec::new();
+    let dir = fs::read_dir(merge_path).map_err(|_| Errors::FailedToReadDatabaseDir)?;
+    for entry in dir.flatten() {
+        let file_os_str = entry.file_name();
+        let file_id = match parse_data_file_id(file_os_str.to_str().unwrap()) {
+            Some(file_id) => file_id,
+            None => continue,
+        };
+        if entry.metadata().map(|meta| meta.len() > 0).unwrap_or(false) {
+            file_ids.push(file_id);
+        }
+    }
+    file_ids.sort();
+    Ok(file_ids)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::utils::rand_kv::{get_test_key, get_test_value};
+    use crate::{
+        data::data_file::HINT_FILE_NAME,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
     use bytes::Bytes;
     use std::{sync::Arc, thread};
 
@@ -327,6 +679,93 @@ mod tests {
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     }
 
+    #[test]
+    fn test_merge_parallel() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-merge-parallel");
+        opts.data_file_size = 64 * 1024;
+        opts.data_file_merge_ratio = 0 as f32;
+        opts.merge_parallelism = 4;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..20000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..5000 {
+            assert!(engine
+                .put(get_test_key(i), Bytes::from("new value"))
+                .is_ok());
+        }
+        for i in 15000..20000 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine2.list_keys().unwrap().len(), 15000);
+        for i in 0..5000 {
+            assert_eq!(
+                engine2.get(get_test_key(i)).unwrap(),
+                Bytes::from("new value")
+            );
+        }
+        for i in 5000..15000 {
+            assert_eq!(engine2.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+        std::mem::drop(engine2);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_merge_tombstone_ttl() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-merge-tombstone-ttl");
+        opts.data_file_size = 64 * 1024;
+        opts.data_file_merge_ratio = 0 as f32;
+        opts.tombstone_ttl = Some(std::time::Duration::from_secs(60 * 60));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..500 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        for i in 0..100 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        std::mem::drop(engine);
+
+        // The deletion records survive the merge, and still delete the entries when the data
+        // files are scanned without the hint file.
+        fs::remove_file(opts.dir_path.join(HINT_FILE_NAME)).unwrap();
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let mut deleted_num = 0;
+        for data_file in engine.old_files().values() {
+            let mut ofs = 0;
+            while let Ok((log_record, size)) = data_file.read_log_record(ofs) {
+                if log_record.record_type == LogRecordType::Deleted {
+                    deleted_num += 1;
+                }
+                ofs += size as u64;
+            }
+        }
+        assert_eq!(deleted_num, 500);
+        assert_eq!(engine.list_keys().unwrap().len(), 600);
+        for i in 100..500 {
+            assert!(engine.get(get_test_key(i)).is_err());
+        }
+        std::mem::drop(engine);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
     #[test]
     fn test_merge_4() {
         let mut opts = Options::default();
@@ -410,4 +849,41 @@ mod tests {
 
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     }
+
+    #[test]
+    fn test_merge_dir_locked() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-merge-locked");
+        opts.data_file_size = 32 * 1024;
+        opts.data_file_merge_ratio = 0 as f32;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+
+        // A directory sharing the name prefix of the engine is not touched by merge.
+        let sibling_path = PathBuf::from("/tmp/bitcask-rs-merge-locked-merge");
+        fs::create_dir_all(&sibling_path).unwrap();
+        fs::write(sibling_path.join("foreign"), b"foreign").unwrap();
+
+        // The merge directory is left alone while it is locked by another merge engine.
+        let merge_path = get_merge_path(&opts.dir_path);
+        let mut merge_opts = Options::default();
+        merge_opts.dir_path = merge_path.clone();
+        let merge_engine = Engine::open(merge_opts).expect("failed to open engine");
+        assert_eq!(engine.merge().err(), Some(Errors::MergeInProgress));
+        assert!(merge_path.is_dir());
+        std::mem::drop(merge_engine);
+
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(!merge_path.exists());
+        assert_eq!(engine2.list_keys().unwrap().len(), 2000);
+        assert!(sibling_path.join("foreign").is_file());
+        std::mem::drop(engine2);
+
+        fs::remove_dir_all(sibling_path).expect("failed to remove path");
+        fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
 }
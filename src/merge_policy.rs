@@ -0,0 +1,143 @@
+//! Merge policies decide when the data files are worth merging. The policy configured in
+//! `Options` is consulted by every merge, manual or in background, and a merge not wanted by it
+//! fails with `Errors::MergeRationUnreached`. Without a policy, merges are triggered by
+//! `Options::data_file_merge_ratio`. Writes stalled at `Options::write_stall_hard_limit` are only
+//! resumed by a merge, so merges are never refused then, whatever the policy.
+
+use std::sync::atomic::Ordering;
+
+use crate::{db::Engine, utils};
+
+/// The state of the data files a merge policy decides on, where:
+/// - `reclaim_size` is the number of bytes reclaimable by a merge.
+/// - `disk_size` is the size of the database directory.
+/// - `data_file_num` is the number of data files, including the active file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MergeMetrics {
+    pub reclaim_size: u64,
+    pub disk_size: u64,
+    pub data_file_num: usize,
+}
+
+/// Decides whether the data files should be merged.
+pub trait MergePolicy: Sync + Send {
+    /// Whether the data files described by METRICS should be merged.
+    fn should_merge(&self, metrics: &MergeMetrics) -> bool;
+}
+
+/// Merge once the reclaimable bytes reach a ratio of the directory size.
+pub struct RatioMergePolicy(pub f32);
+
+impl MergePolicy for RatioMergePolicy {
+    fn should_merge(&self, metrics: &MergeMetrics) -> bool {
+        metrics.reclaim_size as f32 / metrics.disk_size as f32 >= self.0
+    }
+}
+
+/// Merge once the reclaimable bytes reach a number of bytes.
+pub struct DeadBytesMergePolicy(pub u64);
+
+impl MergePolicy for DeadBytesMergePolicy {
+    fn should_merge(&self, metrics: &MergeMetrics) -> bool {
+        metrics.reclaim_size >= self.0
+    }
+}
+
+/// Merge once the number of data files reaches a count.
+pub struct FileCountMergePolicy(pub usize);
+
+impl MergePolicy for FileCountMergePolicy {
+    fn should_merge(&self, metrics: &MergeMetrics) -> bool {
+        metrics.data_file_num >= self.0
+    }
+}
+
+/// Never merge.
+pub struct NeverMergePolicy;
+
+impl MergePolicy for NeverMergePolicy {
+    fn should_merge(&self, _metrics: &MergeMetrics) -> bool {
+        false
+    }
+}
+
+impl Engine {
+    /// Get the state of the data files merge policies decide on.
+    pub fn merge_metrics(&self) -> MergeMetrics {
+        MergeMetrics {
+            reclaim_size: self.reclaim_size.load(Ordering::SeqCst) as u64,
+            disk_size: utils::file::dir_disk_size(&self.options.dir_path),
+            data_file_num: self.old_files().len() + 1,
+        }
+    }
+
+    /// Whether the configured merge policy wants the data files described by METRICS merged, or
+    /// writes are stalled until a merge.
+    pub(crate) fn should_merge(&self, metrics: &MergeMetrics) -> bool {
+        if self.is_write_stalled() {
+            return true;
+        }
+        match &self.options.merge_policy {
+            Some(policy) => policy.should_merge(metrics),
+            None => RatioMergePolicy(self.options.data_file_merge_ratio).should_merge(metrics),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+
+    use crate::{
+        errors::Errors,
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    #[test]
+    fn test_merge_policies() {
+        let metrics = MergeMetrics {
+            reclaim_size: 300,
+            disk_size: 1000,
+            data_file_num: 4,
+        };
+        assert!(RatioMergePolicy(0.3).should_merge(&metrics));
+        assert!(!RatioMergePolicy(0.5).should_merge(&metrics));
+        assert!(DeadBytesMergePolicy(300).should_merge(&metrics));
+        assert!(!DeadBytesMergePolicy(301).should_merge(&metrics));
+        assert!(FileCountMergePolicy(4).should_merge(&metrics));
+        assert!(!FileCountMergePolicy(5).should_merge(&metrics));
+        assert!(!NeverMergePolicy.should_merge(&metrics));
+    }
+
+    #[test]
+    fn test_engine_merge_policy() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-merge-policy");
+        opts.data_file_size = 16 * 1024;
+        opts.merge_policy = Some(Arc::new(NeverMergePolicy));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        assert_eq!(engine.merge().err(), Some(Errors::MergeRationUnreached));
+        std::mem::drop(engine);
+
+        // Live entries only, so the merge is wanted by the file count alone.
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+        opts.merge_policy = Some(Arc::new(FileCountMergePolicy(3)));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(engine.merge_metrics().data_file_num >= 3);
+        assert_eq!(engine.merge_metrics().reclaim_size, 0);
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
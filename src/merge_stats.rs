@@ -0,0 +1,164 @@
+//! Progress and statistics of merges. Counters are updated while data files are rewritten, so a
+//! running merge can be observed through `Engine::merge_status` or the configured progress
+//! callback, and its final statistics are returned by `Engine::merge`.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::db::Engine;
+
+/// Statistics of a merge, where:
+/// - `file_num` is the number of data files to merge.
+/// - `files_processed` is the number of data files merged so far.
+/// - `bytes_read` and `bytes_written` are the bytes of records read from the data files and
+///   written into the merged files.
+/// - `records_written` is the number of live records written into the merged files.
+/// - `records_dropped` is the number of stale, deleted, expired or filtered records dropped.
+/// - `records_expired` is the number of expired records among the dropped ones.
+/// - `elapsed` is the time since the merge started.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MergeStats {
+    pub file_num: usize,
+    pub files_processed: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub records_written: usize,
+    pub records_dropped: usize,
+    pub records_expired: usize,
+    pub elapsed: Duration,
+}
+
+/// Called with the statistics of a merge after each data file is merged.
+pub type MergeProgressCallback = Arc<dyn Fn(&MergeStats) + Sync + Send>;
+
+/// Counters of a running merge, shared by the threads rewriting data files.
+pub(crate) struct MergeCounters {
+    file_num: usize,
+    files_processed: AtomicUsize,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    records_written: AtomicUsize,
+    records_dropped: AtomicUsize,
+    records_expired: AtomicUsize,
+    started_at: Instant,
+}
+
+impl MergeCounters {
+    pub(crate) fn new(file_num: usize) -> Self {
+        Self {
+            file_num,
+            files_processed: AtomicUsize::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            records_written: AtomicUsize::new(0),
+            records_dropped: AtomicUsize::new(0),
+            records_expired: AtomicUsize::new(0),
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Count a record of SIZE bytes read, and written back as WRITTEN bytes unless dropped.
+    pub(crate) fn record(&self, size: u64, written: Option<u64>) {
+        self.bytes_read.fetch_add(size, Ordering::Relaxed);
+        match written {
+            Some(written) => {
+                self.bytes_written.fetch_add(written, Ordering::Relaxed);
+                self.records_written.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                self.records_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Count an expired record, which is also counted as dropped by `record`.
+    pub(crate) fn expired(&self) {
+        self.records_expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> MergeStats {
+        MergeStats {
+            file_num: self.file_num,
+            files_processed: self.files_processed.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            records_written: self.records_written.load(Ordering::Relaxed),
+            records_dropped: self.records_dropped.load(Ordering::Relaxed),
+            records_expired: self.records_expired.load(Ordering::Relaxed),
+            elapsed: self.started_at.elapsed(),
+        }
+    }
+}
+
+impl Engine {
+    /// Get the statistics of the running merge so far, or None if no merge is running.
+    pub fn merge_status(&self) -> Option<MergeStats> {
+        self.merge_counters
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|counters| counters.snapshot())
+    }
+
+    /// Count a data file merged, and report the progress to the configured callback.
+    pub(crate) fn merged_file(&self, counters: &MergeCounters) {
+        counters.files_processed.fetch_add(1, Ordering::Relaxed);
+        if let Some(on_progress) = &self.options.merge_progress {
+            on_progress(&counters.snapshot());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Mutex};
+
+    use super::*;
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    #[test]
+    fn test_merge_stats() {
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let progress_clone = progress.clone();
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-merge-stats");
+        opts.data_file_size = 64 * 1024;
+        opts.data_file_merge_ratio = 0 as f32;
+        opts.merge_progress = Some(Arc::new(move |stats: &MergeStats| {
+            progress_clone.lock().unwrap().push(stats.files_processed);
+        }));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine.merge_status().is_none());
+
+        for i in 0..5000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..1000 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+
+        let stats = engine.merge().expect("failed to merge");
+        assert!(stats.file_num > 1);
+        assert_eq!(stats.files_processed, stats.file_num);
+        assert_eq!(stats.records_written, 4000);
+        assert_eq!(stats.records_dropped, 2000);
+        assert!(stats.bytes_read > stats.bytes_written);
+        assert!(engine.merge_status().is_none());
+        assert_eq!(
+            *progress.lock().unwrap(),
+            (1..=stats.file_num).collect::<Vec<_>>()
+        );
+        std::mem::drop(engine);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
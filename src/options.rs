@@ -1,4 +1,10 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    change_sink::ChangeSink, compaction_filter::CompactionFilter, errors::Result,
+    event_listener::EventListener, fio::IOWrapper, merge_policy::MergePolicy,
+    merge_stats::MergeProgressCallback, prefix::PrefixExtractor,
+};
 
 /// The configuration for database, where:
 #[derive(Clone)]
@@ -18,11 +24,130 @@ pub struct Options {
     /// Determines the indexer used for storage.
     pub index_type: IndexType,
 
+    /// Number of shards of the BTree indexer, each with its own lock, so that concurrent writers
+    /// contend less. Iterators merge the shards back into key order.
+    pub index_shard_num: usize,
+
     /// The IO type used for starting the engine.
     pub startup_io_type: IOType,
 
-    /// Threshold for performing merge process.
+    /// The IO type used for the data files once the engine is started.
+    pub io_type: IOType,
+
+    /// The IO type used for reading the sealed data files once the engine is started, while the
+    /// active file is appended with `io_type`. Sealed files never change, so they can be kept
+    /// memory-mapped with `IOType::MemoryMapped`, serving reads without a system call. Same as
+    /// `io_type` if set to None.
+    pub read_io_type: Option<IOType>,
+
+    /// Reserve the disk space of `data_file_size` bytes for each data file created for appends,
+    /// so appends neither fragment the file nor run out of space midway.
+    pub preallocate_data_files: bool,
+
+    /// Wraps the IO manager of every data file opened by the engine, such as a rate limiter, or a
+    /// fault injector in tests. Disabled if set to None.
+    pub io_wrapper: Option<Arc<dyn IOWrapper>>,
+
+    /// Compresses the values written, when it makes them smaller. Values are read back whatever
+    /// this is set to.
+    pub compression: CompressionType,
+
+    /// Values shorter than this are written uncompressed, since they barely compress.
+    pub compression_min_size: usize,
+
+    /// Values of at least this many bytes, or larger than half of `data_file_size`, are stored
+    /// in their own blob file instead of the data files, so merges do not copy them.
+    pub large_value_threshold: u64,
+
+    /// Writes of keys longer than this many bytes fail with `Errors::KeyTooLarge`.
+    pub max_key_size: usize,
+
+    /// Writes of values longer than this many bytes fail with `Errors::ValueTooLarge`.
+    pub max_value_size: usize,
+
+    /// Threshold for performing merge process, used if `merge_policy` is not set.
     pub data_file_merge_ratio: f32,
+
+    /// The maximum number of sealed data files kept opened for reading. Sealed files are opened
+    /// on their first read, and the least recently read one is closed beyond this limit.
+    pub max_open_files: usize,
+
+    /// Capacity in bytes of the in-memory LRU cache of the values read from the data files.
+    /// Disabled if set to 0.
+    pub cache_size: usize,
+
+    /// Persist the index to a keydir file on close, which is memory-mapped and served directly
+    /// on the next startup while the in-memory index is built in background. Only applicable to
+    /// the BTree and SkipList index.
+    pub persist_keydir: bool,
+
+    /// Extracts key prefixes recorded by per-file bloom filters, which let prefix scans skip the
+    /// index when no data file contains the prefix. Disabled if set to None.
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+
+    /// Writes are delayed progressively once the reclaimable bytes exceed this limit, giving
+    /// merges a chance to catch up. Disabled if set to 0.
+    pub write_stall_soft_limit: usize,
+
+    /// Writes are rejected with `Errors::WriteStalled` once the reclaimable bytes exceed this
+    /// limit, until a merge discards them. Disabled if set to 0.
+    pub write_stall_hard_limit: usize,
+
+    /// The checksum ending each record written, verified when it is read. Records are read
+    /// whatever this is set to. `ChecksumType::None` saves the cost of checksums on trusted
+    /// storage, but a torn write at the end of the active file may then go undetected on startup.
+    pub checksum: ChecksumType,
+
+    /// Determines which reads of user values verify the CRC of the record. Startup, merge and
+    /// integrity verification always verify the CRC.
+    pub read_checksum_policy: ChecksumPolicy,
+
+    /// Determines how a corrupted record found while loading the data files on startup is
+    /// handled.
+    pub corruption_policy: CorruptionPolicy,
+
+    /// Receives every committed change in commit order, with at-least-once delivery. Disabled if
+    /// set to None.
+    pub change_sink: Option<Arc<dyn ChangeSink>>,
+
+    /// Decides whether each live record copied by merge is kept, dropped or rewritten. Disabled
+    /// if set to None.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+
+    /// Deleted entries are kept in a trash for this long, during which `Engine::undelete` can
+    /// restore them. Disabled if set to None.
+    pub trash_retention: Option<Duration>,
+
+    /// The active file is synced in background at this interval, bounding the writes lost on a
+    /// crash. Disabled if set to None.
+    pub sync_interval: Option<Duration>,
+
+    /// The engine is merged in background when due. Only effective for engines opened with
+    /// `Engine::open_shared`. Disabled if set to None.
+    pub auto_merge: Option<AutoMergeOptions>,
+
+    /// The maximum bytes per second read and written by merges, unlimited if set to 0.
+    pub merge_rate_limit: u64,
+
+    /// The number of threads rewriting data files during a merge. Files are merged one after
+    /// another if set to 1 or less.
+    pub merge_parallelism: usize,
+
+    /// Notified of the progress of merges after each data file is merged.
+    pub merge_progress: Option<MergeProgressCallback>,
+
+    /// Deletion records are kept by merges for this long after the deletion, so consumers
+    /// tailing the data files observe the deletes made while they were offline. Deletion records
+    /// are dropped by the first merge if set to None.
+    pub tombstone_ttl: Option<Duration>,
+
+    /// Decides when the data files are merged. Merges are triggered by `data_file_merge_ratio`
+    /// if set to None.
+    pub merge_policy: Option<Arc<dyn MergePolicy>>,
+
+    /// Notified of the events of the engine, such as the expiry of entries. Disabled if set to
+    /// None.
+    pub event_listener: Option<Arc<dyn EventListener>>,
 }
 
 #[derive(Clone, PartialEq)]
@@ -30,6 +155,7 @@ pub enum IndexType {
     BPTree,
     BTree,
     SkipList,
+    Hash,
 }
 
 impl Default for Options {
@@ -40,16 +166,66 @@ impl Default for Options {
             bytes_per_sync: 0,
             sync_writes: false,
             index_type: IndexType::BTree,
+            index_shard_num: 1,
             startup_io_type: IOType::StandardFIO,
+            io_type: IOType::StandardFIO,
+            read_io_type: None,
+            preallocate_data_files: false,
+            io_wrapper: None,
+            compression: CompressionType::None,
+            compression_min_size: 512,
+            large_value_threshold: 16 * 1024 * 1024,
+            max_key_size: 64 * 1024,
+            max_value_size: 256 * 1024 * 1024,
             data_file_merge_ratio: 0.5,
+            max_open_files: 128,
+            cache_size: 0,
+            persist_keydir: false,
+            prefix_extractor: None,
+            write_stall_soft_limit: 0,
+            write_stall_hard_limit: 0,
+            checksum: ChecksumType::Crc32,
+            read_checksum_policy: ChecksumPolicy::Always,
+            corruption_policy: CorruptionPolicy::Fail,
+            change_sink: None,
+            compaction_filter: None,
+            trash_retention: None,
+            sync_interval: None,
+            auto_merge: None,
+            merge_rate_limit: 0,
+            merge_parallelism: 1,
+            merge_progress: None,
+            tombstone_ttl: None,
+            merge_policy: None,
+            event_listener: None,
         }
     }
 }
 
 /// The configuration for iterator.
+#[derive(Clone)]
 pub struct IteratorOptions {
     pub prefix: Vec<u8>,
     pub reverse: bool,
+
+    /// Only iterate through the keys no less than this one, if set.
+    pub lower_bound: Option<Vec<u8>>,
+
+    /// Only iterate through the keys less than this one, if set.
+    pub upper_bound: Option<Vec<u8>>,
+
+    /// Maximum number of entries returned by `next` since the iterator is created, rewound or
+    /// sought, if set.
+    pub limit: Option<usize>,
+
+    /// Resume right after the entry this cursor is taken at, if set. Cursors are returned by
+    /// `Iterator::cursor`, and should only be resumed with the same prefix, bounds and order.
+    pub cursor: Option<Vec<u8>>,
+
+    /// Iterate through the keys in no particular order, which spares the hash indexer from
+    /// sorting them. `seek` then skips the keys before the given one instead of positioning the
+    /// iterator. Ordered indexers ignore it.
+    pub unordered: bool,
 }
 
 impl Default for IteratorOptions {
@@ -57,6 +233,11 @@ impl Default for IteratorOptions {
         Self {
             prefix: Default::default(),
             reverse: false,
+            lower_bound: None,
+            upper_bound: None,
+            limit: None,
+            cursor: None,
+            unordered: false,
         }
     }
 }
@@ -64,9 +245,15 @@ impl Default for IteratorOptions {
 /// The configuration for writing, where:
 /// - `max_batch_num` determines the maximum number of write per batch.
 /// - `sync_writes` ensures the data sync persistence on writing if set to TRUE.
+/// - `auto_commit_bytes` commits the pending writes once their keys and values take up that many
+///   bytes, and starts a new transaction for the next writes.
+/// - `auto_commit_count` commits the pending writes once there are that many of them, and starts
+///   a new transaction for the next writes.
 pub struct WriteBatchOptions {
     pub max_batch_num: usize,
     pub sync_writes: bool,
+    pub auto_commit_bytes: Option<usize>,
+    pub auto_commit_count: Option<usize>,
 }
 
 impl Default for WriteBatchOptions {
@@ -74,12 +261,133 @@ impl Default for WriteBatchOptions {
         Self {
             max_batch_num: 10000,
             sync_writes: true,
+            auto_commit_bytes: None,
+            auto_commit_count: None,
         }
     }
 }
 
+/// The configuration for a single write by `Engine::put_with_options`.
+#[derive(Clone, Default)]
+pub struct PutOptions {
+    /// The entry expires after this long, if set, see `Engine::put_with_ttl`.
+    pub ttl: Option<Duration>,
+
+    /// Flags stored along with the entry and returned by `Engine::get_with_metadata`, such as a
+    /// content type or a tenant tag, whose meaning is up to the application. Kept by merges.
+    pub user_flags: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumPolicy {
+    /// Verify the CRC on every read.
+    Always,
+
+    /// Only verify the CRC when reading sealed files, which are not recently written and more
+    /// likely to suffer from bit rot.
+    SealedOnly,
+
+    /// Never verify the CRC on reads, for trusted storage.
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumType {
+    /// CRC32, the only checksum read by earlier versions.
+    Crc32,
+
+    /// xxHash64, faster on large values and less prone to collisions.
+    XxHash64,
+
+    None,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CorruptionPolicy {
+    /// Refuse to open the engine.
+    Fail,
+
+    /// Skip the corrupted record and continue loading the rest of the file. If the size of the
+    /// record cannot be trusted, the rest of the file is skipped.
+    SkipRecord,
+
+    /// Truncate the file right before the corrupted record, discarding all records after it.
+    TruncateFile,
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum IOType {
     StandardFIO,
     MemoryMapped,
+
+    /// Bypass the page cache of the OS, so large writes do not evict the pages serving reads.
+    Direct,
+
+    /// Standard file IO coalescing the appends in a buffer of the given size, which is written
+    /// once full or on sync. Appends not written yet are lost if the process crashes.
+    BufferedFIO(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompressionType {
+    None,
+
+    /// Fast compression and decompression, for values read often.
+    Lz4,
+
+    /// Higher compression ratio at a higher CPU cost.
+    Zstd,
+}
+
+/// Called with the outcome of each scheduled backup, which is the directory of the backup.
+pub type BackupCallback = Arc<dyn Fn(&Result<PathBuf>) + Sync + Send>;
+
+/// The configuration for scheduled backups, where:
+/// - `dir_path` is the directory holding the backups, each in a subdirectory named after its
+///   creation time.
+/// - `interval` is the time between two scheduled backups.
+/// - `max_backups` is the number of most recent backups kept, all are kept if set to 0.
+/// - `max_age` removes the backups older than it, disabled if set to None.
+/// - `on_backup` is notified of the outcome of each scheduled backup.
+#[derive(Clone)]
+pub struct BackupOptions {
+    pub dir_path: PathBuf,
+    pub interval: Duration,
+    pub max_backups: usize,
+    pub max_age: Option<Duration>,
+    pub on_backup: Option<BackupCallback>,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            dir_path: std::env::temp_dir().join("bitcask-backup"),
+            interval: Duration::from_secs(60 * 60),
+            max_backups: 7,
+            max_age: None,
+            on_backup: None,
+        }
+    }
+}
+
+/// The configuration for background merges, where:
+/// - `check_interval` is the time between two checks of `Options::merge_policy`.
+/// - `min_interval` is the minimum time between two background merges.
+/// - `quiet_hours` is a range of UTC hours [start, end) in which no merge is started, wrapping
+///   around midnight if start is greater than end. Disabled if set to None.
+#[derive(Clone)]
+pub struct AutoMergeOptions {
+    pub check_interval: Duration,
+    pub min_interval: Duration,
+    pub quiet_hours: Option<(u8, u8)>,
+}
+
+impl Default for AutoMergeOptions {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(60),
+            min_interval: Duration::from_secs(60 * 60),
+            quiet_hours: None,
+        }
+    }
 }
@@ -0,0 +1,381 @@
+//! Partial merge compacts only the selected sealed files, instead of rewriting every data file
+//! like `Engine::merge`. It takes effect right away rather than on the next startup:
+//! 1. The active file is sealed, and the next file id is reserved for the merged file, so it
+//!    sorts after every record of the selected files and before every later write.
+//! 2. The live records of the selected files are copied into the merged file. Deletion records
+//!    are carried over as long as an older file is not merged, which may still hold the deleted
+//!    entries.
+//! 3. The merged file is installed, the index is moved to it, and the selected files are removed.
+//!
+//! Records keep their relative order across the data files, so replaying the data files after a
+//! crash at any step yields the same entries.
+
+use std::{collections::HashSet, fs, sync::Arc};
+
+use log::info;
+
+use crate::{
+    batch::NON_TRANSACTION_SEQUENCE,
+    data::{
+        data_file::{get_data_file_name, DataFile},
+        footer::FooterBuilder,
+        log_record::{LogRecordPos, LogRecordType},
+    },
+    db::{encode_log_record_key, parse_log_record_key, Engine},
+    errors::{Errors, Result},
+    fio::{sync_dir, IOPurpose, IOPurposeGuard},
+    index::keydir::KeydirFile,
+    manifest::ManifestEdit,
+    options::IOType,
+    rate_limit::RateLimiter,
+    utils::time::now_millis,
+};
+
+const PARTIAL_MERGE_TMP_FILE_NAME: &str = "partial-merge.tmp";
+
+impl Engine {
+    /// Merge the sealed data files FILE_IDS, rewriting their live records into a single new file
+    /// and removing them. Fail with `Errors::DataFileNotFound` if any of them is not a sealed
+    /// file. Unlike `merge`, the compaction filter is not applied and expired entries are kept,
+    /// as dropping them could bring back older entries of unmerged files.
+    pub fn merge_files(&self, file_ids: &[u64]) -> Result<()> {
+        let _io_purpose = IOPurposeGuard::new(IOPurpose::Merge);
+        let _merge_lock = self
+            .merge_lock
+            .try_lock()
+            .map_err(|_| Errors::MergeInProgress)?;
+        self.ensure_open()?;
+
+        let mut file_ids = file_ids.to_vec();
+        file_ids.sort();
+        file_ids.dedup();
+        let old_files = self.old_files();
+        let merge_files = file_ids
+            .iter()
+            .map(|file_id| old_files.get(file_id).cloned())
+            .collect::<Option<Vec<Arc<DataFile>>>>()
+            .ok_or(Errors::DataFileNotFound)?;
+        if merge_files.is_empty() {
+            return Ok(());
+        }
+
+        // Changes of the selected files are shipped before they are removed. The merged file
+        // may ship them again after a restart, in their original order.
+        self.ship_changes()?;
+        let merged_file_id = self.reserve_merged_file_id()?;
+
+        // Writes appended to the sealed active file may not be indexed yet, wait for them so
+        // the index tells which deleted keys stay deleted.
+        self.write_fence.wait_for_earlier_writes();
+
+        // Deletion records are only needed while an older file may hold the deleted entries.
+        let oldest_kept_fid = self
+            .old_files()
+            .keys()
+            .filter(|file_id| !file_ids.contains(file_id))
+            .min()
+            .copied()
+            .unwrap_or(merged_file_id);
+
+        let dir_path = &self.options.dir_path;
+        let merged_file = DataFile::new_tmp_file(dir_path, PARTIAL_MERGE_TMP_FILE_NAME)?;
+        merged_file.truncate(0)?;
+        let mut moved = Vec::new();
+        let mut tombstones = Vec::new();
+        let mut deleted_keys = HashSet::new();
+        let mut write_ofs = 0;
+        let mut summary = FooterBuilder::default();
+        let now = now_millis();
+        let rate_limiter = RateLimiter::new(self.options.merge_rate_limit);
+        for data_file in &merge_files {
+            let file_id = data_file.get_file_id();
+            let mut ofs = 0;
+            loop {
+                let (mut log_record, size) = match data_file.read_log_record(ofs) {
+                    Ok(result) => result,
+                    Err(Errors::ReadDataFileEOF) => break,
+                    Err(e) => return Err(e),
+                };
+                rate_limiter.acquire(size as u64);
+                let (key, _) = parse_log_record_key(&log_record.key);
+                let index_pos = self.index.get(key.clone());
+                let old_pos = LogRecordPos {
+                    file_id,
+                    ofs,
+                    size: size as u32,
+                    expire_at: log_record.expire_at(),
+                };
+                let keep = if log_record.record_type.is_value() {
+                    index_pos == Some(old_pos)
+                } else if log_record.record_type == LogRecordType::Deleted {
+                    (file_id > oldest_kept_fid || self.is_tombstone_retained(&log_record, now))
+                        && index_pos.is_none()
+                        && deleted_keys.insert(key.clone())
+                } else {
+                    false
+                };
+                if keep {
+                    log_record.key = encode_log_record_key(key.clone(), NON_TRANSACTION_SEQUENCE);
+                    let encoded_record = self.encode_log_record(&log_record)?;
+                    merged_file.write(&encoded_record)?;
+                    summary.add(&log_record.key, log_record.record_type, &encoded_record);
+                    rate_limiter.acquire(encoded_record.len() as u64);
+                    let new_pos = LogRecordPos {
+                        file_id: merged_file_id,
+                        ofs: write_ofs,
+                        size: encoded_record.len() as u32,
+                        expire_at: log_record.expire_at(),
+                    };
+                    write_ofs += encoded_record.len() as u64;
+                    if log_record.record_type == LogRecordType::Deleted {
+                        tombstones.push(new_pos);
+                        ofs += size as u64;
+                        continue;
+                    }
+                    if let Some(extractor) = &self.options.prefix_extractor {
+                        Self::record_loaded_prefix(
+                            &mut self.prefix_blooms.write().unwrap(),
+                            extractor.as_ref(),
+                            merged_file_id,
+                            &key,
+                        );
+                    }
+                    moved.push((key, old_pos, new_pos));
+                }
+                ofs += size as u64;
+            }
+        }
+        let footer = summary.finish();
+        if footer.record_count > 0 {
+            footer.write(&merged_file)?;
+        }
+        merged_file.sync()?;
+
+        // Install the merged file before the index refers to it.
+        fs::rename(
+            dir_path.join(PARTIAL_MERGE_TMP_FILE_NAME),
+            get_data_file_name(dir_path, merged_file_id),
+        )
+        .map_err(|_| Errors::FailedToWriteToDataFile)?;
+        sync_dir(dir_path)?;
+        self.manifest
+            .append(ManifestEdit::NewFile(merged_file_id))?;
+        self.manifest
+            .append(ManifestEdit::SealFile(merged_file_id, write_ofs))?;
+        let installed_file = DataFile::new_lazy(dir_path, merged_file_id, self.read_io_type)
+            .with_io_wrapper(self.options.io_wrapper.clone());
+        installed_file.set_end_ofs(write_ofs);
+        self.update_old_files(|old_files| {
+            old_files.insert(merged_file_id, Arc::new(installed_file));
+        });
+
+        // Entries written since they were copied keep their newer position, and their copies
+        // become garbage.
+        for (key, old_pos, new_pos) in moved {
+            if !self.index.compare_and_put(key, old_pos, new_pos) {
+                self.add_reclaim_size(&new_pos);
+            }
+        }
+        for pos in &tombstones {
+            self.add_reclaim_size(pos);
+        }
+
+        // The removal is recorded first, so a crash never leaves a file missing from the
+        // MANIFEST. Positions recorded by the keydir file may refer to the removed files, whose
+        // dropped deletion records could not be replayed over it.
+        KeydirFile::remove(dir_path)?;
+        for file_id in &file_ids {
+            self.manifest.append(ManifestEdit::RemoveFile(*file_id))?;
+        }
+        self.update_old_files(|old_files| {
+            for file_id in &file_ids {
+                old_files.remove(file_id);
+            }
+        });
+        {
+            let mut prefix_blooms = self.prefix_blooms.write().unwrap();
+            for file_id in &file_ids {
+                prefix_blooms.remove(file_id);
+            }
+        }
+        self.discard_file_reclaim_sizes(&file_ids);
+        if let Some(value_cache) = &self.value_cache {
+            value_cache.remove_files(&file_ids);
+        }
+        // Open snapshots may still read the merged files.
+        self.snapshots.remove_data_files(&file_ids)?;
+        sync_dir(dir_path)?;
+
+        info!(
+            "merged data files {:?} into data file {}",
+            file_ids, merged_file_id
+        );
+        Ok(())
+    }
+
+    /// Merge the sealed files with the highest ratio of reclaimable bytes, as many as fit in
+    /// BUDGET bytes of data files read. Files without reclaimable bytes are never merged. Return
+    /// the ids of the merged files.
+    pub fn merge_incremental(&self, budget: u64) -> Result<Vec<u64>> {
+        let reclaim_sizes = self.reclaim_sizes();
+        let mut candidates: Vec<(f64, u64, u64)> = self
+            .old_files()
+            .iter()
+            .filter_map(|(file_id, data_file)| {
+                let reclaim_size = *reclaim_sizes.get(file_id)? as u64;
+                let file_size = data_file.get_end_ofs().unwrap_or(data_file.file_size());
+                if reclaim_size == 0 || file_size == 0 {
+                    return None;
+                }
+                Some((reclaim_size as f64 / file_size as f64, *file_id, file_size))
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        let mut file_ids = Vec::new();
+        let mut total_size = 0;
+        for (_, file_id, file_size) in candidates {
+            if total_size + file_size > budget {
+                break;
+            }
+            total_size += file_size;
+            file_ids.push(file_id);
+        }
+        self.merge_files(&file_ids)?;
+        file_ids.sort();
+        Ok(file_ids)
+    }
+
+    /// Seal the active file, and open a new active file skipping one file id, which is reserved
+    /// for the merged file. Return the reserved file id.
+    fn reserve_merged_file_id(&self) -> Result<u64> {
+        let _batch_commit_lock = self.batch_commit_lock.lock().unwrap();
+        let mut active_file = self.active_file.write().unwrap();
+        self.ensure_open()?;
+
+        active_file.sync()?;
+        let active_file_id = active_file.get_file_id();
+        let end_ofs = self.write_active_footer(&active_file)?;
+        self.manifest
+            .append(ManifestEdit::SealFile(active_file_id, end_ofs))?;
+        let new_active_file = self.new_data_file(
+            &self.options.dir_path,
+            active_file_id + 2,
+            IOType::StandardFIO,
+        )?;
+        sync_dir(&self.options.dir_path)?;
+        self.manifest
+            .append(ManifestEdit::NewFile(active_file_id + 2))?;
+        *active_file = new_active_file;
+        let old_file =
+            DataFile::new_lazy(&self.options.dir_path, active_file_id, self.read_io_type)
+                .with_io_wrapper(self.options.io_wrapper.clone());
+        old_file.set_end_ofs(end_ofs);
+        self.update_old_files(|old_files| {
+            old_files.insert(active_file_id, Arc::new(old_file));
+        });
+        Ok(active_file_id + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bytes::Bytes;
+
+    use super::*;
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    #[test]
+    fn test_merge_files() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-merge-files");
+        opts.data_file_size = 16 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..3000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..1000 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        for i in 1000..1500 {
+            assert!(engine.put(get_test_key(i), Bytes::from("new")).is_ok());
+        }
+
+        // Merge the middle files, keeping the oldest one, which still holds deleted entries.
+        let mut file_ids: Vec<u64> = engine.old_files().keys().copied().collect();
+        file_ids.sort();
+        assert!(file_ids.len() >= 4);
+        let merged_ids = &file_ids[1..file_ids.len() - 1];
+        assert!(engine.merge_files(merged_ids).is_ok());
+        for file_id in merged_ids {
+            assert!(!engine.old_files().contains_key(file_id));
+            assert!(!get_data_file_name(&opts.dir_path, *file_id).exists());
+        }
+        assert_eq!(
+            engine.merge_files(&[merged_ids[0]]).err(),
+            Some(Errors::DataFileNotFound)
+        );
+
+        let check = |engine: &Engine| {
+            for i in 0..1000 {
+                assert_eq!(engine.get(get_test_key(i)).err(), Some(Errors::KeyNotFound));
+            }
+            for i in 1000..1500 {
+                assert_eq!(engine.get(get_test_key(i)).unwrap(), Bytes::from("new"));
+            }
+            for i in 1500..3000 {
+                assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+            }
+        };
+        check(&engine);
+        assert!(engine.put(get_test_key(0), get_test_value(0)).is_ok());
+        assert!(engine.delete(get_test_key(0)).is_ok());
+        std::mem::drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        check(&engine);
+        std::mem::drop(engine);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_merge_incremental() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-merge-incremental");
+        opts.data_file_size = 16 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..3000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        // Overwrite the entries of the first file only.
+        let first_fid = *engine.old_files().keys().min().unwrap();
+        for i in 0..3000 {
+            if engine.index.get(get_test_key(i).to_vec()).unwrap().file_id == first_fid {
+                assert!(engine.put(get_test_key(i), Bytes::from("new")).is_ok());
+            }
+        }
+
+        assert_eq!(engine.merge_incremental(0).unwrap(), Vec::<u64>::new());
+        let merged_ids = engine.merge_incremental(16 * 1024).unwrap();
+        assert_eq!(merged_ids, vec![first_fid]);
+        assert!(!engine.reclaim_sizes().contains_key(&first_fid));
+        std::mem::drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..3000 {
+            assert!(engine.get(get_test_key(i)).is_ok());
+        }
+        std::mem::drop(engine);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,110 @@
+//! Periodic sync of the active file in background, bounding the window of writes lost on a crash
+//! to `Options::sync_interval` without syncing on every write.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::warn;
+
+use crate::data::data_file::DataFile;
+
+/// Syncs the active file every interval, until it is stopped.
+pub(crate) struct PeriodicSync {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl PeriodicSync {
+    /// Sync ACTIVE_FILE every INTERVAL, resetting BYTES_WRITE once synced.
+    pub(crate) fn start(
+        active_file: Arc<RwLock<DataFile>>,
+        bytes_write: Arc<AtomicUsize>,
+        interval: Duration,
+    ) -> Self {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let stopped_clone = stopped.clone();
+        let handle = thread::spawn(move || {
+            let (lock, cvar) = &*stopped_clone;
+            loop {
+                let guard = lock.lock().unwrap();
+                let (guard, _) = cvar
+                    .wait_timeout_while(guard, interval, |stopped| !*stopped)
+                    .unwrap();
+                if *guard {
+                    return;
+                }
+                drop(guard);
+
+                let active_file = active_file.read().unwrap();
+                match active_file.sync() {
+                    Ok(()) => bytes_write.store(0, Ordering::SeqCst),
+                    Err(e) => warn!("failed to sync active file: {:?}", e),
+                }
+            }
+        });
+        Self {
+            stopped,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stop syncing, waiting for a running sync to finish.
+    pub(crate) fn stop(&mut self) {
+        let (lock, cvar) = &*self.stopped;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for PeriodicSync {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    use crate::{
+        db::Engine,
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    #[test]
+    fn test_engine_sync_interval() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-sync-interval");
+        opts.sync_interval = Some(Duration::from_millis(10));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine.put(get_test_key(0), get_test_value(0)).is_ok());
+        assert!(engine.bytes_write.load(Ordering::SeqCst) > 0);
+
+        let mut synced = false;
+        for _ in 0..100 {
+            thread::sleep(Duration::from_millis(10));
+            if engine.bytes_write.load(Ordering::SeqCst) == 0 {
+                synced = true;
+                break;
+            }
+        }
+        assert!(synced);
+
+        assert!(engine.close().is_ok());
+        assert!(engine.periodic_sync.lock().unwrap().is_none());
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
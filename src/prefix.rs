@@ -0,0 +1,145 @@
+//! Prefix bloom filters record the key prefixes contained in each data file, so a prefix scan can
+//! be skipped entirely when no data file may contain keys with the prefix. Prefixes are derived
+//! from keys by the `PrefixExtractor` configured in `Options`.
+//!
+//! A bloom filter is only maintained for a data file whose records are all seen by the engine,
+//! that is, files written from the beginning or fully scanned during startup. Files without a
+//! bloom filter are always assumed to contain the prefix.
+
+use std::collections::HashMap;
+
+use crate::{db::Engine, utils::bloom::BloomFilter};
+
+/// Number of distinct prefixes a per-file bloom filter is sized for.
+const PREFIX_BLOOM_CAPACITY: usize = 4096;
+
+/// Extracts the prefix of a key, which is recorded by the per-file prefix bloom filters.
+///
+/// An extractor must be consistent with prefix scans: if `extract(p)` returns `Some(e)`, then
+/// `extract(k)` must return `Some(e)` for every key `k` starting with `p`.
+pub trait PrefixExtractor: Sync + Send {
+    /// Get the prefix of KEY, or None if KEY has no prefix to be recorded.
+    fn extract<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]>;
+}
+
+/// Uses the first N bytes as the prefix, keys shorter than N bytes have no prefix.
+pub struct FixedPrefixExtractor(pub usize);
+
+impl PrefixExtractor for FixedPrefixExtractor {
+    fn extract<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        key.get(..self.0)
+    }
+}
+
+pub(crate) fn new_prefix_bloom() -> BloomFilter {
+    BloomFilter::with_capacity(PREFIX_BLOOM_CAPACITY)
+}
+
+impl Engine {
+    /// Record the prefix of KEY written at offset OFS of file FILE_ID. A bloom filter is created
+    /// for a file on its first record, and never for a file with records unseen by the engine.
+    pub(crate) fn record_prefix(&self, file_id: u64, ofs: u64, key: &[u8]) {
+        let extractor = match &self.options.prefix_extractor {
+            Some(extractor) => extractor,
+            None => return,
+        };
+        let prefix = match extractor.extract(key) {
+            Some(prefix) => prefix,
+            None => return,
+        };
+
+        let mut prefix_blooms = self.prefix_blooms.write().unwrap();
+        if ofs == 0 {
+            prefix_blooms
+                .entry(file_id)
+                .or_insert_with(new_prefix_bloom);
+        }
+        if let Some(bloom) = prefix_blooms.get_mut(&file_id) {
+            bloom.insert(prefix);
+        }
+    }
+
+    /// Record the prefix of KEY contained in file FILE_ID, whose records are all seen.
+    pub(crate) fn record_loaded_prefix(
+        prefix_blooms: &mut HashMap<u64, BloomFilter>,
+        extractor: &dyn PrefixExtractor,
+        file_id: u64,
+        key: &[u8],
+    ) {
+        if let Some(prefix) = extractor.extract(key) {
+            prefix_blooms
+                .entry(file_id)
+                .or_insert_with(new_prefix_bloom)
+                .insert(prefix);
+        }
+    }
+
+    /// Whether any data file may contain keys starting with PREFIX.
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        let extracted = match &self.options.prefix_extractor {
+            Some(extractor) if !prefix.is_empty() => extractor.extract(prefix),
+            _ => None,
+        };
+        let extracted = match extracted {
+            Some(extracted) => extracted,
+            None => return true,
+        };
+
+        let active_file = self.active_file.read().unwrap();
+        let old_files = self.old_files();
+        let prefix_blooms = self.prefix_blooms.read().unwrap();
+        std::iter::once(active_file.get_file_id())
+            .chain(old_files.keys().copied())
+            .any(|file_id| match prefix_blooms.get(&file_id) {
+                Some(bloom) => bloom.may_contain(extracted),
+                None => true,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use bytes::Bytes;
+
+    use crate::options::{IteratorOptions, Options};
+
+    #[test]
+    fn test_prefix_bloom() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-prefix-bloom");
+        opts.data_file_size = 64 * 1024;
+        opts.prefix_extractor = Some(Arc::new(super::FixedPrefixExtractor(4)));
+        let engine = crate::db::Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..5000 {
+            let key = std::format!("t{:03}:key-{}", i % 100, i);
+            assert!(engine.put(Bytes::from(key), Bytes::from("value")).is_ok());
+        }
+        assert!(engine.may_contain_prefix(b"t042:"));
+        assert!(engine.may_contain_prefix(b"t"));
+        assert!(!engine.may_contain_prefix(b"t999:"));
+        std::mem::drop(engine);
+
+        let engine2 = crate::db::Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine2.may_contain_prefix(b"t042:"));
+        assert!(!engine2.may_contain_prefix(b"t999:"));
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.prefix = b"t042:".to_vec();
+        let iter = engine2.iter(iter_opts);
+        let mut count = 0;
+        while iter.next().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 50);
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.prefix = b"t999:".to_vec();
+        assert!(engine2.iter(iter_opts).next().is_none());
+
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,350 @@
+//! Two-phase commit. `WriteBatch::prepare` durably writes the pending writes of a batch into a
+//! prepared file of their own, without touching the data files nor the index, and assigns the
+//! transaction its sequence number. The transaction is later either committed by
+//! `Engine::commit_prepared`, which writes it to the data files as a regular transaction, or
+//! dropped by `Engine::abort_prepared`.
+//!
+//! Prepared files end with a marker record, so a file torn by a crash while preparing is
+//! discarded on startup, while the complete ones are prepared again. A crash after committing and
+//! before removing the prepared file leaves the transaction in the data files, so the prepared
+//! file is dropped on startup once the data files replayed show the transaction as committed.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+
+use log::warn;
+
+use crate::{
+    data::{
+        data_file::DataFile,
+        log_record::{LogRecord, LogRecordType},
+    },
+    db::Engine,
+    errors::{Errors, Result},
+    fio::sync_dir,
+};
+
+const PREPARED_DIR_NAME: &str = "prepared";
+const PREPARED_FILE_NAME_SUFFIX: &str = ".prepared";
+const TXN_PREPARED_KEY: &[u8] = "txn-prepared".as_bytes();
+
+impl Engine {
+    /// Durably write RECORDS as a prepared transaction, return its sequence number.
+    pub(crate) fn prepare_transaction(&self, records: Vec<LogRecord>) -> Result<usize> {
+        let _write_guard = self.write_fence.enter()?;
+        let prepared_path = get_prepared_path(&self.options.dir_path);
+        fs::create_dir_all(&prepared_path).map_err(|_| Errors::FailedToCreateDatabaseDir)?;
+
+        let sequence_number = self.sequence_number.fetch_add(1, Ordering::SeqCst);
+        let file_name = get_prepared_file_name(sequence_number);
+        if let Err(e) = write_prepared_file(&prepared_path, &file_name, &records) {
+            let _ = fs::remove_file(prepared_path.join(&file_name));
+            return Err(e);
+        }
+        self.prepared
+            .lock()
+            .unwrap()
+            .insert(sequence_number, records);
+        Ok(sequence_number)
+    }
+
+    /// Get the sequence numbers of the transactions prepared and not yet committed nor aborted,
+    /// including the ones prepared before the last restart.
+    pub fn prepared_transactions(&self) -> Vec<usize> {
+        self.prepared.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Commit the prepared transaction SEQUENCE_NUMBER, so its writes are applied atomically.
+    /// Fail with `Errors::TransactionNotPrepared` if there is no such prepared transaction.
+    pub fn commit_prepared(&self, sequence_number: usize) -> Result<()> {
+        let _write_guard = self.write_fence.enter()?;
+        self.check_write_stall()?;
+
+        let mut prepared = self.prepared.lock().unwrap();
+        let records = prepared
+            .get(&sequence_number)
+            .ok_or(Errors::TransactionNotPrepared)?;
+        {
+            let _key_locks = self
+                .key_locks
+                .lock_all(records.iter().map(|record| record.key.as_slice()));
+            let _batch_commit_lock = self.batch_commit_lock.lock().unwrap();
+            let records: Vec<&LogRecord> = records.iter().collect();
+            self.write_transaction(sequence_number, &records, true)?;
+        }
+        prepared.remove(&sequence_number);
+        remove_prepared_file(&self.options.dir_path, sequence_number)
+    }
+
+    /// Drop the prepared transaction SEQUENCE_NUMBER, none of its writes are applied. Fail with
+    /// `Errors::TransactionNotPrepared` if there is no such prepared transaction.
+    pub fn abort_prepared(&self, sequence_number: usize) -> Result<()> {
+        let _write_guard = self.write_fence.enter()?;
+        let mut prepared = self.prepared.lock().unwrap();
+        if !prepared.contains_key(&sequence_number) {
+            return Err(Errors::TransactionNotPrepared);
+        }
+        remove_prepared_file(&self.options.dir_path, sequence_number)?;
+        prepared.remove(&sequence_number);
+        Ok(())
+    }
+
+    /// Load the transactions left prepared by the last session, whose sequence numbers are never
+    /// handed out again. Those found committed while replaying the data files were committed
+    /// right before a crash, so their prepared files are removed instead.
+    pub(crate) fn load_prepared_transactions(&self) -> Result<()> {
+        let replayed_commits = std::mem::take(&mut *self.replayed_commits.lock().unwrap());
+        let prepared_path = get_prepared_path(&self.options.dir_path);
+        if !prepared_path.is_dir() {
+            return Ok(());
+        }
+
+        let dir = fs::read_dir(&prepared_path).map_err(|_| Errors::FailedToReadDatabaseDir)?;
+        let mut prepared = self.prepared.lock().unwrap();
+        for entry in dir.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let sequence_number = match parse_prepared_file_name(&file_name) {
+                Some(sequence_number) => sequence_number,
+                None => continue,
+            };
+            if replayed_commits.contains(&sequence_number) {
+                warn!(
+                    "discarding prepared transaction {} already committed",
+                    sequence_number
+                );
+                fs::remove_file(entry.path()).map_err(|_| Errors::FailedToWriteToDataFile)?;
+                self.sequence_number
+                    .fetch_max(sequence_number + 1, Ordering::SeqCst);
+                continue;
+            }
+            match read_prepared_file(&prepared_path, &file_name)? {
+                Some(records) => {
+                    prepared.insert(sequence_number, records);
+                    self.sequence_number
+                        .fetch_max(sequence_number + 1, Ordering::SeqCst);
+                }
+                None => {
+                    warn!(
+                        "discarding partially prepared transaction {}",
+                        sequence_number
+                    );
+                    fs::remove_file(entry.path()).map_err(|_| Errors::FailedToWriteToDataFile)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get the directory holding the prepared transactions under DIR_PATH.
+fn get_prepared_path(dir_path: &Path) -> PathBuf {
+    dir_path.join(PREPARED_DIR_NAME)
+}
+
+/// Check whether any transaction is left prepared under DIR_PATH.
+pub(crate) fn has_prepared_files(dir_path: &Path) -> bool {
+    fs::read_dir(get_prepared_path(dir_path))
+        .map(|dir| {
+            dir.flatten().any(|entry| {
+                parse_prepared_file_name(&entry.file_name().to_string_lossy()).is_some()
+            })
+        })
+        .unwrap_or(false)
+}
+
+fn get_prepared_file_name(sequence_number: usize) -> String {
+    format!("{:09}{}", sequence_number, PREPARED_FILE_NAME_SUFFIX)
+}
+
+fn parse_prepared_file_name(file_name: &str) -> Option<usize> {
+    file_name
+        .strip_suffix(PREPARED_FILE_NAME_SUFFIX)?
+        .parse::<usize>()
+        .ok()
+}
+
+/// Write RECORDS followed by the marker record into the prepared file FILE_NAME under
+/// PREPARED_PATH, and sync it.
+fn write_prepared_file(prepared_path: &Path, file_name: &str, records: &[LogRecord]) -> Result<()> {
+    let prepared_file = DataFile::new_prepared_file(prepared_path, file_name)?;
+    for record in records {
+        prepared_file.write(&record.encode())?;
+    }
+    let marker = LogRecord {
+        key: TXN_PREPARED_KEY.to_vec(),
+        value: Default::default(),
+        record_type: LogRecordType::TxnFinished,
+        timestamp: None,
+        user_flags: 0,
+    };
+    prepared_file.write(&marker.encode())?;
+    prepared_file.sync()?;
+    sync_dir(prepared_path)
+}
+
+/// Read the records of the prepared file FILE_NAME under PREPARED_PATH. Return None if the file
+/// is torn before its marker record.
+fn read_prepared_file(prepared_path: &Path, file_name: &str) -> Result<Option<Vec<LogRecord>>> {
+    let prepared_file = DataFile::new_prepared_file(prepared_path, file_name)?;
+    let mut records = Vec::new();
+    let mut ofs = 0;
+    while let Ok((record, size)) = prepared_file.read_log_record(ofs) {
+        if record.record_type == LogRecordType::TxnFinished {
+            return Ok(Some(records));
+        }
+        records.push(record);
+        ofs += size as u64;
+    }
+    Ok(None)
+}
+
+/// Remove the prepared file of the transaction SEQUENCE_NUMBER under DIR_PATH.
+fn remove_prepared_file(dir_path: &Path, sequence_number: usize) -> Result<()> {
+    let prepared_path = get_prepared_path(dir_path);
+    fs::remove_file(prepared_path.join(get_prepared_file_name(sequence_number)))
+        .map_err(|_| Errors::FailedToWriteToDataFile)?;
+    sync_dir(&prepared_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        data::data_file::SEQUENCE_NUMBER_FILE_NAME,
+        options::{IndexType, Options, WriteBatchOptions},
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_engine_prepared_transactions() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-prepared");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine.put(get_test_key(0), get_test_value(0)).is_ok());
+
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert!(wb.put(get_test_key(1), get_test_value(1)).is_ok());
+        assert!(wb.delete(get_test_key(0)).is_ok());
+        let committed = wb.prepare().unwrap();
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert!(wb.put(get_test_key(2), get_test_value(2)).is_ok());
+        let aborted = wb.prepare().unwrap();
+        assert!(engine.get(get_test_key(1)).is_err());
+        assert!(engine.get(get_test_key(0)).is_ok());
+
+        // Prepared transactions survive a restart, torn ones are discarded.
+        std::mem::drop(engine);
+        let torn = get_prepared_path(&opts.dir_path).join(get_prepared_file_name(99));
+        fs::write(&torn, b"").expect("failed to write prepared file");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.prepared_transactions(), vec![committed, aborted]);
+        assert!(!torn.exists());
+        assert!(engine.sequence_number.load(Ordering::SeqCst) > aborted);
+
+        assert!(engine.commit_prepared(committed).is_ok());
+        assert!(engine.abort_prepared(aborted).is_ok());
+        assert_eq!(
+            engine.commit_prepared(aborted).err(),
+            Some(Errors::TransactionNotPrepared)
+        );
+        assert!(engine.prepared_transactions().is_empty());
+        assert_eq!(engine.get(get_test_key(1)).unwrap(), get_test_value(1));
+        assert!(engine.get(get_test_key(0)).is_err());
+
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine.prepared_transactions().is_empty());
+        assert_eq!(engine.get(get_test_key(1)).unwrap(), get_test_value(1));
+        assert!(engine.get(get_test_key(0)).is_err());
+        assert!(engine.get(get_test_key(2)).is_err());
+
+        // Prepared transactions are left untouched once the engine is shut down.
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert!(wb.put(get_test_key(3), get_test_value(3)).is_ok());
+        let fenced = wb.prepare().unwrap();
+        assert!(engine.shutdown().is_ok());
+        assert_eq!(
+            engine.abort_prepared(fenced).err(),
+            Some(Errors::EngineClosed)
+        );
+        assert!(get_prepared_path(&opts.dir_path)
+            .join(get_prepared_file_name(fenced))
+            .exists());
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_prepared_committed_before_crash() {
+        // The last case seals the file holding the commit, whose footer is trusted on restart.
+        for (index_type, dir, data_file_size) in [
+            (IndexType::BTree, "/tmp/bitcask-rs-prepared-crash-btree", 0),
+            (
+                IndexType::BPTree,
+                "/tmp/bitcask-rs-prepared-crash-bptree",
+                0,
+            ),
+            (
+                IndexType::BPTree,
+                "/tmp/bitcask-rs-prepared-crash-sealed",
+                32 * 1024,
+            ),
+        ] {
+            let mut opts = Options::default();
+            opts.dir_path = PathBuf::from(dir);
+            opts.index_type = index_type;
+            if data_file_size > 0 {
+                opts.data_file_size = data_file_size;
+            }
+            let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+            let wb = engine
+                .new_write_batch(WriteBatchOptions::default())
+                .expect("failed to create write batch");
+            assert!(wb.put(get_test_key(1), get_test_value(1)).is_ok());
+            let sequence_number = wb.prepare().unwrap();
+
+            // Crash after the transaction is written to the data files, before its prepared file
+            // is removed.
+            let prepared_file =
+                get_prepared_path(&opts.dir_path).join(get_prepared_file_name(sequence_number));
+            let prepared_content = fs::read(&prepared_file).expect("failed to read prepared file");
+            assert!(engine.commit_prepared(sequence_number).is_ok());
+            assert!(engine.put(get_test_key(2), get_test_value(2)).is_ok());
+            if data_file_size > 0 {
+                for i in 3..2000 {
+                    assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+                }
+                assert!(engine.old_files().len() > 1);
+            }
+            std::mem::drop(engine);
+            fs::write(&prepared_file, prepared_content).expect("failed to write prepared file");
+            let _ = fs::remove_file(opts.dir_path.join(SEQUENCE_NUMBER_FILE_NAME));
+
+            // The transaction is not prepared again, so it cannot be committed twice over the
+            // writes made after it.
+            let engine = Engine::open(opts.clone()).expect("failed to open engine");
+            assert!(engine.prepared_transactions().is_empty());
+            assert!(!prepared_file.exists());
+            assert!(engine.sequence_number.load(Ordering::SeqCst) > sequence_number);
+            assert_eq!(engine.get(get_test_key(1)).unwrap(), get_test_value(1));
+            assert_eq!(engine.get(get_test_key(2)).unwrap(), get_test_value(2));
+
+            std::mem::drop(engine);
+            fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Rate limiting of background IO, so merges do not saturate the disk and hurt the latency of
+//! foreground reads and writes.
+//!
+//! `RateLimiter` evenly paces the IO of a merge, while `TokenBucket` lets short bursts through,
+//! and backs `fio::rate_limited_io` which throttles the data files of a whole engine.
+
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Paces IO to a number of bytes per second, unlimited if set to 0. Bytes are scheduled one
+/// after another, so a caller waits until the bytes acquired before it are due.
+pub(crate) struct RateLimiter {
+    bytes_per_sec: u64,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until BYTES can be read or written.
+    pub(crate) fn acquire(&self, bytes: u64) {
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = (*next).max(now);
+            *next = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            start
+        };
+        if start > now {
+            thread::sleep(start - now);
+        }
+    }
+}
+
+/// A token bucket of bytes refilled at a number of bytes per second, unlimited if set to 0,
+/// where:
+/// - `capacity` is the burst of bytes let through without waiting after the bucket is idle.
+/// - `tokens` is the bytes available, along with the instant it was last refilled. It goes
+///   negative when more bytes than available are acquired, and the caller waits until the debt
+///   is refilled.
+pub(crate) struct TokenBucket {
+    bytes_per_sec: u64,
+    capacity: f64,
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Create a bucket refilled at BYTES_PER_SEC, holding the bytes of a tenth of a second.
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let capacity = bytes_per_sec as f64 / 10.0;
+        Self {
+            bytes_per_sec,
+            capacity,
+            tokens: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Wait until BYTES can be read or written.
+    pub(crate) fn acquire(&self, bytes: u64) {
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+        let debt = {
+            let mut tokens = self.tokens.lock().unwrap();
+            let (available, last) = &mut *tokens;
+            let now = Instant::now();
+            *available = (*available
+                + now.duration_since(*last).as_secs_f64() * self.bytes_per_sec as f64)
+                .min(self.capacity);
+            *last = now;
+            *available -= bytes as f64;
+            -*available
+        };
+        if debt > 0.0 {
+            thread::sleep(Duration::from_secs_f64(debt / self.bytes_per_sec as f64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(100_000);
+        // The burst is let through at once.
+        let now = Instant::now();
+        bucket.acquire(10_000);
+        assert!(now.elapsed() < Duration::from_millis(50));
+
+        let now = Instant::now();
+        for _ in 0..5 {
+            bucket.acquire(10_000);
+        }
+        assert!(now.elapsed() >= Duration::from_millis(400));
+        assert!(now.elapsed() < Duration::from_secs(2));
+
+        let unlimited = TokenBucket::new(0);
+        let now = Instant::now();
+        unlimited.acquire(u64::MAX);
+        assert!(now.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(100_000);
+        let now = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire(10_000);
+        }
+        assert!(now.elapsed() >= Duration::from_millis(400));
+        assert!(now.elapsed() < Duration::from_secs(2));
+    }
+}
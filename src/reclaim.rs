@@ -0,0 +1,186 @@
+//! Reclaimable bytes are tracked per data file, and persisted into the reclaim-stat file on close.
+//! When the indexer is restored without scanning the data files (the BPTree indexer, or a
+//! persisted keydir), the counters are restored from this file instead of starting at zero.
+//!
+//! The file is removed once loaded, so counters are never restored from a stale file after a
+//! crash.
+
+use std::{collections::HashMap, fs, path::Path, sync::atomic::Ordering};
+
+use bytes::BytesMut;
+use prost::encoding::{decode_varint, encode_varint};
+
+use crate::{
+    data::{
+        data_file::{DataFile, RECLAIM_STAT_FILE_NAME},
+        log_record::{LogRecord, LogRecordPos, LogRecordType},
+    },
+    db::Engine,
+    errors::Result,
+};
+
+const RECLAIM_STAT_KEY: &str = "reclaim-stat";
+
+/// Reclaimable bytes by data file id.
+pub(crate) type ReclaimStats = HashMap<u64, usize>;
+
+impl Engine {
+    /// Record the record at POS as reclaimable, its value is no longer cached.
+    pub(crate) fn add_reclaim_size(&self, pos: &LogRecordPos) {
+        if let Some(value_cache) = &self.value_cache {
+            value_cache.remove(pos);
+        }
+        self.reclaim_size
+            .fetch_add(pos.size as usize, Ordering::SeqCst);
+        *self
+            .file_reclaim_sizes
+            .lock()
+            .unwrap()
+            .entry(pos.file_id)
+            .or_insert(0) += pos.size as usize;
+    }
+
+    /// Get the reclaimable bytes of each data file.
+    pub fn reclaim_sizes(&self) -> ReclaimStats {
+        self.file_reclaim_sizes.lock().unwrap().clone()
+    }
+
+    /// Discard the counters of data files with id less than NON_MERGE_FID, which are merged.
+    pub(crate) fn discard_reclaim_sizes(&self, non_merge_fid: u64) {
+        let mut file_reclaim_sizes = self.file_reclaim_sizes.lock().unwrap();
+        file_reclaim_sizes.retain(|file_id, size| {
+            if *file_id < non_merge_fid {
+                self.reclaim_size.fetch_sub(*size, Ordering::SeqCst);
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Discard the counters of the data files FILE_IDS, which are removed by a partial merge.
+    pub(crate) fn discard_file_reclaim_sizes(&self, file_ids: &[u64]) {
+        let mut file_reclaim_sizes = self.file_reclaim_sizes.lock().unwrap();
+        for file_id in file_ids {
+            if let Some(size) = file_reclaim_sizes.remove(file_id) {
+                self.reclaim_size.fetch_sub(size, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Restore the counters in STATS of the data files that still exist.
+    pub(crate) fn restore_reclaim_stats(&self, stats: ReclaimStats) {
+        let mut file_reclaim_sizes = self.file_reclaim_sizes.lock().unwrap();
+        for (file_id, size) in stats {
+            if self.file_ids.contains(&file_id) {
+                *file_reclaim_sizes.entry(file_id).or_insert(0) += size;
+                self.reclaim_size.fetch_add(size, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Persist the counters of all data files.
+    pub(crate) fn save_reclaim_stats(&self) -> Result<()> {
+        let stats = self.reclaim_sizes();
+        write_reclaim_stats(&self.options.dir_path, &stats)
+    }
+}
+
+fn write_reclaim_stats(dir_path: &Path, stats: &ReclaimStats) -> Result<()> {
+    let mut value = BytesMut::new();
+    for (file_id, size) in stats {
+        encode_varint(*file_id, &mut value);
+        encode_varint(*size as u64, &mut value);
+    }
+    let record = LogRecord {
+        key: RECLAIM_STAT_KEY.as_bytes().to_vec(),
+        value: value.to_vec(),
+        record_type: LogRecordType::Normal,
+        timestamp: None,
+        user_flags: 0,
+    };
+
+    let _ = fs::remove_file(dir_path.join(RECLAIM_STAT_FILE_NAME));
+    let stat_file = DataFile::new_reclaim_stat_file(dir_path)?;
+    stat_file.write(&record.encode())?;
+    stat_file.sync()
+}
+
+/// Read the reclaim-stat file under DIR_PATH, return None if it does not exist or is corrupted.
+fn read_reclaim_stats(dir_path: &Path) -> Option<ReclaimStats> {
+    if !dir_path.join(RECLAIM_STAT_FILE_NAME).is_file() {
+        return None;
+    }
+    let stat_file = DataFile::new_reclaim_stat_file(dir_path).ok()?;
+    let record = stat_file.read_log_record(0).ok()?.0;
+
+    let mut stats = HashMap::new();
+    let mut buf = record.value.as_slice();
+    while !buf.is_empty() {
+        let file_id = decode_varint(&mut buf).ok()?;
+        let size = decode_varint(&mut buf).ok()? as usize;
+        stats.insert(file_id, size);
+    }
+    Some(stats)
+}
+
+/// Read and remove the reclaim-stat file under DIR_PATH.
+pub(crate) fn take_reclaim_stats(dir_path: &Path) -> Option<ReclaimStats> {
+    let stats = read_reclaim_stats(dir_path);
+    let _ = fs::remove_file(dir_path.join(RECLAIM_STAT_FILE_NAME));
+    stats
+}
+
+/// Drop the counters of data files with id less than NON_MERGE_FID, which are replaced by merged
+/// files without reclaimable bytes.
+pub(crate) fn drop_merged_reclaim_stats(dir_path: &Path, non_merge_fid: u64) -> Result<()> {
+    let mut stats = match read_reclaim_stats(dir_path) {
+        Some(stats) => stats,
+        None => return Ok(()),
+    };
+    stats.retain(|file_id, _| *file_id >= non_merge_fid);
+    write_reclaim_stats(dir_path, &stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        options::{IndexType, Options},
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_reclaim_stats_persisted() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-reclaim-stats");
+        opts.data_file_size = 64 * 1024;
+        opts.index_type = IndexType::BPTree;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 1000..1500 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        let reclaim_size = engine.reclaim_size.load(Ordering::SeqCst);
+        let reclaim_sizes = engine.reclaim_sizes();
+        assert!(reclaim_size > 0);
+        assert!(reclaim_sizes.len() > 1);
+        assert_eq!(reclaim_sizes.values().sum::<usize>(), reclaim_size);
+        std::mem::drop(engine);
+
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine2.reclaim_size.load(Ordering::SeqCst), reclaim_size);
+        assert_eq!(engine2.reclaim_sizes(), reclaim_sizes);
+        assert!(!opts.dir_path.join(RECLAIM_STAT_FILE_NAME).exists());
+        std::mem::drop(engine2);
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,339 @@
+//! Handling of corrupted records found while loading the data files on startup, according to
+//! `Options::corruption_policy`. A torn record at the end of the active file, left by a crash in
+//! the middle of an append, is always truncated regardless of the policy, since it was never
+//! acknowledged to the writer.
+
+use log::warn;
+
+use crate::{
+    data::{data_file::DataFile, log_record::max_log_record_header_size},
+    db::Engine,
+    errors::{Errors, Result},
+    manifest::ManifestEdit,
+    options::CorruptionPolicy,
+};
+
+/// A corrupted record handled while opening the engine, where `discarded` is the number of bytes
+/// skipped or truncated from offset `ofs` of data file `file_id`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecoveredCorruption {
+    pub file_id: u64,
+    pub ofs: u64,
+    pub discarded: u64,
+    pub error: Errors,
+}
+
+impl Engine {
+    /// Corrupted records skipped or truncated while opening the engine, in the order they were
+    /// found.
+    pub fn recovered_corruptions(&self) -> Vec<RecoveredCorruption> {
+        self.recovered_corruptions.lock().unwrap().clone()
+    }
+
+    /// Record that DISCARDED bytes from offset OFS of the data file FILE_ID were skipped or
+    /// truncated because of ERROR.
+    fn record_corruption(&self, file_id: u64, ofs: u64, discarded: u64, error: Errors) {
+        self.recovered_corruptions
+            .lock()
+            .unwrap()
+            .push(RecoveredCorruption {
+                file_id,
+                ofs,
+                discarded,
+                error,
+            });
+    }
+
+    /// Handle the corrupted record at offset OFS of DATA_FILE, which failed to load with ERROR.
+    /// Return the size of the record if it is skipped, or None if the rest of the file is
+    /// discarded. IS_ACTIVE tells whether DATA_FILE is the active file, which is always truncated
+    /// when the rest of it is discarded, since new records are appended at its end.
+    pub(crate) fn recover_corrupted_record(
+        &self,
+        data_file: &DataFile,
+        is_active: bool,
+        ofs: u64,
+        error: Errors,
+    ) -> Result<Option<usize>> {
+        let is_corruption = matches!(
+            error,
+            Errors::InvalidLogRecordCRC
+                | Errors::InvalidLogRecordHeader
+                | Errors::TruncatedLogRecord
+                | Errors::DecompressionFailed
+        );
+        // Records of an unsupported format are not corrupted, and must not be discarded.
+        if !is_corruption {
+            return Err(error);
+        }
+
+        let file_id = data_file.get_file_id();
+        // The footer of a sealed file is not part of its records.
+        let file_size = data_file
+            .get_end_ofs()
+            .unwrap_or_else(|| data_file.file_size());
+        if is_active && is_torn_tail(data_file, ofs, &error) {
+            warn!(
+                "truncated torn record of {} bytes at the end of active data file {}: {:?}",
+                file_size - ofs,
+                file_id,
+                error
+            );
+            data_file.truncate(ofs)?;
+            self.record_corruption(file_id, ofs, file_size - ofs, error);
+            return Ok(None);
+        }
+
+        match self.options.corruption_policy {
+            CorruptionPolicy::SkipRecord => {
+                // The record can only be skipped if its header is intact.
+                match data_file.read_log_record_size(ofs) {
+                    Ok(size) if ofs + size as u64 <= file_size => {
+                        warn!(
+                            "skipped corrupted record of {} bytes at offset {} of data file {}: {:?}",
+                            size, ofs, file_id, error
+                        );
+                        self.record_corruption(file_id, ofs, size as u64, error);
+                        Ok(Some(size))
+                    }
+                    _ if is_active => {
+                        warn!(
+                            "truncated active data file {} from {} to {} bytes after corrupted record: {:?}",
+                            file_id, file_size, ofs, error
+                        );
+                        data_file.truncate(ofs)?;
+                        self.record_corruption(file_id, ofs, file_size - ofs, error);
+                        Ok(None)
+                    }
+                    _ => {
+                        warn!(
+                            "skipped the rest {} bytes after corrupted record at offset {} of data file {}: {:?}",
+                            file_size - ofs,
+                            ofs,
+                            file_id,
+                            error
+                        );
+                        self.record_corruption(file_id, ofs, file_size - ofs, error);
+                        Ok(None)
+                    }
+                }
+            }
+            CorruptionPolicy::TruncateFile => {
+                warn!(
+                    "truncated data file {} from {} to {} bytes after corrupted record: {:?}",
+                    file_id, file_size, ofs, error
+                );
+                data_file.truncate(ofs)?;
+                if !is_active {
+                    // Record the new logical size, so the shortened file is not reported missing
+                    // its sealed bytes on the next startup.
+                    self.manifest.append(ManifestEdit::SealFile(file_id, ofs))?;
+                }
+                self.record_corruption(file_id, ofs, file_size - ofs, error);
+                Ok(None)
+            }
+            CorruptionPolicy::Fail => Err(error),
+        }
+    }
+}
+
+/// Whether the corrupted record at offset OFS of DATA_FILE, which failed to load with ERROR, is
+/// the last record of the file, partially written by a crash.
+fn is_torn_tail(data_file: &DataFile, ofs: u64, error: &Errors) -> bool {
+    let remaining = data_file.file_size() - ofs;
+    match error {
+        Errors::TruncatedLogRecord => true,
+        Errors::InvalidLogRecordHeader => remaining < max_log_record_header_size() as u64,
+        Errors::InvalidLogRecordCRC => data_file
+            .read_log_record_size(ofs)
+            .is_ok_and(|size| size as u64 == remaining),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        data::data_file::get_data_file_name,
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    /// Write 100 records into a single data file, then apply CORRUPT to the content of the file
+    /// and the offset of the 50th record. Return the options and the record size.
+    fn prepare_corrupted_engine(
+        name: &str,
+        policy: CorruptionPolicy,
+        corrupt: impl FnOnce(&mut Vec<u8>, usize),
+    ) -> (Options, usize) {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from(std::format!("/tmp/bitcask-rs-recovery-{}", name));
+        opts.corruption_policy = policy;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..100 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        let size = engine.index.get(get_test_key(0).to_vec()).unwrap().size as usize;
+        std::mem::drop(engine);
+
+        let file_name = get_data_file_name(&opts.dir_path, 1);
+        let mut content = std::fs::read(&file_name).unwrap();
+        corrupt(&mut content, 50 * size);
+        std::fs::write(&file_name, content).unwrap();
+        (opts, size)
+    }
+
+    #[test]
+    fn test_corruption_policy_fail() {
+        let (opts, _) = prepare_corrupted_engine("fail", CorruptionPolicy::Fail, |content, ofs| {
+            content[ofs + 10] ^= 0xff;
+        });
+        let res = Engine::open(opts.clone());
+        assert_eq!(res.err().unwrap(), Errors::InvalidLogRecordCRC);
+        std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_recover_torn_tail() {
+        // The 50th record is cut in the middle, and is the last one of the file.
+        let (opts, size) =
+            prepare_corrupted_engine("torn-tail", CorruptionPolicy::Fail, |content, ofs| {
+                content.truncate(ofs + 20);
+            });
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 50);
+        assert_eq!(
+            engine.active_file.read().unwrap().file_size(),
+            50 * size as u64
+        );
+        assert!(engine.put(get_test_key(100), get_test_value(100)).is_ok());
+        std::mem::drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 51);
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+
+        // The last record is complete in size, but its content was not fully flushed.
+        let (opts, _) =
+            prepare_corrupted_engine("torn-tail-crc", CorruptionPolicy::Fail, |content, ofs| {
+                let size = ofs / 50;
+                content.truncate(ofs + size);
+                content[ofs + size - 1] ^= 0xff;
+            });
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 50);
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_corruption_policy_skip_record() {
+        let (opts, size) =
+            prepare_corrupted_engine("skip", CorruptionPolicy::SkipRecord, |content, ofs| {
+                content[ofs + 10] ^= 0xff;
+            });
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 99);
+        assert_eq!(
+            engine.get(get_test_key(50)).err().unwrap(),
+            Errors::KeyNotFound
+        );
+        assert_eq!(engine.get(get_test_key(99)).unwrap(), get_test_value(99));
+        assert_eq!(
+            engine.recovered_corruptions(),
+            vec![RecoveredCorruption {
+                file_id: 1,
+                ofs: 50 * size as u64,
+                discarded: size as u64,
+                error: Errors::InvalidLogRecordCRC,
+            }]
+        );
+        assert!(engine.put(get_test_key(100), get_test_value(100)).is_ok());
+        std::mem::drop(engine);
+
+        // The skipped record is kept on disk, and skipped again.
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 100);
+        assert_eq!(engine.get(get_test_key(100)).unwrap(), get_test_value(100));
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_corruption_policy_skip_bad_header() {
+        // An unknown record type cannot be skipped, so the rest of the active file is truncated.
+        let (opts, size) = prepare_corrupted_engine(
+            "skip-header",
+            CorruptionPolicy::SkipRecord,
+            |content, ofs| {
+                content[ofs] = 0x1f;
+            },
+        );
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 50);
+        assert_eq!(
+            engine.active_file.read().unwrap().file_size(),
+            50 * size as u64
+        );
+        assert!(engine.put(get_test_key(100), get_test_value(100)).is_ok());
+        assert_eq!(engine.get(get_test_key(100)).unwrap(), get_test_value(100));
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_corruption_policy_truncate_file() {
+        let (opts, size) = prepare_corrupted_engine(
+            "truncate",
+            CorruptionPolicy::TruncateFile,
+            |content, ofs| {
+                content[ofs + 10] ^= 0xff;
+            },
+        );
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 50);
+        assert_eq!(engine.get(get_test_key(49)).unwrap(), get_test_value(49));
+        assert_eq!(
+            engine.get(get_test_key(51)).err().unwrap(),
+            Errors::KeyNotFound
+        );
+        assert!(engine.put(get_test_key(100), get_test_value(100)).is_ok());
+        std::mem::drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.list_keys().unwrap().len(), 51);
+        assert_eq!(
+            engine.active_file.read().unwrap().file_size(),
+            51 * size as u64
+        );
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_unsupported_format_version() {
+        // The 50th record claims a format written by a newer version.
+        let (opts, size) = prepare_corrupted_engine(
+            "unsupported-format",
+            CorruptionPolicy::TruncateFile,
+            |content, ofs| {
+                content[ofs] |= 0xf0;
+            },
+        );
+        let res = Engine::open(opts.clone());
+        assert_eq!(res.err().unwrap(), Errors::UnsupportedFormatVersion);
+
+        // The records of the newer version are left intact.
+        let file_name = get_data_file_name(&opts.dir_path, 1);
+        assert_eq!(
+            std::fs::metadata(file_name).unwrap().len(),
+            100 * size as u64
+        );
+        std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,166 @@
+//! Distribution of key and value sizes, for finding what takes the space before deciding on
+//! compression or separating large values.
+
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::{
+    data::{data_file::DataFile, log_record::LogRecordPos},
+    db::{Engine, OldFiles},
+    errors::{Errors, Result},
+    options::IteratorOptions,
+};
+
+/// Number of the largest values listed in a size report.
+const LARGEST_VALUE_NUM: usize = 10;
+
+/// A histogram of sizes, where bucket 0 counts the empty ones, and bucket I counts the sizes in
+/// `[2^(I-1), 2^I)`.
+#[derive(Debug, Default, PartialEq)]
+pub struct SizeHistogram {
+    pub buckets: Vec<usize>,
+    pub count: usize,
+    pub total: usize,
+    pub max: usize,
+}
+
+impl SizeHistogram {
+    fn add(&mut self, size: usize) {
+        let bucket = (usize::BITS - size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += size;
+        self.max = self.max.max(size);
+    }
+
+    /// Average of all sizes, or 0 if there is none.
+    pub fn mean(&self) -> usize {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+/// A large value found by a size report.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LargeValue {
+    pub size: usize,
+    pub key: Vec<u8>,
+    pub file_id: u64,
+}
+
+/// Sizes of the sampled live entries, where:
+/// - `sampled_num` is the number of entries sampled, out of `entry_num` live entries.
+/// - `largest_values` are the largest sampled values, in descending order of size.
+#[derive(Debug, Default)]
+pub struct SizeReport {
+    pub entry_num: usize,
+    pub sampled_num: usize,
+    pub key_sizes: SizeHistogram,
+    pub value_sizes: SizeHistogram,
+    pub largest_values: Vec<LargeValue>,
+}
+
+impl Engine {
+    /// Report the distribution of key and value sizes of the live entries. Only a SAMPLE_RATE
+    /// fraction of the entries, chosen by the hash of their keys, is measured, where 1.0 measures
+    /// all of them. Values sizes are read from the record headers.
+    pub fn size_report(&self, sample_rate: f64) -> Result<SizeReport> {
+        if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+            return Err(Errors::InvalidSampleRate);
+        }
+        let threshold = (sample_rate * u32::MAX as f64) as u32;
+
+        let mut report = SizeReport::default();
+        let mut largest = BinaryHeap::new();
+        let mut index_iter = self.index.iterator(IteratorOptions::default());
+        while let Some((key, pos)) = index_iter.next() {
+            report.entry_num += 1;
+            if sample_rate < 1.0 && crc32fast::hash(key) > threshold {
+                continue;
+            }
+
+            let value_size = {
+                let active_file = self.active_file.read().unwrap();
+                read_value_size(&active_file, &self.old_files(), pos)?
+            };
+            report.sampled_num += 1;
+            report.key_sizes.add(key.len());
+            report.value_sizes.add(value_size);
+
+            largest.push(Reverse(LargeValue {
+                size: value_size,
+                key: key.clone(),
+                file_id: pos.file_id,
+            }));
+            if largest.len() > LARGEST_VALUE_NUM {
+                largest.pop();
+            }
+        }
+
+        report.largest_values = largest.into_sorted_vec().into_iter().map(|v| v.0).collect();
+        Ok(report)
+    }
+}
+
+fn read_value_size(
+    active_file: &DataFile,
+    old_files: &OldFiles,
+    pos: &LogRecordPos,
+) -> Result<usize> {
+    if active_file.get_file_id() == pos.file_id {
+        return active_file.read_value_size(pos.ofs);
+    }
+    old_files
+        .get(&pos.file_id)
+        .ok_or(Errors::DataFileNotFound)?
+        .read_value_size(pos.ofs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use bytes::Bytes;
+
+    use crate::{options::Options, utils::rand_kv::get_test_key};
+
+    use super::*;
+
+    #[test]
+    fn test_engine_size_report() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-size-report");
+        opts.data_file_size = 64 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            let value = Bytes::from(vec![b'v'; i as usize]);
+            assert!(engine.put(get_test_key(i), value).is_ok());
+        }
+        assert!(engine.put(get_test_key(0), Bytes::new()).is_ok());
+
+        let report = engine.size_report(1.0).unwrap();
+        assert_eq!(report.entry_num, 1000);
+        assert_eq!(report.sampled_num, 1000);
+        assert_eq!(report.value_sizes.buckets[0], 1);
+        assert_eq!(report.value_sizes.buckets[10], 1000 - 512);
+        assert_eq!(report.value_sizes.max, 999);
+        assert_eq!(report.value_sizes.mean(), 999 * 1000 / 2 / 1000);
+        assert_eq!(report.key_sizes.count, 1000);
+        assert_eq!(report.largest_values.len(), LARGEST_VALUE_NUM);
+        assert_eq!(report.largest_values[0].size, 999);
+        assert_eq!(report.largest_values[0].key, get_test_key(999).to_vec());
+        assert_eq!(report.largest_values[9].size, 990);
+
+        let sampled = engine.size_report(0.2).unwrap();
+        assert_eq!(sampled.entry_num, 1000);
+        assert!(sampled.sampled_num > 100 && sampled.sampled_num < 300);
+        assert_eq!(
+            engine.size_report(0.0).err(),
+            Some(Errors::InvalidSampleRate)
+        );
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,529 @@
+//! Point-in-time views of the engine, taken by `Engine::snapshot` and iterators. Reads through
+//! a snapshot only see the transactions committed before it is taken. A snapshot marks the end
+//! of the log when it is taken, so entries written afterwards are told apart by their position.
+//! As the index only holds the newest position of each key, the position a key had in the
+//! snapshot is recorded by `SnapshotIndex` before it is overwritten or deleted.
+//!
+//! Partial merges remove the merged data files, which still hold the entries of open snapshots.
+//! While any snapshot is open, the files are moved into the obsolete directory instead, and
+//! removed once the last snapshot is released.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, RwLock},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    data::{
+        data_file::{get_data_file_name, DataFile},
+        log_record::LogRecordPos,
+    },
+    db::{Engine, OldFiles},
+    errors::{Errors, Result},
+    index::{
+        bounded_range, preceding_range, remaining_range, IndexIterator, IndexMemoryUsage, Indexer,
+        KeyRange,
+    },
+    options::{IOType, IteratorOptions},
+};
+
+const OBSOLETE_DIR_NAME: &str = "obsolete";
+
+/// State of an open snapshot, where:
+/// - `end` is the position of the end of the log when the snapshot is taken, as (file id,
+///   offset).
+/// - `sequence_number` is the sequence number of the next transaction when the snapshot is taken.
+/// - `replaced` stores the positions of the keys overwritten or deleted since then.
+struct SnapshotState {
+    end: (u64, u64),
+    sequence_number: usize,
+    replaced: RwLock<BTreeMap<Vec<u8>, LogRecordPos>>,
+}
+
+impl SnapshotState {
+    /// Whether POS is written before the snapshot is taken.
+    fn contains(&self, pos: &LogRecordPos) -> bool {
+        (pos.file_id, pos.ofs) < self.end
+    }
+}
+
+/// The open snapshots of an engine, along with the data files removed while they are open.
+pub(crate) struct Snapshots {
+    dir_path: PathBuf,
+    live: RwLock<Vec<Arc<SnapshotState>>>,
+    obsolete_files: RwLock<Arc<OldFiles>>,
+}
+
+impl Snapshots {
+    pub(crate) fn new(dir_path: PathBuf) -> Self {
+        Self {
+            dir_path,
+            live: RwLock::new(Vec::new()),
+            obsolete_files: RwLock::new(Arc::new(HashMap::new())),
+        }
+    }
+
+    /// Record the position KEY has in INDEX into the open snapshots, before it is replaced by
+    /// NEW_POS or deleted if NEW_POS is None.
+    fn record(&self, index: &dyn Indexer, key: &[u8], new_pos: Option<&LogRecordPos>) {
+        let live = self.live.read().unwrap();
+        let mut old_pos = None;
+        for state in live.iter() {
+            // Writes appended before the snapshot but indexed after it belong to the snapshot.
+            if new_pos.is_some_and(|pos| state.contains(pos)) {
+                continue;
+            }
+            let mut replaced = state.replaced.write().unwrap();
+            if replaced.contains_key(key) {
+                continue;
+            }
+            if let Some(pos) = old_pos.get_or_insert_with(|| index.get(key.to_vec())) {
+                if state.contains(pos) {
+                    replaced.insert(key.to_vec(), *pos);
+                }
+            }
+        }
+    }
+
+    /// Remove the data files FILE_IDS from the engine directory. While any snapshot is open, the
+    /// files are moved into the obsolete directory instead.
+    pub(crate) fn remove_data_files(&self, file_ids: &[u64]) -> Result<()> {
+        let live = self.live.read().unwrap();
+        if live.is_empty() {
+            for file_id in file_ids {
+                fs::remove_file(get_data_file_name(&self.dir_path, *file_id))
+                    .map_err(|_| Errors::FailedToWriteToDataFile)?;
+            }
+            return Ok(());
+        }
+
+        let obsolete_path = get_obsolete_path(&self.dir_path);
+        fs::create_dir_all(&obsolete_path).map_err(|_| Errors::FailedToCreateDatabaseDir)?;
+        let mut obsolete_files = self.obsolete_files.write().unwrap();
+        let mut new_obsolete_files = OldFiles::clone(&obsolete_files);
+        for file_id in file_ids {
+            let data_file = DataFile::new_lazy(&obsolete_path, *file_id, IOType::StandardFIO);
+            new_obsolete_files.insert(*file_id, Arc::new(data_file));
+        }
+        // The files are published first, so a read failing on a moved file finds them.
+        *obsolete_files = Arc::new(new_obsolete_files);
+        for file_id in file_ids {
+            fs::rename(
+                get_data_file_name(&self.dir_path, *file_id),
+                get_data_file_name(&obsolete_path, *file_id),
+            )
+            .map_err(|_| Errors::FailedToWriteToDataFile)?;
+        }
+        Ok(())
+    }
+
+    /// Close the snapshot STATE, and remove the obsolete files once no snapshot is open.
+    fn release(&self, state: &Arc<SnapshotState>) {
+        let mut live = self.live.write().unwrap();
+        live.retain(|s| !Arc::ptr_eq(s, state));
+        if !live.is_empty() {
+            return;
+        }
+        let mut obsolete_files = self.obsolete_files.write().unwrap();
+        if !obsolete_files.is_empty() {
+            *obsolete_files = Arc::new(HashMap::new());
+            if let Err(e) = clean_obsolete_dir(&self.dir_path) {
+                log::warn!("failed to remove obsolete data files: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Registration of an open snapshot, which closes it once dropped.
+struct SnapshotGuard {
+    state: Arc<SnapshotState>,
+    snapshots: Arc<Snapshots>,
+}
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        self.snapshots.release(&self.state);
+    }
+}
+
+/// A point-in-time view of an engine, which stays open until it and all the iterators created
+/// from it are dropped. The data files it reads are kept around by merges meanwhile.
+#[derive(Clone)]
+pub struct Snapshot<'a> {
+    guard: Arc<SnapshotGuard>,
+    engine: &'a Engine,
+}
+
+impl<'a> Snapshot<'a> {
+    /// Get the sequence number of the next transaction when the snapshot is taken. Only the
+    /// transactions with a lower sequence number are visible.
+    pub fn sequence_number(&self) -> usize {
+        self.guard.state.sequence_number
+    }
+
+    /// Get the value KEY had when the snapshot is taken.
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        // The index is read first, as the position is recorded before the index is updated.
+        let pos = self.engine.index.get(key.to_vec());
+        let replaced = self
+            .guard
+            .state
+            .replaced
+            .read()
+            .unwrap()
+            .get(key.as_ref())
+            .copied();
+        match replaced.or(pos) {
+            Some(pos) if self.guard.state.contains(&pos) => self.get_value_by_position(&pos),
+            _ => Err(Errors::KeyNotFound),
+        }
+    }
+
+    /// Get the engine the snapshot is taken from.
+    pub(crate) fn engine(&self) -> &'a Engine {
+        self.engine
+    }
+
+    /// Get an iterator through the entries of INDEX in the snapshot, with OPTIONS.
+    pub(crate) fn iterator(
+        &self,
+        index: &dyn Indexer,
+        options: IteratorOptions,
+    ) -> Box<dyn IndexIterator> {
+        // The recorded positions are merged in key order, so the keys are always sorted.
+        let options = IteratorOptions {
+            unordered: false,
+            ..options
+        };
+        Box::new(SnapshotIterator {
+            inner: index.iterator(options.clone()),
+            head: None,
+            state: self.guard.state.clone(),
+            range: bounded_range(&options),
+            bound: Bound::Unbounded,
+            current: None,
+            options,
+        })
+    }
+
+    /// Get the value at LOG_RECORD_POS of the engine, which may have been moved into the
+    /// obsolete directory.
+    pub(crate) fn get_value_by_position(&self, log_record_pos: &LogRecordPos) -> Result<Bytes> {
+        let engine = self.engine;
+        match engine.get_value_by_position(log_record_pos) {
+            Err(e) if e != Errors::KeyNotFound => {
+                let obsolete_files = self.guard.snapshots.obsolete_files.read().unwrap().clone();
+                if !obsolete_files.contains_key(&log_record_pos.file_id) {
+                    return Err(e);
+                }
+                let active_file = engine.active_file.read().unwrap();
+                engine.read_value(&active_file, &obsolete_files, log_record_pos)
+            }
+            res => res,
+        }
+    }
+}
+
+/// Iterator through the entries of a snapshot, where:
+/// - `inner` is the iterator of the index.
+/// - `head` stores the next item of `inner`.
+/// - `state` is the state of the snapshot, whose recorded positions take precedence over the
+///   ones of the index.
+/// - `range` is the range of keys iterated through.
+/// - `bound` is the lower bound of the keys left, or their upper bound in reverse.
+/// - `current` stores the item returned by the last call to `next` or `prev`.
+/// - `options` determines how to iterate through the snapshot.
+///
+/// Positions may be recorded at any time, so they are looked up at each step instead of being
+/// read ahead. Entries written after the snapshot are skipped.
+struct SnapshotIterator {
+    inner: Box<dyn IndexIterator>,
+    head: Option<(Vec<u8>, LogRecordPos)>,
+    state: Arc<SnapshotState>,
+    range: KeyRange,
+    bound: Bound<Vec<u8>>,
+    current: Option<(Vec<u8>, LogRecordPos)>,
+    options: IteratorOptions,
+}
+
+impl SnapshotIterator {
+    /// Whether key A comes before key B in the iteration order.
+    fn is_before(&self, a: &[u8], b: &[u8]) -> bool {
+        match self.options.reverse {
+            true => a > b,
+            false => a < b,
+        }
+    }
+
+    /// Get the first recorded position within RANGE, in descending key order if REVERSE.
+    fn first_replaced(&self, range: &KeyRange, reverse: bool) -> Option<(Vec<u8>, LogRecordPos)> {
+        let replaced = self.state.replaced.read().unwrap();
+        let mut entries = replaced.range::<[u8], _>((
+            range.0.as_ref().map(|key| key.as_slice()),
+            range.1.as_ref().map(|key| key.as_slice()),
+        ));
+        let entry = match reverse {
+            true => entries.next_back(),
+            false => entries.next(),
+        };
+        entry.map(|(key, pos)| (key.clone(), *pos))
+    }
+
+    fn reset(&mut self, bound: Bound<Vec<u8>>) {
+        self.head = None;
+        self.bound = bound;
+    }
+}
+
+impl IndexIterator for SnapshotIterator {
+    fn rewind(&mut self) {
+        self.inner.rewind();
+        self.reset(Bound::Unbounded);
+    }
+
+    fn seek(&mut self, key: Vec<u8>) {
+        self.inner.seek(key.clone());
+        self.reset(Bound::Included(key));
+    }
+
+    fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        self.current = None;
+        loop {
+            if self.head.is_none() {
+                self.head = self.inner.next().map(|(key, pos)| (key.clone(), *pos));
+            }
+            let replaced = remaining_range(&self.bound, &self.range, &self.options)
+                .and_then(|range| self.first_replaced(&range, self.options.reverse));
+
+            // The recorded position of a key is taken over the one of the index.
+            let (key, pos) = match (self.head.take(), replaced) {
+                (None, None) => break,
+                (Some(head), None) => head,
+                (Some(head), Some(replaced)) if self.is_before(&head.0, &replaced.0) => head,
+                (head, Some(replaced)) => {
+                    self.head = head.filter(|(key, _)| *key != replaced.0);
+                    replaced
+                }
+            };
+            self.bound = Bound::Excluded(key.clone());
+            if self.state.contains(&pos) {
+                self.current = Some((key, pos));
+                break;
+            }
+        }
+        self.current.as_ref().map(|(key, pos)| (key, pos))
+    }
+
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        // Put back the item read ahead, so the index iterator is positioned at `bound`.
+        if self.head.take().is_some() {
+            self.inner.prev();
+        }
+        self.current = None;
+        loop {
+            let item = self.inner.prev().map(|(key, pos)| (key.clone(), *pos));
+            let replaced = preceding_range(None, &self.bound, &self.range, &self.options)
+                .and_then(|(range, options)| self.first_replaced(&range, options.reverse));
+
+            let (key, pos) = match (item, replaced) {
+                (None, None) => break,
+                (Some(item), None) => item,
+                (Some(item), Some(replaced)) if self.is_before(&replaced.0, &item.0) => item,
+                (item, Some(replaced)) => {
+                    // The index iterator stays before the recorded key.
+                    if item.is_some_and(|(key, _)| key != replaced.0) {
+                        self.inner.next();
+                    }
+                    replaced
+                }
+            };
+            self.bound = Bound::Included(key.clone());
+            if self.state.contains(&pos) {
+                self.current = Some((key, pos));
+                break;
+            }
+        }
+        self.current.as_ref().map(|(key, pos)| (key, pos))
+    }
+}
+
+/// Indexer recording the positions of the keys into the open snapshots before they are
+/// replaced.
+pub(crate) struct SnapshotIndex {
+    inner: Box<dyn Indexer>,
+    snapshots: Arc<Snapshots>,
+}
+
+impl SnapshotIndex {
+    pub(crate) fn new(inner: Box<dyn Indexer>, snapshots: Arc<Snapshots>) -> Self {
+        Self { inner, snapshots }
+    }
+}
+
+impl Indexer for SnapshotIndex {
+    fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> Option<LogRecordPos> {
+        self.snapshots.record(self.inner.as_ref(), &key, Some(&pos));
+        self.inner.put(key, pos)
+    }
+
+    fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        self.inner.get(key)
+    }
+
+    fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        self.snapshots.record(self.inner.as_ref(), &key, None);
+        self.inner.delete(key)
+    }
+
+    fn compare_and_put(&self, key: Vec<u8>, expected: LogRecordPos, pos: LogRecordPos) -> bool {
+        self.snapshots.record(self.inner.as_ref(), &key, Some(&pos));
+        self.inner.compare_and_put(key, expected, pos)
+    }
+
+    fn compare_and_delete(&self, key: Vec<u8>, expected: LogRecordPos) -> bool {
+        self.snapshots.record(self.inner.as_ref(), &key, None);
+        self.inner.compare_and_delete(key, expected)
+    }
+
+    fn list_keys(&self) -> Result<Vec<Bytes>> {
+        self.inner.list_keys()
+    }
+
+    fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
+        self.inner.iterator(options)
+    }
+
+    fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Box<dyn IndexIterator> {
+        self.inner.range(start, end)
+    }
+
+    fn memory_usage(&self) -> IndexMemoryUsage {
+        self.inner.memory_usage()
+    }
+
+    fn persist(&self) -> Result<()> {
+        self.inner.persist()
+    }
+
+    fn compact(&self) {
+        self.inner.compact()
+    }
+}
+
+impl Engine {
+    /// Take a snapshot of the engine, which pins the current end of the log and sequence
+    /// number until it is dropped.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        // No transaction is split by the snapshot, and no write is appended while it is taken.
+        let _batch_commit_lock = self.batch_commit_lock.lock().unwrap();
+        let active_file = self.active_file.read().unwrap();
+        let mut live = self.snapshots.live.write().unwrap();
+        let state = Arc::new(SnapshotState {
+            end: (active_file.get_file_id(), active_file.get_write_ofs()),
+            sequence_number: self.sequence_number.load(Ordering::SeqCst),
+            replaced: RwLock::new(BTreeMap::new()),
+        });
+        live.push(state.clone());
+        Snapshot {
+            guard: Arc::new(SnapshotGuard {
+                state,
+                snapshots: self.snapshots.clone(),
+            }),
+            engine: self,
+        }
+    }
+}
+
+/// Get the directory holding the data files removed while snapshots are open under DIR_PATH.
+fn get_obsolete_path(dir_path: &Path) -> PathBuf {
+    dir_path.join(OBSOLETE_DIR_NAME)
+}
+
+/// Remove the data files left in the obsolete directory under DIR_PATH, which are no longer
+/// referred to once no snapshot is open.
+pub(crate) fn clean_obsolete_dir(dir_path: &Path) -> Result<()> {
+    let obsolete_path = get_obsolete_path(dir_path);
+    if !obsolete_path.is_dir() {
+        return Ok(());
+    }
+    fs::remove_dir_all(obsolete_path).map_err(|_| Errors::FailedToWriteToDataFile)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-snapshot");
+        opts.data_file_size = 16 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        let snapshot = engine.snapshot();
+        assert!(engine.put(get_test_key(0), Bytes::from("new")).is_ok());
+        assert!(engine.delete(get_test_key(1)).is_ok());
+        assert!(engine.put(get_test_key(1000), get_test_value(1000)).is_ok());
+        let wb = engine
+            .new_write_batch(Default::default())
+            .expect("failed to create write batch");
+        assert!(wb.put(get_test_key(2), Bytes::from("new")).is_ok());
+        assert!(wb.commit().is_ok());
+        assert!(snapshot.sequence_number() < engine.iter(Default::default()).sequence_number());
+
+        // The versions pinned by the snapshot survive the merge of their files.
+        let mut file_ids: Vec<u64> = engine.old_files().keys().copied().collect();
+        file_ids.sort();
+        assert!(engine.merge_files(&file_ids).is_ok());
+
+        assert_eq!(snapshot.get(get_test_key(0)).unwrap(), get_test_value(0));
+        assert_eq!(snapshot.get(get_test_key(1)).unwrap(), get_test_value(1));
+        assert_eq!(snapshot.get(get_test_key(2)).unwrap(), get_test_value(2));
+        assert_eq!(
+            snapshot.get(get_test_key(999)).unwrap(),
+            get_test_value(999)
+        );
+        assert_eq!(
+            snapshot.get(get_test_key(1000)).err(),
+            Some(Errors::KeyNotFound)
+        );
+        assert_eq!(snapshot.get(Bytes::new()).err(), Some(Errors::KeyIsEmpty));
+
+        // Iterators created from the snapshot keep it open once it is dropped.
+        let iter = snapshot.iter(Default::default());
+        std::mem::drop(snapshot);
+        let mut num = 0;
+        while let Some((key, value)) = iter.next() {
+            assert_eq!(key, get_test_key(num));
+            assert_eq!(value, get_test_value(num));
+            num += 1;
+        }
+        assert_eq!(num, 1000);
+        std::mem::drop(iter);
+        assert!(!opts.dir_path.join(OBSOLETE_DIR_NAME).exists());
+
+        assert_eq!(engine.get(get_test_key(0)).unwrap(), Bytes::from("new"));
+        assert!(engine.get(get_test_key(1)).is_err());
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,138 @@
+//! Writes are stalled when merges cannot keep up with the garbage produced by overwrites and
+//! deletions. Beyond `write_stall_soft_limit` reclaimable bytes, each write is delayed in
+//! proportion to how far the garbage is above the soft limit, and beyond `write_stall_hard_limit`
+//! writes are rejected with `Errors::WriteStalled` until a merge discards the garbage. Such a
+//! merge is never refused by the merge policy, however small the garbage is next to the directory.
+
+use std::{sync::atomic::Ordering, thread, time::Duration};
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+};
+
+/// The delay of a write right below the hard limit.
+const MAX_WRITE_STALL_DELAY: Duration = Duration::from_millis(100);
+
+impl Engine {
+    /// Delay or reject a write according to the current reclaimable bytes.
+    pub(crate) fn check_write_stall(&self) -> Result<()> {
+        let delay = self.write_stall_delay()?;
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        Ok(())
+    }
+
+    /// Whether writes are rejected until a merge discards the garbage.
+    pub(crate) fn is_write_stalled(&self) -> bool {
+        self.write_stall_delay().is_err()
+    }
+
+    /// Get the delay applied to a write, or `Errors::WriteStalled` if writes are rejected.
+    fn write_stall_delay(&self) -> Result<Duration> {
+        let soft_limit = self.options.write_stall_soft_limit;
+        let hard_limit = self.options.write_stall_hard_limit;
+        let reclaim_size = self.reclaim_size.load(Ordering::SeqCst);
+
+        if hard_limit > 0 && reclaim_size >= hard_limit {
+            return Err(Errors::WriteStalled);
+        }
+        if soft_limit == 0 || reclaim_size < soft_limit {
+            return Ok(Duration::ZERO);
+        }
+
+        // Without a hard limit, delay each write by the maximum once over the soft limit.
+        if hard_limit <= soft_limit {
+            return Ok(MAX_WRITE_STALL_DELAY);
+        }
+        let ratio = (reclaim_size - soft_limit) as f64 / (hard_limit - soft_limit) as f64;
+        Ok(MAX_WRITE_STALL_DELAY.mul_f64(ratio))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_write_stall() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-write-stall");
+        opts.data_file_size = 32 * 1024;
+        opts.write_stall_soft_limit = 63 * 1024;
+        opts.write_stall_hard_limit = 64 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        assert_eq!(engine.write_stall_delay(), Ok(Duration::ZERO));
+        for i in 0..500 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+
+        // Overwrite the keys until the garbage reaches the hard limit.
+        let mut stalled = false;
+        for i in 0..5000 {
+            let delay = engine.write_stall_delay();
+            if delay.is_ok() && engine.reclaim_size.load(Ordering::SeqCst) > 63 * 1024 {
+                assert!(!delay.unwrap().is_zero());
+            }
+            match engine.put(get_test_key(i % 500), get_test_value(i)) {
+                Ok(()) => (),
+                Err(e) => {
+                    assert_eq!(e, Errors::WriteStalled);
+                    stalled = true;
+                    break;
+                }
+            }
+        }
+        assert!(stalled);
+        assert_eq!(
+            engine.delete(get_test_key(1)).err().unwrap(),
+            Errors::WriteStalled
+        );
+
+        // A merge discards the garbage of the merged files, which resumes the writes.
+        assert!(engine.merge().is_ok());
+        assert!(engine.put(get_test_key(1), get_test_value(1)).is_ok());
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_write_stall_large_dir() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-write-stall-large-dir");
+        opts.data_file_size = 64 * 1024;
+        opts.write_stall_hard_limit = 16 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        // The garbage reaches the hard limit far below the merge ratio of the directory.
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        let mut i = 0;
+        while engine.put(get_test_key(i % 10), get_test_value(i)).is_ok() {
+            i += 1;
+        }
+        let metrics = engine.merge_metrics();
+        assert!(
+            (metrics.reclaim_size as f32) < metrics.disk_size as f32 * opts.data_file_merge_ratio
+        );
+
+        // The stalled writes are resumed by a merge, which the policy would refuse otherwise.
+        assert!(engine.merge().is_ok());
+        assert!(engine.put(get_test_key(1), get_test_value(1)).is_ok());
+        assert_eq!(engine.merge().err(), Some(Errors::MergeRationUnreached));
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,246 @@
+//! Soft deletion. With `Options::trash_retention` set, deleted entries are moved into a trash
+//! engine under the `trash` subdirectory instead of being dropped, and can be restored by
+//! `Engine::undelete` until the retention window elapses. Expired entries are purged on startup
+//! and before each merge.
+//!
+//! Every deletion is covered, that is `Engine::delete`, `compare_and_swap` to None, and the
+//! tombstones committed by transactions, including `delete_prefix`, `delete_range`, the old key of
+//! `rename_key` and prepared transactions. Entries dropped by merges, such as expired ones or the
+//! ones discarded by `Options::compaction_filter`, are not deleted by the user and are not kept.
+
+use std::path::{Path, PathBuf};
+
+use bytes::{Bytes, BytesMut};
+use prost::encoding::{decode_varint, encode_varint};
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+    options::Options,
+    utils::time::now_millis,
+};
+
+const TRASH_DIR_NAME: &str = "trash";
+
+/// Get the directory of the trash engine of the database under DIR_PATH.
+pub(crate) fn get_trash_path(dir_path: &Path) -> PathBuf {
+    dir_path.join(TRASH_DIR_NAME)
+}
+
+impl Engine {
+    /// Open the trash engine if soft deletion is enabled, and purge its expired entries.
+    pub(crate) fn open_trash(&mut self) -> Result<()> {
+        if self.options.trash_retention.is_none() {
+            return Ok(());
+        }
+        let opts = Options {
+            dir_path: get_trash_path(&self.options.dir_path),
+            trash_retention: None,
+            change_sink: None,
+            compaction_filter: None,
+            ..Options::clone(&self.options)
+        };
+        self.trash = Some(Box::new(Engine::open(opts)?));
+        self.purge_trash()?;
+        Ok(())
+    }
+
+    /// Move the value of KEY into the trash before it is deleted.
+    pub(crate) fn move_to_trash(&self, key: &Bytes) -> Result<()> {
+        let trash = match &self.trash {
+            Some(trash) => trash,
+            None => return Ok(()),
+        };
+        let value = match self.get(key.clone()) {
+            Ok(value) => value,
+            Err(Errors::KeyNotFound) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        trash.put(key.clone(), encode_trash_value(now_millis(), &value))
+    }
+
+    /// Restore the deleted entry of KEY from the trash. Fail with `Errors::KeyNotFound` if it is
+    /// not in the trash or its retention window has elapsed.
+    pub fn undelete(&self, key: Bytes) -> Result<()> {
+        let trash = self.trash.as_ref().ok_or(Errors::TrashDisabled)?;
+        let _key_lock = self.key_locks.lock(&key);
+        let (deleted_at, value) = decode_trash_value(trash.get(key.clone())?)?;
+        if self.is_trash_expired(deleted_at) {
+            trash.delete(key)?;
+            return Err(Errors::KeyNotFound);
+        }
+        self.put_record(key.clone(), value)?;
+        trash.delete(key)
+    }
+
+    /// Remove the entries whose retention window has elapsed from the trash, return the number of
+    /// entries removed.
+    pub fn purge_trash(&self) -> Result<usize> {
+        let trash = match &self.trash {
+            Some(trash) => trash,
+            None => return Ok(0),
+        };
+        let mut purged = 0;
+        for key in trash.list_keys()? {
+            let (deleted_at, _) = decode_trash_value(trash.get(key.clone())?)?;
+            if self.is_trash_expired(deleted_at) {
+                trash.delete(key)?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    fn is_trash_expired(&self, deleted_at: u64) -> bool {
+        let retention = self.options.trash_retention.unwrap_or_default();
+        now_millis().saturating_sub(deleted_at) >= retention.as_millis() as u64
+    }
+}
+
+/// Encode VALUE deleted at DELETED_AT milliseconds since the epoch as `deleted_at | value`.
+fn encode_trash_value(deleted_at: u64, value: &Bytes) -> Bytes {
+    let mut buf = BytesMut::new();
+    encode_varint(deleted_at, &mut buf);
+    buf.extend_from_slice(value);
+    buf.freeze()
+}
+
+/// Decode a trash entry into the (deleted_at, value) pair.
+fn decode_trash_value(mut buf: Bytes) -> Result<(u64, Bytes)> {
+    let deleted_at = decode_varint(&mut buf).map_err(|_| Errors::DataDirectoryCorrupted)?;
+    Ok((deleted_at, buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Barrier, thread, time::Duration};
+
+    use crate::{
+        options::WriteBatchOptions,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_engine_undelete() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-trash");
+        opts.trash_retention = Some(Duration::from_millis(200));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..10 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(engine.delete(get_test_key(1)).is_ok());
+        assert!(engine.delete(get_test_key(2)).is_ok());
+        assert_eq!(engine.get(get_test_key(1)).err(), Some(Errors::KeyNotFound));
+
+        assert!(engine.undelete(get_test_key(1)).is_ok());
+        assert_eq!(engine.get(get_test_key(1)).unwrap(), get_test_value(1));
+        assert_eq!(
+            engine.undelete(get_test_key(1)).err(),
+            Some(Errors::KeyNotFound)
+        );
+
+        // The trash survives a restart, and is purged once the retention window elapses.
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.purge_trash().unwrap(), 0);
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(engine.purge_trash().unwrap(), 1);
+        assert_eq!(
+            engine.undelete(get_test_key(2)).err(),
+            Some(Errors::KeyNotFound)
+        );
+        assert_eq!(engine.list_keys().unwrap().len(), 9);
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_undelete_all_deletions() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-trash-all-deletions");
+        opts.trash_retention = Some(Duration::from_secs(60));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..10 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..3 {
+            assert!(engine.put(prefixed_key(i), get_test_value(i)).is_ok());
+        }
+
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert!(wb.delete(get_test_key(1)).is_ok());
+        assert!(wb.delete_prefix(Bytes::from("prefix-")).is_ok());
+        assert!(wb.commit().is_ok());
+        assert!(engine
+            .delete_range(get_test_key(2), get_test_key(4))
+            .is_ok());
+        assert!(engine
+            .rename_key(get_test_key(5), Bytes::from("renamed"))
+            .is_ok());
+        assert!(engine
+            .compare_and_swap(get_test_key(6), Some(get_test_value(6)), None)
+            .is_ok());
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert!(wb.delete(get_test_key(7)).is_ok());
+        let sequence_number = wb.prepare().expect("failed to prepare");
+        assert!(engine.commit_prepared(sequence_number).is_ok());
+
+        for i in [1, 2, 3, 5, 6, 7] {
+            assert_eq!(engine.get(get_test_key(i)).err(), Some(Errors::KeyNotFound));
+            assert!(engine.undelete(get_test_key(i)).is_ok());
+            assert_eq!(engine.get(get_test_key(i)).unwrap(), get_test_value(i));
+        }
+        for i in 0..3 {
+            assert!(engine.undelete(prefixed_key(i)).is_ok());
+            assert_eq!(engine.get(prefixed_key(i)).unwrap(), get_test_value(i));
+        }
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_undelete_concurrent_put() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-trash-concurrent-put");
+        opts.trash_retention = Some(Duration::from_secs(60));
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let key = get_test_key(0);
+
+        // A delete never drops a value other than the one it moved into the trash, so the value
+        // put concurrently is either live or in the trash.
+        for i in 0..2000 {
+            assert!(engine.put(key.clone(), get_test_value(i)).is_ok());
+            let barrier = Barrier::new(2);
+            thread::scope(|s| {
+                s.spawn(|| {
+                    barrier.wait();
+                    assert!(engine.put(key.clone(), get_test_value(i + 1)).is_ok());
+                });
+                s.spawn(|| {
+                    barrier.wait();
+                    assert!(engine.delete(key.clone()).is_ok());
+                });
+            });
+            if engine.get(key.clone()).is_err() {
+                assert!(engine.undelete(key.clone()).is_ok());
+            }
+            assert_eq!(engine.get(key.clone()).unwrap(), get_test_value(i + 1));
+        }
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    fn prefixed_key(i: i32) -> Bytes {
+        Bytes::from(format!("prefix-{}", i))
+    }
+}
@@ -0,0 +1,305 @@
+//! TTL management of existing entries. The expiry of an entry is kept in its record, so changing
+//! it appends a new record of the entry with the same value, which keeps the time the value was
+//! written and its user flags. The value is never sent back by the client, and a value stored in
+//! a blob file is neither read nor copied, as the new record refers to the same blob.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::{
+    batch::NON_TRANSACTION_SEQUENCE,
+    blob::with_blob_expiry,
+    data::log_record::{LogRecord, LogRecordType},
+    db::{encode_log_record_key, Engine},
+    errors::{Errors, Result},
+    options::IteratorOptions,
+    utils::time::now_millis,
+};
+
+impl Engine {
+    /// Make the entry with key KEY expire after TTL, replacing its previous expiry if any. Fail
+    /// with `Errors::KeyNotFound` if there is no such entry.
+    pub fn expire(&self, key: Bytes, ttl: Duration) -> Result<()> {
+        let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.set_expiry(key, Some(expire_at))
+    }
+
+    /// Make the entry with key KEY never expire. Fail with `Errors::KeyNotFound` if there is no
+    /// such entry.
+    pub fn persist(&self, key: Bytes) -> Result<()> {
+        self.set_expiry(key, None)
+    }
+
+    /// Make all the entries with key starting with PREFIX expire after TTL, return the number of
+    /// entries updated. Each entry is updated on its own, so a crash may leave some of them
+    /// unchanged.
+    pub fn expire_prefix(&self, prefix: Bytes, ttl: Duration) -> Result<usize> {
+        if prefix.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
+
+        let mut keys = Vec::new();
+        let mut index_iter = self.index.iterator(IteratorOptions {
+            prefix: prefix.to_vec(),
+            ..Default::default()
+        });
+        while let Some((key, _)) = index_iter.next() {
+            keys.push(Bytes::from(key.clone()));
+        }
+        drop(index_iter);
+
+        let mut updated = 0;
+        for key in keys {
+            match self.set_expiry(key, Some(expire_at)) {
+                Ok(()) => updated += 1,
+                // Deleted or expired since listed.
+                Err(Errors::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(updated)
+    }
+
+    /// Rewrite the entry with key KEY to expire at EXPIRE_AT milliseconds since the epoch, or
+    /// never if None. Nothing is written if the expiry is unchanged.
+    fn set_expiry(&self, key: Bytes, expire_at: Option<u64>) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        // The value is not overwritten between the read and the rewrite.
+        let _key_lock = self.key_locks.lock(&key);
+        let old_record = self.get_live_record(key.clone())?;
+        if old_record.expire_at() == expire_at {
+            return Ok(());
+        }
+
+        let encoded_key = encode_log_record_key(key.to_vec(), NON_TRANSACTION_SEQUENCE);
+        let mut log_record = match with_blob_expiry(&old_record, expire_at) {
+            // Only the reference to the blob is rewritten.
+            Some(log_record) => LogRecord {
+                key: encoded_key,
+                ..log_record
+            },
+            None => match expire_at {
+                Some(expire_at) => {
+                    LogRecord::new_expiring(encoded_key, old_record.user_value(), expire_at)
+                }
+                None => LogRecord {
+                    key: encoded_key,
+                    value: old_record.user_value().to_vec(),
+                    record_type: LogRecordType::Normal,
+                    timestamp: None,
+                    user_flags: 0,
+                },
+            },
+        };
+        log_record.timestamp = old_record.timestamp.or(Some(now_millis()));
+        log_record.user_flags = old_record.user_flags;
+        self.put_log_record(key, log_record).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::{Arc, OnceLock, Weak},
+        thread,
+    };
+
+    use crate::{
+        blob::get_blob_path,
+        options::{Options, PutOptions},
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_engine_expire_and_persist() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-ttl");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..10 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        let options = PutOptions {
+            ttl: Some(Duration::from_millis(100)),
+            user_flags: 7,
+        };
+        assert!(engine
+            .put_with_options(get_test_key(10), get_test_value(10), options)
+            .is_ok());
+        let (_, written) = engine.get_with_metadata(get_test_key(10)).unwrap();
+
+        assert!(engine
+            .expire(get_test_key(1), Duration::from_millis(100))
+            .is_ok());
+        assert!(engine.persist(get_test_key(10)).is_ok());
+        let (value, metadata) = engine.get_with_metadata(get_test_key(10)).unwrap();
+        assert_eq!(value, get_test_value(10));
+        assert_eq!(metadata.expire_at, None);
+        assert_eq!(metadata.user_flags, 7);
+        assert_eq!(metadata.timestamp, written.timestamp);
+        assert_eq!(
+            engine.expire(Bytes::from("not exist"), Duration::from_secs(1)),
+            Err(Errors::KeyNotFound)
+        );
+        assert_eq!(
+            engine.persist(Bytes::from("not exist")),
+            Err(Errors::KeyNotFound)
+        );
+        assert_eq!(engine.persist(Bytes::new()), Err(Errors::KeyIsEmpty));
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(engine.get(get_test_key(1)).err(), Some(Errors::KeyNotFound));
+        assert_eq!(engine.get(get_test_key(2)).unwrap(), get_test_value(2));
+        assert_eq!(engine.get(get_test_key(10)).unwrap(), get_test_value(10));
+
+        // The expiries survive a restart.
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.get(get_test_key(1)).err(), Some(Errors::KeyNotFound));
+        assert_eq!(engine.get(get_test_key(10)).unwrap(), get_test_value(10));
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_expire_prefix() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-ttl-prefix");
+        opts.data_file_merge_ratio = 0.0;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..10 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+            let key = Bytes::from(format!("session-{}", i));
+            assert!(engine.put(key, get_test_value(i)).is_ok());
+        }
+        assert!(engine.delete(Bytes::from("session-0")).is_ok());
+
+        assert_eq!(
+            engine
+                .expire_prefix(Bytes::from("session-"), Duration::from_millis(100))
+                .unwrap(),
+            9
+        );
+        assert_eq!(
+            engine
+                .expire_prefix(Bytes::new(), Duration::from_millis(100))
+                .err(),
+            Some(Errors::KeyIsEmpty)
+        );
+
+        // The read drops the expired entry it finds, the others are dropped by the merge.
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(
+            engine.get(Bytes::from("session-1")).err(),
+            Some(Errors::KeyNotFound)
+        );
+        assert_eq!(engine.list_keys().unwrap().len(), 18);
+        let stats = engine.merge().unwrap();
+        assert_eq!(stats.records_expired, 8);
+        assert_eq!(engine.list_keys().unwrap().len(), 10);
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_expire_large_value() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-ttl-blob");
+        opts.large_value_threshold = 1024;
+        opts.data_file_merge_ratio = 0.0;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let blob_count = || fs::read_dir(get_blob_path(&opts.dir_path)).unwrap().count();
+
+        let value = Bytes::from(vec![7u8; 4 * 1024]);
+        assert!(engine.put(get_test_key(1), value.clone()).is_ok());
+        assert_eq!(blob_count(), 1);
+        let active_ofs = engine.active_file.read().unwrap().get_write_ofs();
+
+        // The new records refer to the blob of the value instead of copying it.
+        assert!(engine
+            .expire(get_test_key(1), Duration::from_secs(60))
+            .is_ok());
+        assert!(engine.persist(get_test_key(1)).is_ok());
+        assert_eq!(blob_count(), 1);
+        assert!(engine.active_file.read().unwrap().get_write_ofs() - active_ofs < 1024);
+        let (stored, metadata) = engine.get_with_metadata(get_test_key(1)).unwrap();
+        assert_eq!(stored, value);
+        assert_eq!(metadata.expire_at, None);
+
+        assert!(engine
+            .expire(get_test_key(1), Duration::from_millis(100))
+            .is_ok());
+        assert_eq!(engine.get(get_test_key(1)).unwrap(), value);
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(engine.get(get_test_key(1)).err(), Some(Errors::KeyNotFound));
+
+        // The blob is removed by the merge dropping the expired entry.
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(blob_count(), 0);
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_expire_large_value_during_merge() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-ttl-blob-merge");
+        opts.large_value_threshold = 1024;
+        opts.data_file_size = 64 * 1024;
+        opts.data_file_merge_ratio = 0.0;
+
+        // The TTLs are changed while the merge runs, once the first file is merged, before the
+        // file holding the records referring to the blobs.
+        let cell: Arc<OnceLock<Weak<Engine>>> = Arc::new(OnceLock::new());
+        let cell_clone = cell.clone();
+        opts.merge_progress = Some(Arc::new(move |stats| {
+            if stats.files_processed != 1 {
+                return;
+            }
+            let engine = cell_clone.get().and_then(Weak::upgrade).unwrap();
+            assert!(engine.persist(get_test_key(1)).is_ok());
+            assert!(engine
+                .expire(get_test_key(2), Duration::from_secs(60))
+                .is_ok());
+        }));
+        let engine = Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+        assert!(cell.set(Arc::downgrade(&engine)).is_ok());
+
+        let mut i = 10;
+        while engine.stat().unwrap().data_file_num < 2 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+            i += 1;
+        }
+        let value = |i: u8| Bytes::from(vec![i; 4 * 1024]);
+        assert!(engine
+            .put_with_ttl(get_test_key(1), value(1), Duration::from_secs(60))
+            .is_ok());
+        assert!(engine.put(get_test_key(2), value(2)).is_ok());
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+
+        // The blobs still referred to are kept when the merged files are installed.
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let (stored, metadata) = engine.get_with_metadata(get_test_key(1)).unwrap();
+        assert_eq!(stored, value(1));
+        assert_eq!(metadata.expire_at, None);
+        let (stored, metadata) = engine.get_with_metadata(get_test_key(2)).unwrap();
+        assert_eq!(stored, value(2));
+        assert!(metadata.expire_at.is_some());
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,123 @@
+use xxhash_rust::xxh64::xxh64;
+
+/// Format of the encoded filters, naming the function hashing the items to bits, so a filter
+/// hashed otherwise is never decoded. Items are hashed by XXH64 with a seed of 0, whose output
+/// is stable across releases, unlike the `DefaultHasher` of the standard library.
+const BLOOM_FORMAT_XXH64: u8 = 1;
+
+/// A fixed size bloom filter, which answers whether an item may have been inserted, with no false
+/// negatives.
+#[derive(Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hash_num: u32,
+}
+
+impl BloomFilter {
+    /// Create a bloom filter with BIT_NUM bits (rounded up to a multiple of 64), and HASH_NUM hash
+    /// functions per item.
+    pub fn new(bit_num: usize, hash_num: u32) -> Self {
+        Self {
+            bits: vec![0; bit_num.div_ceil(64).max(1)],
+            hash_num: hash_num.max(1),
+        }
+    }
+
+    /// Create a bloom filter sized for ITEM_NUM items with a false positive rate of about 1%.
+    pub fn with_capacity(item_num: usize) -> Self {
+        // 10 bits per item and 7 hash functions give a false positive rate slightly below 1%.
+        Self::new(item_num.max(1) * 10, 7)
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        let bit_num = self.bit_num();
+        for idx in bit_indexes(item, self.hash_num, bit_num) {
+            self.bits[idx / 64] |= 1 << (idx % 64);
+        }
+    }
+
+    pub fn may_contain(&self, item: &[u8]) -> bool {
+        let bit_num = self.bit_num();
+        bit_indexes(item, self.hash_num, bit_num)
+            .all(|idx| self.bits[idx / 64] & (1 << (idx % 64)) != 0)
+    }
+
+    /// Encode the bloom filter, which can be restored by `decode`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + 4 + self.bits.len() * 8);
+        buf.push(BLOOM_FORMAT_XXH64);
+        buf.extend_from_slice(&self.hash_num.to_le_bytes());
+        for word in &self.bits {
+            buf.extend_from_slice(&word.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Decode the bloom filter encoded as BUF, or None if it is corrupted or hashed by another
+    /// function than this version's.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let (format, buf) = buf.split_first()?;
+        if *format != BLOOM_FORMAT_XXH64 || buf.len() < 12 || !(buf.len() - 4).is_multiple_of(8) {
+            return None;
+        }
+        let hash_num = u32::from_le_bytes(buf[..4].try_into().ok()?);
+        let bits = buf[4..]
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Some(Self {
+            bits,
+            hash_num: hash_num.max(1),
+        })
+    }
+
+    fn bit_num(&self) -> usize {
+        self.bits.len() * 64
+    }
+}
+
+/// Derive HASH_NUM bit indexes of ITEM by double hashing.
+fn bit_indexes(item: &[u8], hash_num: u32, bit_num: usize) -> impl Iterator<Item = usize> {
+    let h1 = xxh64(item, 0);
+    let h2 = h1.rotate_left(32) | 1;
+    (0..hash_num as u64)
+        .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_num as u64) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut bloom = BloomFilter::with_capacity(1000);
+        for i in 0..1000 {
+            bloom.insert(std::format!("key-{}", i).as_bytes());
+        }
+        for i in 0..1000 {
+            assert!(bloom.may_contain(std::format!("key-{}", i).as_bytes()));
+        }
+
+        let false_positives = (1000..11000)
+            .filter(|i| bloom.may_contain(std::format!("key-{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300);
+
+        let decoded = BloomFilter::decode(&bloom.encode()).unwrap();
+        assert!(decoded.may_contain(b"key-1"));
+        assert!(BloomFilter::decode(b"bad").is_none());
+
+        // Filters hashed by another function are not decoded.
+        let mut encoded = bloom.encode();
+        encoded[0] = BLOOM_FORMAT_XXH64 + 1;
+        assert!(BloomFilter::decode(&encoded).is_none());
+    }
+
+    #[test]
+    fn test_bloom_filter_stable_hash() {
+        // The bits of an item never change across releases, as filters are persisted.
+        let mut bloom = BloomFilter::new(64, 2);
+        bloom.insert(b"yorick");
+        assert_eq!(bloom.bits, vec![(1 << 52) | (1 << 1)]);
+    }
+}
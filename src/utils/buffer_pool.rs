@@ -0,0 +1,112 @@
+//! Thread-local pool of the byte buffers used to encode and read records. The hot paths take a
+//! buffer from the pool and give it back once done, so its allocation is reused by the next
+//! operation of the thread instead of being freed and allocated again.
+
+use std::{
+    cell::RefCell,
+    ops::{Deref, DerefMut},
+};
+
+/// Number of buffers kept by the pool of each thread.
+const MAX_POOLED_BUFFERS: usize = 8;
+
+/// Buffers that have grown larger than this are freed instead of being pooled, so a single large
+/// value does not pin its memory for the lifetime of the thread.
+const MAX_POOLED_CAPACITY: usize = 1024 * 1024;
+
+/// Zeros copied into the buffers to be read into, which is much faster than `Vec::resize` in
+/// unoptimized builds.
+static ZEROS: [u8; 4096] = [0; 4096];
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An empty buffer taken from the pool of the current thread, given back when dropped.
+pub(crate) struct PooledBuffer {
+    buf: Vec<u8>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if self.buf.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        // The pool is gone if the thread is exiting, the buffer is freed then.
+        let _ = POOL.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < MAX_POOLED_BUFFERS {
+                pool.push(buf);
+            }
+        });
+    }
+}
+
+/// Take an empty buffer from the pool of the current thread, or a new one if the pool is empty.
+pub(crate) fn take_buffer() -> PooledBuffer {
+    let buf = POOL
+        .try_with(|pool| pool.borrow_mut().pop())
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    PooledBuffer { buf }
+}
+
+/// Take a buffer of LEN zeroed bytes from the pool of the current thread, to be read into.
+pub(crate) fn take_zeroed_buffer(len: usize) -> PooledBuffer {
+    let mut buf = take_buffer();
+    buf.reserve(len);
+    while buf.len() < len {
+        let n = (len - buf.len()).min(ZEROS.len());
+        buf.extend_from_slice(&ZEROS[..n]);
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        let mut buf = take_buffer();
+        buf.extend_from_slice(b"rosencrantz");
+        let ptr = buf.as_ptr();
+        drop(buf);
+
+        // The allocation of the buffer given back is reused, emptied.
+        let buf = take_zeroed_buffer(4);
+        assert_eq!(buf.as_slice(), &[0; 4]);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        // Buffers in use are never shared.
+        let other = take_buffer();
+        assert!(other.is_empty());
+        assert_ne!(other.as_ptr(), ptr);
+        drop((buf, other));
+
+        // Buffers grown too large are not pooled.
+        let mut buf = take_buffer();
+        buf.reserve(MAX_POOLED_CAPACITY + 1);
+        drop(buf);
+        for _ in 0..MAX_POOLED_BUFFERS + 1 {
+            assert!(take_buffer().capacity() <= MAX_POOLED_CAPACITY);
+        }
+    }
+}
@@ -1,2 +1,5 @@
+pub mod bloom;
+pub mod buffer_pool;
 pub mod file;
 pub mod rand_kv;
+pub mod time;
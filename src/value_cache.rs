@@ -0,0 +1,197 @@
+//! LRU cache of the values read from the data files, enabled by `Options::cache_size`. Values
+//! are cached by the position of their record, which never changes once written, so a cached
+//! value is never stale. Positions superseded by a put or a delete are evicted right away to
+//! leave room for live values, as are the positions of the files removed by a partial merge.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use bytes::Bytes;
+
+use crate::data::log_record::LogRecordPos;
+
+/// Bytes accounted for each cached value on top of the value itself.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// A cached value, where `expire_at` is the time the entry expires in milliseconds since the
+/// epoch, and `tick` orders the entries from the least recently used.
+struct CacheEntry {
+    value: Bytes,
+    expire_at: Option<u64>,
+    tick: u64,
+}
+
+/// The cached values by file id and offset, along with:
+/// - `lru` maps the tick of each entry to its position, from the least recently used.
+/// - `size` is the number of bytes accounted for all entries.
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<(u64, u64), CacheEntry>,
+    lru: BTreeMap<u64, (u64, u64)>,
+    next_tick: u64,
+    size: usize,
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &(u64, u64)) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.size -= entry.value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+/// LRU cache of values holding at most `capacity` bytes.
+pub(crate) struct ValueCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the value cached for POS, unless it has expired at NOW milliseconds since the epoch.
+    pub(crate) fn get(&self, pos: &LogRecordPos, now: u64) -> Option<Bytes> {
+        let key = (pos.file_id, pos.ofs);
+        let mut state = self.state.lock().unwrap();
+        let tick = state.next_tick;
+        let value = match state.entries.get_mut(&key) {
+            Some(entry) if entry.expire_at.is_none_or(|expire_at| expire_at > now) => {
+                let old_tick = entry.tick;
+                entry.tick = tick;
+                Some((entry.value.clone(), old_tick))
+            }
+            _ => None,
+        };
+        let value = match value {
+            Some((value, old_tick)) => {
+                state.next_tick += 1;
+                state.lru.remove(&old_tick);
+                state.lru.insert(tick, key);
+                Some(value)
+            }
+            None => {
+                // Expired entries are never read again.
+                state.remove(&key);
+                None
+            }
+        };
+        match value.is_some() {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        value
+    }
+
+    /// Cache VALUE read at POS, which expires at EXPIRE_AT, evicting the least recently used
+    /// values beyond the capacity. Values larger than the capacity are not cached.
+    pub(crate) fn insert(&self, pos: &LogRecordPos, value: Bytes, expire_at: Option<u64>) {
+        let entry_size = value.len() + ENTRY_OVERHEAD;
+        if entry_size > self.capacity {
+            return;
+        }
+        let key = (pos.file_id, pos.ofs);
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+        while state.size + entry_size > self.capacity {
+            let (_, lru_key) = state.lru.pop_first().unwrap();
+            state.remove(&lru_key);
+        }
+        let tick = state.next_tick;
+        state.next_tick += 1;
+        state.lru.insert(tick, key);
+        state.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                expire_at,
+                tick,
+            },
+        );
+        state.size += entry_size;
+    }
+
+    /// Evict the value cached for POS.
+    pub(crate) fn remove(&self, pos: &LogRecordPos) {
+        self.state.lock().unwrap().remove(&(pos.file_id, pos.ofs));
+    }
+
+    /// Evict the values cached for the data files FILE_IDS.
+    pub(crate) fn remove_files(&self, file_ids: &[u64]) {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<(u64, u64)> = state
+            .entries
+            .keys()
+            .filter(|(file_id, _)| file_ids.contains(file_id))
+            .copied()
+            .collect();
+        for key in keys {
+            state.remove(&key);
+        }
+    }
+
+    /// Get the number of lookups answered by the cache, and the number of those missing it.
+    pub(crate) fn hits_and_misses(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(file_id: u64, ofs: u64) -> LogRecordPos {
+        LogRecordPos {
+            file_id,
+            ofs,
+            size: 0,
+            expire_at: None,
+        }
+    }
+
+    #[test]
+    fn test_value_cache() {
+        let cache = ValueCache::new(3 * (ENTRY_OVERHEAD + 10));
+        for i in 0..3 {
+            cache.insert(&pos(0, i), Bytes::from(vec![i as u8; 10]), None);
+        }
+        assert_eq!(cache.get(&pos(0, 0), 0), Some(Bytes::from(vec![0; 10])));
+
+        // The least recently used value is evicted to make room.
+        cache.insert(&pos(1, 0), Bytes::from(vec![3; 10]), Some(100));
+        assert_eq!(cache.get(&pos(0, 1), 0), None);
+        assert!(cache.get(&pos(0, 0), 0).is_some());
+        assert!(cache.get(&pos(0, 2), 0).is_some());
+
+        // Expired values are not returned.
+        assert!(cache.get(&pos(1, 0), 99).is_some());
+        assert_eq!(cache.get(&pos(1, 0), 100), None);
+
+        cache.remove(&pos(0, 0));
+        assert_eq!(cache.get(&pos(0, 0), 0), None);
+        cache.remove_files(&[0]);
+        assert_eq!(cache.get(&pos(0, 2), 0), None);
+        assert_eq!(cache.hits_and_misses(), (4, 4));
+
+        // Values larger than the capacity are never cached.
+        cache.insert(&pos(2, 0), Bytes::from(vec![0; 1024]), None);
+        assert_eq!(cache.get(&pos(2, 0), 0), None);
+    }
+}
@@ -0,0 +1,332 @@
+//! Integrity verification of a live engine. The quick mode checks that every index entry resolves
+//! to a readable live record with the same key, and that the blob file of a large value is intact,
+//! while the full mode additionally decodes and CRC-checks every record stored in the data files,
+//! and checks that the footers of the sealed files match their records.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    blob::decode_blob_record,
+    data::{
+        data_file::DataFile,
+        footer::{DataFileFooter, FooterBuilder},
+    },
+    db::{parse_log_record_key, Engine},
+    errors::Errors,
+    options::IteratorOptions,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyMode {
+    /// Only check the records referenced by the index.
+    Quick,
+
+    /// Check the records referenced by the index, and every record stored in the data files.
+    Full,
+}
+
+/// An inconsistency found by `Engine::verify_integrity`.
+#[derive(Debug, PartialEq)]
+pub enum IntegrityIssue {
+    /// The index entry KEY points to a record that cannot be read.
+    UnreadableRecord {
+        key: Vec<u8>,
+        file_id: u64,
+        ofs: u64,
+        error: Errors,
+    },
+
+    /// The index entry KEY points to a record with another key.
+    KeyMismatch {
+        key: Vec<u8>,
+        file_id: u64,
+        ofs: u64,
+        record_key: Vec<u8>,
+    },
+
+    /// The index entry KEY points to a record which is not a normal record.
+    NotLiveRecord {
+        key: Vec<u8>,
+        file_id: u64,
+        ofs: u64,
+    },
+
+    /// The record at offset OFS of file FILE_ID fails decoding or the CRC check. The remaining
+    /// records of the file are only checked if the header of the record is intact, otherwise
+    /// their offsets cannot be trusted.
+    CorruptedRecord {
+        file_id: u64,
+        ofs: u64,
+        error: Errors,
+    },
+
+    /// The footer of the sealed file FILE_ID does not summarize its records, which are changed
+    /// after the file is sealed.
+    FooterMismatch { file_id: u64 },
+}
+
+/// The result of `Engine::verify_integrity`.
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    /// Number of index entries checked.
+    pub index_entry_num: usize,
+
+    /// Number of records checked in the data files, always 0 in quick mode.
+    pub record_num: usize,
+
+    /// All inconsistencies found.
+    pub issues: Vec<IntegrityIssue>,
+}
+
+impl IntegrityReport {
+    /// Whether no inconsistency is found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Number of corrupted records found in each data file.
+    pub fn bad_records_per_file(&self) -> BTreeMap<u64, usize> {
+        let mut bad_records = BTreeMap::new();
+        for issue in &self.issues {
+            if let IntegrityIssue::CorruptedRecord { file_id, .. } = issue {
+                *bad_records.entry(*file_id).or_default() += 1;
+            }
+        }
+        bad_records
+    }
+
+    /// Issues of the index entries which do not point to their live record.
+    pub fn dangling_entries(&self) -> Vec<&IntegrityIssue> {
+        self.issues
+            .iter()
+            .filter(|issue| {
+                !matches!(
+                    issue,
+                    IntegrityIssue::CorruptedRecord { .. } | IntegrityIssue::FooterMismatch { .. }
+                )
+            })
+            .collect()
+    }
+}
+
+impl Engine {
+    /// Verify the integrity of the engine according to MODE. The engine remains usable during the
+    /// verification, and entries written meanwhile may or may not be checked.
+    pub fn verify_integrity(&self, mode: VerifyMode) -> IntegrityReport {
+        let mut report = IntegrityReport::default();
+        self.verify_index(&mut report);
+        if mode == VerifyMode::Full {
+            self.verify_data_files(&mut report);
+        }
+        report
+    }
+
+    /// Fully verify the integrity of the engine, same as `verify_integrity(VerifyMode::Full)`.
+    pub fn verify(&self) -> IntegrityReport {
+        self.verify_integrity(VerifyMode::Full)
+    }
+
+    /// Check that every index entry points to a live record with the same key.
+    fn verify_index(&self, report: &mut IntegrityReport) {
+        let mut index_iter = self.index.iterator(IteratorOptions::default());
+        while let Some((key, pos)) = index_iter.next() {
+            report.index_entry_num += 1;
+
+            let active_file = self.active_file.read().unwrap();
+            let old_files = self.old_files();
+            let log_record = match self.read_log_record_at(&active_file, &old_files, pos, true) {
+                Ok(log_record) => log_record,
+                Err(error) => {
+                    report.issues.push(IntegrityIssue::UnreadableRecord {
+                        key: key.clone(),
+                        file_id: pos.file_id,
+                        ofs: pos.ofs,
+                        error,
+                    });
+                    continue;
+                }
+            };
+
+            let (record_key, _) = parse_log_record_key(&log_record.key);
+            if record_key != *key {
+                report.issues.push(IntegrityIssue::KeyMismatch {
+                    key: key.clone(),
+                    file_id: pos.file_id,
+                    ofs: pos.ofs,
+                    record_key,
+                });
+            } else if !log_record.record_type.is_value() {
+                report.issues.push(IntegrityIssue::NotLiveRecord {
+                    key: key.clone(),
+                    file_id: pos.file_id,
+                    ofs: pos.ofs,
+                });
+            } else if let Some(blob_ref) = decode_blob_record(&log_record) {
+                if let Err(error) = self.blob_store.read(&blob_ref) {
+                    report.issues.push(IntegrityIssue::UnreadableRecord {
+                        key: key.clone(),
+                        file_id: pos.file_id,
+                        ofs: pos.ofs,
+                        error,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Decode and CRC-check every record of all data files.
+    fn verify_data_files(&self, report: &mut IntegrityReport) {
+        let old_files = self.old_files();
+        let mut file_ids: Vec<u64> = old_files.keys().copied().collect();
+        file_ids.sort();
+        for file_id in file_ids {
+            let data_file = old_files.get(&file_id).unwrap();
+            let was_open = data_file.is_open();
+            verify_data_file(report, data_file, u64::MAX);
+            verify_footer(report, data_file);
+
+            // Do not keep the files opened only for verification.
+            if !was_open {
+                data_file.close_io();
+            }
+        }
+
+        // Writes are blocked while checking the active file, which is never larger than
+        // `data_file_size`.
+        let active_file = self.active_file.read().unwrap();
+        if !old_files.contains_key(&active_file.get_file_id()) {
+            verify_data_file(report, &active_file, active_file.get_write_ofs());
+        }
+    }
+}
+
+/// Check that the footer of the sealed DATA_FILE, if any, matches its records. Files with
+/// corrupted records are already reported.
+fn verify_footer(report: &mut IntegrityReport, data_file: &DataFile) {
+    let footer = match DataFileFooter::read(data_file) {
+        Some(footer) => footer,
+        None => return,
+    };
+    let end_ofs = data_file.get_end_ofs().unwrap_or(footer.data_size);
+    if let Ok(summary) = FooterBuilder::scan(data_file, end_ofs) {
+        if summary.finish() != footer {
+            report.issues.push(IntegrityIssue::FooterMismatch {
+                file_id: data_file.get_file_id(),
+            });
+        }
+    }
+}
+
+/// Check the records of DATA_FILE before offset END_OFS.
+fn verify_data_file(report: &mut IntegrityReport, data_file: &DataFile, end_ofs: u64) {
+    let mut ofs = 0;
+    while ofs < end_ofs {
+        match data_file.read_log_record(ofs) {
+            Ok((_, size)) => {
+                report.record_num += 1;
+                ofs += size as u64;
+            }
+            Err(Errors::ReadDataFileEOF) => break,
+            Err(error) => {
+                // A record failing only the CRC check has a trustworthy size, so the next records
+                // can still be checked.
+                let size = match error {
+                    Errors::InvalidLogRecordCRC => data_file.read_log_record_size(ofs).ok(),
+                    _ => None,
+                };
+                report.issues.push(IntegrityIssue::CorruptedRecord {
+                    file_id: data_file.get_file_id(),
+                    ofs,
+                    error,
+                });
+                match size {
+                    Some(size) => ofs += size as u64,
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::{Seek, SeekFrom, Write},
+        path::PathBuf,
+    };
+
+    use crate::{
+        data::data_file::get_data_file_name,
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_verify_integrity() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-verify-integrity");
+        opts.data_file_size = 32 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..100 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+
+        let report = engine.verify_integrity(VerifyMode::Quick);
+        assert!(report.is_ok());
+        assert_eq!(report.index_entry_num, 1900);
+        assert_eq!(report.record_num, 0);
+
+        let report = engine.verify_integrity(VerifyMode::Full);
+        assert!(report.is_ok());
+        assert_eq!(report.record_num, 2100);
+
+        // Corrupt the value of a record in the middle of the first data file.
+        let first_record_size = engine.index.get(get_test_key(100).to_vec()).unwrap().size;
+        let corrupted_ofs = 200 * first_record_size as u64;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(get_data_file_name(&opts.dir_path, 1))
+            .unwrap();
+        file.seek(SeekFrom::Start(corrupted_ofs + 20)).unwrap();
+        file.write_all(b"corrupted").unwrap();
+        std::mem::drop(file);
+
+        let report = engine.verify_integrity(VerifyMode::Quick);
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(
+            report.issues[0],
+            IntegrityIssue::UnreadableRecord {
+                key: get_test_key(200).to_vec(),
+                file_id: 1,
+                ofs: corrupted_ofs,
+                error: Errors::InvalidLogRecordCRC,
+            }
+        );
+
+        let report = engine.verify_integrity(VerifyMode::Full);
+        assert_eq!(report.issues.len(), 2);
+        assert_eq!(
+            report.issues[1],
+            IntegrityIssue::CorruptedRecord {
+                file_id: 1,
+                ofs: corrupted_ofs,
+                error: Errors::InvalidLogRecordCRC,
+            }
+        );
+
+        // The records after a record failing the CRC check are still checked.
+        let report = engine.verify();
+        assert_eq!(report.record_num, 2099);
+        assert_eq!(report.bad_records_per_file(), BTreeMap::from([(1, 1)]));
+        assert_eq!(report.dangling_entries(), vec![&report.issues[0]]);
+
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
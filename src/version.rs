@@ -0,0 +1,243 @@
+//! Optimistic concurrency. The version of an entry is the position of its latest record, which
+//! is already kept by the index, so versions cost nothing to record and increase with every
+//! write. A writer reads the version of a key, and later writes conditionally on the key not
+//! having been written since. Writers without a version at hand compare the values instead, by
+//! `Engine::compare_and_swap`.
+//!
+//! Versions are only meaningful within one run of the engine, since merged files are installed
+//! on startup and move the records.
+
+use bytes::Bytes;
+
+use crate::{
+    data::log_record::LogRecordPos,
+    db::Engine,
+    errors::{Errors, Result},
+};
+
+/// The version of an entry, later writes get greater versions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    file_id: u64,
+    ofs: u64,
+}
+
+impl From<&LogRecordPos> for Version {
+    fn from(pos: &LogRecordPos) -> Self {
+        Self {
+            file_id: pos.file_id,
+            ofs: pos.ofs,
+        }
+    }
+}
+
+impl Engine {
+    /// Write the pair (KEY, VALUE) into the database, return the version of the entry.
+    pub fn put_versioned(&self, key: Bytes, value: Bytes) -> Result<Version> {
+        let _key_lock = self.key_locks.lock(&key);
+        self.put_record(key, value).map(|pos| Version::from(&pos))
+    }
+
+    /// Get the current version of the entry with key KEY.
+    pub fn get_version(&self, key: Bytes) -> Result<Version> {
+        self.index
+            .get(key.to_vec())
+            .map(|pos| Version::from(&pos))
+            .ok_or(Errors::KeyNotFound)
+    }
+
+    /// Write the pair (KEY, VALUE) if the version of KEY is still EXPECTED, where None expects
+    /// the key to be absent. Fail with `Errors::VersionMismatch` otherwise. Return the new version
+    /// of the entry.
+    ///
+    /// The check is atomic with respect to all other writes of the key, which wait for it, except
+    /// for `bulk_load`.
+    pub fn put_with_version(
+        &self,
+        key: Bytes,
+        value: Bytes,
+        expected: Option<Version>,
+    ) -> Result<Version> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let _key_lock = self.key_locks.lock(&key);
+        let current = self.index.get(key.to_vec()).map(|pos| Version::from(&pos));
+        if current != expected {
+            return Err(Errors::VersionMismatch);
+        }
+        self.put_record(key, value).map(|pos| Version::from(&pos))
+    }
+
+    /// Set KEY to NEW if its current value is EXPECTED, where None stands for an absent key on
+    /// either side, so NEW being None deletes the key. Fail with `Errors::ValueMismatch`
+    /// otherwise.
+    ///
+    /// Same as `put_with_version`, the check is atomic with respect to all other writes of the
+    /// key, except for `bulk_load`. The key is locked while its value is read, while the writes
+    /// of other keys are not blocked.
+    pub fn compare_and_swap(
+        &self,
+        key: Bytes,
+        expected: Option<Bytes>,
+        new: Option<Bytes>,
+    ) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let _key_lock = self.key_locks.lock(&key);
+        let current = match self.get(key.clone()) {
+            Ok(value) => Some(value),
+            Err(Errors::KeyNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if current != expected {
+            return Err(Errors::ValueMismatch);
+        }
+        match new {
+            Some(value) => self.put_record(key, value).map(|_| ()),
+            None => self.delete_record(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        options::Options,
+        utils::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_engine_put_with_version() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-version");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let v1 = engine
+            .put_with_version(get_test_key(0), get_test_value(0), None)
+            .unwrap();
+        assert_eq!(engine.get_version(get_test_key(0)).unwrap(), v1);
+        assert_eq!(
+            engine
+                .put_with_version(get_test_key(0), get_test_value(1), None)
+                .err(),
+            Some(Errors::VersionMismatch)
+        );
+
+        let v2 = engine
+            .put_with_version(get_test_key(0), get_test_value(2), Some(v1))
+            .unwrap();
+        assert!(v2 > v1);
+        assert_eq!(engine.get(get_test_key(0)).unwrap(), get_test_value(2));
+
+        // A write since the version was read makes the conditional write fail.
+        let v3 = engine
+            .put_versioned(get_test_key(0), get_test_value(3))
+            .unwrap();
+        assert!(v3 > v2);
+        assert_eq!(
+            engine
+                .put_with_version(get_test_key(0), get_test_value(4), Some(v2))
+                .err(),
+            Some(Errors::VersionMismatch)
+        );
+        assert_eq!(engine.get(get_test_key(0)).unwrap(), get_test_value(3));
+
+        assert!(engine.delete(get_test_key(0)).is_ok());
+        assert_eq!(
+            engine.get_version(get_test_key(0)).err(),
+            Some(Errors::KeyNotFound)
+        );
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_compare_and_swap() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-compare-and-swap");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let key = get_test_key(0);
+        assert!(engine
+            .compare_and_swap(key.clone(), None, Some(get_test_value(1)))
+            .is_ok());
+        assert_eq!(
+            engine
+                .compare_and_swap(key.clone(), None, Some(get_test_value(2)))
+                .err(),
+            Some(Errors::ValueMismatch)
+        );
+        assert!(engine
+            .compare_and_swap(
+                key.clone(),
+                Some(get_test_value(1)),
+                Some(get_test_value(2))
+            )
+            .is_ok());
+        assert_eq!(engine.get(key.clone()).unwrap(), get_test_value(2));
+
+        assert_eq!(
+            engine
+                .compare_and_swap(key.clone(), Some(get_test_value(1)), None)
+                .err(),
+            Some(Errors::ValueMismatch)
+        );
+        assert!(engine
+            .compare_and_swap(key.clone(), Some(get_test_value(2)), None)
+            .is_ok());
+        assert_eq!(engine.get(key).err(), Some(Errors::KeyNotFound));
+        std::mem::drop(engine);
+
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_compare_and_swap_interleaving() {
+        let mut opts = Options::default();
+        opts.dir_path = PathBuf::from("/tmp/bitcask-rs-cas-interleaving");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let key = get_test_key(0);
+        assert!(engine.put(key.clone(), get_test_value(0)).is_ok());
+
+        // Plain writes of the key wait for a compare-and-swap holding its lock, so they are never
+        // overwritten by one that has checked the value before them.
+        let writes: [&(dyn Fn() -> Result<()> + Sync); 2] =
+            [&|| engine.put(key.clone(), get_test_value(1)), &|| {
+                engine.delete(key.clone())
+            }];
+        for write in writes {
+            let key_lock = engine.key_locks.lock(&key);
+            let written = AtomicBool::new(false);
+            thread::scope(|s| {
+                s.spawn(|| {
+                    write().unwrap();
+                    written.store(true, Ordering::SeqCst);
+                });
+                thread::sleep(Duration::from_millis(50));
+                assert!(!written.load(Ordering::SeqCst));
+                assert!(engine.put(get_test_key(1), get_test_value(1)).is_ok());
+                std::mem::drop(key_lock);
+            });
+            assert!(written.load(Ordering::SeqCst));
+        }
+        assert_eq!(engine.get(key).err(), Some(Errors::KeyNotFound));
+
+        std::mem::drop(engine);
+        fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+    }
+}